@@ -0,0 +1,22 @@
+CREATE TABLE IF NOT EXISTS user_identities (
+  id SERIAL PRIMARY KEY NOT NULL,
+  user_id INT NOT NULL,
+  provider VARCHAR(64) NOT NULL,
+  subject VARCHAR(255) NOT NULL,
+  email VARCHAR(255),
+  email_verified BOOLEAN DEFAULT FALSE,
+  created_at TIMESTAMPTZ DEFAULT NOW(),
+  updated_at TIMESTAMPTZ DEFAULT NOW(),
+  UNIQUE (provider, subject),
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS user_identities_user_id_idx ON user_identities (user_id);
+
+CREATE TRIGGER set_updated_at
+BEFORE UPDATE ON user_identities
+FOR EACH ROW
+EXECUTE FUNCTION update_updated_at_column();
+
+-- refresh tokens have to go back to the provider that issued them
+ALTER TABLE refresh_tokens ADD COLUMN IF NOT EXISTS provider VARCHAR(64) NOT NULL DEFAULT 'google';
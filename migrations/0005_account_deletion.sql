@@ -0,0 +1,4 @@
+ALTER TABLE users ADD COLUMN IF NOT EXISTS deletion_requested_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS users_deletion_requested_idx ON users (deletion_requested_at)
+WHERE deletion_requested_at IS NOT NULL AND deleted = FALSE;
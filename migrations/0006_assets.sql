@@ -0,0 +1,21 @@
+-- stored objects are keyed by the sha256 of their content, identical uploads share one blob
+CREATE TABLE IF NOT EXISTS asset_blobs (
+  hash CHAR(64) PRIMARY KEY NOT NULL,
+  content_type VARCHAR(64) NOT NULL,
+  size BIGINT NOT NULL,
+  width INT NOT NULL,
+  height INT NOT NULL,
+  ref_count INT NOT NULL DEFAULT 0,
+  created_at TIMESTAMPTZ DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS assets (
+  id CHAR(12) PRIMARY KEY NOT NULL,
+  user_id INT NOT NULL,
+  name VARCHAR(255) NOT NULL,
+  blob_hash CHAR(64) NOT NULL,
+  created_at TIMESTAMPTZ DEFAULT NOW(),
+  UNIQUE (user_id, blob_hash),
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+  FOREIGN KEY (blob_hash) REFERENCES asset_blobs(hash)
+);
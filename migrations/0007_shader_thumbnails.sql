@@ -0,0 +1 @@
+ALTER TABLE shaders ADD COLUMN IF NOT EXISTS thumbnails JSONB;
@@ -0,0 +1,20 @@
+-- a shader published as a library can be included by others as "username/name@revision"
+CREATE TABLE IF NOT EXISTS shader_libraries (
+  shader_id CHAR(6) PRIMARY KEY NOT NULL,
+  user_id UUID NOT NULL,
+  name VARCHAR(64) NOT NULL,
+  created_at TIMESTAMPTZ DEFAULT NOW(),
+  UNIQUE (user_id, name),
+  FOREIGN KEY (shader_id) REFERENCES shaders(id) ON DELETE CASCADE,
+  FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE
+);
+
+-- revisions are immutable snapshots so pinned includes never change under a shader
+CREATE TABLE IF NOT EXISTS shader_library_revisions (
+  shader_id CHAR(6) NOT NULL,
+  revision INT NOT NULL,
+  code TEXT NOT NULL,
+  created_at TIMESTAMPTZ DEFAULT NOW(),
+  PRIMARY KEY (shader_id, revision),
+  FOREIGN KEY (shader_id) REFERENCES shader_libraries(shader_id) ON DELETE CASCADE
+);
@@ -0,0 +1,170 @@
+-- templates without a user are the built-in ones, managed by admins
+CREATE TABLE IF NOT EXISTS shader_templates (
+  id VARCHAR(64) PRIMARY KEY NOT NULL,
+  user_id UUID,
+  name VARCHAR(255) NOT NULL,
+  description VARCHAR(8192) NOT NULL DEFAULT '',
+  data JSONB NOT NULL,
+  position INT NOT NULL DEFAULT 0,
+  created_at TIMESTAMPTZ DEFAULT NOW(),
+  updated_at TIMESTAMPTZ DEFAULT NOW(),
+  FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS shader_templates_user_idx ON shader_templates (user_id);
+
+CREATE TRIGGER set_updated_at
+BEFORE UPDATE ON shader_templates
+FOR EACH ROW
+EXECUTE FUNCTION update_updated_at_column();
+
+INSERT INTO shader_templates (id, name, description, data, position) VALUES
+(
+  'blank',
+  'Blank',
+  'A gradient over time, the smallest useful starting point.',
+  jsonb_build_object('code', $$void mainImage(out vec4 fragColor, in vec2 fragCoord) {
+  vec2 uv = fragCoord / iResolution.xy;
+  vec3 col = 0.5 + 0.5 * cos(iTime + uv.xyx + vec3(0.0, 2.0, 4.0));
+  fragColor = vec4(col, 1.0);
+}
+$$),
+  0
+),
+(
+  'raymarcher',
+  'Raymarcher',
+  'Sphere tracing of a signed distance field with a camera, normals and simple lighting.',
+  jsonb_build_object('code', $$float map(vec3 p) {
+  float sphere = length(p - vec3(0.0, 0.0, 0.0)) - 1.0;
+  float ground = p.y + 1.0;
+  return min(sphere, ground);
+}
+
+vec3 normal(vec3 p) {
+  vec2 e = vec2(0.001, 0.0);
+  return normalize(vec3(
+    map(p + e.xyy) - map(p - e.xyy),
+    map(p + e.yxy) - map(p - e.yxy),
+    map(p + e.yyx) - map(p - e.yyx)
+  ));
+}
+
+void mainImage(out vec4 fragColor, in vec2 fragCoord) {
+  vec2 uv = (2.0 * fragCoord - iResolution.xy) / iResolution.y;
+
+  vec3 ro = vec3(3.0 * sin(iTime * 0.3), 1.0, 3.0 * cos(iTime * 0.3));
+  vec3 forward = normalize(-ro);
+  vec3 right = normalize(cross(vec3(0.0, 1.0, 0.0), forward));
+  vec3 up = cross(forward, right);
+  vec3 rd = normalize(uv.x * right + uv.y * up + 1.5 * forward);
+
+  float t = 0.0;
+  for (int i = 0; i < 128; i++) {
+    float d = map(ro + rd * t);
+    if (d < 0.001 || t > 50.0) break;
+    t += d;
+  }
+
+  vec3 col = vec3(0.6, 0.7, 0.9) - rd.y * 0.3;
+  if (t < 50.0) {
+    vec3 p = ro + rd * t;
+    vec3 light = normalize(vec3(0.6, 0.8, 0.4));
+    col = vec3(0.9, 0.8, 0.7) * max(dot(normal(p), light), 0.05);
+  }
+
+  fragColor = vec4(pow(col, vec3(0.4545)), 1.0);
+}
+$$),
+  1
+),
+(
+  'sdf-2d',
+  '2D SDF',
+  'Distance field shapes in screen space, shaded with the classic distance bands.',
+  jsonb_build_object('code', $$float sdCircle(vec2 p, float r) {
+  return length(p) - r;
+}
+
+float sdBox(vec2 p, vec2 b) {
+  vec2 d = abs(p) - b;
+  return length(max(d, 0.0)) + min(max(d.x, d.y), 0.0);
+}
+
+void mainImage(out vec4 fragColor, in vec2 fragCoord) {
+  vec2 p = (2.0 * fragCoord - iResolution.xy) / iResolution.y;
+
+  float d = min(sdCircle(p - vec2(0.5 * sin(iTime), 0.0), 0.4), sdBox(p + vec2(0.5, 0.0), vec2(0.3)));
+
+  vec3 col = d > 0.0 ? vec3(0.9, 0.6, 0.3) : vec3(0.65, 0.85, 1.0);
+  col *= 1.0 - exp(-6.0 * abs(d));
+  col *= 0.8 + 0.2 * cos(150.0 * d);
+  col = mix(col, vec3(1.0), 1.0 - smoothstep(0.0, 0.01, abs(d)));
+
+  fragColor = vec4(col, 1.0);
+}
+$$),
+  2
+),
+(
+  'post-process',
+  'Post-process',
+  'Renders a scene into a buffer and applies a vignette and chromatic aberration in the image pass.',
+  jsonb_build_object(
+    'code', $$void mainImage(out vec4 fragColor, in vec2 fragCoord) {
+  vec2 uv = fragCoord / iResolution.xy;
+  vec2 offset = (uv - 0.5) * 0.01;
+
+  vec3 col = vec3(
+    texture(iChannel0, uv + offset).r,
+    texture(iChannel0, uv).g,
+    texture(iChannel0, uv - offset).b
+  );
+
+  col *= 1.0 - 0.6 * dot(uv - 0.5, uv - 0.5);
+  fragColor = vec4(col, 1.0);
+}
+$$,
+    'channels', jsonb_build_array(jsonb_build_object('channel', 0, 'type', 'buffer', 'pass', 'Scene')),
+    'passes', jsonb_build_array(jsonb_build_object(
+      'name', 'Scene',
+      'kind', 'buffer',
+      'code', $$void mainImage(out vec4 fragColor, in vec2 fragCoord) {
+  vec2 uv = fragCoord / iResolution.xy;
+  float stripes = step(0.5, fract(10.0 * (uv.x + uv.y) + iTime));
+  fragColor = vec4(mix(vec3(0.1, 0.2, 0.4), vec3(1.0, 0.8, 0.4), stripes), 1.0);
+}
+$$
+    ))
+  ),
+  3
+),
+(
+  'feedback',
+  'Multi-pass feedback',
+  'A buffer that reads its own previous frame, the base for trails, simulations and accumulation.',
+  jsonb_build_object(
+    'code', $$void mainImage(out vec4 fragColor, in vec2 fragCoord) {
+  fragColor = texture(iChannel0, fragCoord / iResolution.xy);
+}
+$$,
+    'channels', jsonb_build_array(jsonb_build_object('channel', 0, 'type', 'buffer', 'pass', 'Feedback')),
+    'passes', jsonb_build_array(jsonb_build_object(
+      'name', 'Feedback',
+      'kind', 'buffer',
+      'code', $$void mainImage(out vec4 fragColor, in vec2 fragCoord) {
+  vec2 uv = fragCoord / iResolution.xy;
+  vec4 previous = texture(iChannel0, uv);
+
+  vec2 center = 0.5 + 0.3 * vec2(cos(iTime), sin(1.3 * iTime));
+  float spot = smoothstep(0.03, 0.0, length((uv - center) * vec2(iResolution.x / iResolution.y, 1.0)));
+
+  fragColor = iFrame == 0 ? vec4(0.0) : max(previous * 0.98, vec4(spot));
+}
+$$,
+      'channels', jsonb_build_array(jsonb_build_object('channel', 0, 'type', 'buffer', 'pass', 'Feedback'))
+    ))
+  ),
+  4
+)
+ON CONFLICT (id) DO NOTHING;
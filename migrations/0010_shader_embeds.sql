@@ -0,0 +1,3 @@
+-- an empty list lets any site frame the embed player
+ALTER TABLE shaders ADD COLUMN IF NOT EXISTS embed_enabled BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE shaders ADD COLUMN IF NOT EXISTS embed_origins TEXT[] NOT NULL DEFAULT '{}';
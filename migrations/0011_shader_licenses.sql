@@ -0,0 +1,7 @@
+-- SPDX identifiers, shaders written before licenses existed get Shadertoy's
+ALTER TABLE users ADD COLUMN IF NOT EXISTS default_license VARCHAR(64) NOT NULL DEFAULT 'CC-BY-NC-SA-3.0';
+
+ALTER TABLE shaders ADD COLUMN IF NOT EXISTS license VARCHAR(64) NOT NULL DEFAULT 'CC-BY-NC-SA-3.0';
+-- upstream shaders the code was copied from, oldest first
+ALTER TABLE shaders ADD COLUMN IF NOT EXISTS attribution JSONB NOT NULL DEFAULT '[]';
+ALTER TABLE shaders ADD COLUMN IF NOT EXISTS forked_from CHAR(6) REFERENCES shaders(id) ON DELETE SET NULL;
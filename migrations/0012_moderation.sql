@@ -0,0 +1,47 @@
+CREATE TYPE report_target AS ENUM('shader', 'user');
+CREATE TYPE report_reason AS ENUM('spam', 'harassment', 'nsfw', 'copyright', 'malicious', 'other');
+CREATE TYPE report_status AS ENUM('open', 'actioned', 'dismissed');
+CREATE TYPE moderation_action AS ENUM('hide_shader', 'unhide_shader', 'force_private', 'suspend_user', 'dismiss_report');
+
+-- target_id is the shader id or the public user_id of the reported user
+CREATE TABLE IF NOT EXISTS reports (
+  id SERIAL PRIMARY KEY NOT NULL,
+  reporter_id INT NOT NULL,
+  target_type report_target NOT NULL,
+  target_id VARCHAR(64) NOT NULL,
+  reason report_reason NOT NULL,
+  details VARCHAR(2000) NOT NULL DEFAULT '',
+  status report_status NOT NULL DEFAULT 'open',
+  resolved_by INT,
+  resolved_at TIMESTAMPTZ,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  FOREIGN KEY (reporter_id) REFERENCES users(id) ON DELETE CASCADE,
+  FOREIGN KEY (resolved_by) REFERENCES users(id) ON DELETE SET NULL
+);
+
+-- a user can have one open report per target
+CREATE UNIQUE INDEX IF NOT EXISTS reports_open_idx ON reports (reporter_id, target_type, target_id) WHERE status = 'open';
+CREATE INDEX IF NOT EXISTS reports_queue_idx ON reports (status, created_at);
+CREATE INDEX IF NOT EXISTS reports_target_idx ON reports (target_type, target_id);
+
+-- append only, rows outlive the moderator's account
+CREATE TABLE IF NOT EXISTS moderation_actions (
+  id SERIAL PRIMARY KEY NOT NULL,
+  actor_id INT,
+  action moderation_action NOT NULL,
+  target_type report_target NOT NULL,
+  target_id VARCHAR(64) NOT NULL,
+  report_id INT,
+  reason VARCHAR(2000) NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  FOREIGN KEY (actor_id) REFERENCES users(id) ON DELETE SET NULL,
+  FOREIGN KEY (report_id) REFERENCES reports(id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS moderation_actions_target_idx ON moderation_actions (target_type, target_id, created_at);
+
+-- hidden shaders are only visible to their owner, locked ones can't leave private
+ALTER TABLE shaders ADD COLUMN IF NOT EXISTS hidden BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE shaders ADD COLUMN IF NOT EXISTS access_locked BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS suspended_at TIMESTAMPTZ;
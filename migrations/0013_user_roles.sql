@@ -0,0 +1,28 @@
+CREATE TYPE user_role AS ENUM('user', 'moderator', 'admin');
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS role user_role NOT NULL DEFAULT 'user';
+
+ALTER TYPE moderation_action ADD VALUE IF NOT EXISTS 'unsuspend_user';
+
+-- admins looking at things users can't see, like someone else's private shaders
+CREATE TABLE IF NOT EXISTS admin_audit_log (
+  id SERIAL PRIMARY KEY NOT NULL,
+  actor_id INT,
+  action VARCHAR(64) NOT NULL,
+  target_type VARCHAR(32) NOT NULL,
+  target_id VARCHAR(64) NOT NULL,
+  details JSONB NOT NULL DEFAULT '{}',
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  FOREIGN KEY (actor_id) REFERENCES users(id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS admin_audit_log_target_idx ON admin_audit_log (target_type, target_id, created_at);
+
+CREATE TABLE IF NOT EXISTS featured_shaders (
+  shader_id CHAR(6) PRIMARY KEY NOT NULL,
+  position INT NOT NULL DEFAULT 0,
+  featured_by INT,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  FOREIGN KEY (shader_id) REFERENCES shaders(id) ON DELETE CASCADE,
+  FOREIGN KEY (featured_by) REFERENCES users(id) ON DELETE SET NULL
+);
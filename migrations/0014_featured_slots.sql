@@ -0,0 +1,13 @@
+-- featured shaders are pinned into named slots for a window of time
+ALTER TABLE featured_shaders DROP CONSTRAINT IF EXISTS featured_shaders_pkey;
+ALTER TABLE featured_shaders ADD COLUMN IF NOT EXISTS id SERIAL PRIMARY KEY NOT NULL;
+ALTER TABLE featured_shaders ADD COLUMN IF NOT EXISTS slot VARCHAR(64) NOT NULL DEFAULT 'front';
+ALTER TABLE featured_shaders ADD COLUMN IF NOT EXISTS starts_at TIMESTAMPTZ NOT NULL DEFAULT NOW();
+ALTER TABLE featured_shaders ADD COLUMN IF NOT EXISTS ends_at TIMESTAMPTZ;
+
+UPDATE featured_shaders SET starts_at = created_at;
+
+ALTER TABLE featured_shaders ADD CONSTRAINT featured_shaders_window_check CHECK (ends_at IS NULL OR ends_at > starts_at);
+
+CREATE UNIQUE INDEX IF NOT EXISTS featured_shaders_slot_shader_idx ON featured_shaders (slot, shader_id);
+CREATE INDEX IF NOT EXISTS featured_shaders_slot_position_idx ON featured_shaders (slot, position);
@@ -0,0 +1,40 @@
+-- append-only record of security and content events, actors aren't foreign keys so
+-- nothing done to users can rewrite history
+CREATE TABLE IF NOT EXISTS audit_events (
+  id BIGSERIAL PRIMARY KEY NOT NULL,
+  actor_id INT,
+  action VARCHAR(64) NOT NULL,
+  target_type VARCHAR(32) NOT NULL,
+  target_id VARCHAR(64) NOT NULL,
+  before JSONB,
+  after JSONB,
+  request_id VARCHAR(64),
+  ip_address INET,
+  user_agent TEXT,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS audit_events_created_at_idx ON audit_events (created_at);
+CREATE INDEX IF NOT EXISTS audit_events_target_idx ON audit_events (target_type, target_id, created_at);
+CREATE INDEX IF NOT EXISTS audit_events_actor_idx ON audit_events (actor_id, created_at);
+CREATE INDEX IF NOT EXISTS audit_events_action_idx ON audit_events (action, created_at);
+
+-- role changes are named like the other user actions now
+INSERT INTO audit_events (actor_id, action, target_type, target_id, after, created_at)
+  SELECT actor_id, CASE action WHEN 'change_role' THEN 'user_role_change' ELSE action END,
+    target_type, target_id, details, created_at
+  FROM admin_audit_log ORDER BY id;
+
+DROP TABLE IF EXISTS admin_audit_log;
+
+CREATE OR REPLACE FUNCTION reject_audit_event_change() RETURNS TRIGGER AS $$
+BEGIN
+  RAISE EXCEPTION 'audit_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_events_no_update BEFORE UPDATE OR DELETE ON audit_events
+  FOR EACH ROW EXECUTE FUNCTION reject_audit_event_change();
+
+CREATE TRIGGER audit_events_no_truncate BEFORE TRUNCATE ON audit_events
+  FOR EACH STATEMENT EXECUTE FUNCTION reject_audit_event_change();
@@ -0,0 +1,19 @@
+ALTER TABLE shaders ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+ALTER TABLE shaders ADD COLUMN IF NOT EXISTS purge_warned_at TIMESTAMPTZ;
+
+-- shaders trashed before we tracked when get the full retention period from now
+UPDATE shaders SET deleted_at = NOW() WHERE deleted = true AND deleted_at IS NULL;
+
+CREATE INDEX IF NOT EXISTS shaders_deleted_at_idx ON shaders (deleted_at) WHERE deleted = true;
+
+CREATE TABLE IF NOT EXISTS notifications (
+  id SERIAL PRIMARY KEY NOT NULL,
+  user_id INT NOT NULL,
+  kind VARCHAR(64) NOT NULL,
+  payload JSONB NOT NULL DEFAULT '{}',
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  read_at TIMESTAMPTZ,
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS notifications_user_id_idx ON notifications (user_id, created_at);
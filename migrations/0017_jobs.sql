@@ -0,0 +1,31 @@
+CREATE TYPE job_status AS ENUM('queued', 'running', 'done', 'dead');
+
+CREATE TABLE IF NOT EXISTS jobs (
+  id BIGSERIAL PRIMARY KEY NOT NULL,
+  kind VARCHAR(64) NOT NULL,
+  payload JSONB NOT NULL DEFAULT '{}',
+  status job_status NOT NULL DEFAULT 'queued',
+  attempts INT NOT NULL DEFAULT 0,
+  max_attempts INT NOT NULL,
+  run_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  locked_at TIMESTAMPTZ,
+  locked_by VARCHAR(64),
+  last_error TEXT,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  finished_at TIMESTAMPTZ
+);
+
+-- workers only ever look for queued jobs that are due
+CREATE INDEX IF NOT EXISTS jobs_queued_idx ON jobs (run_at, id) WHERE status = 'queued';
+CREATE INDEX IF NOT EXISTS jobs_running_idx ON jobs (locked_at) WHERE status = 'running';
+CREATE INDEX IF NOT EXISTS jobs_status_idx ON jobs (status, created_at);
+
+-- one row per recurring job, whoever moves next_run_at on enqueues the run
+CREATE TABLE IF NOT EXISTS job_schedules (
+  name VARCHAR(64) PRIMARY KEY NOT NULL,
+  kind VARCHAR(64) NOT NULL,
+  cron VARCHAR(128) NOT NULL,
+  payload JSONB NOT NULL DEFAULT '{}',
+  next_run_at TIMESTAMPTZ NOT NULL,
+  last_run_at TIMESTAMPTZ
+);
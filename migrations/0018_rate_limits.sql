@@ -0,0 +1,8 @@
+-- counters are worthless after a crash, no need to write them to the wal
+CREATE UNLOGGED TABLE IF NOT EXISTS rate_limits (
+  key TEXT PRIMARY KEY NOT NULL,
+  window_end TIMESTAMPTZ NOT NULL,
+  hits INT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS rate_limits_window_end_idx ON rate_limits (window_end);
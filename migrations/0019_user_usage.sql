@@ -0,0 +1,107 @@
+-- what every user has stored, kept up to date by the triggers below so quota checks
+-- don't have to add everything up on each write
+CREATE TABLE IF NOT EXISTS user_usage (
+  user_id INT PRIMARY KEY NOT NULL,
+  -- trashed shaders count until they're purged
+  shaders INT NOT NULL DEFAULT 0,
+  -- shader documents and library revisions
+  code_bytes BIGINT NOT NULL DEFAULT 0,
+  asset_bytes BIGINT NOT NULL DEFAULT 0,
+  revisions INT NOT NULL DEFAULT 0,
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE OR REPLACE FUNCTION add_user_usage(target INT, shader_delta INT, code_delta BIGINT, asset_delta BIGINT, revision_delta INT)
+RETURNS VOID AS $$
+BEGIN
+  IF target IS NULL THEN
+    RETURN;
+  END IF;
+
+  INSERT INTO user_usage AS usage (user_id, shaders, code_bytes, asset_bytes, revisions)
+  VALUES (target, shader_delta, code_delta, asset_delta, revision_delta)
+  ON CONFLICT (user_id) DO UPDATE SET
+    shaders = usage.shaders + EXCLUDED.shaders,
+    code_bytes = usage.code_bytes + EXCLUDED.code_bytes,
+    asset_bytes = usage.asset_bytes + EXCLUDED.asset_bytes,
+    revisions = usage.revisions + EXCLUDED.revisions,
+    updated_at = NOW();
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION track_shader_usage() RETURNS TRIGGER AS $$
+BEGIN
+  IF TG_OP IN ('UPDATE', 'DELETE') THEN
+    PERFORM add_user_usage((SELECT id FROM users WHERE user_id = OLD.user_id),
+      -1, -COALESCE(OCTET_LENGTH(OLD.data::TEXT), 0), 0, 0);
+  END IF;
+
+  IF TG_OP IN ('INSERT', 'UPDATE') THEN
+    PERFORM add_user_usage((SELECT id FROM users WHERE user_id = NEW.user_id),
+      1, COALESCE(OCTET_LENGTH(NEW.data::TEXT), 0), 0, 0);
+  END IF;
+
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER shaders_track_usage AFTER INSERT OR DELETE OR UPDATE OF data, user_id ON shaders
+  FOR EACH ROW EXECUTE FUNCTION track_shader_usage();
+
+CREATE OR REPLACE FUNCTION track_revision_usage() RETURNS TRIGGER AS $$
+BEGIN
+  PERFORM add_user_usage(
+    (SELECT users.id FROM shaders INNER JOIN users ON users.user_id = shaders.user_id WHERE shaders.id = NEW.shader_id),
+    0, OCTET_LENGTH(NEW.code), 0, 1);
+
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER shader_library_revisions_track_usage AFTER INSERT ON shader_library_revisions
+  FOR EACH ROW EXECUTE FUNCTION track_revision_usage();
+
+-- revisions only go away with their shader, by the time a cascade reaches them the shader
+-- that knows their owner is gone already
+CREATE OR REPLACE FUNCTION release_revision_usage() RETURNS TRIGGER AS $$
+BEGIN
+  PERFORM add_user_usage((SELECT id FROM users WHERE user_id = OLD.user_id), 0,
+    -(SELECT COALESCE(SUM(OCTET_LENGTH(code)), 0) FROM shader_library_revisions WHERE shader_id = OLD.id),
+    0, -(SELECT COUNT(*) FROM shader_library_revisions WHERE shader_id = OLD.id)::INT);
+
+  RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER shaders_release_revision_usage BEFORE DELETE ON shaders
+  FOR EACH ROW EXECUTE FUNCTION release_revision_usage();
+
+-- blobs outlive the assets pointing at them, so their size is still there on delete
+CREATE OR REPLACE FUNCTION track_asset_usage() RETURNS TRIGGER AS $$
+BEGIN
+  IF TG_OP = 'DELETE' THEN
+    PERFORM add_user_usage(OLD.user_id, 0, 0, -(SELECT size FROM asset_blobs WHERE hash = OLD.blob_hash), 0);
+  ELSE
+    PERFORM add_user_usage(NEW.user_id, 0, 0, (SELECT size FROM asset_blobs WHERE hash = NEW.blob_hash), 0);
+  END IF;
+
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER assets_track_usage AFTER INSERT OR DELETE ON assets
+  FOR EACH ROW EXECUTE FUNCTION track_asset_usage();
+
+INSERT INTO user_usage (user_id, shaders, code_bytes, asset_bytes, revisions)
+  SELECT users.id,
+    (SELECT COUNT(*) FROM shaders WHERE shaders.user_id = users.user_id),
+    (SELECT COALESCE(SUM(OCTET_LENGTH(data::TEXT)), 0) FROM shaders WHERE shaders.user_id = users.user_id)
+      + (SELECT COALESCE(SUM(OCTET_LENGTH(shader_library_revisions.code)), 0) FROM shader_library_revisions
+        INNER JOIN shaders ON shaders.id = shader_library_revisions.shader_id WHERE shaders.user_id = users.user_id),
+    (SELECT COALESCE(SUM(asset_blobs.size), 0) FROM assets
+      INNER JOIN asset_blobs ON asset_blobs.hash = assets.blob_hash WHERE assets.user_id = users.id),
+    (SELECT COUNT(*) FROM shader_library_revisions
+      INNER JOIN shaders ON shaders.id = shader_library_revisions.shader_id WHERE shaders.user_id = users.user_id)
+  FROM users
+ON CONFLICT (user_id) DO NOTHING;
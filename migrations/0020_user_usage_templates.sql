@@ -0,0 +1,112 @@
+-- templates are documents like shaders, thumbnails are stored like assets
+ALTER TABLE user_usage ADD COLUMN IF NOT EXISTS templates INT NOT NULL DEFAULT 0;
+
+DROP FUNCTION IF EXISTS add_user_usage(INT, INT, BIGINT, BIGINT, INT);
+
+CREATE OR REPLACE FUNCTION add_user_usage(target INT, shader_delta INT, template_delta INT, code_delta BIGINT, asset_delta BIGINT, revision_delta INT)
+RETURNS VOID AS $$
+BEGIN
+  IF target IS NULL THEN
+    RETURN;
+  END IF;
+
+  INSERT INTO user_usage AS usage (user_id, shaders, templates, code_bytes, asset_bytes, revisions)
+  VALUES (target, shader_delta, template_delta, code_delta, asset_delta, revision_delta)
+  ON CONFLICT (user_id) DO UPDATE SET
+    shaders = usage.shaders + EXCLUDED.shaders,
+    templates = usage.templates + EXCLUDED.templates,
+    code_bytes = usage.code_bytes + EXCLUDED.code_bytes,
+    asset_bytes = usage.asset_bytes + EXCLUDED.asset_bytes,
+    revisions = usage.revisions + EXCLUDED.revisions,
+    updated_at = NOW();
+END;
+$$ LANGUAGE plpgsql;
+
+-- thumbnails stored before their size was kept count as nothing
+CREATE OR REPLACE FUNCTION thumbnail_bytes(thumbnails JSONB) RETURNS BIGINT AS $$
+  SELECT COALESCE(SUM((image->>'bytes')::BIGINT), 0) FROM jsonb_array_elements(COALESCE(thumbnails->'images', '[]')) image;
+$$ LANGUAGE sql IMMUTABLE;
+
+CREATE OR REPLACE FUNCTION track_shader_usage() RETURNS TRIGGER AS $$
+BEGIN
+  IF TG_OP IN ('UPDATE', 'DELETE') THEN
+    PERFORM add_user_usage((SELECT id FROM users WHERE user_id = OLD.user_id),
+      -1, 0, -COALESCE(OCTET_LENGTH(OLD.data::TEXT), 0), -thumbnail_bytes(OLD.thumbnails), 0);
+  END IF;
+
+  IF TG_OP IN ('INSERT', 'UPDATE') THEN
+    PERFORM add_user_usage((SELECT id FROM users WHERE user_id = NEW.user_id),
+      1, 0, COALESCE(OCTET_LENGTH(NEW.data::TEXT), 0), thumbnail_bytes(NEW.thumbnails), 0);
+  END IF;
+
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS shaders_track_usage ON shaders;
+CREATE TRIGGER shaders_track_usage AFTER INSERT OR DELETE OR UPDATE OF data, user_id, thumbnails ON shaders
+  FOR EACH ROW EXECUTE FUNCTION track_shader_usage();
+
+CREATE OR REPLACE FUNCTION track_revision_usage() RETURNS TRIGGER AS $$
+BEGIN
+  PERFORM add_user_usage(
+    (SELECT users.id FROM shaders INNER JOIN users ON users.user_id = shaders.user_id WHERE shaders.id = NEW.shader_id),
+    0, 0, OCTET_LENGTH(NEW.code), 0, 1);
+
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION release_revision_usage() RETURNS TRIGGER AS $$
+BEGIN
+  PERFORM add_user_usage((SELECT id FROM users WHERE user_id = OLD.user_id), 0, 0,
+    -(SELECT COALESCE(SUM(OCTET_LENGTH(code)), 0) FROM shader_library_revisions WHERE shader_id = OLD.id),
+    0, -(SELECT COUNT(*) FROM shader_library_revisions WHERE shader_id = OLD.id)::INT);
+
+  RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION track_asset_usage() RETURNS TRIGGER AS $$
+BEGIN
+  IF TG_OP = 'DELETE' THEN
+    PERFORM add_user_usage(OLD.user_id, 0, 0, 0, -(SELECT size FROM asset_blobs WHERE hash = OLD.blob_hash), 0);
+  ELSE
+    PERFORM add_user_usage(NEW.user_id, 0, 0, 0, (SELECT size FROM asset_blobs WHERE hash = NEW.blob_hash), 0);
+  END IF;
+
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+-- built-in templates have no user and count against nobody
+CREATE OR REPLACE FUNCTION track_template_usage() RETURNS TRIGGER AS $$
+BEGIN
+  IF TG_OP IN ('UPDATE', 'DELETE') THEN
+    PERFORM add_user_usage((SELECT id FROM users WHERE user_id = OLD.user_id),
+      0, -1, -OCTET_LENGTH(OLD.data::TEXT), 0, 0);
+  END IF;
+
+  IF TG_OP IN ('INSERT', 'UPDATE') THEN
+    PERFORM add_user_usage((SELECT id FROM users WHERE user_id = NEW.user_id),
+      0, 1, OCTET_LENGTH(NEW.data::TEXT), 0, 0);
+  END IF;
+
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER shader_templates_track_usage AFTER INSERT OR DELETE OR UPDATE OF data, user_id ON shader_templates
+  FOR EACH ROW EXECUTE FUNCTION track_template_usage();
+
+UPDATE user_usage SET
+  templates = (SELECT COUNT(*) FROM shader_templates INNER JOIN users ON users.user_id = shader_templates.user_id
+    WHERE users.id = user_usage.user_id),
+  code_bytes = code_bytes + (SELECT COALESCE(SUM(OCTET_LENGTH(shader_templates.data::TEXT)), 0) FROM shader_templates
+    INNER JOIN users ON users.user_id = shader_templates.user_id WHERE users.id = user_usage.user_id);
+
+INSERT INTO user_usage (user_id, templates, code_bytes)
+  SELECT users.id, COUNT(*), SUM(OCTET_LENGTH(shader_templates.data::TEXT))
+  FROM shader_templates INNER JOIN users ON users.user_id = shader_templates.user_id
+  GROUP BY users.id
+ON CONFLICT (user_id) DO NOTHING;
@@ -0,0 +1,15 @@
+-- libraries keep their latest revisions only, pruned ones stop counting against their owner.
+-- when a whole shader goes the cascade finds it gone already, release_revision_usage has
+-- released its revisions by then
+CREATE OR REPLACE FUNCTION prune_revision_usage() RETURNS TRIGGER AS $$
+BEGIN
+  PERFORM add_user_usage(
+    (SELECT users.id FROM shaders INNER JOIN users ON users.user_id = shaders.user_id WHERE shaders.id = OLD.shader_id),
+    0, 0, -OCTET_LENGTH(OLD.code), 0, -1);
+
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER shader_library_revisions_prune_usage AFTER DELETE ON shader_library_revisions
+  FOR EACH ROW EXECUTE FUNCTION prune_revision_usage();
@@ -0,0 +1,602 @@
+use std::{collections::HashMap, io::{self, BufWriter, Cursor, Read, Write}};
+
+use axum::body::Bytes;
+use futures_util::Stream;
+use image::ImageFormat;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::{assets::{blob_key, storage::Storage, Asset}, env::ShaderLimits, errors::{ApiError, FieldError}, licenses::{find_license, validate_attribution, Attribution}, routes::{asset::MAX_ASSET_NAME_LENGTH, shader::{AccessLevel, ChannelBinding, PassKind, Shader, ShaderData, ShaderPass, MAX_CHANNELS, MAX_SHADER_DESCRIPTION_LENGTH, MAX_SHADER_NAME_LENGTH}}, validation::{Validate, Validator}};
+
+pub const ARCHIVE_VERSION: u32 = 1;
+pub const MANIFEST_FILE: &str = "manifest.json";
+pub const SHADER_FILE: &str = "shader.json";
+pub const IMAGE_FILE: &str = "image.glsl";
+pub const MAX_ARCHIVED_SHADERS: usize = 1000;
+
+const MAX_METADATA_BYTES: usize = 1024 * 1024;
+const MAX_INFLATED_BYTES: usize = 512 * 1024 * 1024;
+const WRITE_BUFFER_BYTES: usize = 64 * 1024;
+const CHANNEL_CAPACITY: usize = 8;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+  pub version: u32,
+  pub exported_at: chrono::DateTime<chrono::Utc>,
+  pub shaders: usize,
+  #[serde(default)]
+  pub author: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchivedImage {
+  pub file: String,
+  #[serde(default)]
+  pub channels: Vec<ChannelBinding>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchivedPass {
+  pub name: String,
+  pub kind: PassKind,
+  pub file: String,
+  #[serde(default)]
+  pub channels: Vec<ChannelBinding>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivedAsset {
+  pub id: String,
+  pub name: String,
+  pub content_type: String,
+  pub file: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchivedShader {
+  pub id: String,
+  pub name: String,
+  pub description: String,
+  pub access: AccessLevel,
+  #[serde(default)]
+  pub tags: Option<sqlx::types::JsonValue>,
+  pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+  #[serde(default)]
+  pub license: Option<String>,
+  #[serde(default)]
+  pub attribution: Vec<Attribution>,
+  pub image: ArchivedImage,
+  #[serde(default)]
+  pub passes: Vec<ArchivedPass>,
+  #[serde(default)]
+  pub assets: Vec<ArchivedAsset>,
+}
+
+pub struct ArchiveEntry {
+  pub path: String,
+  pub data: Vec<u8>,
+}
+
+impl ArchiveEntry {
+  pub fn json<T: Serialize>(path: String, value: &T) -> Self {
+    // our own types always serialize
+    let data = serde_json::to_vec_pretty(value).unwrap_or_default();
+    Self { path, data }
+  }
+}
+
+fn slug(name: &str) -> String {
+  let slug: String = name.chars()
+    .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+    .collect();
+
+  slug.split('-').filter(|part| !part.is_empty()).collect::<Vec<_>>().join("-")
+}
+
+pub fn pass_file_name(index: usize, pass: &ShaderPass) -> String {
+  format!("{:02}-{}.glsl", index, slug(&pass.name))
+}
+
+pub fn asset_file_name(id: &str, content_type: &str) -> String {
+  let extension = ImageFormat::from_mime_type(content_type)
+    .map(|format| format.extensions_str()[0])
+    .unwrap_or("bin");
+
+  format!("assets/{}.{}", id, extension)
+}
+
+struct ChannelWriter(mpsc::Sender<io::Result<Bytes>>);
+
+impl Write for ChannelWriter {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.0.blocking_send(Ok(Bytes::copy_from_slice(buf)))
+      .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client went away"))?;
+
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+fn write_zip(mut entries: mpsc::Receiver<io::Result<ArchiveEntry>>, out: mpsc::Sender<io::Result<Bytes>>) -> io::Result<()> {
+  let writer = BufWriter::with_capacity(WRITE_BUFFER_BYTES, ChannelWriter(out));
+  let mut zip = ZipWriter::new_stream(writer);
+
+  while let Some(entry) = entries.blocking_recv() {
+    let entry = entry?;
+
+    // images are compressed already
+    let method = if entry.path.ends_with(".json") || entry.path.ends_with(".glsl") {
+      CompressionMethod::Deflated
+    } else {
+      CompressionMethod::Stored
+    };
+
+    zip.start_file(entry.path, SimpleFileOptions::default().compression_method(method))
+      .map_err(io::Error::other)?;
+    zip.write_all(&entry.data)?;
+  }
+
+  zip.finish().map_err(io::Error::other)?
+    .into_inner()
+    .into_inner()
+    .map_err(|e| e.into_error())?;
+
+  Ok(())
+}
+
+pub fn zip_stream() -> (mpsc::Sender<io::Result<ArchiveEntry>>, impl Stream<Item = io::Result<Bytes>>) {
+  let (entries_tx, entries_rx) = mpsc::channel::<io::Result<ArchiveEntry>>(CHANNEL_CAPACITY);
+  let (out_tx, mut out_rx) = mpsc::channel::<io::Result<Bytes>>(CHANNEL_CAPACITY);
+
+  tokio::task::spawn_blocking(move || {
+    if let Err(e) = write_zip(entries_rx, out_tx.clone()) {
+      if e.kind() != io::ErrorKind::BrokenPipe {
+        log::error!("failed to write shader archive: {:?}", e);
+      }
+
+      // breaks the response off so the client doesn't take a truncated archive for a complete one
+      out_tx.blocking_send(Err(e)).ok();
+    }
+  });
+
+  (entries_tx, futures_util::stream::poll_fn(move |cx| out_rx.poll_recv(cx)))
+}
+
+fn archive_shader(shader: &Shader, assets: &HashMap<String, Asset>) -> (ArchivedShader, Vec<(String, String)>) {
+  let data = &shader.data.0;
+
+  let mut archived_assets = Vec::new();
+  let mut blobs = Vec::new();
+
+  for (_, binding) in data.channel_bindings() {
+    let Some(asset) = binding.asset_id().and_then(|id| assets.get(id)) else {
+      continue;
+    };
+
+    if archived_assets.iter().any(|archived: &ArchivedAsset| archived.id == asset.id) {
+      continue;
+    }
+
+    let file = asset_file_name(&asset.id, &asset.content_type);
+    blobs.push((file.clone(), asset.hash.clone()));
+    archived_assets.push(ArchivedAsset {
+      id: asset.id.clone(),
+      name: asset.name.clone(),
+      content_type: asset.content_type.clone(),
+      file,
+    });
+  }
+
+  let archived = ArchivedShader {
+    id: shader.id.clone(),
+    name: shader.name.clone(),
+    description: shader.description.clone(),
+    access: shader.access,
+    tags: Some(shader.tags.clone()),
+    created_at: Some(shader.created_at),
+    license: Some(shader.license.clone()),
+    attribution: shader.attribution.0.clone(),
+    image: ArchivedImage { file: IMAGE_FILE.to_string(), channels: data.channels.clone() },
+    passes: data.passes.iter().enumerate().map(|(i, pass)| ArchivedPass {
+      name: pass.name.clone(),
+      kind: pass.kind,
+      file: pass_file_name(i, pass),
+      channels: pass.channels.clone(),
+    }).collect(),
+    assets: archived_assets,
+  };
+
+  (archived, blobs)
+}
+
+async fn send(entries: &mpsc::Sender<io::Result<ArchiveEntry>>, entry: ArchiveEntry) -> io::Result<()> {
+  entries.send(Ok(entry)).await
+    .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "archive writer went away"))
+}
+
+pub async fn write_shaders(
+  entries: &mpsc::Sender<io::Result<ArchiveEntry>>,
+  storage: &Storage,
+  author: String,
+  shaders: Vec<Shader>,
+  assets: HashMap<String, Asset>,
+) -> io::Result<()> {
+  let manifest = Manifest {
+    version: ARCHIVE_VERSION,
+    exported_at: chrono::Utc::now(),
+    shaders: shaders.len(),
+    author: Some(author),
+  };
+  send(entries, ArchiveEntry::json(MANIFEST_FILE.to_string(), &manifest)).await?;
+
+  for shader in shaders {
+    let (archived, blobs) = archive_shader(&shader, &assets);
+    let dir = &shader.id;
+    let data = shader.data.0;
+
+    send(entries, ArchiveEntry::json(format!("{}/{}", dir, SHADER_FILE), &archived)).await?;
+    send(entries, ArchiveEntry { path: format!("{}/{}", dir, IMAGE_FILE), data: data.code.into_bytes() }).await?;
+
+    for (pass, archived_pass) in data.passes.into_iter().zip(&archived.passes) {
+      send(entries, ArchiveEntry { path: format!("{}/{}", dir, archived_pass.file), data: pass.code.into_bytes() }).await?;
+    }
+
+    for (file, hash) in blobs {
+      let data = storage.get(&blob_key(&hash)).await
+        .map_err(io::Error::other)?
+        .ok_or_else(|| io::Error::other(format!("blob {} is missing", hash)))?;
+
+      send(entries, ArchiveEntry { path: format!("{}/{}", dir, file), data }).await?;
+    }
+  }
+
+  Ok(())
+}
+
+pub struct ParsedAsset {
+  pub meta: ArchivedAsset,
+  pub data: Vec<u8>,
+}
+
+pub struct ParsedArchive {
+  pub author: Option<String>,
+  pub shaders: Vec<ParsedShader>,
+}
+
+pub struct ParsedShader {
+  pub dir: String,
+  pub meta: ArchivedShader,
+  pub data: ShaderData,
+  pub assets: Vec<ParsedAsset>,
+}
+
+impl Validate for ParsedShader {
+  fn validate(&self, v: &mut Validator) {
+    v.length("name", &self.meta.name, 1, MAX_SHADER_NAME_LENGTH);
+    v.length("description", &self.meta.description, 0, MAX_SHADER_DESCRIPTION_LENGTH);
+    self.data.validate(v);
+    validate_attribution(v, "attribution", &self.meta.attribution);
+
+    if let Some(license) = &self.meta.license {
+      v.check("license", find_license(license).is_some(), "must be a supported SPDX license identifier");
+    }
+
+    for (i, asset) in self.assets.iter().enumerate() {
+      v.length(&format!("assets[{}].name", i), &asset.meta.name, 1, MAX_ASSET_NAME_LENGTH);
+      v.check(
+        &format!("assets[{}].id", i),
+        !self.assets[..i].iter().any(|other| other.meta.id == asset.meta.id),
+        "asset ids must be unique",
+      );
+    }
+  }
+}
+
+struct ArchiveReader<'a> {
+  archive: ZipArchive<Cursor<&'a [u8]>>,
+  remaining: usize,
+}
+
+impl ArchiveReader<'_> {
+  fn read_file(&mut self, path: &str, max_bytes: usize) -> Result<Vec<u8>, FieldError> {
+    let file = self.archive.by_name(path).map_err(|_| FieldError::new(path, "is missing from the archive"))?;
+
+    // the sizes in the archive can't be trusted, never inflate more than the limit
+    let limit = max_bytes.min(self.remaining);
+    let mut data = Vec::new();
+    file.take(limit as u64 + 1).read_to_end(&mut data)
+      .map_err(|_| FieldError::new(path, "couldn't be read"))?;
+
+    if data.len() > max_bytes {
+      return Err(FieldError::new(path, format!("is larger than {} bytes", max_bytes)));
+    }
+
+    if data.len() > self.remaining {
+      return Err(FieldError::new(path, format!("archive inflates to more than {} bytes", MAX_INFLATED_BYTES)));
+    }
+
+    self.remaining -= data.len();
+    Ok(data)
+  }
+
+  fn read_code(&mut self, path: &str, limits: &ShaderLimits) -> Result<String, FieldError> {
+    let data = self.read_file(path, limits.max_pass_code_bytes)?;
+    String::from_utf8(data).map_err(|_| FieldError::new(path, "is not valid utf-8"))
+  }
+}
+
+fn shader_path(dir: &str, file: &str) -> Result<String, FieldError> {
+  let path = format!("{}/{}", dir, file);
+
+  if file.is_empty() || file.starts_with('/') || file.split('/').any(|part| part == ".." || part.is_empty()) {
+    return Err(FieldError::new(&path, "is not a file of the shader's directory"));
+  }
+
+  Ok(path)
+}
+
+fn read_shader(
+  reader: &mut ArchiveReader,
+  dir: &str,
+  limits: &ShaderLimits,
+  max_asset_bytes: usize,
+) -> Result<ParsedShader, FieldError> {
+  let metadata_path = format!("{}/{}", dir, SHADER_FILE);
+  let metadata = reader.read_file(&metadata_path, MAX_METADATA_BYTES)?;
+  let meta: ArchivedShader = serde_json::from_slice(&metadata)
+    .map_err(|e| FieldError::new(&metadata_path, e.to_string()))?;
+
+  // counted before anything is read so an archive can't make us inflate thousands of passes
+  if meta.passes.len() >= limits.max_passes {
+    return Err(FieldError::new(&metadata_path, format!("has more than {} passes", limits.max_passes)));
+  }
+
+  // every channel of every pass bound to a different asset is the most a shader can use
+  let max_assets = limits.max_passes * usize::from(MAX_CHANNELS);
+  if meta.assets.len() > max_assets {
+    return Err(FieldError::new(&metadata_path, format!("has more than {} assets", max_assets)));
+  }
+
+  let code = reader.read_code(&shader_path(dir, &meta.image.file)?, limits)?;
+
+  let mut passes = Vec::new();
+  for pass in &meta.passes {
+    passes.push(ShaderPass {
+      name: pass.name.clone(),
+      kind: pass.kind,
+      code: reader.read_code(&shader_path(dir, &pass.file)?, limits)?,
+      channels: pass.channels.clone(),
+    });
+  }
+
+  let mut assets = Vec::new();
+  for asset in &meta.assets {
+    let data = reader.read_file(&shader_path(dir, &asset.file)?, max_asset_bytes)?;
+    assets.push(ParsedAsset { meta: asset.clone(), data });
+  }
+
+  Ok(ParsedShader {
+    dir: dir.to_string(),
+    data: ShaderData { code, channels: meta.image.channels.clone(), passes },
+    meta,
+    assets,
+  })
+}
+
+pub fn read_archive(bytes: &[u8], limits: &ShaderLimits, max_asset_bytes: usize) -> Result<ParsedArchive, ApiError> {
+  let invalid = |message: &str| ApiError::Validation(vec![FieldError::new("archive", message)]);
+
+  let archive = ZipArchive::new(Cursor::new(bytes)).map_err(|_| invalid("is not a zip file"))?;
+  let mut reader = ArchiveReader { archive, remaining: MAX_INFLATED_BYTES };
+
+  let manifest = reader.read_file(MANIFEST_FILE, MAX_METADATA_BYTES)
+    .map_err(|e| ApiError::Validation(vec![e]))?;
+  let manifest: Manifest = serde_json::from_slice(&manifest)
+    .map_err(|e| ApiError::Validation(vec![FieldError::new(MANIFEST_FILE, e.to_string())]))?;
+
+  if manifest.version != ARCHIVE_VERSION {
+    return Err(ApiError::Validation(vec![
+      FieldError::new(MANIFEST_FILE, format!("archive version {} is not supported", manifest.version)),
+    ]));
+  }
+
+  let mut dirs: Vec<String> = reader.archive.file_names()
+    .filter_map(|name| name.strip_suffix(&format!("/{}", SHADER_FILE)))
+    .filter(|dir| !dir.is_empty() && !dir.contains('/'))
+    .map(|dir| dir.to_string())
+    .collect();
+  dirs.sort();
+
+  if dirs.len() > MAX_ARCHIVED_SHADERS {
+    return Err(invalid(&format!("can contain at most {} shaders", MAX_ARCHIVED_SHADERS)));
+  }
+
+  let mut shaders = Vec::new();
+  let mut errors = Vec::new();
+
+  for dir in dirs {
+    match read_shader(&mut reader, &dir, limits, max_asset_bytes) {
+      Ok(shader) => shaders.push(shader),
+      Err(e) => errors.push(e),
+    }
+  }
+
+  if !errors.is_empty() {
+    return Err(ApiError::Validation(errors));
+  }
+
+  let author = manifest.author.filter(|author| !author.trim().is_empty());
+  Ok(ParsedArchive { author, shaders })
+}
+
+#[cfg(test)]
+mod tests {
+  use futures_util::StreamExt;
+  use serde_json::json;
+
+  use crate::{assets::storage::build_storage, env::AssetStorageConfig, test_support::{temp_dir, test_shader}};
+
+  use super::*;
+
+  const LIMITS: ShaderLimits = ShaderLimits { max_pass_code_bytes: 1024, max_document_bytes: 16 * 1024, max_passes: 4 };
+  const ASSET_ID: &str = "assetAAAAAAA";
+  const BLOB: &[u8] = b"not really a png";
+
+  type Files<'a> = Vec<(&'a str, &'a [u8])>;
+
+  async fn export(shaders: Vec<Shader>) -> Vec<u8> {
+    let storage = build_storage(&AssetStorageConfig::Local { root: temp_dir().to_string_lossy().into_owned() });
+    storage.put(&blob_key("ab01"), "image/png", BLOB.to_vec()).await.unwrap();
+
+    let asset = Asset {
+      id: ASSET_ID.to_string(),
+      name: "noise.png".to_string(),
+      content_type: "image/png".to_string(),
+      size: BLOB.len() as i64,
+      width: 1,
+      height: 1,
+      hash: "ab01".to_string(),
+      created_at: chrono::Utc::now(),
+    };
+
+    let (entries, stream) = zip_stream();
+    write_shaders(&entries, &storage, "ada".to_string(), shaders, HashMap::from([(ASSET_ID.to_string(), asset)])).await.unwrap();
+    drop(entries);
+
+    let mut stream = std::pin::pin!(stream);
+    let mut archive = Vec::new();
+    while let Some(chunk) = stream.next().await {
+      archive.extend_from_slice(&chunk.unwrap());
+    }
+
+    archive
+  }
+
+  fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    for (path, data) in files {
+      zip.start_file(*path, SimpleFileOptions::default()).unwrap();
+      zip.write_all(data).unwrap();
+    }
+
+    zip.finish().unwrap().into_inner()
+  }
+
+  fn manifest() -> Vec<u8> {
+    json!({ "version": ARCHIVE_VERSION, "exported_at": chrono::Utc::now(), "shaders": 1 }).to_string().into_bytes()
+  }
+
+  fn metadata(extra: serde_json::Value) -> Vec<u8> {
+    let mut metadata = json!({ "id": "AbCdEf", "name": "a", "description": "", "access": "Private", "image": { "file": "image.glsl" } });
+    metadata.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+    metadata.to_string().into_bytes()
+  }
+
+  fn errors(archive: &[u8]) -> Vec<String> {
+    match read_archive(archive, &LIMITS, 64) {
+      Ok(_) => Vec::new(),
+      Err(ApiError::Validation(errors)) => errors.into_iter().map(|error| format!("{}: {}", error.field, error.message)).collect(),
+      Err(e) => panic!("unexpected error {:?}", e),
+    }
+  }
+
+  #[tokio::test]
+  async fn exports_import_unchanged() {
+    let shaders = vec![
+      test_shader("AbCdEf", json!({
+        "code": "void mainImage(out vec4 c, in vec2 p) {}",
+        "channels": [
+          { "channel": 0, "type": "asset", "asset_id": ASSET_ID, "filter": "nearest" },
+          { "channel": 1, "type": "buffer", "pass": "Feedback Loop", "wrap": "repeat" },
+        ],
+        "passes": [
+          { "name": "Common", "kind": "common", "code": "// common" },
+          { "name": "Feedback Loop", "kind": "buffer", "code": "// buffer", "channels": [{ "channel": 3, "type": "asset", "asset_id": ASSET_ID }] },
+        ],
+      })),
+      test_shader("GhIjKl", json!({ "code": "// single pass" })),
+    ];
+    let expected: Vec<(String, serde_json::Value)> = shaders.iter()
+      .map(|shader| (shader.id.clone(), serde_json::to_value(&shader.data.0).unwrap()))
+      .collect();
+
+    let archive = read_archive(&export(shaders).await, &LIMITS, 64).unwrap();
+    assert_eq!(archive.author.as_deref(), Some("ada"));
+    assert_eq!(archive.shaders.len(), 2);
+
+    for (shader, (id, data)) in archive.shaders.iter().zip(expected) {
+      assert_eq!((&shader.dir, &shader.meta.id), (&id, &id));
+      assert_eq!(serde_json::to_value(&shader.data).unwrap(), data);
+      assert_eq!((shader.meta.name.as_str(), shader.meta.access, shader.meta.license.as_deref()), ("Test shader", AccessLevel::Unlisted, Some("MIT")));
+      assert_eq!(shader.meta.tags, Some(json!(["test", "buffers"])));
+    }
+
+    // bound twice, archived once
+    let assets = &archive.shaders[0].assets;
+    assert_eq!(assets.len(), 1);
+    assert_eq!((assets[0].meta.id.as_str(), assets[0].meta.file.as_str()), (ASSET_ID, "assets/assetAAAAAAA.png"));
+    assert_eq!(assets[0].data, BLOB);
+    assert_eq!(archive.shaders[0].meta.passes[1].file, "01-feedback-loop.glsl");
+  }
+
+  #[test]
+  fn rejects_malformed_archives() {
+    let manifest = manifest();
+    let code: &[u8] = b"// code";
+
+    assert_eq!(errors(b"not a zip"), ["archive: is not a zip file"]);
+    assert_eq!(errors(&zip(&[("AbCdEf/shader.json", &metadata(json!({})))])), ["manifest.json: is missing from the archive"]);
+    assert_eq!(errors(&zip(&[(MANIFEST_FILE, br#"{ "version": 2, "exported_at": "2024-01-01T00:00:00Z", "shaders": 0 }"#)])), [
+      "manifest.json: archive version 2 is not supported",
+    ]);
+
+    let cases: Vec<(serde_json::Value, Files, &str)> = vec![
+      (json!({}), vec![("AbCdEf/image.glsl", code)], ""),
+      (json!({}), vec![], "AbCdEf/image.glsl: is missing from the archive"),
+      (json!({ "image": { "file": "../GhIjKl/image.glsl" } }), vec![], "AbCdEf/../GhIjKl/image.glsl: is not a file of the shader's directory"),
+      (json!({ "image": { "file": "/etc/passwd" } }), vec![], "AbCdEf//etc/passwd: is not a file of the shader's directory"),
+      (json!({}), vec![("AbCdEf/image.glsl", b"\xff\xfe")], "AbCdEf/image.glsl: is not valid utf-8"),
+      (json!({}), vec![("AbCdEf/image.glsl", &[b'x'; 1025])], "AbCdEf/image.glsl: is larger than 1024 bytes"),
+      (
+        json!({ "passes": vec![json!({ "name": "a", "kind": "buffer", "file": "a.glsl" }); 4] }),
+        vec![("AbCdEf/image.glsl", code)],
+        "AbCdEf/shader.json: has more than 4 passes",
+      ),
+      (
+        json!({ "assets": [{ "id": ASSET_ID, "name": "big.png", "content_type": "image/png", "file": "assets/big.png" }] }),
+        vec![("AbCdEf/image.glsl", code), ("AbCdEf/assets/big.png", &[0; 65])],
+        "AbCdEf/assets/big.png: is larger than 64 bytes",
+      ),
+      (json!({ "access": "secret" }), vec![], "AbCdEf/shader.json: unknown variant `secret`"),
+    ];
+
+    for (extra, files, expected) in cases {
+      let metadata = metadata(extra);
+      let mut files = files;
+      files.extend([(MANIFEST_FILE, manifest.as_slice()), ("AbCdEf/shader.json", metadata.as_slice())]);
+
+      let errors = errors(&zip(&files));
+      if expected.is_empty() {
+        assert!(errors.is_empty(), "{:?}", errors);
+      } else {
+        assert!(errors.len() == 1 && errors[0].starts_with(expected), "{:?}, expected {}", errors, expected);
+      }
+    }
+  }
+
+  #[test]
+  fn refuses_to_inflate_too_much() {
+    let archive = zip(&[("big", &[0; 100])]);
+    let mut reader = ArchiveReader {
+      archive: ZipArchive::new(Cursor::new(archive.as_slice())).unwrap(),
+      remaining: 50,
+    };
+
+    let error = reader.read_file("big", 1000).unwrap_err();
+    assert_eq!(error.message, format!("archive inflates to more than {} bytes", MAX_INFLATED_BYTES));
+  }
+}
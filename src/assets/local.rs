@@ -0,0 +1,55 @@
+use std::{io::ErrorKind, path::PathBuf};
+
+use async_trait::async_trait;
+
+use super::storage::AssetStorage;
+
+#[derive(Debug)]
+pub struct LocalStorage {
+  root: PathBuf,
+}
+
+impl LocalStorage {
+  pub fn new(root: &str) -> Self {
+    Self { root: PathBuf::from(root) }
+  }
+
+  fn path(&self, key: &str) -> PathBuf {
+    self.root.join(key)
+  }
+}
+
+#[async_trait]
+impl AssetStorage for LocalStorage {
+  async fn put(&self, key: &str, _content_type: &str, data: Vec<u8>) -> anyhow::Result<()> {
+    let path = self.path(key);
+    if let Some(parent) = path.parent() {
+      tokio::fs::create_dir_all(parent).await?;
+    }
+
+    // write next to the target and rename so readers never see a half written file
+    let tmp = path.with_extension(format!("tmp-{}", nanoid::nanoid!(8)));
+    tokio::fs::write(&tmp, data).await?;
+    if let Err(e) = tokio::fs::rename(&tmp, &path).await {
+      tokio::fs::remove_file(&tmp).await.ok();
+      return Err(e.into());
+    }
+
+    Ok(())
+  }
+
+  async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+    match tokio::fs::read(self.path(key)).await {
+      Ok(data) => Ok(Some(data)),
+      Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  async fn delete(&self, key: &str) -> anyhow::Result<()> {
+    match tokio::fs::remove_file(self.path(key)).await {
+      Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+      _ => Ok(()),
+    }
+  }
+}
@@ -0,0 +1,428 @@
+use std::{collections::HashMap, io::Cursor};
+
+use image::{ImageFormat, ImageReader};
+use nanoid::nanoid;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::PgConnection;
+
+use crate::{constants::ASSET_BLOB_LOCK, errors::{ApiError, FieldError}, jobs::{self, Job}, quotas, router_state::{RouterState, UserProfile}, routes::shader::ShaderData};
+
+pub mod local;
+pub mod s3;
+pub mod storage;
+pub mod thumbnail;
+
+pub const ASSET_ID_LENGTH: usize = 12;
+
+const SUPPORTED_FORMATS: [ImageFormat; 4] = [ImageFormat::Png, ImageFormat::Jpeg, ImageFormat::WebP, ImageFormat::Hdr];
+
+pub const ASSET_QUERY: &str = "SELECT assets.id, assets.name, asset_blobs.content_type, asset_blobs.size,
+  asset_blobs.width, asset_blobs.height, asset_blobs.hash, assets.created_at
+  FROM assets INNER JOIN asset_blobs ON assets.blob_hash = asset_blobs.hash";
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Asset {
+  pub id: String,
+  pub name: String,
+  pub content_type: String,
+  pub size: i64,
+  pub width: i32,
+  pub height: i32,
+  pub hash: String,
+  pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ImageInfo {
+  pub content_type: &'static str,
+  pub width: u32,
+  pub height: u32,
+}
+
+pub fn inspect_image(data: &[u8], max_dimension: u32) -> Result<ImageInfo, ApiError> {
+  let format = image::guess_format(data).ok()
+    .filter(|format| SUPPORTED_FORMATS.contains(format))
+    .ok_or_else(|| ApiError::UnsupportedMediaType("only png, jpeg, webp and hdr images are supported".to_string()))?;
+
+  // only the header is read, the image is never decoded
+  let (width, height) = ImageReader::with_format(Cursor::new(data), format)
+    .into_dimensions()
+    .map_err(|_| ApiError::Validation(vec![FieldError::new("file", "is not a valid image")]))?;
+
+  if width == 0 || height == 0 || width > max_dimension || height > max_dimension {
+    return Err(ApiError::Validation(vec![
+      FieldError::new("file", format!("must be between 1 and {} pixels on each side", max_dimension)),
+    ]));
+  }
+
+  Ok(ImageInfo { content_type: format.to_mime_type(), width, height })
+}
+
+pub fn blob_key(hash: &str) -> String {
+  format!("blobs/{}/{}", &hash[..2], hash)
+}
+
+pub async fn find_asset(state: &RouterState, id: &str) -> Result<Option<Asset>, ApiError> {
+  let asset = sqlx::query_as::<_, Asset>(&format!("{} WHERE assets.id = $1", ASSET_QUERY))
+    .bind(id)
+    .fetch_optional(&state.db)
+    .await?;
+
+  Ok(asset)
+}
+
+pub async fn store_asset(
+  state: &RouterState,
+  profile: &UserProfile,
+  name: &str,
+  data: Vec<u8>,
+) -> Result<(Asset, bool), ApiError> {
+  let mut tx = state.db.begin().await?;
+  let (id, created) = store_asset_in(&mut tx, state, profile, name, data).await?;
+  tx.commit().await?;
+
+  let asset = find_asset(state, &id).await?.ok_or(sqlx::Error::RowNotFound)?;
+  Ok((asset, created))
+}
+
+// files stay in storage on rollback, the next upload of the same content overwrites them
+pub async fn store_asset_in(
+  conn: &mut PgConnection,
+  state: &RouterState,
+  profile: &UserProfile,
+  name: &str,
+  data: Vec<u8>,
+) -> Result<(String, bool), ApiError> {
+  let info = inspect_image(&data, state.env.assets.max_dimension)?;
+  let hash = hex::encode(Sha256::digest(&data));
+  let blob = BlobInfo {
+    hash: &hash,
+    content_type: info.content_type,
+    size: data.len().try_into()?,
+    width: info.width.try_into()?,
+    height: info.height.try_into()?,
+  };
+
+  add_user_asset(conn, state, profile, name, &blob, Some(data)).await
+}
+
+pub async fn copy_asset_in(
+  conn: &mut PgConnection,
+  state: &RouterState,
+  profile: &UserProfile,
+  asset: &Asset,
+) -> Result<(String, bool), ApiError> {
+  let blob = BlobInfo {
+    hash: &asset.hash,
+    content_type: &asset.content_type,
+    size: asset.size,
+    width: asset.width,
+    height: asset.height,
+  };
+
+  add_user_asset(conn, state, profile, &asset.name, &blob, None).await
+}
+
+struct BlobInfo<'a> {
+  hash: &'a str,
+  content_type: &'a str,
+  size: i64,
+  width: i32,
+  height: i32,
+}
+
+async fn add_user_asset(
+  conn: &mut PgConnection,
+  state: &RouterState,
+  profile: &UserProfile,
+  name: &str,
+  blob: &BlobInfo<'_>,
+  data: Option<Vec<u8>>,
+) -> Result<(String, bool), ApiError> {
+  let BlobInfo { hash, content_type, size, width, height } = *blob;
+
+  // serializes the uploads of one user so they can't race past the quota together
+  let usage = quotas::lock_usage(conn, profile.id).await?;
+
+  let existing: Option<(String,)> = sqlx::query_as("SELECT id FROM assets WHERE user_id = $1 AND blob_hash = $2")
+    .bind(profile.id)
+    .bind(hash)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+  if let Some((id,)) = existing {
+    return Ok((id, false));
+  }
+
+  quotas::check_asset_upload(&state.env, &usage, size)?;
+
+  // the row lock taken here keeps a concurrent release from dropping the blob under us,
+  // the blob lock keeps a pending file deletion from removing what's stored below
+  lock_blob(conn, hash).await?;
+  let (ref_count,): (i32,) = sqlx::query_as(
+    "INSERT INTO asset_blobs (hash, content_type, size, width, height, ref_count)
+    VALUES ($1, $2, $3, $4, $5, 1)
+    ON CONFLICT (hash) DO UPDATE SET ref_count = asset_blobs.ref_count + 1
+    RETURNING ref_count")
+    .bind(hash)
+    .bind(content_type)
+    .bind(size)
+    .bind(width)
+    .bind(height)
+    .fetch_one(&mut *conn)
+    .await?;
+
+  if ref_count == 1 {
+    let Some(data) = data else {
+      return Err(ApiError::NotFound("asset not found".to_string()));
+    };
+
+    state.storage.put(&blob_key(hash), content_type, data).await
+      .map_err(ApiError::Storage)?;
+  }
+
+  let id = nanoid!(ASSET_ID_LENGTH);
+  sqlx::query("INSERT INTO assets (id, user_id, name, blob_hash) VALUES ($1, $2, $3, $4)")
+    .bind(&id)
+    .bind(profile.id)
+    .bind(name)
+    .bind(hash)
+    .execute(&mut *conn)
+    .await?;
+
+  Ok((id, true))
+}
+
+// serializes storing a blob file with deleting it, there may be no row to lock for either
+async fn lock_blob(conn: &mut PgConnection, hash: &str) -> Result<(), ApiError> {
+  sqlx::query("SELECT pg_advisory_xact_lock($1, hashtext($2))")
+    .bind(ASSET_BLOB_LOCK)
+    .bind(hash)
+    .execute(&mut *conn)
+    .await?;
+
+  Ok(())
+}
+
+// the file is deleted by a job once the transaction has committed
+pub async fn release_blob(conn: &mut PgConnection, hash: &str) -> Result<(), ApiError> {
+  let (ref_count,): (i32,) = sqlx::query_as(
+    "UPDATE asset_blobs SET ref_count = ref_count - 1 WHERE hash = $1 RETURNING ref_count")
+    .bind(hash)
+    .fetch_one(&mut *conn)
+    .await?;
+
+  if ref_count > 0 {
+    return Ok(());
+  }
+
+  sqlx::query("DELETE FROM asset_blobs WHERE hash = $1")
+    .bind(hash)
+    .execute(&mut *conn)
+    .await?;
+
+  jobs::enqueue(&mut *conn, &DeleteBlobFile { hash: hash.to_string() }).await?;
+  Ok(())
+}
+
+pub async fn delete_user_assets(conn: &mut PgConnection, user_id: i32) -> Result<(), ApiError> {
+  let hashes: Vec<(String,)> = sqlx::query_as("DELETE FROM assets WHERE user_id = $1 RETURNING blob_hash")
+    .bind(user_id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+  for (hash,) in hashes {
+    release_blob(conn, &hash).await?;
+  }
+
+  Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteBlobFile {
+  pub hash: String,
+}
+
+#[async_trait]
+impl Job for DeleteBlobFile {
+  const KIND: &'static str = "delete_blob_file";
+
+  async fn run(self, state: &RouterState) -> Result<(), ApiError> {
+    let mut tx = state.db.begin().await?;
+    lock_blob(&mut tx, &self.hash).await?;
+
+    let stored: Option<(String,)> = sqlx::query_as("SELECT hash FROM asset_blobs WHERE hash = $1")
+      .bind(&self.hash)
+      .fetch_optional(&mut *tx)
+      .await?;
+
+    if stored.is_none() {
+      state.storage.delete(&blob_key(&self.hash)).await.map_err(ApiError::Storage)?;
+    }
+
+    tx.commit().await?;
+    Ok(())
+  }
+}
+
+pub async fn verify_channel_assets(
+  state: &RouterState,
+  profile: &UserProfile,
+  data: &ShaderData,
+) -> Result<(), ApiError> {
+  let references: HashMap<String, &str> = data.channel_bindings()
+    .filter_map(|(field, binding)| binding.asset_id().map(|id| (format!("{}.asset_id", field), id)))
+    .collect();
+
+  if references.is_empty() {
+    return Ok(());
+  }
+
+  let ids: Vec<&str> = references.values().copied().collect();
+  let found: Vec<(String,)> = sqlx::query_as("SELECT id FROM assets WHERE user_id = $1 AND id = ANY($2)")
+    .bind(profile.id)
+    .bind(&ids)
+    .fetch_all(&state.db)
+    .await?;
+
+  let mut errors: Vec<FieldError> = references.iter()
+    .filter(|(_, id)| !found.iter().any(|(found,)| found == *id))
+    .map(|(field, _)| FieldError::new(field, "asset not found"))
+    .collect();
+
+  if !errors.is_empty() {
+    errors.sort_by(|a, b| a.field.cmp(&b.field));
+    return Err(ApiError::Validation(errors));
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use image::{Rgb, RgbImage};
+
+  use crate::test_support::{create_user, delete_user, test_db, test_state};
+
+  use super::*;
+
+  // the pixels are random so every test stores a blob of its own
+  fn image(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+    let seed = nanoid!(3).into_bytes();
+    let image = RgbImage::from_pixel(width, height, Rgb([seed[0], seed[1], seed[2]]));
+
+    let mut data = Cursor::new(Vec::new());
+    image.write_to(&mut data, format).unwrap();
+    data.into_inner()
+  }
+
+  #[test]
+  fn inspects_the_content_not_the_name() {
+    let info = inspect_image(&image(3, 2, ImageFormat::Png), 4).unwrap();
+    assert_eq!((info.content_type, info.width, info.height), ("image/png", 3, 2));
+
+    let info = inspect_image(&image(1, 1, ImageFormat::Jpeg), 4).unwrap();
+    assert_eq!(info.content_type, "image/jpeg");
+
+    let unsupported = [image(1, 1, ImageFormat::Gif), b"<svg></svg>".to_vec(), Vec::new()];
+    for data in unsupported {
+      assert!(matches!(inspect_image(&data, 4), Err(ApiError::UnsupportedMediaType(_))));
+    }
+
+    // a png signature with no header behind it
+    let truncated = image(1, 1, ImageFormat::Png)[..12].to_vec();
+    assert!(matches!(inspect_image(&truncated, 4), Err(ApiError::Validation(_))));
+    assert!(matches!(inspect_image(&image(5, 1, ImageFormat::Png), 4), Err(ApiError::Validation(_))));
+  }
+
+  async fn ref_count(state: &RouterState, hash: &str) -> Option<i32> {
+    sqlx::query_as("SELECT ref_count FROM asset_blobs WHERE hash = $1")
+      .bind(hash)
+      .fetch_optional(&state.db)
+      .await.unwrap()
+      .map(|(count,)| count)
+  }
+
+  async fn release_all(state: &RouterState, profile: &UserProfile) {
+    let mut tx = state.db.begin().await.unwrap();
+    delete_user_assets(&mut tx, profile.id).await.unwrap();
+    tx.commit().await.unwrap();
+  }
+
+  async fn take_delete_job(state: &RouterState, hash: &str) -> bool {
+    sqlx::query("DELETE FROM jobs WHERE kind = $1 AND payload->>'hash' = $2")
+      .bind(DeleteBlobFile::KIND)
+      .bind(hash)
+      .execute(&state.db)
+      .await.unwrap()
+      .rows_affected() == 1
+  }
+
+  #[tokio::test]
+  async fn identical_uploads_share_a_blob() {
+    let Some(db) = test_db().await else {
+      return;
+    };
+
+    let state = test_state(db);
+    let (alice, bob) = (create_user(&state.db).await, create_user(&state.db).await);
+    let data = image(2, 2, ImageFormat::Png);
+
+    let (first, created) = store_asset(&state, &alice, "first.png", data.clone()).await.unwrap();
+    assert!(created);
+    let key = blob_key(&first.hash);
+    assert_eq!(state.storage.get(&key).await.unwrap(), Some(data.clone()));
+
+    // the same user gets the asset they already have
+    let (again, created) = store_asset(&state, &alice, "again.png", data.clone()).await.unwrap();
+    assert!(!created);
+    assert_eq!(again.id, first.id);
+    assert_eq!(ref_count(&state, &first.hash).await, Some(1));
+
+    let (other, created) = store_asset(&state, &bob, "other.png", data.clone()).await.unwrap();
+    assert!(created);
+    assert_ne!(other.id, first.id);
+    assert_eq!(ref_count(&state, &first.hash).await, Some(2));
+
+    release_all(&state, &alice).await;
+    assert_eq!(ref_count(&state, &first.hash).await, Some(1));
+    assert!(!take_delete_job(&state, &first.hash).await);
+
+    // the last reference drops the blob, its file goes with the job
+    release_all(&state, &bob).await;
+    assert_eq!(ref_count(&state, &first.hash).await, None);
+    assert!(take_delete_job(&state, &first.hash).await);
+    assert!(state.storage.get(&key).await.unwrap().is_some());
+
+    DeleteBlobFile { hash: first.hash.clone() }.run(&state).await.unwrap();
+    assert_eq!(state.storage.get(&key).await.unwrap(), None);
+
+    delete_user(&state.db, &alice).await;
+    delete_user(&state.db, &bob).await;
+  }
+
+  #[tokio::test]
+  async fn reuploads_survive_a_pending_deletion() {
+    let Some(db) = test_db().await else {
+      return;
+    };
+
+    let state = test_state(db);
+    let user = create_user(&state.db).await;
+    let data = image(2, 2, ImageFormat::Png);
+
+    let (asset, _) = store_asset(&state, &user, "first.png", data.clone()).await.unwrap();
+    release_all(&state, &user).await;
+    assert!(take_delete_job(&state, &asset.hash).await);
+
+    store_asset(&state, &user, "again.png", data.clone()).await.unwrap();
+    DeleteBlobFile { hash: asset.hash.clone() }.run(&state).await.unwrap();
+    assert_eq!(state.storage.get(&blob_key(&asset.hash)).await.unwrap(), Some(data));
+
+    release_all(&state, &user).await;
+    take_delete_job(&state, &asset.hash).await;
+    delete_user(&state.db, &user).await;
+  }
+}
@@ -0,0 +1,76 @@
+use async_trait::async_trait;
+use aws_sdk_s3::{config::{BehaviorVersion, Credentials, Region}, primitives::ByteStream, Client};
+
+use crate::env::S3Config;
+
+use super::storage::AssetStorage;
+
+#[derive(Debug)]
+pub struct S3Storage {
+  client: Client,
+  bucket: String,
+}
+
+impl S3Storage {
+  pub fn new(config: &S3Config) -> Self {
+    let credentials = Credentials::new(
+      &config.access_key_id,
+      &config.secret_access_key,
+      None,
+      None,
+      "shaderx",
+    );
+
+    let mut builder = aws_sdk_s3::Config::builder()
+      .behavior_version(BehaviorVersion::latest())
+      .region(Region::new(config.region.clone()))
+      .credentials_provider(credentials);
+
+    // minio and friends don't do virtual hosted buckets
+    if let Some(endpoint) = &config.endpoint {
+      builder = builder.endpoint_url(endpoint).force_path_style(true);
+    }
+
+    Self {
+      client: Client::from_conf(builder.build()),
+      bucket: config.bucket.clone(),
+    }
+  }
+}
+
+#[async_trait]
+impl AssetStorage for S3Storage {
+  async fn put(&self, key: &str, content_type: &str, data: Vec<u8>) -> anyhow::Result<()> {
+    self.client.put_object()
+      .bucket(&self.bucket)
+      .key(key)
+      .content_type(content_type)
+      .body(ByteStream::from(data))
+      .send()
+      .await?;
+
+    Ok(())
+  }
+
+  async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+    let object = match self.client.get_object().bucket(&self.bucket).key(key).send().await {
+      Ok(object) => object,
+      Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => return Ok(None),
+      Err(e) => return Err(e.into()),
+    };
+
+    let data = object.body.collect().await?;
+    Ok(Some(data.into_bytes().to_vec()))
+  }
+
+  async fn delete(&self, key: &str) -> anyhow::Result<()> {
+    // deleting a missing key is not an error on s3
+    self.client.delete_object()
+      .bucket(&self.bucket)
+      .key(key)
+      .send()
+      .await?;
+
+    Ok(())
+  }
+}
@@ -0,0 +1,126 @@
+use std::{fmt::Debug, sync::Arc};
+
+use async_trait::async_trait;
+
+use crate::env::AssetStorageConfig;
+
+use super::{local::LocalStorage, s3::S3Storage};
+
+#[async_trait]
+pub trait AssetStorage: Debug + Send + Sync {
+  async fn put(&self, key: &str, content_type: &str, data: Vec<u8>) -> anyhow::Result<()>;
+  async fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+  async fn delete(&self, key: &str) -> anyhow::Result<()>;
+}
+
+pub type Storage = Arc<dyn AssetStorage>;
+
+pub fn build_storage(config: &AssetStorageConfig) -> Storage {
+  match config {
+    AssetStorageConfig::Local { root } => Arc::new(LocalStorage::new(root)),
+    AssetStorageConfig::S3(config) => Arc::new(S3Storage::new(config)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{collections::HashMap, sync::{Arc, Mutex}};
+
+  use axum::{body::Bytes, extract::{Path, State}, http::{HeaderMap, Method, StatusCode}, response::IntoResponse, routing::put, Router};
+  use tokio::net::TcpListener;
+
+  use crate::{env::S3Config, test_support::temp_dir};
+
+  use super::*;
+
+  const BUCKET: &str = "assets";
+
+  type Objects = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+  // just enough of a path style s3 api, like minio serves it, to store objects in memory
+  async fn object(
+    State(objects): State<Objects>,
+    Path((bucket, key)): Path<(String, String)>,
+    method: Method,
+    headers: HeaderMap,
+    body: Bytes,
+  ) -> impl IntoResponse {
+    if bucket != BUCKET {
+      return (StatusCode::NOT_FOUND, "<Error><Code>NoSuchBucket</Code></Error>".as_bytes().to_vec()).into_response();
+    }
+
+    let mut objects = objects.lock().unwrap();
+    match method {
+      Method::PUT => {
+        assert!(headers.contains_key("content-type"));
+        objects.insert(key, body.to_vec());
+        StatusCode::OK.into_response()
+      },
+      Method::GET => match objects.get(&key) {
+        Some(data) => data.clone().into_response(),
+        None => (StatusCode::NOT_FOUND, "<Error><Code>NoSuchKey</Code></Error>").into_response(),
+      },
+      Method::DELETE => {
+        objects.remove(&key);
+        StatusCode::NO_CONTENT.into_response()
+      },
+      _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+    }
+  }
+
+  async fn fake_s3() -> (String, Objects) {
+    let objects = Objects::default();
+    let app = Router::new()
+      .route("/:bucket/*key", put(object).get(object).delete(object))
+      .with_state(objects.clone());
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    (url, objects)
+  }
+
+  async fn stores_and_deletes(storage: &Storage) {
+    let key = "blobs/ab/abcdef";
+    assert_eq!(storage.get(key).await.unwrap(), None);
+
+    storage.put(key, "image/png", b"first".to_vec()).await.unwrap();
+    assert_eq!(storage.get(key).await.unwrap().as_deref(), Some(&b"first"[..]));
+
+    storage.put(key, "image/png", b"second".to_vec()).await.unwrap();
+    assert_eq!(storage.get(key).await.unwrap().as_deref(), Some(&b"second"[..]));
+
+    storage.delete(key).await.unwrap();
+    assert_eq!(storage.get(key).await.unwrap(), None);
+
+    // deleting twice is fine, a retried job may do that
+    storage.delete(key).await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn local_storage() {
+    let root = temp_dir();
+    let storage = build_storage(&AssetStorageConfig::Local { root: root.to_string_lossy().into_owned() });
+    stores_and_deletes(&storage).await;
+
+    // nothing is left behind by the writes
+    assert_eq!(std::fs::read_dir(root.join("blobs/ab")).unwrap().count(), 0);
+    std::fs::remove_dir_all(root).unwrap();
+  }
+
+  #[tokio::test]
+  async fn s3_storage() {
+    let (url, objects) = fake_s3().await;
+    let storage = build_storage(&AssetStorageConfig::S3(S3Config {
+      endpoint: Some(url),
+      bucket: BUCKET.to_string(),
+      region: "us-east-1".to_string(),
+      access_key_id: "minio".to_string(),
+      secret_access_key: "minio-secret".to_string(),
+    }));
+
+    stores_and_deletes(&storage).await;
+    assert!(objects.lock().unwrap().is_empty());
+  }
+}
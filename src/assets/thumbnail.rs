@@ -0,0 +1,247 @@
+use std::io::Cursor;
+
+use async_trait::async_trait;
+use image::{codecs::{gif::{GifDecoder, GifEncoder, Repeat}, jpeg::JpegEncoder, png::PngDecoder, webp::WebPDecoder}, imageops::{self, FilterType}, AnimationDecoder, DynamicImage, Frame, ImageDecoder, ImageFormat, Limits};
+use serde::{Deserialize, Serialize};
+
+use crate::{constants::{THUMBNAIL_MAX_DIMENSION, THUMBNAIL_MAX_FRAMES}, errors::{ApiError, FieldError}, jobs::Job, router_state::RouterState};
+
+use super::storage::Storage;
+
+const JPEG_QUALITY: u8 = 85;
+const GIF_ENCODER_SPEED: i32 = 10;
+// decoded frames are rgba, this is what a couple of seconds of a large preview take up
+const MAX_DECODE_ALLOC: u64 = 512 * 1024 * 1024;
+
+const STILL_SIZES: [(&str, u32, u32); 2] = [("small", 320, 180), ("large", 640, 360)];
+const ANIMATED_SIZE: (&str, u32, u32) = ("animated", 320, 180);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThumbnailImage {
+  pub size: String,
+  pub width: u32,
+  pub height: u32,
+  pub content_type: String,
+  pub url: String,
+  #[serde(default)]
+  pub bytes: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Thumbnails {
+  pub version: String,
+  pub images: Vec<ThumbnailImage>,
+}
+
+pub struct RenderedThumbnail {
+  pub size: &'static str,
+  pub width: u32,
+  pub height: u32,
+  pub format: ImageFormat,
+  pub data: Vec<u8>,
+}
+
+impl RenderedThumbnail {
+  pub fn file_name(&self, version: &str) -> String {
+    thumbnail_file_name(version, self.size, self.format)
+  }
+}
+
+pub fn thumbnail_file_name(version: &str, size: &str, format: ImageFormat) -> String {
+  format!("{}-{}.{}", version, size, format.extensions_str()[0])
+}
+
+pub fn thumbnail_key(shader_id: &str, file_name: &str) -> String {
+  format!("thumbnails/{}/{}", shader_id, file_name)
+}
+
+impl Thumbnails {
+  pub fn file_name(&self, image: &ThumbnailImage) -> Option<String> {
+    let format = ImageFormat::from_mime_type(&image.content_type)?;
+    Some(thumbnail_file_name(&self.version, &image.size, format))
+  }
+
+  pub fn find(&self, file_name: &str) -> Option<&ThumbnailImage> {
+    self.images.iter().find(|image| self.file_name(image).as_deref() == Some(file_name))
+  }
+}
+
+fn invalid_image(message: impl Into<String>) -> ApiError {
+  ApiError::Validation(vec![FieldError::new("thumbnail", message)])
+}
+
+fn decode_limits() -> Limits {
+  let mut limits = Limits::default();
+  limits.max_image_width = Some(THUMBNAIL_MAX_DIMENSION);
+  limits.max_image_height = Some(THUMBNAIL_MAX_DIMENSION);
+  limits.max_alloc = Some(MAX_DECODE_ALLOC);
+  limits
+}
+
+fn collect_frames<'a>(decoder: impl AnimationDecoder<'a>) -> Result<Vec<Frame>, ApiError> {
+  let frames = decoder.into_frames()
+    .take(THUMBNAIL_MAX_FRAMES + 1)
+    .collect::<Result<Vec<_>, _>>()
+    .map_err(|_| invalid_image("is not a valid animation"))?;
+
+  if frames.len() > THUMBNAIL_MAX_FRAMES {
+    return Err(invalid_image(format!("animations can have at most {} frames", THUMBNAIL_MAX_FRAMES)));
+  }
+
+  Ok(frames)
+}
+
+fn decode_animation(data: &[u8], format: ImageFormat) -> Result<Option<Vec<Frame>>, ApiError> {
+  let invalid = |_| invalid_image("is not a valid image");
+
+  match format {
+    ImageFormat::Gif => {
+      let mut decoder = GifDecoder::new(Cursor::new(data)).map_err(invalid)?;
+      decoder.set_limits(decode_limits()).map_err(invalid)?;
+      collect_frames(decoder).map(Some)
+    },
+    ImageFormat::Png => {
+      let mut decoder = PngDecoder::new(Cursor::new(data)).map_err(invalid)?;
+      decoder.set_limits(decode_limits()).map_err(invalid)?;
+
+      if !decoder.is_apng().map_err(invalid)? {
+        return Ok(None);
+      }
+
+      collect_frames(decoder.apng().map_err(invalid)?).map(Some)
+    },
+    ImageFormat::WebP => {
+      let mut decoder = WebPDecoder::new(Cursor::new(data)).map_err(invalid)?;
+      decoder.set_limits(decode_limits()).map_err(invalid)?;
+
+      if !decoder.has_animation() {
+        return Ok(None);
+      }
+
+      collect_frames(decoder).map(Some)
+    },
+    _ => Ok(None),
+  }
+}
+
+fn decode_still(data: &[u8], format: ImageFormat) -> Result<DynamicImage, ApiError> {
+  let mut reader = image::ImageReader::with_format(Cursor::new(data), format);
+  reader.limits(decode_limits());
+  reader.decode().map_err(|_| invalid_image("is not a valid image"))
+}
+
+fn encode_jpeg(image: &DynamicImage) -> Result<Vec<u8>, ApiError> {
+  let mut data = Vec::new();
+  JpegEncoder::new_with_quality(&mut data, JPEG_QUALITY).encode_image(&image.to_rgb8())?;
+
+  Ok(data)
+}
+
+fn encode_gif(frames: &[Frame], width: u32, height: u32) -> Result<Vec<u8>, ApiError> {
+  let resized = frames.iter().map(|frame| {
+    let buffer = imageops::resize(frame.buffer(), width, height, FilterType::Triangle);
+    Frame::from_parts(buffer, 0, 0, frame.delay())
+  });
+
+  let mut data = Vec::new();
+  {
+    let mut encoder = GifEncoder::new_with_speed(&mut data, GIF_ENCODER_SPEED);
+    encoder.set_repeat(Repeat::Infinite)?;
+    encoder.encode_frames(resized)?;
+  }
+
+  Ok(data)
+}
+
+pub fn render_thumbnails(data: &[u8]) -> Result<Vec<RenderedThumbnail>, ApiError> {
+  let format = image::guess_format(data).ok()
+    .filter(|format| matches!(format, ImageFormat::Png | ImageFormat::WebP | ImageFormat::Gif))
+    .ok_or_else(|| ApiError::UnsupportedMediaType("thumbnails must be png, webp or gif images".to_string()))?;
+
+  let frames = decode_animation(data, format)?;
+  let still = match &frames {
+    Some(frames) => DynamicImage::ImageRgba8(
+      frames.first().ok_or_else(|| invalid_image("animation has no frames"))?.buffer().clone(),
+    ),
+    None => decode_still(data, format)?,
+  };
+
+  let mut rendered = Vec::new();
+
+  for (size, width, height) in STILL_SIZES {
+    let image = still.resize_to_fill(width, height, FilterType::Lanczos3);
+    rendered.push(RenderedThumbnail {
+      size,
+      width,
+      height,
+      format: ImageFormat::Jpeg,
+      data: encode_jpeg(&image)?,
+    });
+  }
+
+  if let Some(frames) = frames.filter(|frames| frames.len() > 1) {
+    let (size, width, height) = ANIMATED_SIZE;
+    let cropped = crop_frames(frames, width, height);
+    rendered.push(RenderedThumbnail {
+      size,
+      width,
+      height,
+      format: ImageFormat::Gif,
+      data: encode_gif(&cropped, width, height)?,
+    });
+  }
+
+  Ok(rendered)
+}
+
+fn crop_frames(frames: Vec<Frame>, width: u32, height: u32) -> Vec<Frame> {
+  frames.into_iter().map(|frame| {
+    let delay = frame.delay();
+    let mut buffer = frame.into_buffer();
+    let (w, h) = buffer.dimensions();
+
+    let (crop_w, crop_h) = if u64::from(w) * u64::from(height) > u64::from(h) * u64::from(width) {
+      ((u64::from(h) * u64::from(width) / u64::from(height)) as u32, h)
+    } else {
+      (w, (u64::from(w) * u64::from(height) / u64::from(width)) as u32)
+    };
+
+    let cropped = imageops::crop(&mut buffer, (w - crop_w) / 2, (h - crop_h) / 2, crop_w.max(1), crop_h.max(1)).to_image();
+    Frame::from_parts(cropped, 0, 0, delay)
+  }).collect()
+}
+
+pub async fn delete_thumbnail_files(storage: &Storage, shader_id: &str, thumbnails: &Thumbnails) {
+  for image in &thumbnails.images {
+    let Some(file_name) = thumbnails.file_name(image) else {
+      continue;
+    };
+
+    if let Err(e) = storage.delete(&thumbnail_key(shader_id, &file_name)).await {
+      log::warn!("failed to delete thumbnail {} of shader {}: {:?}", file_name, shader_id, e);
+    }
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteThumbnailFiles {
+  pub shader_id: String,
+  pub thumbnails: Thumbnails,
+}
+
+#[async_trait]
+impl Job for DeleteThumbnailFiles {
+  const KIND: &'static str = "delete_thumbnail_files";
+
+  async fn run(self, state: &RouterState) -> Result<(), ApiError> {
+    for image in &self.thumbnails.images {
+      let Some(file_name) = self.thumbnails.file_name(image) else {
+        continue;
+      };
+
+      state.storage.delete(&thumbnail_key(&self.shader_id, &file_name)).await.map_err(ApiError::Storage)?;
+    }
+
+    Ok(())
+  }
+}
@@ -0,0 +1,245 @@
+use std::{convert::Infallible, net::{IpAddr, SocketAddr}};
+
+use axum::{extract::{ConnectInfo, FromRef, FromRequestParts}, http::{header::USER_AGENT, request::Parts}};
+use sqlx::{types::JsonValue, PgExecutor};
+
+use crate::{env::ProxyConfig, errors::ApiError, middlewares::request_id::current_request_id, router_state::RouterState};
+
+const MAX_USER_AGENT_LENGTH: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+  Login,
+  LoginDenied,
+  Logout,
+  SessionRefresh,
+  SessionRefreshFailed,
+  ShaderCreate,
+  ShaderUpdate,
+  ShaderAccessChange,
+  ShaderDelete,
+  ShaderRestore,
+  ShaderForceDelete,
+  ShaderPurge,
+  UserSuspend,
+  UserUnsuspend,
+  UserRoleChange,
+  ViewUserShaders,
+  FeatureShader,
+  UpdateFeatured,
+  UnfeatureShader,
+  RetryJob,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditTarget {
+  User,
+  Session,
+  Shader,
+  Job,
+}
+
+impl AuditAction {
+  pub fn name(self) -> &'static str {
+    match self {
+      Self::Login => "login",
+      Self::LoginDenied => "login_denied",
+      Self::Logout => "logout",
+      Self::SessionRefresh => "session_refresh",
+      Self::SessionRefreshFailed => "session_refresh_failed",
+      Self::ShaderCreate => "shader_create",
+      Self::ShaderUpdate => "shader_update",
+      Self::ShaderAccessChange => "shader_access_change",
+      Self::ShaderDelete => "shader_delete",
+      Self::ShaderRestore => "shader_restore",
+      Self::ShaderForceDelete => "shader_force_delete",
+      Self::ShaderPurge => "shader_purge",
+      Self::UserSuspend => "user_suspend",
+      Self::UserUnsuspend => "user_unsuspend",
+      Self::UserRoleChange => "user_role_change",
+      Self::ViewUserShaders => "view_user_shaders",
+      Self::FeatureShader => "feature_shader",
+      Self::UpdateFeatured => "update_featured",
+      Self::UnfeatureShader => "unfeature_shader",
+      Self::RetryJob => "retry_job",
+    }
+  }
+}
+
+impl AuditTarget {
+  pub fn name(self) -> &'static str {
+    match self {
+      Self::User => "user",
+      Self::Session => "session",
+      Self::Shader => "shader",
+      Self::Job => "job",
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RequestMeta {
+  pub request_id: Option<String>,
+  pub ip_address: Option<IpAddr>,
+  pub user_agent: Option<String>,
+}
+
+// each trusted proxy appends the address it got the request from
+pub fn client_ip(parts: &Parts, proxy: Option<&ProxyConfig>) -> Option<IpAddr> {
+  let forwarded = proxy.and_then(|proxy| {
+    let entries: Vec<&str> = parts.headers.get_all(&proxy.ip_header).iter()
+      .filter_map(|header| header.to_str().ok())
+      .flat_map(|header| header.split(','))
+      .collect();
+
+    // a shorter chain than expected was written by the proxies alone
+    let index = entries.len().saturating_sub(proxy.trusted_hops);
+    entries.get(index).and_then(|ip| ip.trim().parse().ok())
+  });
+
+  forwarded.or_else(|| parts.extensions.get::<ConnectInfo<SocketAddr>>().map(|info| info.0.ip()))
+}
+
+impl RequestMeta {
+  pub fn from_parts(parts: &Parts, proxy: Option<&ProxyConfig>) -> Self {
+    let user_agent = parts.headers.get(USER_AGENT)
+      .and_then(|header| header.to_str().ok())
+      .map(|agent| agent.chars().take(MAX_USER_AGENT_LENGTH).collect());
+
+    Self {
+      request_id: current_request_id(),
+      ip_address: client_ip(parts, proxy),
+      user_agent,
+    }
+  }
+}
+
+#[axum::async_trait]
+impl<S> FromRequestParts<S> for RequestMeta
+where
+    RouterState: FromRef<S>,
+    S: Send + Sync,
+{
+  type Rejection = Infallible;
+  async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+    let router_state = RouterState::from_ref(state);
+    Ok(Self::from_parts(parts, router_state.env.proxy.as_ref()))
+  }
+}
+
+#[derive(Debug)]
+pub struct AuditEvent {
+  pub actor_id: Option<i32>,
+  pub action: AuditAction,
+  pub target_type: AuditTarget,
+  pub target_id: String,
+  pub before: Option<JsonValue>,
+  pub after: Option<JsonValue>,
+}
+
+impl AuditEvent {
+  pub fn new(actor_id: Option<i32>, action: AuditAction, target_type: AuditTarget, target_id: impl Into<String>) -> Self {
+    Self { actor_id, action, target_type, target_id: target_id.into(), before: None, after: None }
+  }
+
+  pub fn before(mut self, before: JsonValue) -> Self {
+    self.before = Some(before);
+    self
+  }
+
+  pub fn after(mut self, after: JsonValue) -> Self {
+    self.after = Some(after);
+    self
+  }
+
+  pub fn changes(mut self, before: &JsonValue, after: &JsonValue) -> Self {
+    let (JsonValue::Object(before), JsonValue::Object(after)) = (before, after) else {
+      return self.before(before.clone()).after(after.clone());
+    };
+
+    let changed: Vec<&String> = before.keys()
+      .chain(after.keys().filter(|key| !before.contains_key(*key)))
+      .filter(|key| before.get(*key) != after.get(*key))
+      .collect();
+
+    let pick = |snapshot: &serde_json::Map<String, JsonValue>| changed.iter()
+      .map(|key| (key.to_string(), snapshot.get(*key).cloned().unwrap_or(JsonValue::Null)))
+      .collect::<serde_json::Map<_, _>>();
+
+    self.before = Some(JsonValue::Object(pick(before)));
+    self.after = Some(JsonValue::Object(pick(after)));
+    self
+  }
+}
+
+pub async fn record<'e>(executor: impl PgExecutor<'e>, meta: &RequestMeta, event: AuditEvent) -> Result<(), ApiError> {
+  sqlx::query(
+    "INSERT INTO audit_events (actor_id, action, target_type, target_id, before, after, request_id, ip_address, user_agent)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8::TEXT::INET, $9)")
+    .bind(event.actor_id)
+    .bind(event.action.name())
+    .bind(event.target_type.name())
+    .bind(&event.target_id)
+    .bind(event.before)
+    .bind(event.after)
+    .bind(&meta.request_id)
+    .bind(meta.ip_address.map(|ip| ip.to_string()))
+    .bind(&meta.user_agent)
+    .execute(executor)
+    .await?;
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use axum::http::{HeaderName, Request};
+
+  use super::*;
+
+  fn parts(forwarded: &[&str]) -> Parts {
+    let mut request = Request::builder();
+    for header in forwarded {
+      request = request.header("x-forwarded-for", *header);
+    }
+
+    let (mut parts, _) = request.body(()).unwrap().into_parts();
+    parts.extensions.insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))));
+    parts
+  }
+
+  fn proxy(trusted_hops: usize) -> ProxyConfig {
+    ProxyConfig { ip_header: HeaderName::from_static("x-forwarded-for"), trusted_hops }
+  }
+
+  fn ip(ip: &str) -> Option<IpAddr> {
+    Some(ip.parse().unwrap())
+  }
+
+  #[test]
+  fn picks_the_entry_the_last_trusted_proxy_saw() {
+    let joined = parts(&["1.1.1.1, 2.2.2.2,3.3.3.3"]);
+    assert_eq!(client_ip(&joined, Some(&proxy(1))), ip("3.3.3.3"));
+    assert_eq!(client_ip(&joined, Some(&proxy(2))), ip("2.2.2.2"));
+    assert_eq!(client_ip(&joined, Some(&proxy(3))), ip("1.1.1.1"));
+
+    // proxies may add their own header line instead of appending
+    let separate = parts(&["1.1.1.1, 2.2.2.2", "3.3.3.3"]);
+    assert_eq!(client_ip(&separate, Some(&proxy(1))), ip("3.3.3.3"));
+    assert_eq!(client_ip(&separate, Some(&proxy(2))), ip("2.2.2.2"));
+  }
+
+  #[test]
+  fn short_chains_use_the_first_entry() {
+    let request = parts(&["2001:db8::1, 2.2.2.2"]);
+    assert_eq!(client_ip(&request, Some(&proxy(3))), ip("2001:db8::1"));
+    assert_eq!(client_ip(&request, Some(&proxy(0))), ip("10.0.0.1"));
+  }
+
+  #[test]
+  fn falls_back_to_the_peer() {
+    assert_eq!(client_ip(&parts(&["1.1.1.1"]), None), ip("10.0.0.1"));
+    assert_eq!(client_ip(&parts(&[]), Some(&proxy(1))), ip("10.0.0.1"));
+    assert_eq!(client_ip(&parts(&["unknown"]), Some(&proxy(1))), ip("10.0.0.1"));
+  }
+}
@@ -0,0 +1,84 @@
+use reqwest::Client as ReqwestClient;
+use serde::Deserialize;
+
+use crate::{env::OAuthCredentials, errors::ApiError};
+
+use super::{ExternalProfile, IdentityProvider, ProviderKind};
+
+const GITHUB_AUTH_URL: &str = "https://github.com/login/oauth/authorize";
+const GITHUB_TOKEN_URL: &str = "https://github.com/login/oauth/access_token";
+const GITHUB_API_URL: &str = "https://api.github.com";
+
+// the github api rejects requests without a user agent
+const USER_AGENT: &str = "shaderx-backend";
+
+#[derive(Debug, Deserialize)]
+struct GithubUser {
+  id: i64,
+  login: String,
+  name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GithubEmail {
+  email: String,
+  primary: bool,
+  verified: bool,
+}
+
+pub fn build_provider(credentials: &OAuthCredentials, redirect_url: String) -> anyhow::Result<IdentityProvider> {
+  let provider = IdentityProvider::new(
+    "github",
+    credentials,
+    GITHUB_AUTH_URL.to_string(),
+    GITHUB_TOKEN_URL.to_string(),
+    redirect_url,
+    ProviderKind::Github { api_url: GITHUB_API_URL.to_string() },
+  )?
+    .with_scopes(&["read:user", "user:email"]);
+
+  Ok(provider)
+}
+
+pub async fn fetch_profile(
+  http: &ReqwestClient,
+  api_url: &str,
+  access_token: &str,
+) -> Result<ExternalProfile, ApiError> {
+  let user = http.get(format!("{}/user", api_url))
+    .bearer_auth(access_token)
+    .header(reqwest::header::USER_AGENT, USER_AGENT)
+    .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+    .send()
+    .await?
+    .error_for_status()?
+    .json::<GithubUser>()
+    .await?;
+
+  // the public profile email is optional, the emails endpoint tells us which one is verified
+  let emails = http.get(format!("{}/user/emails", api_url))
+    .bearer_auth(access_token)
+    .header(reqwest::header::USER_AGENT, USER_AGENT)
+    .header(reqwest::header::ACCEPT, "application/vnd.github+json")
+    .send()
+    .await?
+    .error_for_status()?
+    .json::<Vec<GithubEmail>>()
+    .await?;
+
+  let email = emails.iter()
+    .find(|email| email.primary && email.verified)
+    .or_else(|| emails.iter().find(|email| email.verified))
+    .or_else(|| emails.iter().find(|email| email.primary));
+
+  let Some(email) = email else {
+    return Err(ApiError::Provider("github account has no email address".to_string()));
+  };
+
+  Ok(ExternalProfile {
+    subject: user.id.to_string(),
+    email: email.email.clone(),
+    email_verified: email.verified,
+    name: user.name.unwrap_or(user.login),
+  })
+}
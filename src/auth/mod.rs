@@ -0,0 +1,241 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum_extra::extract::{cookie::Cookie, PrivateCookieJar};
+use oauth2::{basic::{BasicClient, BasicTokenResponse}, reqwest::async_http_client, AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, RefreshToken, Scope, TokenUrl};
+use reqwest::Client as ReqwestClient;
+use serde::{Deserialize, Serialize};
+
+use crate::{constants::{ACCESS_COOKIE_EXPIRE_TIME, LOGIN_STATE_COOKIE_EXPIRE_TIME}, env::{Env, OAuthCredentials}, errors::ApiError};
+
+pub mod github;
+pub mod oidc;
+
+const GOOGLE_AUTH_URL: &str = "https://accounts.google.com/o/oauth2/v2/auth";
+const GOOGLE_TOKEN_URL: &str = "https://www.googleapis.com/oauth2/v3/token";
+const GOOGLE_USERINFO_URL: &str = "https://openidconnect.googleapis.com/v1/userinfo";
+
+const OIDC_SCOPES: [&str; 3] = ["openid", "profile", "email"];
+
+pub const LOGIN_STATE_COOKIE: &str = "login_state";
+
+#[derive(Debug, Clone)]
+pub enum ProviderKind {
+  Oidc { userinfo_url: String },
+  Github { api_url: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct ExternalProfile {
+  pub subject: String,
+  pub email: String,
+  pub email_verified: bool,
+  pub name: String,
+}
+
+// kept in a private cookie so codes can only be redeemed by the browser that asked for them
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoginState {
+  pub provider: String,
+  pub csrf: String,
+  pub pkce_verifier: String,
+}
+
+impl LoginState {
+  pub fn take(jar: &PrivateCookieJar, provider: &str, csrf: &str) -> Result<Self, ApiError> {
+    let state = jar.get(LOGIN_STATE_COOKIE)
+      .and_then(|cookie| serde_json::from_str::<LoginState>(cookie.value()).ok())
+      .filter(|state| state.provider == provider && state.csrf == csrf);
+
+    state.ok_or_else(|| ApiError::Forbidden("login state doesn't match, start signing in again".to_string()))
+  }
+}
+
+#[derive(Debug)]
+pub struct IdentityProvider {
+  pub name: String,
+  client: BasicClient,
+  scopes: Vec<String>,
+  extra_params: Vec<(String, String)>,
+  kind: ProviderKind,
+}
+
+impl IdentityProvider {
+  fn new(
+    name: &str,
+    credentials: &OAuthCredentials,
+    auth_url: String,
+    token_url: String,
+    redirect_url: String,
+    kind: ProviderKind,
+  ) -> anyhow::Result<Self> {
+    let client = BasicClient::new(
+      ClientId::new(credentials.client_id.clone()),
+      Some(ClientSecret::new(credentials.client_secret.clone())),
+      AuthUrl::new(auth_url)?,
+      Some(TokenUrl::new(token_url)?),
+    ).set_redirect_uri(RedirectUrl::new(redirect_url)?);
+
+    Ok(Self {
+      name: name.to_string(),
+      client,
+      scopes: OIDC_SCOPES.iter().map(|scope| scope.to_string()).collect(),
+      extra_params: Vec::new(),
+      kind,
+    })
+  }
+
+  fn with_scopes(mut self, scopes: &[&str]) -> Self {
+    self.scopes = scopes.iter().map(|scope| scope.to_string()).collect();
+    self
+  }
+
+  fn with_extra_param(mut self, name: &str, value: &str) -> Self {
+    self.extra_params.push((name.to_string(), value.to_string()));
+    self
+  }
+
+  pub fn login_url(&self) -> (String, LoginState) {
+    let (challenge, verifier) = PkceCodeChallenge::new_random_sha256();
+
+    let mut request = self.client
+      .authorize_url(CsrfToken::new_random)
+      .add_scopes(self.scopes.iter().cloned().map(Scope::new))
+      .set_pkce_challenge(challenge);
+
+    for (name, value) in &self.extra_params {
+      request = request.add_extra_param(name, value);
+    }
+
+    let (url, csrf) = request.url();
+    let state = LoginState {
+      provider: self.name.clone(),
+      csrf: csrf.secret().to_owned(),
+      pkce_verifier: verifier.secret().to_owned(),
+    };
+
+    (url.to_string(), state)
+  }
+
+  pub async fn exchange_code(&self, code: String, state: LoginState) -> Result<BasicTokenResponse, ApiError> {
+    let token = self.client
+      .exchange_code(AuthorizationCode::new(code))
+      .set_pkce_verifier(PkceCodeVerifier::new(state.pkce_verifier))
+      .request_async(async_http_client)
+      .await?;
+
+    Ok(token)
+  }
+
+  pub async fn exchange_refresh_token(&self, refresh_token: String) -> Result<BasicTokenResponse, ApiError> {
+    let token = self.client
+      .exchange_refresh_token(&RefreshToken::new(refresh_token))
+      .request_async(async_http_client)
+      .await?;
+
+    Ok(token)
+  }
+
+  pub async fn fetch_profile(&self, http: &ReqwestClient, access_token: &str) -> Result<ExternalProfile, ApiError> {
+    match &self.kind {
+      ProviderKind::Oidc { userinfo_url } => oidc::fetch_profile(http, userinfo_url, access_token).await,
+      ProviderKind::Github { api_url } => github::fetch_profile(http, api_url, access_token).await,
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IdentityProviders(Arc<HashMap<String, IdentityProvider>>);
+
+impl IdentityProviders {
+  pub fn get(&self, name: &str) -> Result<&IdentityProvider, ApiError> {
+    self.0.get(name).ok_or_else(|| ApiError::UnknownProvider(name.to_string()))
+  }
+
+  pub fn names(&self) -> Vec<String> {
+    let mut names: Vec<String> = self.0.keys().cloned().collect();
+    names.sort();
+    names
+  }
+}
+
+fn redirect_url(env: &Env, provider: &str) -> String {
+  format!("{}/auth/{}/callback", env.frontend_url, provider)
+}
+
+fn build_google_provider(env: &Env, credentials: &OAuthCredentials) -> anyhow::Result<IdentityProvider> {
+  // google keeps the legacy redirect path so the uri registered in the console stays valid
+  let provider = IdentityProvider::new(
+    "google",
+    credentials,
+    GOOGLE_AUTH_URL.to_string(),
+    GOOGLE_TOKEN_URL.to_string(),
+    format!("{}/auth/google_callback", env.frontend_url),
+    ProviderKind::Oidc { userinfo_url: GOOGLE_USERINFO_URL.to_string() },
+  )?
+    .with_extra_param("access_type", "offline")
+    .with_extra_param("prompt", "consent");
+
+  Ok(provider)
+}
+
+pub async fn build_identity_providers(env: &Env, http: &ReqwestClient) -> anyhow::Result<IdentityProviders> {
+  let mut providers = Vec::new();
+
+  if let Some(credentials) = &env.google_oauth {
+    providers.push(build_google_provider(env, credentials)?);
+  }
+
+  if let Some(credentials) = &env.github_oauth {
+    providers.push(github::build_provider(credentials, redirect_url(env, "github"))?);
+  }
+
+  if let Some(credentials) = &env.gitlab_oauth {
+    providers.push(oidc::discover_provider(http, "gitlab", &env.gitlab_url, credentials, redirect_url(env, "gitlab")).await?);
+  }
+
+  if let Some(oidc) = &env.oidc {
+    providers.push(oidc::discover_provider(http, &oidc.name, &oidc.issuer_url, &oidc.credentials, redirect_url(env, &oidc.name)).await?);
+  }
+
+  let providers = providers.into_iter()
+    .map(|provider| (provider.name.clone(), provider))
+    .collect();
+
+  Ok(IdentityProviders(Arc::new(providers)))
+}
+
+pub fn build_session_cookie(env: &Env, session_id: String) -> Cookie<'static> {
+  Cookie::build(("sid", session_id))
+    .domain(env.frontend_domain.clone())
+    .path("/")
+    .secure(true)
+    .http_only(true)
+    .max_age(cookie::time::Duration::seconds(ACCESS_COOKIE_EXPIRE_TIME))
+    .build()
+}
+
+pub fn build_session_removal_cookie(env: &Env) -> Cookie<'static> {
+  Cookie::build("sid")
+    .domain(env.frontend_domain.clone())
+    .path("/")
+    .build()
+}
+
+pub fn build_login_state_cookie(env: &Env, state: &LoginState) -> Result<Cookie<'static>, ApiError> {
+  let cookie = Cookie::build((LOGIN_STATE_COOKIE, serde_json::to_string(state)?))
+    .domain(env.frontend_domain.clone())
+    .path("/")
+    .secure(true)
+    .http_only(true)
+    .max_age(cookie::time::Duration::seconds(LOGIN_STATE_COOKIE_EXPIRE_TIME))
+    .build();
+
+  Ok(cookie)
+}
+
+pub fn build_login_state_removal_cookie(env: &Env) -> Cookie<'static> {
+  Cookie::build(LOGIN_STATE_COOKIE)
+    .domain(env.frontend_domain.clone())
+    .path("/")
+    .build()
+}
@@ -0,0 +1,88 @@
+use reqwest::Client as ReqwestClient;
+use serde::Deserialize;
+
+use crate::{env::OAuthCredentials, errors::ApiError};
+
+use super::{ExternalProfile, IdentityProvider, ProviderKind};
+
+#[derive(Debug, Deserialize)]
+struct DiscoveryDocument {
+  authorization_endpoint: String,
+  token_endpoint: String,
+  userinfo_endpoint: String,
+}
+
+// some providers send `email_verified` as a string
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum EmailVerified {
+  Bool(bool),
+  String(String),
+}
+
+#[derive(Debug, Deserialize)]
+struct UserInfo {
+  sub: String,
+  email: Option<String>,
+  email_verified: Option<EmailVerified>,
+  name: Option<String>,
+  preferred_username: Option<String>,
+}
+
+pub async fn discover_provider(
+  http: &ReqwestClient,
+  name: &str,
+  issuer_url: &str,
+  credentials: &OAuthCredentials,
+  redirect_url: String,
+) -> anyhow::Result<IdentityProvider> {
+  let url = format!("{}/.well-known/openid-configuration", issuer_url.trim_end_matches('/'));
+
+  log::trace!("discovering {} provider at {}", name, url);
+  let document = http.get(&url)
+    .send()
+    .await?
+    .error_for_status()?
+    .json::<DiscoveryDocument>()
+    .await?;
+
+  IdentityProvider::new(
+    name,
+    credentials,
+    document.authorization_endpoint,
+    document.token_endpoint,
+    redirect_url,
+    ProviderKind::Oidc { userinfo_url: document.userinfo_endpoint },
+  )
+}
+
+pub async fn fetch_profile(
+  http: &ReqwestClient,
+  userinfo_url: &str,
+  access_token: &str,
+) -> Result<ExternalProfile, ApiError> {
+  let info = http.get(userinfo_url)
+    .bearer_auth(access_token)
+    .send()
+    .await?
+    .error_for_status()?
+    .json::<UserInfo>()
+    .await?;
+
+  let Some(email) = info.email else {
+    return Err(ApiError::Provider("userinfo response did not include an email".to_string()));
+  };
+
+  let email_verified = match info.email_verified {
+    Some(EmailVerified::Bool(verified)) => verified,
+    Some(EmailVerified::String(verified)) => verified == "true",
+    None => false,
+  };
+
+  Ok(ExternalProfile {
+    subject: info.sub,
+    email,
+    email_verified,
+    name: info.name.or(info.preferred_username).unwrap_or_default(),
+  })
+}
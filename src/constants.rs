@@ -1,3 +1,26 @@
 
 pub const ACCESS_TOKEN_EXPIRE_TIME: i64 = 900; // 15 minutes
 pub const ACCESS_COOKIE_EXPIRE_TIME: i64 = 2592000; // 30 days
+pub const LOGIN_STATE_COOKIE_EXPIRE_TIME: i64 = 600; // 10 minutes
+pub const REFRESH_TOKEN_EXPIRE_DAYS: i64 = 7;
+pub const SESSION_REFRESH_GRACE_TIME: i64 = 300; // 5 minutes
+pub const SESSION_GC_BATCH_SIZE: i64 = 1000;
+pub const ACCOUNT_DELETION_GRACE_DAYS: i64 = 14;
+pub const ACCOUNT_PURGE_SCHEDULE: &str = "0 * * * *"; // hourly
+pub const SHADER_PURGE_SCHEDULE: &str = "30 * * * *"; // hourly, half past
+pub const SHADER_PURGE_LOCK: i64 = 0x5348_5052_4745; // "SHPRGE"
+pub const ASSET_BLOB_LOCK: i32 = 0x424C_4F42; // "BLOB"
+pub const THUMBNAIL_MAX_UPLOAD_BYTES: usize = 4 * 1024 * 1024; // 4 MiB
+pub const THUMBNAIL_MAX_DIMENSION: u32 = 2048;
+pub const THUMBNAIL_MAX_FRAMES: usize = 120;
+pub const ARCHIVE_MAX_UPLOAD_BYTES: usize = 64 * 1024 * 1024; // 64 MiB
+pub const JOB_POLL_INTERVAL: u64 = 1;
+pub const JOB_SCHEDULER_INTERVAL: u64 = 15;
+pub const JOB_MAX_ATTEMPTS: i32 = 5;
+pub const JOB_TIMEOUT: u64 = 600; // 10 minutes
+pub const JOB_STALE_LOCK: u64 = 3600; // 1 hour
+pub const JOB_RETRY_BASE_DELAY: i64 = 30;
+pub const JOB_RETRY_MAX_DELAY: i64 = 21600; // 6 hours
+pub const JOB_RETENTION_DAYS: i64 = 7;
+pub const RATE_LIMIT_MEMORY_MAX_KEYS: usize = 100_000;
+pub const RATE_LIMIT_GC_SCHEDULE: &str = "*/10 * * * *";
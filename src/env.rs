@@ -1,35 +1,402 @@
-
+use axum::http::{HeaderName, Method};
 use dotenv::dotenv;
 
+use crate::jobs::cron::CronSchedule;
+
+#[derive(Debug, Clone)]
+pub struct OAuthCredentials {
+  pub client_id: String,
+  pub client_secret: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+  pub name: String,
+  pub issuer_url: String,
+  pub credentials: OAuthCredentials,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ShaderLimits {
+  pub max_pass_code_bytes: usize,
+  pub max_document_bytes: usize,
+  pub max_passes: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct S3Config {
+  pub endpoint: Option<String>,
+  pub bucket: String,
+  pub region: String,
+  pub access_key_id: String,
+  pub secret_access_key: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum AssetStorageConfig {
+  Local { root: String },
+  S3(S3Config),
+}
+
+#[derive(Debug, Clone)]
+pub struct AssetConfig {
+  pub storage: AssetStorageConfig,
+  pub max_upload_bytes: usize,
+  pub max_dimension: u32,
+  pub user_quota_bytes: i64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QuotaConfig {
+  pub max_shaders: i64,
+  pub max_templates: i64,
+  pub max_code_bytes: i64,
+  pub max_revisions_per_shader: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+  pub ip_header: HeaderName,
+  pub trusted_hops: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TrashConfig {
+  pub retention_days: i64,
+  pub warning_days: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionGcConfig {
+  pub schedule: String,
+  pub grace_hours: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitBackend {
+  Memory,
+  Postgres,
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitRule {
+  pub method: Option<Method>,
+  pub path: String,
+  pub limit: u64,
+  pub window: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+  pub backend: RateLimitBackend,
+  pub rules: Vec<RateLimitRule>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppEnv {
+  Development,
+  Production,
+}
+
 #[derive(Debug, Clone)]
 pub struct Env {
+  pub app_env: AppEnv,
   pub database_url: String,
   pub database_pwd: String,
   pub database_usr: String,
-  pub google_client_id: String,
-  pub google_client_secret: String,
+  pub google_oauth: Option<OAuthCredentials>,
+  pub github_oauth: Option<OAuthCredentials>,
+  pub gitlab_oauth: Option<OAuthCredentials>,
+  pub gitlab_url: String,
+  pub oidc: Option<OidcConfig>,
+  pub dev_auth_enabled: bool,
+  pub admin_emails: Vec<String>,
+  pub shader_limits: ShaderLimits,
+  pub quotas: QuotaConfig,
+  pub shader_trash: TrashConfig,
+  pub session_gc: Option<SessionGcConfig>,
+  pub assets: AssetConfig,
+  pub rate_limits: RateLimitConfig,
   pub frontend_url: String,
   pub frontend_domain: String,
+  pub public_url: String,
   pub backend_port: u16,
+  pub job_workers: usize,
+  pub proxy: Option<ProxyConfig>,
 }
 
 const DEFAULT_PORT: u16 = 3000;
+const DEFAULT_GITLAB_URL: &str = "https://gitlab.com";
+const DEFAULT_OIDC_PROVIDER_NAME: &str = "oidc";
+const DEFAULT_MAX_PASS_CODE_BYTES: usize = 64 * 1024;
+const DEFAULT_MAX_DOCUMENT_BYTES: usize = 256 * 1024;
+const DEFAULT_MAX_PASSES: usize = 8;
+const DEFAULT_ASSET_LOCAL_PATH: &str = "./assets";
+const DEFAULT_S3_REGION: &str = "us-east-1";
+const DEFAULT_MAX_UPLOAD_BYTES: usize = 8 * 1024 * 1024;
+const DEFAULT_MAX_IMAGE_DIMENSION: usize = 4096;
+const DEFAULT_USER_ASSET_QUOTA_BYTES: usize = 100 * 1024 * 1024;
+const DEFAULT_SESSION_GC_SCHEDULE: &str = "45 * * * *";
+const DEFAULT_SESSION_GC_GRACE_HOURS: usize = 24;
+const DEFAULT_RATE_LIMITS: &str = "POST /shader=30/60,POST /shader/import=10/60,POST /shader/:id/fork=30/60,\
+  POST /auth/:provider/callback=10/60,POST /auth/google_callback=10/60";
+const DEFAULT_PROXY_IP_HEADER: &str = "x-forwarded-for";
+const DEFAULT_TRUSTED_PROXY_HOPS: usize = 1;
+const DEFAULT_JOB_WORKERS: usize = 2;
+const DEFAULT_USER_MAX_SHADERS: usize = 500;
+const DEFAULT_USER_MAX_TEMPLATES: usize = 50;
+const DEFAULT_USER_MAX_CODE_BYTES: usize = 50 * 1024 * 1024;
+const DEFAULT_MAX_REVISIONS_PER_SHADER: usize = 100;
+const DEFAULT_TRASH_RETENTION_DAYS: usize = 30;
+const DEFAULT_TRASH_WARNING_DAYS: usize = 3;
+
+fn parse_credentials(id_var: &str, secret_var: &str) -> Option<OAuthCredentials> {
+  let client_id = std::env::var(id_var).ok()?;
+  let client_secret = std::env::var(secret_var)
+    .unwrap_or_else(|_| panic!("{} must be set when {} is set", secret_var, id_var));
+
+  Some(OAuthCredentials { client_id, client_secret })
+}
+
+fn parse_oidc_config() -> Option<OidcConfig> {
+  let credentials = parse_credentials("OIDC_CLIENT_ID", "OIDC_CLIENT_SECRET")?;
+
+  Some(OidcConfig {
+    name: std::env::var("OIDC_PROVIDER_NAME").unwrap_or(DEFAULT_OIDC_PROVIDER_NAME.to_string()),
+    issuer_url: std::env::var("OIDC_ISSUER_URL").expect("OIDC_ISSUER_URL must be set when OIDC_CLIENT_ID is set"),
+    credentials,
+  })
+}
+
+fn parse_app_env() -> AppEnv {
+  // anything that isn't explicitly a development setup is treated as production
+  match std::env::var("APP_ENV").as_deref() {
+    Ok("development") => AppEnv::Development,
+    _ => AppEnv::Production,
+  }
+}
+
+fn parse_usize(var: &str, default: usize) -> usize {
+  std::env::var(var)
+    .map(|value| value.parse().unwrap_or_else(|_| panic!("{} must be a number", var)))
+    .unwrap_or(default)
+}
+
+fn parse_shader_limits() -> ShaderLimits {
+  ShaderLimits {
+    max_pass_code_bytes: parse_usize("SHADER_MAX_PASS_CODE_BYTES", DEFAULT_MAX_PASS_CODE_BYTES),
+    max_document_bytes: parse_usize("SHADER_MAX_DOCUMENT_BYTES", DEFAULT_MAX_DOCUMENT_BYTES),
+    max_passes: parse_usize("SHADER_MAX_PASSES", DEFAULT_MAX_PASSES),
+  }
+}
+
+fn parse_asset_storage_config() -> AssetStorageConfig {
+  match std::env::var("ASSET_STORAGE").as_deref() {
+    Ok("s3") => AssetStorageConfig::S3(S3Config {
+      // unset for aws itself, set it to point at minio or any other s3 compatible store
+      endpoint: std::env::var("S3_ENDPOINT").ok(),
+      bucket: std::env::var("S3_BUCKET").expect("S3_BUCKET must be set when ASSET_STORAGE is s3"),
+      region: std::env::var("S3_REGION").unwrap_or(DEFAULT_S3_REGION.to_string()),
+      access_key_id: std::env::var("S3_ACCESS_KEY_ID").expect("S3_ACCESS_KEY_ID must be set when ASSET_STORAGE is s3"),
+      secret_access_key: std::env::var("S3_SECRET_ACCESS_KEY").expect("S3_SECRET_ACCESS_KEY must be set when ASSET_STORAGE is s3"),
+    }),
+    Ok("local") | Err(_) => AssetStorageConfig::Local {
+      root: std::env::var("ASSET_LOCAL_PATH").unwrap_or(DEFAULT_ASSET_LOCAL_PATH.to_string()),
+    },
+    Ok(other) => panic!("ASSET_STORAGE must be local or s3, got {}", other),
+  }
+}
+
+fn parse_asset_config() -> AssetConfig {
+  AssetConfig {
+    storage: parse_asset_storage_config(),
+    max_upload_bytes: parse_usize("ASSET_MAX_UPLOAD_BYTES", DEFAULT_MAX_UPLOAD_BYTES),
+    max_dimension: parse_usize("ASSET_MAX_DIMENSION", DEFAULT_MAX_IMAGE_DIMENSION)
+      .try_into().expect("ASSET_MAX_DIMENSION is too large"),
+    user_quota_bytes: parse_usize("ASSET_USER_QUOTA_BYTES", DEFAULT_USER_ASSET_QUOTA_BYTES)
+      .try_into().expect("ASSET_USER_QUOTA_BYTES is too large"),
+  }
+}
+
+fn parse_quota_config() -> QuotaConfig {
+  QuotaConfig {
+    max_shaders: parse_usize("USER_QUOTA_SHADERS", DEFAULT_USER_MAX_SHADERS)
+      .try_into().expect("USER_QUOTA_SHADERS is too large"),
+    max_templates: parse_usize("USER_QUOTA_TEMPLATES", DEFAULT_USER_MAX_TEMPLATES)
+      .try_into().expect("USER_QUOTA_TEMPLATES is too large"),
+    max_code_bytes: parse_usize("USER_QUOTA_CODE_BYTES", DEFAULT_USER_MAX_CODE_BYTES)
+      .try_into().expect("USER_QUOTA_CODE_BYTES is too large"),
+    max_revisions_per_shader: parse_usize("USER_QUOTA_REVISIONS_PER_SHADER", DEFAULT_MAX_REVISIONS_PER_SHADER)
+      .try_into().expect("USER_QUOTA_REVISIONS_PER_SHADER is too large"),
+  }
+}
+
+fn parse_trash_config() -> TrashConfig {
+  let retention_days = parse_usize("SHADER_TRASH_RETENTION_DAYS", DEFAULT_TRASH_RETENTION_DAYS);
+  let warning_days = parse_usize("SHADER_TRASH_WARNING_DAYS", DEFAULT_TRASH_WARNING_DAYS);
+
+  if retention_days == 0 || warning_days >= retention_days {
+    panic!("SHADER_TRASH_WARNING_DAYS must be less than SHADER_TRASH_RETENTION_DAYS");
+  }
+
+  TrashConfig {
+    retention_days: retention_days.try_into().expect("SHADER_TRASH_RETENTION_DAYS is too large"),
+    warning_days: warning_days.try_into().expect("SHADER_TRASH_WARNING_DAYS is too large"),
+  }
+}
+
+fn parse_session_gc_config() -> Option<SessionGcConfig> {
+  let schedule = std::env::var("SESSION_GC_SCHEDULE").unwrap_or(DEFAULT_SESSION_GC_SCHEDULE.to_string());
+  if schedule.trim() == "off" {
+    return None;
+  }
+
+  if let Err(e) = CronSchedule::parse(&schedule) {
+    panic!("SESSION_GC_SCHEDULE must be a cron expression or off: {}", e);
+  }
+
+  Some(SessionGcConfig {
+    schedule,
+    grace_hours: parse_usize("SESSION_GC_GRACE_HOURS", DEFAULT_SESSION_GC_GRACE_HOURS)
+      .try_into().expect("SESSION_GC_GRACE_HOURS is too large"),
+  })
+}
+
+fn parse_rate_limit_rule(rule: &str) -> Option<RateLimitRule> {
+  let (route, budget) = rule.split_once('=')?;
+  let (method, path) = route.trim().split_once(' ')?;
+  let (limit, window) = budget.trim().split_once('/')?;
+
+  let method = match method {
+    "*" => None,
+    method => Some(method.parse().ok()?),
+  };
+
+  let (limit, window) = (limit.parse().ok()?, window.parse().ok()?);
+  if limit == 0 || window == 0 {
+    return None;
+  }
+
+  Some(RateLimitRule { method, path: path.trim().to_string(), limit, window })
+}
+
+fn parse_rate_limit_config() -> RateLimitConfig {
+  let backend = match std::env::var("RATE_LIMIT_STORE").as_deref() {
+    Ok("postgres") => RateLimitBackend::Postgres,
+    Ok("memory") | Err(_) => RateLimitBackend::Memory,
+    Ok(_) => panic!("RATE_LIMIT_STORE must be memory or postgres"),
+  };
+
+  let rules = std::env::var("RATE_LIMITS").unwrap_or(DEFAULT_RATE_LIMITS.to_string());
+  if rules.trim() == "off" {
+    return RateLimitConfig { backend, rules: Vec::new() };
+  }
+
+  let rules = rules.split(',')
+    .filter(|rule| !rule.trim().is_empty())
+    .map(|rule| parse_rate_limit_rule(rule)
+      .unwrap_or_else(|| panic!("RATE_LIMITS rule '{}' must look like METHOD PATH=LIMIT/WINDOW", rule.trim())))
+    .collect();
+
+  RateLimitConfig { backend, rules }
+}
+
+fn parse_proxy_config() -> Option<ProxyConfig> {
+  if !parse_bool("TRUST_PROXY") {
+    return None;
+  }
+
+  let header = std::env::var("PROXY_IP_HEADER").unwrap_or(DEFAULT_PROXY_IP_HEADER.to_string());
+  let trusted_hops = parse_usize("TRUSTED_PROXY_HOPS", DEFAULT_TRUSTED_PROXY_HOPS);
+  assert!(trusted_hops > 0, "TRUSTED_PROXY_HOPS must be at least 1");
+
+  Some(ProxyConfig {
+    ip_header: header.parse().expect("PROXY_IP_HEADER must be a header name"),
+    trusted_hops,
+  })
+}
+
+fn parse_admin_emails() -> Vec<String> {
+  std::env::var("ADMIN_EMAILS")
+    .map(|emails| emails.split(',').map(|email| email.trim().to_lowercase()).filter(|email| !email.is_empty()).collect())
+    .unwrap_or_default()
+}
+
+fn parse_bool(var: &str) -> bool {
+  std::env::var(var)
+    .map(|value| matches!(value.as_str(), "1" | "true"))
+    .unwrap_or(false)
+}
 
 pub fn parse_env() -> Env {
   dotenv().ok();
 
+  let app_env = parse_app_env();
+  let dev_auth_enabled = parse_bool("DEV_AUTH_ENABLED");
+
+  if dev_auth_enabled && app_env == AppEnv::Production {
+    panic!("DEV_AUTH_ENABLED can only be set when APP_ENV is development");
+  }
+
   let port = std::env::var("BACKEND_PORT")
     .map(|port| port.parse().expect("BACKEND_PORT must be a number"))
     .unwrap_or(DEFAULT_PORT);
 
   Env {
+    app_env,
     database_url: std::env::var("DATABASE_URL").expect("DATABASE_URL must be set"),
     database_pwd: std::env::var("DATABASE_PWD").expect("DATABASE_PWD must be set"),
     database_usr: std::env::var("DATABASE_USR").expect("DATABASE_USR must be set"),
-    google_client_id: std::env::var("GOOGLE_OAUTH_CLIENT_ID").expect("GOOGLE_OAUTH_CLIENT_ID must be set"),
-    google_client_secret: std::env::var("GOOGLE_OAUTH_CLIENT_SECRET").expect("GOOGLE_OAUTH_CLIENT_SECRET must be set"),
+    google_oauth: parse_credentials("GOOGLE_OAUTH_CLIENT_ID", "GOOGLE_OAUTH_CLIENT_SECRET"),
+    github_oauth: parse_credentials("GITHUB_OAUTH_CLIENT_ID", "GITHUB_OAUTH_CLIENT_SECRET"),
+    gitlab_oauth: parse_credentials("GITLAB_OAUTH_CLIENT_ID", "GITLAB_OAUTH_CLIENT_SECRET"),
+    gitlab_url: std::env::var("GITLAB_URL").unwrap_or(DEFAULT_GITLAB_URL.to_string()),
+    oidc: parse_oidc_config(),
+    dev_auth_enabled,
+    admin_emails: parse_admin_emails(),
+    shader_limits: parse_shader_limits(),
+    quotas: parse_quota_config(),
+    shader_trash: parse_trash_config(),
+    session_gc: parse_session_gc_config(),
+    assets: parse_asset_config(),
+    rate_limits: parse_rate_limit_config(),
     frontend_url: std::env::var("FRONTEND_URL").expect("FRONTEND_URL must be set"),
     frontend_domain: std::env::var("FRONTEND_DOMAIN").expect("FRONTEND_DOMAIN must be set"),
+    public_url: std::env::var("PUBLIC_URL")
+      .map(|url| url.trim_end_matches('/').to_string())
+      .unwrap_or(format!("http://localhost:{}", port)),
     backend_port: port,
+    job_workers: parse_usize("JOB_WORKERS", DEFAULT_JOB_WORKERS),
+    proxy: parse_proxy_config(),
+  }
+}
+
+// the defaults of everything, without providers, so tests don't depend on the environment
+#[cfg(test)]
+pub fn test_env() -> Env {
+  Env {
+    app_env: AppEnv::Development,
+    database_url: String::new(),
+    database_pwd: String::new(),
+    database_usr: String::new(),
+    google_oauth: None,
+    github_oauth: None,
+    gitlab_oauth: None,
+    gitlab_url: DEFAULT_GITLAB_URL.to_string(),
+    oidc: None,
+    dev_auth_enabled: false,
+    admin_emails: Vec::new(),
+    shader_limits: parse_shader_limits(),
+    quotas: parse_quota_config(),
+    shader_trash: parse_trash_config(),
+    session_gc: parse_session_gc_config(),
+    assets: parse_asset_config(),
+    rate_limits: parse_rate_limit_config(),
+    frontend_url: "http://localhost:5173".to_string(),
+    frontend_domain: "localhost".to_string(),
+    public_url: format!("http://localhost:{}", DEFAULT_PORT),
+    backend_port: DEFAULT_PORT,
+    job_workers: 0,
+    proxy: None,
   }
 }
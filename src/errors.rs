@@ -1,14 +1,41 @@
-
-use axum::http::StatusCode;
+use axum::http::{header::RETRY_AFTER, StatusCode};
 use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
 use thiserror::Error;
 
+use crate::{middlewares::request_id::current_request_id, quotas::QuotaExceeded};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+  pub field: String,
+  pub message: String,
+}
+
+impl FieldError {
+  pub fn new(field: &str, message: impl Into<String>) -> Self {
+    Self { field: field.to_string(), message: message.into() }
+  }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+  pub code: &'static str,
+  pub message: String,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub details: Vec<FieldError>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub quota: Option<QuotaExceeded>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub request_id: Option<String>,
+}
+
 #[derive(Debug, Error)]
 pub enum ApiError {
   #[error("SQL error: {0}")]
-  SQL(#[from] sqlx::Error),
+  Sql(#[from] sqlx::Error),
   #[error("HTTP request error: {0}")]
-  Request(#[from] reqwest::Error),
+  Request(reqwest::Error),
   #[error("OAuth token error: {0}")]
   TokenError(
     #[from]
@@ -19,30 +46,154 @@ pub enum ApiError {
   ),
   #[error("You're not authorized!")]
   Unauthorized,
-  #[error("Attempted to get a non-none value but found none")]
-  OptionError,
+  #[error("Validation failed: {0:?}")]
+  Validation(Vec<FieldError>),
+  #[error("Forbidden: {0}")]
+  Forbidden(String),
+  #[error("Not found: {0}")]
+  NotFound(String),
+  #[error("Conflict: {0}")]
+  Conflict(String),
+  #[error("Rate limited, retry after {retry_after:?} seconds")]
+  RateLimited { retry_after: Option<u64> },
+  #[error("Payload too large: {0}")]
+  PayloadTooLarge(String),
+  #[error("Unsupported media type: {0}")]
+  UnsupportedMediaType(String),
+  #[error("Quota exceeded: {0}")]
+  QuotaExceeded(QuotaExceeded),
+  #[error("Not implemented: {0}")]
+  NotImplemented(String),
+  #[error("Multipart error: {0}")]
+  Multipart(#[from] axum::extract::multipart::MultipartError),
+  #[error("Asset storage error: {0:?}")]
+  Storage(anyhow::Error),
+  #[error("Image processing error: {0}")]
+  Image(#[from] image::ImageError),
+  #[error("Background task failed: {0}")]
+  Task(#[from] tokio::task::JoinError),
+  #[error("Invalid job payload: {0}")]
+  JobPayload(#[from] serde_json::Error),
+  #[error("Unknown identity provider: {0}")]
+  UnknownProvider(String),
+  #[error("Identity provider error: {0}")]
+  Provider(String),
   #[error("Attempted to parse a number to an integer but errored out: {0}")]
   ParseIntError(#[from] std::num::TryFromIntError),
   #[error("Encountered an error trying to convert an infallible value: {0}")]
   FromRequestPartsError(#[from] std::convert::Infallible),
 }
 
+impl From<reqwest::Error> for ApiError {
+  fn from(e: reqwest::Error) -> Self {
+    if e.status() == Some(reqwest::StatusCode::TOO_MANY_REQUESTS) {
+      return Self::RateLimited { retry_after: None };
+    }
+
+    Self::Request(e)
+  }
+}
+
+pub const INTERNAL_ERROR_MESSAGE: &str = "internal server error";
+
+pub fn error_response(status: StatusCode, code: &'static str, message: String, details: Vec<FieldError>) -> Response {
+  let body = ErrorBody {
+    code,
+    message,
+    details,
+    quota: None,
+    request_id: current_request_id(),
+  };
+
+  (status, Json(body)).into_response()
+}
+
+pub fn status_code_name(status: StatusCode) -> &'static str {
+  match status {
+    StatusCode::BAD_REQUEST => "bad_request",
+    StatusCode::UNAUTHORIZED => "unauthorized",
+    StatusCode::FORBIDDEN => "forbidden",
+    StatusCode::NOT_FOUND => "not_found",
+    StatusCode::METHOD_NOT_ALLOWED => "method_not_allowed",
+    StatusCode::CONFLICT => "conflict",
+    StatusCode::PAYLOAD_TOO_LARGE => "payload_too_large",
+    StatusCode::UNSUPPORTED_MEDIA_TYPE => "unsupported_media_type",
+    StatusCode::UNPROCESSABLE_ENTITY => "validation_failed",
+    StatusCode::TOO_MANY_REQUESTS => "rate_limited",
+    StatusCode::NOT_IMPLEMENTED => "not_implemented",
+    status if status.is_client_error() => "bad_request",
+    _ => "internal_error",
+  }
+}
+
+impl ApiError {
+  fn status(&self) -> (StatusCode, &'static str, String) {
+    match self {
+      Self::Sql(sqlx::Error::RowNotFound) => (StatusCode::NOT_FOUND, "not_found", "resource not found".to_string()),
+      Self::Sql(sqlx::Error::Database(e)) if e.is_unique_violation() => (
+        StatusCode::CONFLICT, "conflict", "resource already exists".to_string(),
+      ),
+      Self::Sql(sqlx::Error::Database(e)) if e.is_foreign_key_violation() => (
+        StatusCode::CONFLICT, "conflict", "resource is still referenced or references a missing resource".to_string(),
+      ),
+      Self::Request(_) => (StatusCode::BAD_GATEWAY, "provider_error", "identity provider request failed".to_string()),
+      Self::TokenError(oauth2::RequestTokenError::ServerResponse(response)) => (
+        StatusCode::BAD_REQUEST, "oauth_error", response.error().to_string(),
+      ),
+      Self::TokenError(_) => (StatusCode::BAD_GATEWAY, "provider_error", "identity provider token request failed".to_string()),
+      Self::Unauthorized => (StatusCode::UNAUTHORIZED, "unauthorized", "Unauthorized!".to_string()),
+      Self::Validation(_) => (StatusCode::UNPROCESSABLE_ENTITY, "validation_failed", "request validation failed".to_string()),
+      Self::Forbidden(e) => (StatusCode::FORBIDDEN, "forbidden", e.clone()),
+      Self::NotFound(e) => (StatusCode::NOT_FOUND, "not_found", e.clone()),
+      Self::Conflict(e) => (StatusCode::CONFLICT, "conflict", e.clone()),
+      Self::RateLimited { .. } => (StatusCode::TOO_MANY_REQUESTS, "rate_limited", "too many requests, try again later".to_string()),
+      Self::PayloadTooLarge(e) => (StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", e.clone()),
+      Self::UnsupportedMediaType(e) => (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type", e.clone()),
+      Self::QuotaExceeded(e) => (StatusCode::FORBIDDEN, "quota_exceeded", e.to_string()),
+      Self::NotImplemented(e) => (StatusCode::NOT_IMPLEMENTED, "not_implemented", e.clone()),
+      Self::Multipart(e) => (e.status(), status_code_name(e.status()), e.body_text()),
+      Self::UnknownProvider(name) => (StatusCode::NOT_FOUND, "unknown_provider", format!("Unknown identity provider: {}", name)),
+      Self::Provider(e) => (StatusCode::BAD_GATEWAY, "provider_error", e.clone()),
+      Self::Sql(_) | Self::Storage(_) | Self::Image(_) | Self::Task(_) | Self::JobPayload(_) | Self::ParseIntError(_) | Self::FromRequestPartsError(_) => (
+        StatusCode::INTERNAL_SERVER_ERROR, "internal_error", INTERNAL_ERROR_MESSAGE.to_string(),
+      ),
+    }
+  }
+}
+
 impl IntoResponse for ApiError {
   fn into_response(self) -> Response {
-    let response = match self {
-      Self::SQL(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
-      Self::Request(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
-      Self::TokenError(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
-      Self::Unauthorized => (StatusCode::UNAUTHORIZED, "Unauthorized!".to_string()),
-      Self::OptionError => (
-        StatusCode::INTERNAL_SERVER_ERROR,
-        "Attempted to get a non-none value but found none".to_string(),
-      ),
-      Self::ParseIntError(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
-      Self::FromRequestPartsError(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    let (status, code, message) = self.status();
+
+    // internals are only logged, the client gets the generic message
+    if status.is_server_error() {
+      log::error!("[{}] {:?}", current_request_id().unwrap_or_default(), self);
+    }
+
+    let retry_after = match &self {
+      Self::RateLimited { retry_after } => *retry_after,
+      _ => None,
+    };
+
+    let (details, quota) = match self {
+      Self::Validation(details) => (details, None),
+      Self::QuotaExceeded(quota) => (Vec::new(), Some(quota)),
+      _ => (Vec::new(), None),
     };
 
-    response.into_response()
+    let body = ErrorBody {
+      code,
+      message,
+      details,
+      quota,
+      request_id: current_request_id(),
+    };
+
+    let mut response = (status, Json(body)).into_response();
+    if let Some(retry_after) = retry_after {
+      response.headers_mut().insert(RETRY_AFTER, retry_after.into());
+    }
+
+    response
   }
 }
-
@@ -0,0 +1,329 @@
+use std::{collections::{HashMap, VecDeque}, fmt};
+
+use serde::Serialize;
+use sqlx::{types::uuid::Uuid, PgPool};
+
+use crate::{errors::{ApiError, FieldError}, routes::shader::{AccessLevel, ShaderData}};
+
+pub const MAX_LIBRARY_NAME_LENGTH: usize = 64;
+const MAX_INCLUDE_DEPTH: usize = 16;
+const MAX_INCLUDED_LIBRARIES: usize = 64;
+const INCLUDE_DIRECTIVE: &str = "#include";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct IncludePath {
+  pub username: String,
+  pub library: String,
+  pub revision: Option<i32>,
+}
+
+impl IncludePath {
+  fn library_key(&self) -> String {
+    format!("{}/{}", self.username, self.library)
+  }
+}
+
+impl fmt::Display for IncludePath {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self.revision {
+      Some(revision) => write!(f, "{}/{}@{}", self.username, self.library, revision),
+      None => write!(f, "{}/{}", self.username, self.library),
+    }
+  }
+}
+
+pub fn is_library_name(name: &str) -> bool {
+  !name.is_empty()
+    && name.len() <= MAX_LIBRARY_NAME_LENGTH
+    && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+fn parse_include_path(rest: &str) -> Result<IncludePath, String> {
+  let expected = || "expected #include \"username/library@revision\"".to_string();
+
+  let (path, trailing) = rest.trim().strip_prefix('"')
+    .and_then(|rest| rest.split_once('"'))
+    .ok_or_else(expected)?;
+
+  let trailing = trailing.trim();
+  if !trailing.is_empty() && !trailing.starts_with("//") {
+    return Err("unexpected text after the include".to_string());
+  }
+
+  let (username, library) = path.split_once('/').ok_or_else(expected)?;
+  let (library, revision) = match library.split_once('@') {
+    Some((library, revision)) => {
+      let revision = revision.parse::<i32>().ok()
+        .filter(|revision| *revision > 0)
+        .ok_or_else(|| "revision must be a positive number".to_string())?;
+
+      (library, Some(revision))
+    },
+    None => (library, None),
+  };
+
+  if username.is_empty() || !is_library_name(library) {
+    return Err(expected());
+  }
+
+  Ok(IncludePath { username: username.to_string(), library: library.to_string(), revision })
+}
+
+fn parse_include(line: &str) -> Option<Result<IncludePath, String>> {
+  let rest = line.trim_start().strip_prefix(INCLUDE_DIRECTIVE)?;
+  Some(parse_include_path(rest))
+}
+
+fn includes_of(code: &str) -> impl Iterator<Item = IncludePath> + '_ {
+  code.lines().filter_map(|line| parse_include(line)?.ok())
+}
+
+#[derive(Debug)]
+struct Library {
+  shader_id: String,
+  revision: i32,
+  code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResolvedInclude {
+  pub path: String,
+  pub shader_id: String,
+  pub revision: i32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResolvedPass {
+  pub name: String,
+  pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResolvedShader {
+  pub code: String,
+  pub passes: Vec<ResolvedPass>,
+  pub includes: Vec<ResolvedInclude>,
+}
+
+// anyone who can see the shader sees the code of its includes
+async fn fetch_library(db: &PgPool, owner: Uuid, access: AccessLevel, path: &IncludePath) -> Result<Option<Library>, ApiError> {
+  let library: Option<(String, i32, String)> = sqlx::query_as(
+    "SELECT shader_libraries.shader_id, shader_library_revisions.revision, shader_library_revisions.code
+    FROM shader_libraries
+    INNER JOIN users ON users.user_id = shader_libraries.user_id
+    INNER JOIN shaders ON shaders.id = shader_libraries.shader_id
+    INNER JOIN shader_library_revisions ON shader_library_revisions.shader_id = shader_libraries.shader_id
+    WHERE users.username = $1 AND shader_libraries.name = $2
+    AND users.deleted = false AND shaders.deleted = false
+    AND ((shaders.access <> 'private' AND shaders.hidden = false) OR (shaders.user_id = $3 AND $5))
+    AND ($4::INT IS NULL OR shader_library_revisions.revision = $4)
+    ORDER BY shader_library_revisions.revision DESC LIMIT 1")
+    .bind(&path.username)
+    .bind(&path.library)
+    .bind(owner)
+    .bind(path.revision)
+    .bind(access == AccessLevel::Private)
+    .fetch_optional(db)
+    .await?;
+
+  Ok(library.map(|(shader_id, revision, code)| Library { shader_id, revision, code }))
+}
+
+struct Flattener<'a> {
+  libraries: &'a HashMap<IncludePath, Library>,
+  max_bytes: usize,
+  included: HashMap<String, i32>,
+  stack: Vec<String>,
+}
+
+impl Flattener<'_> {
+  fn flatten(&mut self, code: &str, out: &mut String) -> Result<(), String> {
+    for (i, line) in code.lines().enumerate() {
+      let Some(path) = parse_include(line) else {
+        out.push_str(line);
+        out.push('\n');
+        continue;
+      };
+
+      let at_line = |message: String| format!("line {}: {}", i + 1, message);
+      let path = path.map_err(at_line)?;
+      let key = path.library_key();
+
+      let library = self.libraries.get(&path)
+        .ok_or_else(|| at_line(format!("library \"{}\" not found", path)))?;
+
+      if self.stack.contains(&key) {
+        return Err(at_line(format!("include cycle {} -> {}", self.stack.join(" -> "), key)));
+      }
+
+      match self.included.get(&key) {
+        Some(revision) if *revision == library.revision => {
+          out.push_str(&format!("// \"{}\" is included already\n", path));
+          continue;
+        },
+        Some(revision) => return Err(at_line(format!(
+          "\"{}\" resolves to revision {} but revision {} is included already", path, library.revision, revision,
+        ))),
+        None => (),
+      }
+
+      if self.stack.len() >= MAX_INCLUDE_DEPTH {
+        return Err(at_line(format!("includes can be nested at most {} levels deep", MAX_INCLUDE_DEPTH)));
+      }
+
+      self.included.insert(key.clone(), library.revision);
+      self.stack.push(key.clone());
+
+      out.push_str(&format!("// begin include \"{}@{}\"\n", key, library.revision));
+      self.flatten(&library.code, out).map_err(|e| format!("in \"{}\": {}", path, e))?;
+      out.push_str(&format!("// end include \"{}@{}\"\n", key, library.revision));
+
+      self.stack.pop();
+
+      if out.len() > self.max_bytes {
+        return Err(format!("expands to more than {} bytes", self.max_bytes));
+      }
+    }
+
+    Ok(())
+  }
+}
+
+pub async fn resolve_shader(
+  db: &PgPool,
+  owner: Uuid,
+  access: AccessLevel,
+  library: Option<&str>,
+  data: &ShaderData,
+  max_bytes: usize,
+) -> Result<ResolvedShader, ApiError> {
+  let sources: Vec<(String, &str)> = std::iter::once(("data.code".to_string(), data.code.as_str()))
+    .chain(data.passes.iter().enumerate().map(|(i, pass)| (format!("data.passes[{}].code", i), pass.code.as_str())))
+    .collect();
+
+  // fetch everything reachable first, the expansion itself is plain string work
+  let mut libraries: HashMap<IncludePath, Library> = HashMap::new();
+  let mut missing: Vec<IncludePath> = Vec::new();
+  let mut queue: VecDeque<IncludePath> = sources.iter().flat_map(|(_, code)| includes_of(code)).collect();
+
+  while let Some(path) = queue.pop_front() {
+    if libraries.contains_key(&path) || missing.contains(&path) {
+      continue;
+    }
+
+    if libraries.len() >= MAX_INCLUDED_LIBRARIES {
+      return Err(ApiError::Validation(vec![
+        FieldError::new("data", format!("can include at most {} libraries", MAX_INCLUDED_LIBRARIES)),
+      ]));
+    }
+
+    match fetch_library(db, owner, access, &path).await? {
+      Some(fetched) => {
+        queue.extend(includes_of(&fetched.code));
+        libraries.insert(path, fetched);
+      },
+      None => missing.push(path),
+    }
+  }
+
+  let mut errors = Vec::new();
+  let mut flattened = Vec::new();
+
+  for (field, code) in &sources {
+    let mut flattener = Flattener {
+      libraries: &libraries,
+      max_bytes,
+      included: HashMap::new(),
+      stack: library.map(|library| vec![library.to_string()]).unwrap_or_default(),
+    };
+
+    let mut out = String::new();
+    match flattener.flatten(code, &mut out) {
+      Ok(()) => flattened.push(out),
+      Err(e) => errors.push(FieldError::new(field, e)),
+    }
+  }
+
+  if !errors.is_empty() {
+    return Err(ApiError::Validation(errors));
+  }
+
+  let mut flattened = flattened.into_iter();
+  let code = flattened.next().unwrap_or_default();
+  let passes = data.passes.iter().zip(flattened)
+    .map(|(pass, code)| ResolvedPass { name: pass.name.clone(), code })
+    .collect();
+
+  let mut includes: Vec<ResolvedInclude> = libraries.into_iter()
+    .map(|(path, library)| ResolvedInclude { path: path.to_string(), shader_id: library.shader_id, revision: library.revision })
+    .collect();
+  includes.sort_by(|a, b| a.path.cmp(&b.path));
+
+  Ok(ResolvedShader { code, passes, includes })
+}
+
+pub async fn verify_includes(
+  db: &PgPool,
+  owner: Uuid,
+  access: AccessLevel,
+  library: Option<&str>,
+  data: &ShaderData,
+  max_bytes: usize,
+) -> Result<(), ApiError> {
+  // most shaders include nothing, those never touch the database
+  if !data.code.contains(INCLUDE_DIRECTIVE) && !data.passes.iter().any(|pass| pass.code.contains(INCLUDE_DIRECTIVE)) {
+    return Ok(());
+  }
+
+  resolve_shader(db, owner, access, library, data, max_bytes).await.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn path(username: &str, library: &str, revision: Option<i32>) -> IncludePath {
+    IncludePath { username: username.to_string(), library: library.to_string(), revision }
+  }
+
+  #[test]
+  fn parses_pinned_and_latest_includes() {
+    assert_eq!(parse_include("#include \"alice/noise@3\""), Some(Ok(path("alice", "noise", Some(3)))));
+    assert_eq!(parse_include("  #include \"alice/noise\" // fbm"), Some(Ok(path("alice", "noise", None))));
+  }
+
+  #[test]
+  fn ignores_other_lines() {
+    assert_eq!(parse_include("float noise(vec2 p);"), None);
+    assert_eq!(parse_include("// #include \"alice/noise\""), None);
+  }
+
+  #[test]
+  fn rejects_malformed_includes() {
+    for line in [
+      "#include alice/noise",
+      "#include \"alice/noise",
+      "#include \"noise\"",
+      "#include \"/noise\"",
+      "#include \"alice/Noise\"",
+      "#include \"alice/noise@0\"",
+      "#include \"alice/noise@latest\"",
+      "#include \"alice/noise\" vec3",
+    ] {
+      assert!(matches!(parse_include(line), Some(Err(_))), "{}", line);
+    }
+  }
+
+  #[test]
+  fn lists_valid_includes_of_code() {
+    let code = "#include \"alice/noise@2\"\n#include \"bob/Bad\"\nvoid main() {}\n#include \"bob/sdf\"";
+    let includes: Vec<IncludePath> = includes_of(code).collect();
+    assert_eq!(includes, [path("alice", "noise", Some(2)), path("bob", "sdf", None)]);
+  }
+
+  #[test]
+  fn displays_as_written() {
+    assert_eq!(path("alice", "noise", Some(2)).to_string(), "alice/noise@2");
+    assert_eq!(path("alice", "noise", None).to_string(), "alice/noise");
+  }
+}
@@ -0,0 +1,184 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Timelike, Utc};
+
+const MAX_LOOKAHEAD_DAYS: i64 = 5 * 366;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+  minutes: u64,
+  hours: u64,
+  days: u64,
+  months: u64,
+  weekdays: u64,
+  // like every cron, a day matches either day field when both are restricted
+  either_day: bool,
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+  let mut bits = 0;
+
+  for part in field.split(',') {
+    let (range, step) = match part.split_once('/') {
+      Some((range, step)) => (range, step.parse::<u32>().map_err(|_| format!("invalid step in '{}'", part))?),
+      None => (part, 1),
+    };
+
+    if step == 0 {
+      return Err(format!("step can't be zero in '{}'", part));
+    }
+
+    let (start, end) = if range == "*" {
+      (min, max)
+    } else if let Some((start, end)) = range.split_once('-') {
+      let start = start.parse().map_err(|_| format!("invalid range '{}'", range))?;
+      let end = end.parse().map_err(|_| format!("invalid range '{}'", range))?;
+      (start, end)
+    } else {
+      let start = range.parse().map_err(|_| format!("invalid value '{}'", range))?;
+      // `5/15` means from 5 on
+      (start, if part.contains('/') { max } else { start })
+    };
+
+    if start < min || end > max || start > end {
+      return Err(format!("'{}' is outside of {}-{}", part, min, max));
+    }
+
+    for value in (start..=end).step_by(step as usize) {
+      bits |= 1 << value;
+    }
+  }
+
+  Ok(bits)
+}
+
+fn has(bits: u64, value: u32) -> bool {
+  bits & (1 << value) != 0
+}
+
+impl CronSchedule {
+  pub fn parse(expression: &str) -> Result<Self, String> {
+    let expression = match expression.trim() {
+      "@hourly" => "0 * * * *",
+      "@daily" => "0 0 * * *",
+      "@weekly" => "0 0 * * 0",
+      "@monthly" => "0 0 1 * *",
+      expression => expression,
+    };
+
+    let fields: Vec<&str> = expression.split_whitespace().collect();
+    let [minutes, hours, days, months, weekdays] = fields[..] else {
+      return Err(format!("expected 5 fields, got {}", fields.len()));
+    };
+
+    let mut weekday_bits = parse_field(weekdays, 0, 7)?;
+    // both 0 and 7 are sunday
+    if has(weekday_bits, 7) {
+      weekday_bits = (weekday_bits | 1) & !(1 << 7);
+    }
+
+    Ok(Self {
+      minutes: parse_field(minutes, 0, 59)?,
+      hours: parse_field(hours, 0, 23)?,
+      days: parse_field(days, 1, 31)?,
+      months: parse_field(months, 1, 12)?,
+      weekdays: weekday_bits,
+      either_day: !days.starts_with('*') && !weekdays.starts_with('*'),
+    })
+  }
+
+  fn day_matches(&self, date: NaiveDate) -> bool {
+    let day = has(self.days, date.day());
+    let weekday = has(self.weekdays, date.weekday().num_days_from_sunday());
+
+    if self.either_day { day || weekday } else { day && weekday }
+  }
+
+  pub fn next_after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let midnight = |date: NaiveDate| date.and_hms_opt(0, 0, 0).map(|time| time.and_utc());
+
+    let mut time = time.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+    let limit = time + Duration::days(MAX_LOOKAHEAD_DAYS);
+
+    while time < limit {
+      let date = time.date_naive();
+
+      if !has(self.months, time.month()) {
+        let (year, month) = if time.month() == 12 { (time.year() + 1, 1) } else { (time.year(), time.month() + 1) };
+        time = midnight(NaiveDate::from_ymd_opt(year, month, 1)?)?;
+      } else if !self.day_matches(date) {
+        time = midnight(date.succ_opt()?)?;
+      } else if !has(self.hours, time.hour()) {
+        time = time.with_minute(0)? + Duration::hours(1);
+      } else if !has(self.minutes, time.minute()) {
+        time += Duration::minutes(1);
+      } else {
+        return Some(time);
+      }
+    }
+
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use chrono::TimeZone;
+
+  use super::*;
+
+  fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(year, month, day, hour, minute, 0).unwrap()
+  }
+
+  fn next(expression: &str, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    CronSchedule::parse(expression).unwrap().next_after(time)
+  }
+
+  #[test]
+  fn rejects_malformed_expressions() {
+    assert!(CronSchedule::parse("* * * *").is_err());
+    assert!(CronSchedule::parse("*/0 * * * *").is_err());
+    assert!(CronSchedule::parse("60 * * * *").is_err());
+    assert!(CronSchedule::parse("* * 0 * *").is_err());
+    assert!(CronSchedule::parse("* 5-2 * * *").is_err());
+    assert!(CronSchedule::parse("a * * * *").is_err());
+  }
+
+  #[test]
+  fn shorthands_match_their_expressions() {
+    assert_eq!(CronSchedule::parse("@daily"), CronSchedule::parse("0 0 * * *"));
+    assert_eq!(CronSchedule::parse("@weekly"), CronSchedule::parse("0 0 * * 0"));
+    // both 0 and 7 are sunday
+    assert_eq!(CronSchedule::parse("0 0 * * 7"), CronSchedule::parse("0 0 * * 0"));
+  }
+
+  #[test]
+  fn next_is_strictly_after() {
+    let time = at(2024, 3, 6, 10, 15) + Duration::seconds(30);
+    assert_eq!(next("*/15 * * * *", time), Some(at(2024, 3, 6, 10, 30)));
+    assert_eq!(next("*/15 * * * *", at(2024, 3, 6, 10, 7)), Some(at(2024, 3, 6, 10, 15)));
+  }
+
+  #[test]
+  fn rolls_over_hours_days_and_months() {
+    assert_eq!(next("45 * * * *", at(2024, 3, 6, 23, 50)), Some(at(2024, 3, 7, 0, 45)));
+    assert_eq!(next("0 3 * * *", at(2024, 12, 31, 4, 0)), Some(at(2025, 1, 1, 3, 0)));
+    assert_eq!(next("0 0 1 */3 *", at(2024, 2, 10, 0, 0)), Some(at(2024, 4, 1, 0, 0)));
+    // 2024-03-06 is a wednesday
+    assert_eq!(next("@weekly", at(2024, 3, 6, 12, 0)), Some(at(2024, 3, 10, 0, 0)));
+  }
+
+  #[test]
+  fn restricted_day_fields_match_either() {
+    // the 13th or any friday, 2024-03-08 is a friday
+    assert_eq!(next("0 0 13 * 5", at(2024, 3, 6, 0, 0)), Some(at(2024, 3, 8, 0, 0)));
+    assert_eq!(next("0 0 13 * 5", at(2024, 3, 9, 0, 0)), Some(at(2024, 3, 13, 0, 0)));
+    // with one of them unrestricted both have to match
+    assert_eq!(next("0 0 1-7 * *", at(2024, 3, 6, 0, 0)), Some(at(2024, 3, 7, 0, 0)));
+  }
+
+  #[test]
+  fn impossible_dates_never_fire() {
+    assert_eq!(next("0 0 30 2 *", at(2024, 1, 1, 0, 0)), None);
+    assert_eq!(next("0 0 29 2 *", at(2024, 3, 1, 0, 0)), Some(at(2028, 2, 29, 0, 0)));
+  }
+}
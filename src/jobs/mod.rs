@@ -0,0 +1,122 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use futures_util::future::BoxFuture;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sqlx::{types::JsonValue, PgExecutor};
+
+use crate::{constants::{JOB_MAX_ATTEMPTS, JOB_STALE_LOCK, JOB_TIMEOUT}, errors::ApiError, router_state::RouterState};
+
+use self::cron::CronSchedule;
+
+pub mod cron;
+mod worker;
+
+pub use worker::start;
+
+#[async_trait]
+pub trait Job: Serialize + DeserializeOwned + Send + Sync + 'static {
+  // must stay the same across releases
+  const KIND: &'static str;
+  const MAX_ATTEMPTS: i32 = JOB_MAX_ATTEMPTS;
+  const TIMEOUT: Duration = Duration::from_secs(JOB_TIMEOUT);
+
+  async fn run(self, state: &RouterState) -> Result<(), ApiError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "job_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+  Queued,
+  Running,
+  Done,
+  Dead,
+}
+
+type Handler = Arc<dyn Fn(RouterState, JsonValue) -> BoxFuture<'static, Result<(), ApiError>> + Send + Sync>;
+
+#[derive(Clone)]
+struct JobHandler {
+  run: Handler,
+  timeout: Duration,
+}
+
+struct RecurringJob {
+  name: &'static str,
+  kind: &'static str,
+  expression: String,
+  schedule: CronSchedule,
+  payload: JsonValue,
+  max_attempts: i32,
+}
+
+#[derive(Default)]
+pub struct JobRegistry {
+  handlers: HashMap<&'static str, JobHandler>,
+  recurring: Vec<RecurringJob>,
+}
+
+impl JobRegistry {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn register<J: Job>(mut self) -> Self {
+    if J::TIMEOUT.as_secs() >= JOB_STALE_LOCK {
+      panic!("timeout of job {} must be shorter than {} seconds", J::KIND, JOB_STALE_LOCK);
+    }
+
+    let run: Handler = Arc::new(|state, payload| Box::pin(async move {
+      let job: J = serde_json::from_value(payload)?;
+      job.run(&state).await
+    }));
+
+    if self.handlers.insert(J::KIND, JobHandler { run, timeout: J::TIMEOUT }).is_some() {
+      panic!("job {} is registered twice", J::KIND);
+    }
+
+    self
+  }
+
+  pub fn schedule<J: Job>(mut self, name: &'static str, expression: &str, job: J) -> Self {
+    let schedule = CronSchedule::parse(expression)
+      .unwrap_or_else(|e| panic!("invalid schedule '{}' for {}: {}", expression, name, e));
+
+    if self.recurring.iter().any(|recurring| recurring.name == name) {
+      panic!("schedule {} is registered twice", name);
+    }
+
+    self.recurring.push(RecurringJob {
+      name,
+      kind: J::KIND,
+      expression: expression.to_string(),
+      schedule,
+      payload: serde_json::to_value(&job).expect("failed to serialize scheduled job"),
+      max_attempts: J::MAX_ATTEMPTS,
+    });
+
+    if self.handlers.contains_key(J::KIND) { self } else { self.register::<J>() }
+  }
+}
+
+pub async fn enqueue<'e, J: Job>(executor: impl PgExecutor<'e>, job: &J) -> Result<i64, ApiError> {
+  insert_job(executor, J::KIND, serde_json::to_value(job)?, J::MAX_ATTEMPTS).await
+}
+
+async fn insert_job<'e>(
+  executor: impl PgExecutor<'e>,
+  kind: &str,
+  payload: JsonValue,
+  max_attempts: i32,
+) -> Result<i64, ApiError> {
+  let (id,): (i64,) = sqlx::query_as(
+    "INSERT INTO jobs (kind, payload, max_attempts, run_at) VALUES ($1, $2, $3, NOW()) RETURNING id")
+    .bind(kind)
+    .bind(payload)
+    .bind(max_attempts)
+    .fetch_one(executor)
+    .await?;
+
+  Ok(id)
+}
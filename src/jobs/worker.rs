@@ -0,0 +1,352 @@
+use std::{sync::Arc, time::Duration};
+
+use sqlx::{types::JsonValue, PgPool};
+
+use crate::{constants::{JOB_POLL_INTERVAL, JOB_RETENTION_DAYS, JOB_RETRY_BASE_DELAY, JOB_RETRY_MAX_DELAY, JOB_SCHEDULER_INTERVAL, JOB_STALE_LOCK}, errors::ApiError, router_state::RouterState};
+
+use super::{cron::CronSchedule, insert_job, JobRegistry, JobStatus};
+
+#[derive(Debug, sqlx::FromRow)]
+struct ClaimedJob {
+  id: i64,
+  kind: String,
+  payload: JsonValue,
+  attempts: i32,
+  max_attempts: i32,
+}
+
+async fn claim_job(db: &PgPool, kinds: &[&str], worker: &str) -> Result<Option<ClaimedJob>, ApiError> {
+  let job = sqlx::query_as(
+    "UPDATE jobs SET status = 'running', attempts = attempts + 1, locked_at = NOW(), locked_by = $2
+    WHERE id = (
+      SELECT id FROM jobs WHERE status = 'queued' AND run_at <= NOW() AND kind = ANY($1)
+      ORDER BY run_at, id LIMIT 1 FOR UPDATE SKIP LOCKED
+    )
+    RETURNING id, kind, payload, attempts, max_attempts")
+    .bind(kinds)
+    .bind(worker)
+    .fetch_optional(db)
+    .await?;
+
+  Ok(job)
+}
+
+fn retry_delay(attempts: i32) -> i64 {
+  let exponent = attempts.clamp(1, 20) - 1;
+  JOB_RETRY_BASE_DELAY.saturating_mul(1 << exponent).min(JOB_RETRY_MAX_DELAY)
+}
+
+async fn run_job(state: &RouterState, registry: &JobRegistry, job: &ClaimedJob) -> Result<(), String> {
+  let Some(handler) = registry.handlers.get(job.kind.as_str()) else {
+    return Err(format!("no handler for job kind {}", job.kind));
+  };
+
+  // a panicking job only fails its attempt
+  let task = tokio::spawn((handler.run)(state.clone(), job.payload.clone()));
+  let abort = task.abort_handle();
+
+  match tokio::time::timeout(handler.timeout, task).await {
+    Ok(Ok(Ok(()))) => Ok(()),
+    Ok(Ok(Err(e))) => Err(format!("{:?}", e)),
+    Ok(Err(e)) => Err(format!("{:?}", ApiError::Task(e))),
+    Err(_) => {
+      abort.abort();
+      Err(format!("timed out after {} seconds", handler.timeout.as_secs()))
+    },
+  }
+}
+
+async fn finish_job(db: &PgPool, job: &ClaimedJob, worker: &str, result: Result<(), String>) -> Result<(), ApiError> {
+  let (status, delay, error) = match result {
+    Ok(()) => (JobStatus::Done, 0, None),
+    Err(e) if job.attempts >= job.max_attempts => {
+      log::error!("job {} ({}) failed for good after {} attempts: {}", job.id, job.kind, job.attempts, e);
+      (JobStatus::Dead, 0, Some(e))
+    },
+    Err(e) => {
+      let delay = retry_delay(job.attempts);
+      log::warn!("job {} ({}) failed attempt {}, retrying in {} seconds: {}", job.id, job.kind, job.attempts, delay, e);
+      (JobStatus::Queued, delay, Some(e))
+    },
+  };
+
+  // the lock may have been taken away if this took longer than the stale lock timeout
+  sqlx::query(
+    "UPDATE jobs SET status = $3, last_error = COALESCE($4, last_error), locked_at = NULL, locked_by = NULL,
+    run_at = CASE WHEN $3 = 'queued' THEN NOW() + $5 * INTERVAL '1 second' ELSE run_at END,
+    finished_at = CASE WHEN $3 = 'queued' THEN NULL ELSE NOW() END
+    WHERE id = $1 AND locked_by = $2 AND status = 'running'")
+    .bind(job.id)
+    .bind(worker)
+    .bind(status)
+    .bind(error)
+    .bind(delay)
+    .execute(db)
+    .await?;
+
+  Ok(())
+}
+
+async fn work(state: RouterState, registry: Arc<JobRegistry>, worker: String) {
+  let kinds: Vec<&str> = registry.handlers.keys().copied().collect();
+
+  loop {
+    let job = match claim_job(&state.db, &kinds, &worker).await {
+      Ok(Some(job)) => job,
+      Ok(None) => {
+        tokio::time::sleep(Duration::from_secs(JOB_POLL_INTERVAL)).await;
+        continue;
+      },
+      Err(e) => {
+        log::error!("failed to claim a job: {:?}", e);
+        tokio::time::sleep(Duration::from_secs(JOB_POLL_INTERVAL)).await;
+        continue;
+      },
+    };
+
+    let result = run_job(&state, &registry, &job).await;
+    if let Err(e) = finish_job(&state.db, &job, &worker, result).await {
+      log::error!("failed to finish job {}: {:?}", job.id, e);
+    }
+  }
+}
+
+async fn sync_schedules(db: &PgPool, registry: &JobRegistry) -> Result<(), ApiError> {
+  let now = chrono::Utc::now();
+  let mut tx = db.begin().await?;
+
+  for recurring in &registry.recurring {
+    sqlx::query(
+      "INSERT INTO job_schedules (name, kind, cron, payload, next_run_at) VALUES ($1, $2, $3, $4, $5)
+      ON CONFLICT (name) DO UPDATE SET kind = EXCLUDED.kind, payload = EXCLUDED.payload, cron = EXCLUDED.cron,
+      next_run_at = CASE WHEN job_schedules.cron = EXCLUDED.cron THEN job_schedules.next_run_at ELSE EXCLUDED.next_run_at END")
+      .bind(recurring.name)
+      .bind(recurring.kind)
+      .bind(&recurring.expression)
+      .bind(&recurring.payload)
+      .bind(recurring.schedule.next_after(now))
+      .execute(&mut *tx)
+      .await?;
+  }
+
+  let names: Vec<&str> = registry.recurring.iter().map(|recurring| recurring.name).collect();
+  sqlx::query("DELETE FROM job_schedules WHERE name <> ALL($1)")
+    .bind(&names)
+    .execute(&mut *tx)
+    .await?;
+
+  tx.commit().await?;
+
+  Ok(())
+}
+
+async fn enqueue_due_jobs(db: &PgPool, registry: &JobRegistry) -> Result<u64, ApiError> {
+  let mut tx = db.begin().await?;
+
+  let due: Vec<(String, String, String, JsonValue)> = sqlx::query_as(
+    "SELECT name, kind, cron, payload FROM job_schedules WHERE next_run_at <= NOW() FOR UPDATE SKIP LOCKED")
+    .fetch_all(&mut *tx)
+    .await?;
+
+  let mut enqueued = 0;
+  for (name, kind, expression, payload) in due {
+    let Some(recurring) = registry.recurring.iter().find(|recurring| recurring.name == name) else {
+      continue;
+    };
+
+    let next_run_at = match CronSchedule::parse(&expression) {
+      Ok(schedule) => schedule.next_after(chrono::Utc::now()),
+      Err(e) => {
+        log::error!("schedule {} has an invalid expression '{}': {}", name, expression, e);
+        continue;
+      },
+    };
+
+    let (pending,): (bool,) = sqlx::query_as(
+      "SELECT EXISTS (SELECT 1 FROM jobs WHERE kind = $1 AND payload = $2 AND status IN ('queued', 'running'))")
+      .bind(&kind)
+      .bind(&payload)
+      .fetch_one(&mut *tx)
+      .await?;
+
+    if !pending {
+      insert_job(&mut *tx, &kind, payload, recurring.max_attempts).await?;
+      enqueued += 1;
+    }
+
+    sqlx::query("UPDATE job_schedules SET next_run_at = COALESCE($2, 'infinity'), last_run_at = NOW() WHERE name = $1")
+      .bind(&name)
+      .bind(next_run_at)
+      .execute(&mut *tx)
+      .await?;
+  }
+
+  tx.commit().await?;
+
+  Ok(enqueued)
+}
+
+async fn maintain(db: &PgPool) -> Result<(u64, u64), ApiError> {
+  let recovered = sqlx::query(
+    "UPDATE jobs SET locked_at = NULL, locked_by = NULL, last_error = 'worker stopped responding',
+    status = CASE WHEN attempts >= max_attempts THEN 'dead' ELSE 'queued' END::job_status,
+    finished_at = CASE WHEN attempts >= max_attempts THEN NOW() END
+    WHERE status = 'running' AND locked_at < NOW() - $1 * INTERVAL '1 second'")
+    .bind(JOB_STALE_LOCK as i64)
+    .execute(db)
+    .await?
+    .rows_affected();
+
+  let deleted = sqlx::query("DELETE FROM jobs WHERE status = 'done' AND finished_at < NOW() - $1 * INTERVAL '1 day'")
+    .bind(JOB_RETENTION_DAYS)
+    .execute(db)
+    .await?
+    .rows_affected();
+
+  Ok((recovered, deleted))
+}
+
+async fn schedule(state: RouterState, registry: Arc<JobRegistry>) {
+  let mut interval = tokio::time::interval(Duration::from_secs(JOB_SCHEDULER_INTERVAL));
+
+  loop {
+    interval.tick().await;
+
+    if let Err(e) = enqueue_due_jobs(&state.db, &registry).await {
+      log::error!("failed to enqueue scheduled jobs: {:?}", e);
+    }
+
+    match maintain(&state.db).await {
+      Ok((0, 0)) => (),
+      Ok((recovered, deleted)) => log::info!("recovered {} stale jobs, deleted {} finished jobs", recovered, deleted),
+      Err(e) => log::error!("failed to maintain the job queue: {:?}", e),
+    }
+  }
+}
+
+pub async fn start(state: RouterState, registry: JobRegistry, workers: usize) -> Result<(), ApiError> {
+  if workers == 0 {
+    log::warn!("no job workers, this replica won't run background jobs");
+    return Ok(());
+  }
+
+  sync_schedules(&state.db, &registry).await?;
+
+  let registry = Arc::new(registry);
+  let process = nanoid::nanoid!(8);
+
+  tokio::spawn(schedule(state.clone(), registry.clone()));
+  for n in 0..workers {
+    tokio::spawn(work(state.clone(), registry.clone(), format!("{}-{}", process, n)));
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use async_trait::async_trait;
+  use serde::{Deserialize, Serialize};
+
+  use crate::{jobs::{enqueue, Job}, test_support::{test_db, test_state}};
+
+  use super::*;
+
+  const WORKER: &str = "test-worker";
+
+  #[derive(Serialize, Deserialize)]
+  struct AlwaysFails {}
+
+  #[async_trait]
+  impl Job for AlwaysFails {
+    const KIND: &'static str = "test_always_fails";
+    const MAX_ATTEMPTS: i32 = 2;
+
+    async fn run(self, _state: &RouterState) -> Result<(), ApiError> {
+      Err(ApiError::Provider("nope".to_string()))
+    }
+  }
+
+  async fn job_status(db: &PgPool, id: i64) -> (JobStatus, Option<String>, bool) {
+    sqlx::query_as("SELECT status, last_error, run_at > NOW() FROM jobs WHERE id = $1")
+      .bind(id)
+      .fetch_one(db)
+      .await.unwrap()
+  }
+
+  #[test]
+  fn retry_delay_doubles_up_to_the_cap() {
+    assert_eq!(retry_delay(0), JOB_RETRY_BASE_DELAY);
+    assert_eq!(retry_delay(1), JOB_RETRY_BASE_DELAY);
+    assert_eq!(retry_delay(2), JOB_RETRY_BASE_DELAY * 2);
+    assert_eq!(retry_delay(4), JOB_RETRY_BASE_DELAY * 8);
+    assert_eq!(retry_delay(30), JOB_RETRY_MAX_DELAY);
+    assert_eq!(retry_delay(i32::MAX), JOB_RETRY_MAX_DELAY);
+  }
+
+  #[tokio::test]
+  async fn failing_jobs_back_off_then_die() {
+    let Some(db) = test_db().await else {
+      return;
+    };
+
+    let state = test_state(db.clone());
+    let registry = JobRegistry::new().register::<AlwaysFails>();
+    let kinds = [AlwaysFails::KIND];
+
+    sqlx::query("DELETE FROM jobs WHERE kind = $1").bind(AlwaysFails::KIND).execute(&db).await.unwrap();
+    let id = enqueue(&db, &AlwaysFails {}).await.unwrap();
+
+    let job = claim_job(&db, &kinds, WORKER).await.unwrap().expect("job wasn't claimed");
+    assert_eq!((job.id, job.attempts), (id, 1));
+    let result = run_job(&state, &registry, &job).await;
+    assert!(result.is_err());
+    finish_job(&db, &job, WORKER, result).await.unwrap();
+
+    let (status, error, delayed) = job_status(&db, id).await;
+    assert_eq!(status, JobStatus::Queued);
+    assert!(error.unwrap().contains("nope"));
+    assert!(delayed);
+    assert!(claim_job(&db, &kinds, WORKER).await.unwrap().is_none());
+
+    sqlx::query("UPDATE jobs SET run_at = NOW() WHERE id = $1").bind(id).execute(&db).await.unwrap();
+    let job = claim_job(&db, &kinds, WORKER).await.unwrap().expect("retry wasn't claimed");
+    assert_eq!(job.attempts, 2);
+    let result = run_job(&state, &registry, &job).await;
+    finish_job(&db, &job, WORKER, result).await.unwrap();
+
+    assert_eq!(job_status(&db, id).await.0, JobStatus::Dead);
+    assert!(claim_job(&db, &kinds, WORKER).await.unwrap().is_none());
+
+    sqlx::query("DELETE FROM jobs WHERE kind = $1").bind(AlwaysFails::KIND).execute(&db).await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn stale_locks_are_recovered() {
+    let Some(db) = test_db().await else {
+      return;
+    };
+
+    let kinds = ["test_stale_lock"];
+    sqlx::query("DELETE FROM jobs WHERE kind = $1").bind(kinds[0]).execute(&db).await.unwrap();
+
+    let queued = insert_job(&db, kinds[0], JsonValue::Null, 2).await.unwrap();
+    let exhausted = insert_job(&db, kinds[0], JsonValue::Null, 1).await.unwrap();
+    for _ in 0..2 {
+      claim_job(&db, &kinds, WORKER).await.unwrap().expect("job wasn't claimed");
+    }
+
+    sqlx::query("UPDATE jobs SET locked_at = NOW() - $2 * INTERVAL '2 second' WHERE kind = $1")
+      .bind(kinds[0])
+      .bind(JOB_STALE_LOCK as i64)
+      .execute(&db)
+      .await.unwrap();
+
+    let (recovered, _) = maintain(&db).await.unwrap();
+    assert!(recovered >= 2);
+    assert_eq!(job_status(&db, queued).await.0, JobStatus::Queued);
+    assert_eq!(job_status(&db, exhausted).await.0, JobStatus::Dead);
+
+    sqlx::query("DELETE FROM jobs WHERE kind = $1").bind(kinds[0]).execute(&db).await.unwrap();
+  }
+}
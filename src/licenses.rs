@@ -0,0 +1,185 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{errors::FieldError, validation::Validator};
+
+pub const DEFAULT_LICENSE: &str = "CC-BY-NC-SA-3.0";
+pub const MAX_ATTRIBUTIONS: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Terms {
+  pub share_alike: bool,
+  pub non_commercial: bool,
+  pub no_derivatives: bool,
+}
+
+const PERMISSIVE: Terms = Terms { share_alike: false, non_commercial: false, no_derivatives: false };
+const SHARE_ALIKE: Terms = Terms { share_alike: true, non_commercial: false, no_derivatives: false };
+const NON_COMMERCIAL: Terms = Terms { share_alike: false, non_commercial: true, no_derivatives: false };
+const NON_COMMERCIAL_SHARE_ALIKE: Terms = Terms { share_alike: true, non_commercial: true, no_derivatives: false };
+const NO_DERIVATIVES: Terms = Terms { share_alike: false, non_commercial: false, no_derivatives: true };
+const NON_COMMERCIAL_NO_DERIVATIVES: Terms = Terms { share_alike: false, non_commercial: true, no_derivatives: true };
+
+#[derive(Debug)]
+pub struct License {
+  pub id: &'static str,
+  pub terms: Terms,
+}
+
+pub const LICENSES: &[License] = &[
+  License { id: "0BSD", terms: PERMISSIVE },
+  License { id: "Apache-2.0", terms: PERMISSIVE },
+  License { id: "BSD-2-Clause", terms: PERMISSIVE },
+  License { id: "BSD-3-Clause", terms: PERMISSIVE },
+  License { id: "BSL-1.0", terms: PERMISSIVE },
+  License { id: "CC0-1.0", terms: PERMISSIVE },
+  License { id: "CC-BY-3.0", terms: PERMISSIVE },
+  License { id: "CC-BY-4.0", terms: PERMISSIVE },
+  License { id: "ISC", terms: PERMISSIVE },
+  License { id: "MIT", terms: PERMISSIVE },
+  License { id: "MIT-0", terms: PERMISSIVE },
+  License { id: "Unlicense", terms: PERMISSIVE },
+  License { id: "WTFPL", terms: PERMISSIVE },
+  License { id: "Zlib", terms: PERMISSIVE },
+  License { id: "AGPL-3.0-only", terms: SHARE_ALIKE },
+  License { id: "AGPL-3.0-or-later", terms: SHARE_ALIKE },
+  License { id: "CC-BY-SA-3.0", terms: SHARE_ALIKE },
+  License { id: "CC-BY-SA-4.0", terms: SHARE_ALIKE },
+  License { id: "GPL-2.0-only", terms: SHARE_ALIKE },
+  License { id: "GPL-2.0-or-later", terms: SHARE_ALIKE },
+  License { id: "GPL-3.0-only", terms: SHARE_ALIKE },
+  License { id: "GPL-3.0-or-later", terms: SHARE_ALIKE },
+  License { id: "LGPL-2.1-only", terms: SHARE_ALIKE },
+  License { id: "LGPL-2.1-or-later", terms: SHARE_ALIKE },
+  License { id: "LGPL-3.0-only", terms: SHARE_ALIKE },
+  License { id: "LGPL-3.0-or-later", terms: SHARE_ALIKE },
+  License { id: "MPL-2.0", terms: SHARE_ALIKE },
+  License { id: "CC-BY-NC-3.0", terms: NON_COMMERCIAL },
+  License { id: "CC-BY-NC-4.0", terms: NON_COMMERCIAL },
+  License { id: "CC-BY-NC-SA-3.0", terms: NON_COMMERCIAL_SHARE_ALIKE },
+  License { id: "CC-BY-NC-SA-4.0", terms: NON_COMMERCIAL_SHARE_ALIKE },
+  License { id: "CC-BY-ND-3.0", terms: NO_DERIVATIVES },
+  License { id: "CC-BY-ND-4.0", terms: NO_DERIVATIVES },
+  License { id: "CC-BY-NC-ND-3.0", terms: NON_COMMERCIAL_NO_DERIVATIVES },
+  License { id: "CC-BY-NC-ND-4.0", terms: NON_COMMERCIAL_NO_DERIVATIVES },
+];
+
+pub fn find_license(id: &str) -> Option<&'static License> {
+  LICENSES.iter().find(|license| license.id.eq_ignore_ascii_case(id.trim()))
+}
+
+impl License {
+  pub fn keeps_terms_of(&self, upstream: &License) -> bool {
+    if self.id == upstream.id {
+      return true;
+    }
+
+    !upstream.terms.share_alike
+      && !upstream.terms.no_derivatives
+      && (!upstream.terms.non_commercial || self.terms.non_commercial)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attribution {
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub shader_id: Option<String>,
+  pub name: String,
+  pub author: String,
+  pub license: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub url: Option<String>,
+}
+
+pub fn check_license(field: &str, license: &str, attribution: &[Attribution]) -> Result<&'static str, FieldError> {
+  let Some(license) = find_license(license) else {
+    return Err(FieldError::new(field, "must be a supported SPDX license identifier"));
+  };
+
+  let upstream = attribution.iter()
+    .filter_map(|entry| find_license(&entry.license))
+    .find(|upstream| !license.keeps_terms_of(upstream));
+
+  match upstream {
+    Some(upstream) => Err(FieldError::new(field, format!("copies of {} code can't be relicensed as {}", upstream.id, license.id))),
+    None => Ok(license.id),
+  }
+}
+
+pub fn license_for(field: &str, requested: Option<&str>, default: &str, attribution: &[Attribution]) -> Result<&'static str, FieldError> {
+  if let Some(requested) = requested {
+    return check_license(field, requested, attribution);
+  }
+
+  std::iter::once(default)
+    .chain(attribution.iter().rev().map(|entry| entry.license.as_str()))
+    .find_map(|license| check_license(field, license, attribution).ok())
+    .ok_or_else(|| FieldError::new(field, "no license keeps the terms of every upstream shader"))
+}
+
+pub fn validate_attribution(v: &mut Validator, field: &str, attribution: &[Attribution]) {
+  v.check(field, attribution.len() <= MAX_ATTRIBUTIONS, format!("can have at most {} entries", MAX_ATTRIBUTIONS));
+
+  for (i, entry) in attribution.iter().enumerate() {
+    v.check(
+      &format!("{}[{}].license", field, i),
+      find_license(&entry.license).is_some(),
+      "must be a supported SPDX license identifier",
+    );
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn keeps(license: &str, upstream: &str) -> bool {
+    find_license(license).unwrap().keeps_terms_of(find_license(upstream).unwrap())
+  }
+
+  fn attribution(license: &str) -> Attribution {
+    Attribution { shader_id: None, name: "upstream".to_string(), author: "someone".to_string(), license: license.to_string(), url: None }
+  }
+
+  #[test]
+  fn same_license_always_keeps_terms() {
+    for license in LICENSES {
+      assert!(license.keeps_terms_of(license), "{}", license.id);
+    }
+  }
+
+  #[test]
+  fn permissive_code_goes_anywhere() {
+    assert!(keeps("GPL-3.0-only", "MIT"));
+    assert!(keeps("CC-BY-NC-SA-4.0", "CC0-1.0"));
+    assert!(keeps("CC-BY-ND-4.0", "Apache-2.0"));
+  }
+
+  #[test]
+  fn share_alike_and_no_derivatives_stay_put() {
+    assert!(!keeps("MIT", "GPL-3.0-only"));
+    assert!(!keeps("GPL-3.0-or-later", "GPL-3.0-only"));
+    assert!(!keeps("CC-BY-SA-4.0", "CC-BY-SA-3.0"));
+    assert!(!keeps("CC-BY-NC-ND-4.0", "CC-BY-ND-4.0"));
+  }
+
+  #[test]
+  fn non_commercial_has_to_be_kept() {
+    assert!(keeps("CC-BY-NC-SA-4.0", "CC-BY-NC-4.0"));
+    assert!(!keeps("MIT", "CC-BY-NC-4.0"));
+    assert!(!keeps("CC-BY-NC-4.0", "CC-BY-NC-SA-3.0"));
+  }
+
+  #[test]
+  fn finds_licenses_case_insensitively() {
+    assert_eq!(find_license(" mit ").map(|license| license.id), Some("MIT"));
+    assert!(find_license("proprietary").is_none());
+  }
+
+  #[test]
+  fn license_for_falls_back_to_upstream() {
+    let upstream = [attribution("CC-BY-NC-SA-3.0")];
+    assert_eq!(license_for("license", None, "MIT", &upstream).unwrap(), "CC-BY-NC-SA-3.0");
+    assert_eq!(license_for("license", None, "MIT", &[attribution("MIT")]).unwrap(), "MIT");
+    assert!(license_for("license", Some("MIT"), "MIT", &upstream).is_err());
+  }
+}
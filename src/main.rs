@@ -1,4 +1,4 @@
-use axum::{http::{header::CONTENT_TYPE, Method, StatusCode}, middleware, response::{Html, IntoResponse}, routing::{get, post}, serve, Extension, Json, Router};
+use axum::{http::{header::{AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER}, Method, StatusCode}, middleware, response::IntoResponse, routing::{delete, get, post}, serve, Extension, Json, Router};
 use router_state::{RouterState, UserProfile};
 use sqlx::postgres::PgPoolOptions;
 use tokio::net::TcpListener;
@@ -12,6 +12,22 @@ mod auth;
 mod errors;
 mod middlewares;
 mod constants;
+mod tokens;
+mod tasks;
+mod validation;
+mod assets;
+mod shadertoy;
+mod archive;
+mod includes;
+mod embed;
+mod licenses;
+mod audit;
+mod notifications;
+mod jobs;
+mod rate_limit;
+mod quotas;
+#[cfg(test)]
+mod test_support;
 
 #[tokio::main]
 async fn main() {
@@ -36,38 +52,70 @@ async fn main() {
     .await.expect("failed to run migrations");
   log::trace!("migrations ran successfully");
 
+  let promoted = routes::admin::bootstrap_admins(&pool, &env.admin_emails)
+    .await.expect("failed to bootstrap admins");
+  if promoted > 0 {
+    log::info!("promoted {} users to admin", promoted);
+  }
+
   let router_state = router_state::RouterState::new(pool, &env);
 
+  jobs::start(router_state.clone(), tasks::build_job_registry(&env), env.job_workers)
+    .await.expect("failed to start job workers");
+
   let protected_router: Router<RouterState> = Router::new()
     .route("/", get(protected_page));
 
-  let client = auth::build_oauth_client(&env);
+  log::trace!("building identity providers");
+  let providers = auth::build_identity_providers(&env, &router_state.ctx)
+    .await.expect("failed to build identity providers");
+  log::trace!("identity providers ready: {}", providers.names().join(", "));
 
-  let auth_router: Router<RouterState> = Router::new()
-    .route("/login", get(routes::oauth::get_login_url))
+  let mut auth_router: Router<RouterState> = Router::new()
+    .route("/providers", get(routes::oauth::get_providers))
+    .route("/login", get(routes::oauth::get_google_login_url))
     .route("/google_callback", post(routes::oauth::google_callback))
+    .route("/:provider/login", get(routes::oauth::get_login_url))
+    .route("/:provider/callback", post(routes::oauth::callback))
+    .route("/:provider/link", post(routes::oauth::link_identity))
+    .route("/identities", get(routes::oauth::get_identities))
+    .route("/identities/:id", delete(routes::oauth::unlink_identity))
     .route("/validate", get(routes::oauth::validate))
-    .route("/logout", post(routes::oauth::logout))
-    .layer(Extension(env.google_client_id.clone()));
+    .route("/logout", post(routes::oauth::logout));
+
+  if env.dev_auth_enabled {
+    log::warn!("dev auth is enabled, anyone can log in as any user through /auth/dev/login");
+    auth_router = auth_router.route("/dev/login", post(routes::oauth::dev_login));
+  }
 
   let app: Router = Router::new()
     .nest("/shader", routes::shader::build_shader_router())
+    .nest("/user", routes::user::build_user_router())
+    .nest("/asset", routes::asset::build_asset_router(&env))
+    .nest("/template", routes::template::build_template_router())
+    .nest("/admin", routes::admin::build_admin_router())
+    .nest("/moderation", routes::moderation::build_moderation_router())
+    .merge(routes::embed::build_embed_router())
     .nest("/auth", auth_router)
     .nest("/protected", protected_router)
     .with_state(router_state.clone())
     .layer(middleware::from_fn_with_state(router_state.clone(), middlewares::token_refresh::token_refresh_middleware))
-    .layer(Extension(client))
+    .layer(middleware::from_fn_with_state(router_state.clone(), middlewares::rate_limit::rate_limit_middleware))
+    .layer(Extension(providers))
+    .layer(middleware::from_fn(middlewares::request_id::request_id_middleware))
     .layer(build_cors_layer(&env));
 
   let url = format!("0.0.0.0:{}", env.backend_port);
-  let listener = TcpListener::bind(&url).await.expect(format!("failed to bind to {}", url).as_str());
+  let listener = TcpListener::bind(&url).await.unwrap_or_else(|_| panic!("failed to bind to {}", url));
 
   log::trace!("listening on {}", url);
-  serve(listener, app).await.expect("failed to start server");
+  serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>()).await.expect("failed to start server");
 }
 
-async fn protected_page(profile: UserProfile) -> impl IntoResponse {
-  (StatusCode::OK, Json(profile))
+async fn protected_page(profile: UserProfile) -> Result<impl IntoResponse, errors::ApiError> {
+  profile.require_scope(tokens::TokenScope::ProfileRead)?;
+
+  Ok((StatusCode::OK, Json(profile)))
 }
 
 fn build_cors_layer(env: &env::Env) -> CorsLayer {
@@ -77,6 +125,7 @@ fn build_cors_layer(env: &env::Env) -> CorsLayer {
 
   let headers = [
     CONTENT_TYPE,
+    AUTHORIZATION,
   ];
 
   CorsLayer::new()
@@ -84,4 +133,12 @@ fn build_cors_layer(env: &env::Env) -> CorsLayer {
     .allow_origin(origins)
     .allow_credentials(true)
     .allow_headers(headers)
+    .expose_headers([
+      middlewares::request_id::X_REQUEST_ID.clone(),
+      RETRY_AFTER,
+      middlewares::rate_limit::RATELIMIT_LIMIT.clone(),
+      middlewares::rate_limit::RATELIMIT_REMAINING.clone(),
+      middlewares::rate_limit::RATELIMIT_RESET.clone(),
+      middlewares::rate_limit::RATELIMIT_POLICY.clone(),
+    ])
 }
@@ -0,0 +1,143 @@
+use std::net::IpAddr;
+
+use axum::{extract::{FromRequestParts, MatchedPath, Request, State}, http::{request::Parts, HeaderName, HeaderValue, Method}, middleware::Next, response::{IntoResponse, Response}};
+use axum_extra::extract::{cookie::Key, PrivateCookieJar};
+
+use crate::{audit::client_ip, env::RateLimitRule, errors::ApiError, router_state::{bearer_token, RouterState}, tokens::hash_token};
+
+pub static RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+pub static RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+pub static RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+pub static RATELIMIT_POLICY: HeaderName = HeaderName::from_static("ratelimit-policy");
+
+fn find_rule<'a>(rules: &'a [RateLimitRule], method: &Method, path: &str) -> Option<&'a RateLimitRule> {
+  rules.iter().find(|rule| {
+    rule.method.as_ref().is_none_or(|rule_method| rule_method == method)
+      && (rule.path == "*" || rule.path == path)
+  })
+}
+
+fn network(ip: IpAddr) -> String {
+  match ip {
+    IpAddr::V4(ip) => ip.to_string(),
+    IpAddr::V6(ip) => {
+      let segments = ip.segments();
+      format!("{:x}:{:x}:{:x}:{:x}::/64", segments[0], segments[1], segments[2], segments[3])
+    },
+  }
+}
+
+// only tokens and sessions that exist are believed, made up credentials don't get a budget
+async fn identify(state: &RouterState, parts: &mut Parts) -> Result<String, ApiError> {
+  if let Some(token) = bearer_token(parts) {
+    let entry: Option<(i32,)> = sqlx::query_as(
+      "SELECT id FROM personal_access_tokens WHERE token_hash = $1 AND (expires_at IS NULL OR expires_at > NOW())")
+      .bind(hash_token(&token))
+      .fetch_optional(&state.db)
+      .await?;
+
+    if let Some((id,)) = entry {
+      return Ok(format!("token:{}", id));
+    }
+  }
+
+  let jar = PrivateCookieJar::<Key>::from_request_parts(parts, state).await?;
+  if let Some(cookie) = jar.get("sid") {
+    let session: Option<(i32,)> = sqlx::query_as("SELECT user_id FROM sessions WHERE session_id = $1")
+      .bind(cookie.value())
+      .fetch_optional(&state.db)
+      .await?;
+
+    if let Some((user_id,)) = session {
+      return Ok(format!("user:{}", user_id));
+    }
+  }
+
+  let ip = client_ip(parts, state.env.proxy.as_ref());
+  Ok(format!("ip:{}", ip.map(network).unwrap_or_default()))
+}
+
+pub async fn rate_limit_middleware(State(state): State<RouterState>, req: Request, next: Next) -> Response {
+  let Some(path) = req.extensions().get::<MatchedPath>().map(|path| path.as_str().to_owned()) else {
+    return next.run(req).await;
+  };
+
+  let Some(rule) = find_rule(&state.env.rate_limits.rules, req.method(), &path) else {
+    return next.run(req).await;
+  };
+
+  let (mut parts, body) = req.into_parts();
+
+  let now = chrono::Utc::now().timestamp();
+  let window = rule.window as i64;
+  let window_end = now - now.rem_euclid(window) + window;
+
+  let counted = match identify(&state, &mut parts).await {
+    Ok(client) => {
+      let key = format!("{} {}|{}", rule.method.as_ref().map_or("*", Method::as_str), rule.path, client);
+      state.rate_limiter.hit(&key, window_end).await
+    },
+    Err(e) => Err(e),
+  };
+
+  // a broken store shouldn't take the api down with it
+  let hits = match counted {
+    Ok(hits) => hits,
+    Err(e) => {
+      log::error!("failed to count request against its rate limit: {:?}", e);
+      return next.run(Request::from_parts(parts, body)).await;
+    },
+  };
+
+  let reset = (window_end - now) as u64;
+  let mut response = if hits > rule.limit {
+    ApiError::RateLimited { retry_after: Some(reset) }.into_response()
+  } else {
+    next.run(Request::from_parts(parts, body)).await
+  };
+
+  let headers = response.headers_mut();
+  headers.insert(&RATELIMIT_LIMIT, rule.limit.into());
+  headers.insert(&RATELIMIT_REMAINING, rule.limit.saturating_sub(hits).into());
+  headers.insert(&RATELIMIT_RESET, reset.into());
+  if let Ok(policy) = HeaderValue::from_str(&format!("{};w={}", rule.limit, rule.window)) {
+    headers.insert(&RATELIMIT_POLICY, policy);
+  }
+
+  response
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn rule(method: Option<Method>, path: &str, limit: u64) -> RateLimitRule {
+    RateLimitRule { method, path: path.to_string(), limit, window: 60 }
+  }
+
+  #[test]
+  fn first_matching_rule_applies() {
+    let rules = [
+      rule(Some(Method::POST), "/shader/:id/fork", 1),
+      rule(None, "/shader/:id/fork", 2),
+      rule(Some(Method::POST), "*", 3),
+      rule(None, "*", 4),
+    ];
+
+    let limit = |method: Method, path: &str| find_rule(&rules, &method, path).map(|rule| rule.limit);
+    assert_eq!(limit(Method::POST, "/shader/:id/fork"), Some(1));
+    assert_eq!(limit(Method::GET, "/shader/:id/fork"), Some(2));
+    assert_eq!(limit(Method::POST, "/shader"), Some(3));
+    assert_eq!(limit(Method::GET, "/shader"), Some(4));
+    assert!(find_rule(&rules[..2], &Method::GET, "/shader").is_none());
+  }
+
+  #[test]
+  fn ipv6_clients_are_counted_by_network() {
+    let ip = |ip: &str| network(ip.parse().unwrap());
+    assert_eq!(ip("203.0.113.7"), "203.0.113.7");
+    assert_eq!(ip("2001:db8:0:1::1"), "2001:db8:0:1::/64");
+    assert_eq!(ip("2001:db8:0:1:ffff:2:3:4"), ip("2001:db8:0:1::1"));
+    assert_ne!(ip("2001:db8:0:2::1"), ip("2001:db8:0:1::1"));
+  }
+}
@@ -0,0 +1,73 @@
+use axum::{body::to_bytes, extract::Request, http::{header::CONTENT_TYPE, HeaderName, HeaderValue}, middleware::Next, response::{IntoResponse, Response}};
+use nanoid::nanoid;
+
+use crate::errors::{error_response, status_code_name, INTERNAL_ERROR_MESSAGE};
+
+pub static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+const MAX_ERROR_BODY_SIZE: usize = 64 * 1024;
+
+tokio::task_local! {
+  static REQUEST_ID: String;
+}
+
+pub fn current_request_id() -> Option<String> {
+  REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+fn incoming_request_id(req: &Request) -> Option<String> {
+  let id = req.headers().get(&X_REQUEST_ID)?.to_str().ok()?;
+  let valid = !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH
+    && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+  valid.then(|| id.to_owned())
+}
+
+async fn normalize_error_response(res: Response) -> Response {
+  let status = res.status();
+  if !status.is_client_error() && !status.is_server_error() {
+    return res;
+  }
+
+  let is_json = res.headers().get(CONTENT_TYPE)
+    .and_then(|value| value.to_str().ok())
+    .is_some_and(|value| value.starts_with("application/json"));
+
+  if is_json {
+    return res;
+  }
+
+  let (parts, body) = res.into_parts();
+  let message = match to_bytes(body, MAX_ERROR_BODY_SIZE).await {
+    _ if status.is_server_error() => INTERNAL_ERROR_MESSAGE.to_string(),
+    Ok(bytes) if !bytes.is_empty() => String::from_utf8_lossy(&bytes).into_owned(),
+    _ => status.canonical_reason().unwrap_or("error").to_lowercase(),
+  };
+
+  let mut normalized = error_response(status, status_code_name(status), message, Vec::new());
+  for (name, value) in parts.headers.iter() {
+    if name != CONTENT_TYPE && name != axum::http::header::CONTENT_LENGTH {
+      normalized.headers_mut().append(name.clone(), value.clone());
+    }
+  }
+
+  normalized.into_response()
+}
+
+pub async fn request_id_middleware(
+  mut req: Request,
+  next: Next,
+) -> Response {
+  let id = incoming_request_id(&req).unwrap_or_else(|| nanoid!(16));
+  let header = HeaderValue::from_str(&id).expect("request ids are ascii");
+  req.headers_mut().insert(X_REQUEST_ID.clone(), header.clone());
+
+  let mut res = REQUEST_ID.scope(id, async move {
+    let res = next.run(req).await;
+    normalize_error_response(res).await
+  }).await;
+
+  res.headers_mut().insert(X_REQUEST_ID.clone(), header);
+  res
+}
@@ -1,12 +1,13 @@
-use axum::{body::Body, extract::{FromRequestParts, Request, State}, http::{header::{COOKIE, SET_COOKIE}, request::Parts}, middleware::Next, response::{IntoResponse, IntoResponseParts, Response}, Extension};
+use std::{collections::HashMap, sync::{Arc, Mutex}};
+
+use axum::{extract::{FromRequestParts, Request, State}, http::{header::{COOKIE, SET_COOKIE}, request::Parts, HeaderValue}, middleware::Next, response::{IntoResponse, Response}, Extension};
 use axum_extra::extract::PrivateCookieJar;
-use chrono::{Duration, Local, Utc};
-use cookie::Cookie;
-use oauth2::{basic::{BasicClient, BasicTokenType}, reqwest::async_http_client, EmptyExtraTokenFields, RefreshToken, StandardTokenResponse, TokenResponse};
+use chrono::{Duration, Utc};
+use oauth2::{basic::BasicErrorResponseType, RequestTokenError, TokenResponse};
 
-use crate::{constants::{ACCESS_COOKIE_EXPIRE_TIME, ACCESS_TOKEN_EXPIRE_TIME}, errors::ApiError, router_state::{RouterState, UserProfile}};
+use crate::{audit::{self, AuditAction, AuditEvent, AuditTarget, RequestMeta}, auth::{build_session_cookie, build_session_removal_cookie, IdentityProviders}, constants::{ACCESS_TOKEN_EXPIRE_TIME, SESSION_REFRESH_GRACE_TIME}, errors::ApiError, router_state::RouterState};
 
-#[derive(Debug, sqlx::FromRow)]
+#[derive(Debug, Clone, sqlx::FromRow)]
 struct Session {
   pub id: i32,
   pub user_id: i32,
@@ -16,108 +17,482 @@ struct Session {
 
 #[derive(Debug, sqlx::FromRow)]
 struct RefreshTokenEntry {
-  pub id: i32,
-  pub user_id: i32,
   pub refresh_token: String,
+  pub provider: String,
+  pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug)]
+enum RefreshOutcome {
+  Active(String),
+  Grace,
+  Invalid,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RefreshLocks(Arc<Mutex<HashMap<i32, Arc<tokio::sync::Mutex<()>>>>>);
+
+impl RefreshLocks {
+  fn acquire(&self, session: i32) -> Arc<tokio::sync::Mutex<()>> {
+    let mut locks = self.0.lock().unwrap_or_else(|e| e.into_inner());
+    locks.entry(session).or_default().clone()
+  }
+
+  fn release(&self, session: i32, lock: Arc<tokio::sync::Mutex<()>>) {
+    let mut locks = self.0.lock().unwrap_or_else(|e| e.into_inner());
+
+    // the map and this handle are the only references left, nobody else is waiting
+    if Arc::strong_count(&lock) <= 2 {
+      locks.remove(&session);
+    }
+  }
+}
+
+fn is_revoked(error: &ApiError) -> bool {
+  matches!(
+    error,
+    ApiError::TokenError(RequestTokenError::ServerResponse(response))
+      if *response.error() == BasicErrorResponseType::InvalidGrant
+  )
 }
 
-async fn default_req(parts: Parts, body: Body, next: Next) -> Response {
-  let req = Request::from_parts(parts, body);
-  next.run(req).await
+fn within_grace(session: &Session) -> bool {
+  Utc::now() < session.expires_at + Duration::seconds(SESSION_REFRESH_GRACE_TIME)
 }
 
-async fn refresh_access_token(
-  client: &BasicClient,
-  refresh_token: &RefreshToken,
-) -> Result<StandardTokenResponse<EmptyExtraTokenFields, BasicTokenType>, ApiError> {
-  let token = client
-    .exchange_refresh_token(refresh_token)
-    .request_async(async_http_client)
+async fn find_session(state: &RouterState, id: i32) -> Result<Option<Session>, ApiError> {
+  let session = sqlx::query_as::<_, Session>("
+    SELECT id, user_id, session_id, expires_at FROM sessions WHERE id = $1
+  ").bind(id).fetch_optional(&state.db).await?;
+
+  Ok(session)
+}
+
+async fn invalidate_session(state: &RouterState, session: &Session) -> Result<(), ApiError> {
+  sqlx::query("DELETE FROM sessions WHERE id = $1")
+    .bind(session.id)
+    .execute(&state.db)
+    .await?;
+
+  sqlx::query("DELETE FROM refresh_tokens WHERE user_id = $1")
+    .bind(session.user_id)
+    .execute(&state.db)
     .await?;
 
-  Ok(token)
+  Ok(())
+}
+
+async fn refresh_session(
+  state: &RouterState,
+  providers: &IdentityProviders,
+  session: &Session,
+) -> Result<RefreshOutcome, ApiError> {
+  // another request may have refreshed the session while we waited for the lock
+  let Some(current) = find_session(state, session.id).await? else {
+    return Ok(RefreshOutcome::Invalid);
+  };
+
+  if current.session_id != session.session_id || Utc::now() < current.expires_at {
+    return Ok(RefreshOutcome::Active(current.session_id));
+  }
+
+  let entry = sqlx::query_as::<_, RefreshTokenEntry>("
+    SELECT refresh_token, provider, expires_at FROM refresh_tokens WHERE user_id = $1
+  ").bind(session.user_id).fetch_optional(&state.db).await?;
+
+  let Some(entry) = entry else {
+    return Ok(RefreshOutcome::Invalid);
+  };
+
+  if Utc::now() >= entry.expires_at {
+    log::info!("refresh token of user {} expired", session.user_id);
+    return Ok(RefreshOutcome::Invalid);
+  }
+
+  let provider = match providers.get(&entry.provider) {
+    Ok(provider) => provider,
+    Err(e) => {
+      log::warn!("can't refresh session of user {}: {}", session.user_id, e);
+      return Ok(RefreshOutcome::Invalid);
+    },
+  };
+
+  let token = match provider.exchange_refresh_token(entry.refresh_token).await {
+    Ok(token) => token,
+    Err(e) if is_revoked(&e) => {
+      // a racing replica may have rotated the refresh token under us
+      return match find_session(state, session.id).await? {
+        Some(current) if current.session_id != session.session_id => Ok(RefreshOutcome::Active(current.session_id)),
+        _ => {
+          log::info!("refresh token of user {} was revoked by {}", session.user_id, provider.name);
+          Ok(RefreshOutcome::Invalid)
+        },
+      };
+    },
+    Err(e) => {
+      log::warn!("failed to refresh session of user {}: {}", session.user_id, e);
+      return Ok(if within_grace(session) { RefreshOutcome::Grace } else { RefreshOutcome::Invalid });
+    },
+  };
+
+  let new_session_id = token.access_token().secret().to_owned();
+
+  let secs: i64 = match token.expires_in() {
+    Some(secs) => secs.as_secs().try_into()?,
+    None => ACCESS_TOKEN_EXPIRE_TIME,
+  };
+
+  let max_age = Utc::now() + Duration::seconds(
+    std::cmp::min(secs, ACCESS_TOKEN_EXPIRE_TIME),
+  );
+
+  // only replace the session we refreshed, a concurrent replica may have won the race
+  let updated = sqlx::query("UPDATE sessions SET session_id = $1, expires_at = $2 WHERE id = $3 AND session_id = $4")
+    .bind(&new_session_id)
+    .bind(max_age)
+    .bind(session.id)
+    .bind(&session.session_id)
+    .execute(&state.db)
+    .await?;
+
+  if updated.rows_affected() == 0 {
+    return match find_session(state, session.id).await? {
+      Some(current) => Ok(RefreshOutcome::Active(current.session_id)),
+      None => Ok(RefreshOutcome::Invalid),
+    };
+  }
+
+  // providers that rotate refresh tokens invalidate the one we just used
+  if let Some(new_refresh_token) = token.refresh_token() {
+    sqlx::query("UPDATE refresh_tokens SET refresh_token = $1 WHERE user_id = $2")
+      .bind(new_refresh_token.secret())
+      .bind(session.user_id)
+      .execute(&state.db)
+      .await?;
+  }
+
+  Ok(RefreshOutcome::Active(new_session_id))
+}
+
+async fn record_refresh(state: &RouterState, meta: &RequestMeta, session: &Session, action: AuditAction) {
+  let event = AuditEvent::new(Some(session.user_id), action, AuditTarget::Session, session.id.to_string())
+    .before(serde_json::json!({ "expires_at": session.expires_at }));
+
+  if let Err(e) = audit::record(&state.db, meta, event).await {
+    log::error!("failed to record session refresh of user {}: {:?}", session.user_id, e);
+  }
+}
+
+fn replace_request_cookie(parts: &mut Parts, sid: Option<&str>) {
+  let mut cookies: Vec<String> = parts.headers.get_all(COOKIE).iter()
+    .filter_map(|header| header.to_str().ok())
+    .flat_map(|header| header.split(';'))
+    .map(|cookie| cookie.trim())
+    .filter(|cookie| !cookie.is_empty() && !cookie.starts_with("sid="))
+    .map(|cookie| cookie.to_owned())
+    .collect();
+
+  if let Some(sid) = sid {
+    cookies.push(format!("sid={}", sid));
+  }
+
+  parts.headers.remove(COOKIE);
+  if let Ok(header) = HeaderValue::from_str(&cookies.join("; ")) {
+    parts.headers.insert(COOKIE, header);
+  }
 }
-  
 
 pub async fn token_refresh_middleware(
   State(state): State<RouterState>,
-  Extension(client): Extension<BasicClient>,
+  Extension(providers): Extension<IdentityProviders>,
   req: Request,
   next: Next,
 ) -> Response {
   let (mut parts, body) = req.into_parts();
 
-  let mut cookie_jar: PrivateCookieJar = match PrivateCookieJar::from_request_parts(&mut parts, &state).await {
+  let cookie_jar: PrivateCookieJar = match PrivateCookieJar::from_request_parts(&mut parts, &state).await {
     Ok(jar) => jar,
-    Err(_) => return default_req(parts, body, next).await,
+    Err(_) => return next.run(Request::from_parts(parts, body)).await,
   };
 
   let Some(cookie) = cookie_jar.get("sid").map(|cookie| cookie.value().to_owned()) else {
-    return default_req(parts, body, next).await;
+    return next.run(Request::from_parts(parts, body)).await;
   };
 
   let session = match sqlx::query_as::<_, Session>("
-    SELECT * FROM sessions WHERE session_id = $1
-  ").bind(cookie).fetch_one(&state.db).await {
-    Ok(session) => session,
-    Err(_) => return default_req(parts, body, next).await,
+    SELECT id, user_id, session_id, expires_at FROM sessions WHERE session_id = $1
+  ").bind(cookie).fetch_optional(&state.db).await {
+    Ok(Some(session)) => session,
+    Ok(None) => return next.run(Request::from_parts(parts, body)).await,
+    Err(e) => {
+      log::error!("failed to look up session: {:?}", e);
+      return next.run(Request::from_parts(parts, body)).await;
+    },
   };
 
-  if Utc::now() >= session.expires_at {
-    let refresh_token = match sqlx::query_as::<_, RefreshTokenEntry>("
-      SELECT * FROM refresh_tokens WHERE user_id = $1
-      ").bind(session.user_id).fetch_one(&state.db).await {
-        Ok(refresh_token) => refresh_token,
-        Err(_) => return default_req(parts, body, next).await,
-      };
+  if Utc::now() < session.expires_at {
+    return next.run(Request::from_parts(parts, body)).await;
+  }
 
-    match refresh_access_token(&client, &RefreshToken::new(refresh_token.refresh_token)).await {
-      Ok(access_token) => {
-        let token = access_token.access_token().secret().to_owned();
-
-        let Some(secs) = access_token.expires_in() else {
-          return default_req(parts, body, next).await;
-        };
-
-        let secs: i64 = secs.as_secs().try_into().unwrap();
-
-        let max_age = Local::now().naive_utc() + Duration::seconds(
-          std::cmp::min(secs, ACCESS_TOKEN_EXPIRE_TIME),
-        );
-
-        sqlx::query("UPDATE sessions SET session_id = $1, expires_at = $2 WHERE id = $3")
-          .bind(&token)
-          .bind(max_age)
-          .bind(session.id)
-          .execute(&state.db)
-          .await
-          .unwrap();
-
-        let cookie = Cookie::build(("sid", token.clone()))
-          .domain(state.env.frontend_domain)
-          .path("/")
-          .secure(true)
-          .http_only(true)
-          .max_age(cookie::time::Duration::seconds(ACCESS_COOKIE_EXPIRE_TIME));
-
-        cookie_jar = cookie_jar.add(cookie.clone());
-        assert_eq!(cookie_jar.get("sid").unwrap().value(), token);
-
-        // insert new cookie in request
-        let encoded_cookie = cookie_jar.into_response();
-        encoded_cookie.headers().get(SET_COOKIE).map(|cookie| {
-          parts.headers.insert(COOKIE, cookie.clone());
-        });
-
-        // insert new cookie in response
-        let mut res = next.run(Request::from_parts(parts, body)).await;
-        res.headers_mut().insert(SET_COOKIE, encoded_cookie.headers().get(SET_COOKIE).unwrap().clone());
-
-        return res;
-      },
-      Err(_) => return default_req(parts, body, next).await,
+  let lock = state.refresh_locks.acquire(session.id);
+  let outcome = {
+    let _guard = lock.lock().await;
+    refresh_session(&state, &providers, &session).await
+  };
+  state.refresh_locks.release(session.id, lock);
+
+  let meta = RequestMeta::from_parts(&parts, state.env.proxy.as_ref());
+
+  let cookie_jar = match outcome {
+    Ok(RefreshOutcome::Active(session_id)) if session_id == session.session_id => {
+      return next.run(Request::from_parts(parts, body)).await;
+    },
+    Ok(RefreshOutcome::Active(session_id)) => {
+      record_refresh(&state, &meta, &session, AuditAction::SessionRefresh).await;
+      cookie_jar.add(build_session_cookie(&state.env, session_id))
+    },
+    Ok(RefreshOutcome::Grace) => return next.run(Request::from_parts(parts, body)).await,
+    Ok(RefreshOutcome::Invalid) => {
+      if let Err(e) = invalidate_session(&state, &session).await {
+        log::error!("failed to invalidate session of user {}: {:?}", session.user_id, e);
+      }
+
+      record_refresh(&state, &meta, &session, AuditAction::SessionRefreshFailed).await;
+
+      cookie_jar.remove(build_session_removal_cookie(&state.env))
+    },
+    Err(e) => {
+      log::error!("failed to refresh session of user {}: {:?}", session.user_id, e);
+      return next.run(Request::from_parts(parts, body)).await;
+    },
+  };
+
+  let encoded_cookie = cookie_jar.into_response();
+  let set_cookie = encoded_cookie.headers().get(SET_COOKIE).cloned();
+
+  // hand the new cookie (or its removal) to the handlers of this request
+  let sid = set_cookie.as_ref()
+    .and_then(|header| header.to_str().ok())
+    .and_then(|header| cookie::Cookie::parse(header).ok())
+    .filter(|cookie| !cookie.value().is_empty())
+    .map(|cookie| cookie.value().to_owned());
+  replace_request_cookie(&mut parts, sid.as_deref());
+
+  let mut res = next.run(Request::from_parts(parts, body)).await;
+  if let Some(set_cookie) = set_cookie {
+    res.headers_mut().append(SET_COOKIE, set_cookie);
+  }
+
+  res
+}
+
+#[cfg(test)]
+mod tests {
+  use axum::{extract::Form, http::StatusCode, routing::{get, post}, Json, Router};
+  use oauth2::basic::BasicErrorResponse;
+  use serde::Deserialize;
+  use serde_json::{json, Value};
+  use tokio::net::TcpListener;
+
+  use crate::{auth::build_identity_providers, env::{test_env, OAuthCredentials, OidcConfig}, router_state::UserProfile, test_support::{create_user, delete_user, test_db, test_state}};
+
+  use super::*;
+
+  const PROVIDER: &str = "mock";
+
+  #[derive(Debug, Clone, Copy)]
+  enum Reply {
+    Rotate,
+    Revoked,
+    Down,
+  }
+
+  // a token endpoint that answers refreshes however the test wants it to
+  #[derive(Clone)]
+  struct MockIssuer {
+    url: String,
+    reply: Arc<Mutex<Reply>>,
+  }
+
+  #[derive(Deserialize)]
+  struct RefreshRequest {
+    grant_type: String,
+  }
+
+  async fn discovery(State(issuer): State<MockIssuer>) -> Json<Value> {
+    Json(json!({
+      "issuer": issuer.url,
+      "authorization_endpoint": format!("{}/authorize", issuer.url),
+      "token_endpoint": format!("{}/token", issuer.url),
+      "userinfo_endpoint": format!("{}/userinfo", issuer.url),
+    }))
+  }
+
+  async fn token(State(issuer): State<MockIssuer>, Form(request): Form<RefreshRequest>) -> (StatusCode, Json<Value>) {
+    assert_eq!(request.grant_type, "refresh_token");
+
+    let reply = *issuer.reply.lock().unwrap();
+    match reply {
+      Reply::Rotate => (StatusCode::OK, Json(json!({
+        "access_token": format!("access-{}", nanoid::nanoid!()),
+        "refresh_token": format!("refresh-{}", nanoid::nanoid!()),
+        "token_type": "bearer",
+        "expires_in": 3600,
+      }))),
+      Reply::Revoked => (StatusCode::BAD_REQUEST, Json(json!({ "error": "invalid_grant" }))),
+      Reply::Down => (StatusCode::SERVICE_UNAVAILABLE, Json(json!({}))),
+    }
+  }
+
+  async fn start_issuer() -> MockIssuer {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let issuer = MockIssuer {
+      url: format!("http://{}", listener.local_addr().unwrap()),
+      reply: Arc::new(Mutex::new(Reply::Rotate)),
+    };
+
+    let app = Router::new()
+      .route("/.well-known/openid-configuration", get(discovery))
+      .route("/token", post(token))
+      .with_state(issuer.clone());
+
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    issuer
+  }
+
+  async fn start_session(state: &RouterState, user: &UserProfile, expired: Duration, provider: &str) -> Session {
+    sqlx::query("INSERT INTO refresh_tokens (user_id, refresh_token, provider, expires_at) VALUES ($1, $2, $3, NOW() + INTERVAL '1 day')")
+      .bind(user.id)
+      .bind(format!("refresh-{}", nanoid::nanoid!()))
+      .bind(provider)
+      .execute(&state.db)
+      .await.unwrap();
+
+    sqlx::query_as("INSERT INTO sessions (user_id, session_id, expires_at) VALUES ($1, $2, $3)
+      RETURNING id, user_id, session_id, expires_at")
+      .bind(user.id)
+      .bind(format!("access-{}", nanoid::nanoid!()))
+      .bind(Utc::now() - expired)
+      .fetch_one(&state.db)
+      .await.unwrap()
+  }
+
+  async fn end_session(state: &RouterState, user: &UserProfile) {
+    sqlx::query("DELETE FROM sessions WHERE user_id = $1").bind(user.id).execute(&state.db).await.unwrap();
+    sqlx::query("DELETE FROM refresh_tokens WHERE user_id = $1").bind(user.id).execute(&state.db).await.unwrap();
+  }
+
+  fn token_error(error: BasicErrorResponseType) -> ApiError {
+    ApiError::TokenError(RequestTokenError::ServerResponse(BasicErrorResponse::new(error, None, None)))
+  }
+
+  #[test]
+  fn only_invalid_grant_revokes() {
+    assert!(is_revoked(&token_error(BasicErrorResponseType::InvalidGrant)));
+    assert!(!is_revoked(&token_error(BasicErrorResponseType::InvalidClient)));
+    assert!(!is_revoked(&ApiError::TokenError(RequestTokenError::Other("unreachable".to_string()))));
+    assert!(!is_revoked(&ApiError::Unauthorized));
+  }
+
+  #[test]
+  fn grace_ends_after_the_grace_time() {
+    let session = |expired: i64| Session {
+      id: 1,
+      user_id: 1,
+      session_id: String::new(),
+      expires_at: Utc::now() - Duration::seconds(expired),
     };
+
+    assert!(within_grace(&session(0)));
+    assert!(within_grace(&session(SESSION_REFRESH_GRACE_TIME - 10)));
+    assert!(!within_grace(&session(SESSION_REFRESH_GRACE_TIME + 10)));
+  }
+
+  #[tokio::test]
+  async fn locks_are_shared_until_released() {
+    let locks = RefreshLocks::default();
+    let first = locks.acquire(1);
+    let second = locks.acquire(1);
+    assert!(Arc::ptr_eq(&first, &second));
+    assert!(!Arc::ptr_eq(&first, &locks.acquire(2)));
+
+    let guard = first.lock().await;
+    assert!(second.try_lock().is_err());
+    drop(guard);
+
+    // the lock stays while somebody still holds it
+    locks.release(1, first);
+    assert!(locks.0.lock().unwrap().contains_key(&1));
+    locks.release(1, second);
+    assert!(!locks.0.lock().unwrap().contains_key(&1));
   }
 
-  default_req(parts, body, next).await
+  #[tokio::test]
+  async fn refresh_outcomes() {
+    let Some(db) = test_db().await else {
+      return;
+    };
+
+    let issuer = start_issuer().await;
+    let mut env = test_env();
+    env.oidc = Some(OidcConfig {
+      name: PROVIDER.to_string(),
+      issuer_url: issuer.url.clone(),
+      credentials: OAuthCredentials { client_id: "shaderx".to_string(), client_secret: "secret".to_string() },
+    });
+
+    let state = test_state(db);
+    let providers = build_identity_providers(&env, &state.ctx).await.unwrap();
+    let user = create_user(&state.db).await;
+    let expired = Duration::seconds(10);
+
+    // a refresh replaces the session and the rotated refresh token
+    let session = start_session(&state, &user, expired, PROVIDER).await;
+    let outcome = refresh_session(&state, &providers, &session).await.unwrap();
+    let RefreshOutcome::Active(session_id) = outcome else {
+      panic!("expected an active session, got {:?}", outcome);
+    };
+    let current = find_session(&state, session.id).await.unwrap().unwrap();
+    assert_ne!(session_id, session.session_id);
+    assert_eq!(current.session_id, session_id);
+    assert!(current.expires_at > Utc::now());
+
+    // a request holding the old session finds it refreshed without asking the provider again
+    *issuer.reply.lock().unwrap() = Reply::Down;
+    let outcome = refresh_session(&state, &providers, &session).await.unwrap();
+    assert!(matches!(outcome, RefreshOutcome::Active(ref id) if *id == session_id), "{:?}", outcome);
+    end_session(&state, &user).await;
+
+    let cases = [
+      (Reply::Revoked, expired, PROVIDER, "invalid"),
+      (Reply::Down, expired, PROVIDER, "grace"),
+      (Reply::Down, Duration::seconds(SESSION_REFRESH_GRACE_TIME + 10), PROVIDER, "invalid"),
+      (Reply::Rotate, expired, "gone", "invalid"),
+    ];
+
+    for (reply, expired, provider, expected) in cases {
+      *issuer.reply.lock().unwrap() = reply;
+      let session = start_session(&state, &user, expired, provider).await;
+      let outcome = refresh_session(&state, &providers, &session).await.unwrap();
+
+      let outcome = match outcome {
+        RefreshOutcome::Active(_) => "active",
+        RefreshOutcome::Grace => "grace",
+        RefreshOutcome::Invalid => "invalid",
+      };
+      assert_eq!(outcome, expected, "{:?} after {:?} with {}", reply, expired, provider);
+      end_session(&state, &user).await;
+    }
+
+    // no refresh token to use, or one that has expired
+    let session = start_session(&state, &user, expired, PROVIDER).await;
+    sqlx::query("UPDATE refresh_tokens SET expires_at = NOW() WHERE user_id = $1").bind(user.id).execute(&state.db).await.unwrap();
+    assert!(matches!(refresh_session(&state, &providers, &session).await.unwrap(), RefreshOutcome::Invalid));
+    sqlx::query("DELETE FROM refresh_tokens WHERE user_id = $1").bind(user.id).execute(&state.db).await.unwrap();
+    assert!(matches!(refresh_session(&state, &providers, &session).await.unwrap(), RefreshOutcome::Invalid));
+    end_session(&state, &user).await;
+
+    delete_user(&state.db, &user).await;
+  }
 }
@@ -0,0 +1,26 @@
+use serde::Serialize;
+use sqlx::{types::JsonValue, PgExecutor};
+
+use crate::errors::ApiError;
+
+pub const SHADER_PURGE_SCHEDULED: &str = "shader_purge_scheduled";
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Notification {
+  pub id: i32,
+  pub kind: String,
+  pub payload: JsonValue,
+  pub created_at: chrono::DateTime<chrono::Utc>,
+  pub read_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+pub async fn notify<'e>(executor: impl PgExecutor<'e>, user_id: i32, kind: &str, payload: JsonValue) -> Result<(), ApiError> {
+  sqlx::query("INSERT INTO notifications (user_id, kind, payload) VALUES ($1, $2, $3)")
+    .bind(user_id)
+    .bind(kind)
+    .bind(payload)
+    .execute(executor)
+    .await?;
+
+  Ok(())
+}
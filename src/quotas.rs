@@ -0,0 +1,274 @@
+use serde::Serialize;
+use sqlx::{PgConnection, PgPool};
+
+use crate::{env::Env, errors::ApiError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Quota {
+  Shaders,
+  Templates,
+  CodeBytes,
+  AssetBytes,
+}
+
+impl Quota {
+  pub fn name(self) -> &'static str {
+    match self {
+      Self::Shaders => "shader",
+      Self::Templates => "template",
+      Self::CodeBytes => "code storage",
+      Self::AssetBytes => "asset storage",
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaExceeded {
+  pub quota: Quota,
+  pub limit: i64,
+  pub used: i64,
+  pub requested: i64,
+}
+
+impl std::fmt::Display for QuotaExceeded {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{} quota of {} exceeded, {} in use and {} more requested", self.quota.name(), self.limit, self.used, self.requested)
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, sqlx::FromRow)]
+pub struct Usage {
+  pub shaders: i64,
+  pub templates: i64,
+  pub code_bytes: i64,
+  pub asset_bytes: i64,
+  pub revisions: i64,
+}
+
+pub async fn lock_usage(conn: &mut PgConnection, user_id: i32) -> Result<Usage, ApiError> {
+  sqlx::query("INSERT INTO user_usage (user_id) VALUES ($1) ON CONFLICT (user_id) DO NOTHING")
+    .bind(user_id)
+    .execute(&mut *conn)
+    .await?;
+
+  let usage = sqlx::query_as(
+    "SELECT shaders::BIGINT, templates::BIGINT, code_bytes, asset_bytes, revisions::BIGINT FROM user_usage WHERE user_id = $1 FOR UPDATE")
+    .bind(user_id)
+    .fetch_one(&mut *conn)
+    .await?;
+
+  Ok(usage)
+}
+
+pub async fn get_usage(db: &PgPool, user_id: i32) -> Result<Usage, ApiError> {
+  let usage: Option<Usage> = sqlx::query_as(
+    "SELECT shaders::BIGINT, templates::BIGINT, code_bytes, asset_bytes, revisions::BIGINT FROM user_usage WHERE user_id = $1")
+    .bind(user_id)
+    .fetch_optional(db)
+    .await?;
+
+  Ok(usage.unwrap_or_default())
+}
+
+fn exceeded(quota: Quota, limit: i64, used: i64, requested: i64) -> ApiError {
+  ApiError::QuotaExceeded(QuotaExceeded { quota, limit, used, requested })
+}
+
+// usage that went down passes even when over, quotas may have been lowered since
+pub async fn check_usage(conn: &mut PgConnection, env: &Env, user_id: i32, before: &Usage) -> Result<(), ApiError> {
+  let after = lock_usage(conn, user_id).await?;
+
+  let quotas = [
+    (Quota::Shaders, env.quotas.max_shaders, before.shaders, after.shaders),
+    (Quota::Templates, env.quotas.max_templates, before.templates, after.templates),
+    (Quota::CodeBytes, env.quotas.max_code_bytes, before.code_bytes, after.code_bytes),
+    (Quota::AssetBytes, env.assets.user_quota_bytes, before.asset_bytes, after.asset_bytes),
+  ];
+
+  for (quota, limit, before, after) in quotas {
+    if after > before && after > limit {
+      return Err(exceeded(quota, limit, before, after - before));
+    }
+  }
+
+  Ok(())
+}
+
+pub fn check_asset_upload(env: &Env, usage: &Usage, size: i64) -> Result<(), ApiError> {
+  let limit = env.assets.user_quota_bytes;
+  if usage.asset_bytes + size > limit {
+    return Err(exceeded(Quota::AssetBytes, limit, usage.asset_bytes, size));
+  }
+
+  Ok(())
+}
+
+// includes pinned to a pruned revision stop resolving, the latest ones are kept
+pub async fn prune_revisions(conn: &mut PgConnection, env: &Env, shader_id: &str) -> Result<(), ApiError> {
+  sqlx::query(
+    "DELETE FROM shader_library_revisions WHERE shader_id = $1 AND revision <= (
+      SELECT MAX(revision) - $2 FROM shader_library_revisions WHERE shader_id = $1)")
+    .bind(shader_id)
+    .bind(env.quotas.max_revisions_per_shader)
+    .execute(&mut *conn)
+    .await?;
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use crate::{env::test_env, router_state::UserProfile, test_support::{create_user, delete_user, test_db}};
+
+  use super::*;
+
+  async fn usage(db: &PgPool, user: &UserProfile) -> Usage {
+    get_usage(db, user.id).await.unwrap()
+  }
+
+  async fn insert_shader(conn: impl sqlx::PgExecutor<'_>, user: &UserProfile, code: &str) -> String {
+    let id = nanoid::nanoid!(6);
+    sqlx::query("INSERT INTO shaders (id, user_id, name, data) VALUES ($1, $2, 'test', $3)")
+      .bind(&id)
+      .bind(user.user_id)
+      .bind(json!({ "code": code }))
+      .execute(conn)
+      .await.unwrap();
+
+    id
+  }
+
+  async fn execute(db: &PgPool, query: &str, id: &str) {
+    sqlx::query(query).bind(id).execute(db).await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn triggers_track_shaders() {
+    let Some(db) = test_db().await else {
+      return;
+    };
+
+    let user = create_user(&db).await;
+    assert_eq!(usage(&db, &user).await.shaders, 0);
+
+    let id = insert_shader(&db, &user, "void main() {}").await;
+    let stored = usage(&db, &user).await;
+    // as jsonb prints it
+    let empty = r#"{"code": ""}"#.len() as i64;
+    assert_eq!((stored.shaders, stored.code_bytes), (1, empty + 14));
+
+    execute(&db, "UPDATE shaders SET data = jsonb_build_object('code', '') WHERE id = $1", &id).await;
+    let updated = usage(&db, &user).await;
+    assert_eq!((updated.shaders, updated.code_bytes), (1, empty));
+
+    // trashed shaders count until they're purged
+    execute(&db, "UPDATE shaders SET deleted = TRUE, deleted_at = NOW() WHERE id = $1", &id).await;
+    assert_eq!(usage(&db, &user).await.shaders, 1);
+    execute(&db, "UPDATE shaders SET deleted = FALSE, deleted_at = NULL WHERE id = $1", &id).await;
+    assert_eq!(usage(&db, &user).await.shaders, 1);
+
+    execute(&db, "DELETE FROM shaders WHERE id = $1", &id).await;
+    let deleted = usage(&db, &user).await;
+    assert_eq!((deleted.shaders, deleted.code_bytes), (0, 0));
+
+    sqlx::query("INSERT INTO shader_templates (id, user_id, name, data) VALUES ($1, $2, 'test', '{}')")
+      .bind(&id)
+      .bind(user.user_id)
+      .execute(&db)
+      .await.unwrap();
+    let templates = usage(&db, &user).await;
+    assert_eq!((templates.shaders, templates.templates, templates.code_bytes), (0, 1, 2));
+
+    execute(&db, "DELETE FROM shader_templates WHERE id = $1", &id).await;
+    let templates = usage(&db, &user).await;
+    assert_eq!((templates.templates, templates.code_bytes), (0, 0));
+
+    delete_user(&db, &user).await;
+  }
+
+  #[tokio::test]
+  async fn revisions_are_released_once() {
+    let Some(db) = test_db().await else {
+      return;
+    };
+
+    let mut env = test_env();
+    env.quotas.max_revisions_per_shader = 2;
+
+    let user = create_user(&db).await;
+    let id = insert_shader(&db, &user, "").await;
+    sqlx::query("INSERT INTO shader_libraries (shader_id, user_id, name) VALUES ($1, $2, 'lib')")
+      .bind(&id)
+      .bind(user.user_id)
+      .execute(&db)
+      .await.unwrap();
+
+    let shader_bytes = usage(&db, &user).await.code_bytes;
+    let mut conn = db.acquire().await.unwrap();
+    for revision in 1..=3 {
+      sqlx::query("INSERT INTO shader_library_revisions (shader_id, revision, code) VALUES ($1, $2, $3)")
+        .bind(&id)
+        .bind(revision)
+        .bind("x".repeat(revision as usize))
+        .execute(&mut *conn)
+        .await.unwrap();
+
+      prune_revisions(&mut conn, &env, &id).await.unwrap();
+    }
+
+    // the first revision is gone, the latest two are kept
+    let revisions: Vec<(i32,)> = sqlx::query_as("SELECT revision FROM shader_library_revisions WHERE shader_id = $1 ORDER BY revision")
+      .bind(&id)
+      .fetch_all(&db)
+      .await.unwrap();
+    assert_eq!(revisions, [(2,), (3,)]);
+
+    let pruned = usage(&db, &user).await;
+    assert_eq!((pruned.revisions, pruned.code_bytes), (2, shader_bytes + 5));
+
+    // deleting the shader cascades to the revisions, they're released by the shader alone
+    execute(&db, "DELETE FROM shaders WHERE id = $1", &id).await;
+    let deleted = usage(&db, &user).await;
+    assert_eq!((deleted.shaders, deleted.revisions, deleted.code_bytes), (0, 0, 0));
+
+    delete_user(&db, &user).await;
+  }
+
+  #[tokio::test]
+  async fn writes_past_a_quota_are_refused() {
+    let Some(db) = test_db().await else {
+      return;
+    };
+
+    let mut env = test_env();
+    env.quotas.max_shaders = 1;
+
+    let user = create_user(&db).await;
+    let mut tx = db.begin().await.unwrap();
+    let before = lock_usage(&mut tx, user.id).await.unwrap();
+    insert_shader(&mut *tx, &user, "").await;
+    check_usage(&mut tx, &env, user.id, &before).await.unwrap();
+
+    let before = lock_usage(&mut tx, user.id).await.unwrap();
+    insert_shader(&mut *tx, &user, "").await;
+    insert_shader(&mut *tx, &user, "").await;
+    let Err(ApiError::QuotaExceeded(exceeded)) = check_usage(&mut tx, &env, user.id, &before).await else {
+      panic!("expected the shader quota to be exceeded");
+    };
+    assert_eq!((exceeded.quota, exceeded.limit, exceeded.used, exceeded.requested), (Quota::Shaders, 1, 1, 2));
+
+    // over the quota is fine as long as it doesn't grow
+    let before = lock_usage(&mut tx, user.id).await.unwrap();
+    sqlx::query("DELETE FROM shaders WHERE id IN (SELECT id FROM shaders WHERE user_id = $1 LIMIT 1)")
+      .bind(user.user_id)
+      .execute(&mut *tx)
+      .await.unwrap();
+    check_usage(&mut tx, &env, user.id, &before).await.unwrap();
+
+    tx.rollback().await.unwrap();
+    delete_user(&db, &user).await;
+  }
+}
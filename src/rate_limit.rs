@@ -0,0 +1,116 @@
+use std::{collections::{HashMap, VecDeque}, sync::{Arc, Mutex}};
+
+use async_trait::async_trait;
+use sqlx::PgPool;
+
+use crate::{constants::RATE_LIMIT_MEMORY_MAX_KEYS, env::{RateLimitBackend, RateLimitConfig}, errors::ApiError};
+
+#[async_trait]
+pub trait RateLimitStore: Send + Sync + std::fmt::Debug {
+  async fn hit(&self, key: &str, window_end: i64) -> Result<u64, ApiError>;
+}
+
+pub type RateLimiter = Arc<dyn RateLimitStore>;
+
+pub fn build_rate_limiter(config: &RateLimitConfig, db: &PgPool) -> RateLimiter {
+  match config.backend {
+    RateLimitBackend::Memory => Arc::new(MemoryStore::default()),
+    RateLimitBackend::Postgres => Arc::new(PgStore { db: db.clone() }),
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+  windows: Mutex<Windows>,
+}
+
+#[derive(Debug, Default)]
+struct Windows {
+  hits: HashMap<String, (i64, u64)>,
+  opened: VecDeque<(String, i64)>,
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryStore {
+  async fn hit(&self, key: &str, window_end: i64) -> Result<u64, ApiError> {
+    let mut windows = self.windows.lock().unwrap_or_else(|e| e.into_inner());
+    let Windows { hits, opened } = &mut *windows;
+
+    let (end, count) = hits.entry(key.to_string()).or_insert((0, 0));
+    if *end != window_end {
+      (*end, *count) = (window_end, 0);
+      opened.push_back((key.to_string(), window_end));
+    }
+    *count += 1;
+    let count = *count;
+
+    // every counter has its window in the queue, so capping the queue caps the counters
+    while opened.len() > RATE_LIMIT_MEMORY_MAX_KEYS {
+      let Some((key, end)) = opened.pop_front() else {
+        break;
+      };
+
+      // the key may have opened a newer window since
+      if hits.get(&key).is_some_and(|(current, _)| *current == end) {
+        hits.remove(&key);
+      }
+    }
+
+    Ok(count)
+  }
+}
+
+#[derive(Debug)]
+pub struct PgStore {
+  db: PgPool,
+}
+
+#[async_trait]
+impl RateLimitStore for PgStore {
+  async fn hit(&self, key: &str, window_end: i64) -> Result<u64, ApiError> {
+    let (hits,): (i32,) = sqlx::query_as(
+      "INSERT INTO rate_limits (key, window_end, hits) VALUES ($1, TO_TIMESTAMP($2), 1)
+      ON CONFLICT (key) DO UPDATE SET
+      hits = CASE WHEN rate_limits.window_end = EXCLUDED.window_end THEN rate_limits.hits + 1 ELSE 1 END,
+      window_end = EXCLUDED.window_end
+      RETURNING hits")
+      .bind(key)
+      .bind(window_end as f64)
+      .fetch_one(&self.db)
+      .await?;
+
+    Ok(hits as u64)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn counts_reset_with_the_window() {
+    let store = MemoryStore::default();
+    assert_eq!(store.hit("a", 60).await.unwrap(), 1);
+    assert_eq!(store.hit("a", 60).await.unwrap(), 2);
+    assert_eq!(store.hit("b", 60).await.unwrap(), 1);
+    assert_eq!(store.hit("a", 120).await.unwrap(), 1);
+  }
+
+  #[tokio::test]
+  async fn eviction_keeps_windows_opened_since() {
+    let store = MemoryStore::default();
+    store.hit("a", 60).await.unwrap();
+    store.hit("a", 120).await.unwrap();
+    store.hit("a", 120).await.unwrap();
+
+    // pushes out the first window of "a" but not the one it's counting in now
+    for i in 0..RATE_LIMIT_MEMORY_MAX_KEYS - 1 {
+      store.hit(&i.to_string(), 60).await.unwrap();
+    }
+    assert_eq!(store.hit("a", 120).await.unwrap(), 3);
+
+    store.hit("last", 60).await.unwrap();
+    assert_eq!(store.hit("a", 120).await.unwrap(), 1);
+    assert!(store.windows.lock().unwrap().hits.len() <= RATE_LIMIT_MEMORY_MAX_KEYS);
+  }
+}
@@ -1,10 +1,10 @@
-use axum::{extract::{FromRef, FromRequestParts}, http::request::Parts};
+use axum::{extract::{FromRef, FromRequestParts}, http::{header::AUTHORIZATION, request::Parts}};
 use axum_extra::extract::{cookie::Key, PrivateCookieJar};
 use sqlx::{Pool, Postgres};
 use reqwest::Client as ReqwestClient;
 use serde::{Deserialize, Serialize};
 
-use crate::{env::Env, errors::ApiError};
+use crate::{assets::storage::{build_storage, Storage}, constants::SESSION_REFRESH_GRACE_TIME, env::Env, errors::ApiError, middlewares::token_refresh::RefreshLocks, rate_limit::{build_rate_limiter, RateLimiter}, tokens::{hash_token, TokenScope, TOKEN_PREFIX}};
 
 #[derive(Debug, Clone)]
 pub struct RouterState {
@@ -12,6 +12,18 @@ pub struct RouterState {
   pub key: Key,
   pub ctx: ReqwestClient,
   pub env: Env,
+  pub refresh_locks: RefreshLocks,
+  pub storage: Storage,
+  pub rate_limiter: RateLimiter,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "user_role", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+  User,
+  Moderator,
+  Admin,
 }
 
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow, Clone)]
@@ -21,17 +33,99 @@ pub struct UserProfile {
   pub email: String,
   pub name: String,
   pub username: Option<String>,
+  pub default_license: String,
+  pub role: Role,
   pub created_at: chrono::DateTime<chrono::Utc>,
   pub updated_at: chrono::DateTime<chrono::Utc>,
+  #[serde(skip)]
+  #[sqlx(skip)]
+  pub scopes: Option<Vec<TokenScope>>,
+}
+
+impl UserProfile {
+  pub fn has_scope(&self, scope: TokenScope) -> bool {
+    self.scopes.as_ref().is_none_or(|scopes| scopes.contains(&scope))
+  }
+
+  pub fn require_scope(&self, scope: TokenScope) -> Result<(), ApiError> {
+    if !self.has_scope(scope) {
+      return Err(ApiError::Forbidden("token is missing a required scope".to_string()));
+    }
+
+    Ok(())
+  }
+
+  pub fn require_role(&self, role: Role) -> Result<(), ApiError> {
+    self.require_session()?;
+
+    if self.role < role {
+      return Err(ApiError::Forbidden(format!("this action requires the {} role", role.name())));
+    }
+
+    Ok(())
+  }
+
+  pub fn require_session(&self) -> Result<(), ApiError> {
+    if self.scopes.is_some() {
+      return Err(ApiError::Forbidden("this action requires a browser session".to_string()));
+    }
+
+    Ok(())
+  }
+}
+
+impl Role {
+  pub fn name(self) -> &'static str {
+    match self {
+      Self::User => "user",
+      Self::Moderator => "moderator",
+      Self::Admin => "admin",
+    }
+  }
+}
+
+pub struct Admin(pub UserProfile);
+
+pub struct Moderator(pub UserProfile);
+
+#[axum::async_trait]
+impl<S> FromRequestParts<S> for Admin
+where
+    RouterState: FromRef<S>,
+    S: Send + Sync,
+{
+  type Rejection = ApiError;
+  async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+    let profile = UserProfile::from_request_parts(parts, state).await?;
+    profile.require_role(Role::Admin)?;
+    Ok(Self(profile))
+  }
+}
+
+#[axum::async_trait]
+impl<S> FromRequestParts<S> for Moderator
+where
+    RouterState: FromRef<S>,
+    S: Send + Sync,
+{
+  type Rejection = ApiError;
+  async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+    let profile = UserProfile::from_request_parts(parts, state).await?;
+    profile.require_role(Role::Moderator)?;
+    Ok(Self(profile))
+  }
 }
 
 impl RouterState {
   pub fn new(db: Pool<Postgres>, env: &Env) -> Self {
     Self {
-      db,
       key: Key::generate(),
       ctx: ReqwestClient::new(),
       env: env.clone(),
+      refresh_locks: RefreshLocks::default(),
+      storage: build_storage(&env.assets.storage),
+      rate_limiter: build_rate_limiter(&env.rate_limits, &db),
+      db,
     }
   }
 }
@@ -51,19 +145,57 @@ where
   type Rejection = ApiError;
   async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
     let router_state = RouterState::from_ref(state).to_owned();
+
+    if let Some(token) = bearer_token(parts) {
+      return authenticate_token(&router_state, &token).await;
+    }
+
     let cookie_jar: PrivateCookieJar = PrivateCookieJar::from_request_parts(parts, &router_state).await?;
 
     let Some(cookie) = cookie_jar.get("sid").map(|cookie| cookie.value().to_owned()) else {
       return Err(ApiError::Unauthorized);
     };
 
+    // expired sessions stay usable for the grace period while the provider is unreachable
     let res = sqlx::query_as::<_, UserProfile>("
       SELECT users.* FROM sessions
-      LEFT JOIN users ON sessions.user_id = users.id
-      WHERE sessions.session_id = $1 LIMIT 1
-    ").bind(cookie).fetch_one(&router_state.db).await?;
+      INNER JOIN users ON sessions.user_id = users.id
+      WHERE sessions.session_id = $1 AND users.deleted = false AND users.suspended_at IS NULL
+      AND sessions.expires_at > NOW() - $2 * INTERVAL '1 second'
+      LIMIT 1
+    ").bind(cookie).bind(SESSION_REFRESH_GRACE_TIME).fetch_optional(&router_state.db).await?;
 
-    Ok(res)
+    res.ok_or(ApiError::Unauthorized)
   }
 }
 
+pub fn bearer_token(parts: &Parts) -> Option<String> {
+  let header = parts.headers.get(AUTHORIZATION)?.to_str().ok()?;
+  let token = header.strip_prefix("Bearer ")?.trim();
+
+  token.starts_with(TOKEN_PREFIX).then(|| token.to_owned())
+}
+
+async fn authenticate_token(router_state: &RouterState, token: &str) -> Result<UserProfile, ApiError> {
+  let entry: Option<(i32, Vec<TokenScope>)> = sqlx::query_as("
+    UPDATE personal_access_tokens SET last_used_at = NOW()
+    WHERE token_hash = $1 AND (expires_at IS NULL OR expires_at > NOW())
+    RETURNING user_id, scopes
+  ").bind(hash_token(token)).fetch_optional(&router_state.db).await?;
+
+  let Some((user_id, scopes)) = entry else {
+    return Err(ApiError::Unauthorized);
+  };
+
+  let profile = sqlx::query_as::<_, UserProfile>("SELECT * FROM users WHERE id = $1 AND deleted = false AND suspended_at IS NULL LIMIT 1")
+    .bind(user_id)
+    .fetch_optional(&router_state.db)
+    .await?;
+
+  let Some(mut profile) = profile else {
+    return Err(ApiError::Unauthorized);
+  };
+
+  profile.scopes = Some(scopes);
+  Ok(profile)
+}
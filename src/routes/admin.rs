@@ -0,0 +1,664 @@
+use axum::{extract::{Path, Query, State}, http::StatusCode, response::IntoResponse, routing::{delete, get, post, put}, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::uuid::Uuid, PgConnection, PgPool};
+
+use crate::{audit::{self, AuditAction, AuditEvent, AuditTarget, RequestMeta}, errors::{ApiError, FieldError}, includes::{is_library_name, MAX_LIBRARY_NAME_LENGTH}, jobs::JobStatus, router_state::{Admin, Role, RouterState, UserProfile}, routes::{featured::{featured_entry_query, FeaturedEntry, FeaturedQuery, FeaturedSchedule, NewFeaturedEntry}, moderation::{page, parse_user_id, take_action, ModerationActionKind, NewModerationAction}, shader::{Shader, ShaderData}, template::{delete_template_row, insert_template, update_template_row, NewTemplate, Template, UpdateTemplate, TEMPLATE_QUERY}}, validation::{ValidatedJson, Validate, Validator}};
+
+const MAX_REASON_LENGTH: usize = 2000;
+
+const ADMIN_USER_QUERY: &str = "SELECT users.user_id, users.email, users.name, users.username, users.role,
+  users.suspended_at, users.deletion_requested_at, users.created_at,
+  (SELECT COUNT(*) FROM shaders WHERE shaders.user_id = users.user_id AND shaders.deleted = false) AS shaders
+  FROM users";
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct AdminUser {
+  pub user_id: Uuid,
+  pub email: String,
+  pub name: String,
+  pub username: Option<String>,
+  pub role: Role,
+  pub suspended_at: Option<chrono::DateTime<chrono::Utc>>,
+  pub deletion_requested_at: Option<chrono::DateTime<chrono::Utc>>,
+  pub created_at: chrono::DateTime<chrono::Utc>,
+  pub shaders: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserSearchQuery {
+  pub q: Option<String>,
+  pub role: Option<Role>,
+  pub suspended: Option<bool>,
+  pub limit: Option<i64>,
+  pub offset: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserShadersQuery {
+  #[serde(default)]
+  pub trashed: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoleUpdate {
+  pub role: Role,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SuspensionChange {
+  pub reason: String,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct AuditEventView {
+  pub id: i64,
+  pub actor_id: Option<Uuid>,
+  pub actor_name: Option<String>,
+  pub action: String,
+  pub target_type: String,
+  pub target_id: String,
+  pub before: Option<serde_json::Value>,
+  pub after: Option<serde_json::Value>,
+  pub request_id: Option<String>,
+  pub ip_address: Option<String>,
+  pub user_agent: Option<String>,
+  pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+  pub actor: Option<String>,
+  pub action: Option<String>,
+  pub target_type: Option<String>,
+  pub target_id: Option<String>,
+  pub request_id: Option<String>,
+  pub since: Option<chrono::DateTime<chrono::Utc>>,
+  pub until: Option<chrono::DateTime<chrono::Utc>>,
+  pub limit: Option<i64>,
+  pub offset: Option<i64>,
+}
+
+const JOB_QUERY: &str = "SELECT id, kind, payload, status, attempts, max_attempts, run_at, locked_by,
+  last_error, created_at, finished_at FROM jobs";
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct JobView {
+  pub id: i64,
+  pub kind: String,
+  pub payload: serde_json::Value,
+  pub status: JobStatus,
+  pub attempts: i32,
+  pub max_attempts: i32,
+  pub run_at: chrono::DateTime<chrono::Utc>,
+  pub locked_by: Option<String>,
+  pub last_error: Option<String>,
+  pub created_at: chrono::DateTime<chrono::Utc>,
+  pub finished_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JobQuery {
+  pub status: Option<JobStatus>,
+  pub kind: Option<String>,
+  pub limit: Option<i64>,
+  pub offset: Option<i64>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct SystemStats {
+  pub users: i64,
+  pub suspended_users: i64,
+  pub pending_deletions: i64,
+  pub moderators: i64,
+  pub admins: i64,
+  pub shaders: i64,
+  pub public_shaders: i64,
+  pub unlisted_shaders: i64,
+  pub private_shaders: i64,
+  pub trashed_shaders: i64,
+  pub hidden_shaders: i64,
+  pub libraries: i64,
+  pub assets: i64,
+  pub asset_bytes: i64,
+  pub open_reports: i64,
+  pub active_sessions: i64,
+  // left for the session sweeper, expired sessions with a live refresh token are kept on purpose
+  pub expired_sessions: i64,
+  pub expired_refresh_tokens: i64,
+  pub access_tokens: i64,
+  pub queued_jobs: i64,
+  pub dead_jobs: i64,
+}
+
+impl Validate for SuspensionChange {
+  fn validate(&self, v: &mut Validator) {
+    v.length("reason", self.reason.trim(), 1, MAX_REASON_LENGTH);
+  }
+}
+
+// only an identity that verified the address counts, anyone can sign up claiming it
+pub async fn bootstrap_admins(db: &PgPool, emails: &[String]) -> Result<u64, ApiError> {
+  if emails.is_empty() {
+    return Ok(0);
+  }
+
+  let result = sqlx::query("UPDATE users SET role = 'admin' WHERE role <> 'admin' AND deleted = false
+    AND EXISTS (
+      SELECT 1 FROM user_identities
+      WHERE user_identities.user_id = users.id AND user_identities.email_verified AND LOWER(user_identities.email) = ANY($1)
+    )")
+    .bind(emails)
+    .execute(db)
+    .await?;
+
+  Ok(result.rows_affected())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewBuiltinTemplate {
+  pub id: String,
+  #[serde(flatten)]
+  pub template: NewTemplate,
+}
+
+fn validate_builtin_data(v: &mut Validator, data: &ShaderData) {
+  // assets belong to a single user, nobody else could use them
+  v.check(
+    "data",
+    data.channel_bindings().all(|(_, binding)| binding.asset_id().is_none()),
+    "built-in templates can't bind assets",
+  );
+}
+
+impl Validate for NewBuiltinTemplate {
+  fn validate(&self, v: &mut Validator) {
+    v.check(
+      "id",
+      is_library_name(&self.id),
+      format!("must be 1 to {} lowercase letters, digits, dashes or underscores", MAX_LIBRARY_NAME_LENGTH),
+    );
+    self.template.validate(v);
+    validate_builtin_data(v, &self.template.data);
+  }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(transparent)]
+pub struct BuiltinTemplateUpdate(UpdateTemplate);
+
+impl Validate for BuiltinTemplateUpdate {
+  fn validate(&self, v: &mut Validator) {
+    self.0.validate(v);
+
+    if let Some(data) = &self.0.data {
+      validate_builtin_data(v, data);
+    }
+  }
+}
+
+pub async fn get_builtin_templates(
+  _admin: Admin,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  let templates: Vec<Template> = sqlx::query_as(&format!("{} WHERE user_id IS NULL ORDER BY position, created_at", TEMPLATE_QUERY))
+    .fetch_all(&router_state.db)
+    .await?;
+
+  Ok(Json(templates))
+}
+
+pub async fn create_builtin_template(
+  _admin: Admin,
+  State(router_state): State<RouterState>,
+  ValidatedJson(new_template): ValidatedJson<NewBuiltinTemplate>,
+) -> Result<impl IntoResponse, ApiError> {
+  let template = insert_template(&router_state.db, &new_template.id, None, new_template.template).await?;
+  Ok((StatusCode::CREATED, Json(template)))
+}
+
+pub async fn update_builtin_template(
+  Path(id): Path<String>,
+  _admin: Admin,
+  State(router_state): State<RouterState>,
+  ValidatedJson(update): ValidatedJson<BuiltinTemplateUpdate>,
+) -> Result<impl IntoResponse, ApiError> {
+  update_template_row(&router_state.db, &id, None, update.0).await
+    .map(Json)
+}
+
+pub async fn delete_builtin_template(
+  Path(id): Path<String>,
+  _admin: Admin,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  delete_template_row(&router_state.db, &id, None).await?;
+  Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn search_users(
+  Query(query): Query<UserSearchQuery>,
+  _admin: Admin,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  let (limit, offset) = page(query.limit, query.offset);
+  let pattern = query.q.as_deref()
+    .map(str::trim)
+    .filter(|q| !q.is_empty())
+    .map(|q| format!("%{}%", q.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")));
+
+  let users: Vec<AdminUser> = sqlx::query_as(&format!(
+    "{} WHERE users.deleted = false
+    AND ($1::TEXT IS NULL OR users.email ILIKE $1 OR users.name ILIKE $1 OR users.username ILIKE $1)
+    AND ($2::user_role IS NULL OR users.role = $2)
+    AND ($3::BOOLEAN IS NULL OR (users.suspended_at IS NOT NULL) = $3)
+    ORDER BY users.created_at DESC, users.id DESC LIMIT $4 OFFSET $5", ADMIN_USER_QUERY,
+  ))
+    .bind(pattern)
+    .bind(query.role)
+    .bind(query.suspended)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(&router_state.db)
+    .await?;
+
+  Ok(Json(users))
+}
+
+async fn find_admin_user(db: &PgPool, user_id: Uuid) -> Result<AdminUser, ApiError> {
+  let user: Option<AdminUser> = sqlx::query_as(&format!("{} WHERE users.user_id = $1 AND users.deleted = false", ADMIN_USER_QUERY))
+    .bind(user_id)
+    .fetch_optional(db)
+    .await?;
+
+  user.ok_or_else(|| ApiError::NotFound("user not found".to_string()))
+}
+
+pub async fn get_user_shaders(
+  Path(user_id): Path<String>,
+  Query(query): Query<UserShadersQuery>,
+  meta: RequestMeta,
+  Admin(admin): Admin,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  let user_id = parse_user_id("user_id", &user_id)?;
+  find_admin_user(&router_state.db, user_id).await?;
+
+  let mut tx = router_state.db.begin().await?;
+
+  let shaders: Vec<Shader> = sqlx::query_as("SELECT * FROM shaders WHERE user_id = $1 AND deleted = $2 ORDER BY created_at")
+    .bind(user_id)
+    .bind(query.trashed)
+    .fetch_all(&mut *tx)
+    .await?;
+
+  audit::record(&mut *tx, &meta, AuditEvent::new(Some(admin.id), AuditAction::ViewUserShaders, AuditTarget::User, user_id.to_string())
+    .after(serde_json::json!({
+      "trashed": query.trashed,
+      "shaders": shaders.iter().map(|shader| shader.id.as_str()).collect::<Vec<_>>(),
+    }))).await?;
+
+  tx.commit().await?;
+
+  Ok(Json(shaders))
+}
+
+pub async fn update_user_role(
+  Path(user_id): Path<String>,
+  meta: RequestMeta,
+  Admin(admin): Admin,
+  State(router_state): State<RouterState>,
+  Json(update): Json<RoleUpdate>,
+) -> Result<impl IntoResponse, ApiError> {
+  let user_id = parse_user_id("user_id", &user_id)?;
+
+  if user_id == admin.user_id {
+    return Err(ApiError::Validation(vec![FieldError::new("user_id", "you can't change your own role")]));
+  }
+
+  let mut tx = router_state.db.begin().await?;
+
+  let previous: Option<(Role,)> = sqlx::query_as(
+    "SELECT role FROM users WHERE user_id = $1 AND deleted = false FOR UPDATE")
+    .bind(user_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+  let Some((previous,)) = previous else {
+    return Err(ApiError::NotFound("user not found".to_string()));
+  };
+
+  sqlx::query("UPDATE users SET role = $2 WHERE user_id = $1")
+    .bind(user_id)
+    .bind(update.role)
+    .execute(&mut *tx)
+    .await?;
+
+  audit::record(&mut *tx, &meta, AuditEvent::new(Some(admin.id), AuditAction::UserRoleChange, AuditTarget::User, user_id.to_string())
+    .before(serde_json::json!({ "role": previous }))
+    .after(serde_json::json!({ "role": update.role }))).await?;
+
+  tx.commit().await?;
+
+  find_admin_user(&router_state.db, user_id).await
+    .map(Json)
+}
+
+async fn change_suspension(
+  router_state: &RouterState,
+  meta: &RequestMeta,
+  admin: &UserProfile,
+  action: ModerationActionKind,
+  user_id: &str,
+  reason: String,
+) -> Result<AdminUser, ApiError> {
+  let user_id = parse_user_id("user_id", user_id)?;
+
+  let mut tx = router_state.db.begin().await?;
+  take_action(&mut tx, meta, admin, NewModerationAction {
+    action,
+    target_id: Some(user_id.to_string()),
+    report_id: None,
+    reason,
+  }).await?;
+  tx.commit().await?;
+
+  find_admin_user(&router_state.db, user_id).await
+}
+
+pub async fn suspend_user(
+  Path(user_id): Path<String>,
+  meta: RequestMeta,
+  Admin(admin): Admin,
+  State(router_state): State<RouterState>,
+  ValidatedJson(change): ValidatedJson<SuspensionChange>,
+) -> Result<impl IntoResponse, ApiError> {
+  change_suspension(&router_state, &meta, &admin, ModerationActionKind::SuspendUser, &user_id, change.reason).await
+    .map(Json)
+}
+
+pub async fn unsuspend_user(
+  Path(user_id): Path<String>,
+  meta: RequestMeta,
+  Admin(admin): Admin,
+  State(router_state): State<RouterState>,
+  ValidatedJson(change): ValidatedJson<SuspensionChange>,
+) -> Result<impl IntoResponse, ApiError> {
+  change_suspension(&router_state, &meta, &admin, ModerationActionKind::UnsuspendUser, &user_id, change.reason).await
+    .map(Json)
+}
+
+pub async fn get_featured_entries(
+  Query(query): Query<FeaturedQuery>,
+  _admin: Admin,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  let entries: Vec<FeaturedEntry> = sqlx::query_as(&format!(
+    "{} WHERE ($1::TEXT IS NULL OR featured_shaders.slot = $1)
+    ORDER BY featured_shaders.slot, featured_shaders.position, featured_shaders.starts_at", featured_entry_query(),
+  ))
+    .bind(query.slot)
+    .fetch_all(&router_state.db)
+    .await?;
+
+  Ok(Json(entries))
+}
+
+async fn find_featured_entry(conn: &mut PgConnection, id: i32) -> Result<FeaturedEntry, ApiError> {
+  let entry: Option<FeaturedEntry> = sqlx::query_as(&format!("{} WHERE featured_shaders.id = $1", featured_entry_query()))
+    .bind(id)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+  entry.ok_or_else(|| ApiError::NotFound("featured entry not found".to_string()))
+}
+
+pub async fn feature_shader(
+  meta: RequestMeta,
+  Admin(admin): Admin,
+  State(router_state): State<RouterState>,
+  ValidatedJson(new_entry): ValidatedJson<NewFeaturedEntry>,
+) -> Result<impl IntoResponse, ApiError> {
+  let mut tx = router_state.db.begin().await?;
+
+  let shader: Option<(String,)> = sqlx::query_as(
+    "SELECT id FROM shaders WHERE id = $1 AND deleted = false AND access = 'public' AND hidden = false")
+    .bind(&new_entry.shader_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+  if shader.is_none() {
+    return Err(ApiError::NotFound("shader not found".to_string()));
+  }
+
+  let schedule = &new_entry.schedule;
+  let (id,): (i32,) = sqlx::query_as(
+    "INSERT INTO featured_shaders (slot, shader_id, position, starts_at, ends_at, featured_by)
+    VALUES ($1, $2, $3, COALESCE($4, NOW()), $5, $6) RETURNING id")
+    .bind(&schedule.slot)
+    .bind(&new_entry.shader_id)
+    .bind(schedule.position)
+    .bind(schedule.starts_at)
+    .bind(schedule.ends_at)
+    .bind(admin.id)
+    .fetch_one(&mut *tx)
+    .await?;
+
+  let entry = find_featured_entry(&mut tx, id).await?;
+
+  audit::record(&mut *tx, &meta, AuditEvent::new(Some(admin.id), AuditAction::FeatureShader, AuditTarget::Shader, &entry.shader_id)
+    .after(entry.schedule_snapshot())).await?;
+
+  tx.commit().await?;
+
+  Ok((StatusCode::CREATED, Json(entry)))
+}
+
+pub async fn update_featured_entry(
+  Path(id): Path<i32>,
+  meta: RequestMeta,
+  Admin(admin): Admin,
+  State(router_state): State<RouterState>,
+  ValidatedJson(schedule): ValidatedJson<FeaturedSchedule>,
+) -> Result<impl IntoResponse, ApiError> {
+  let mut tx = router_state.db.begin().await?;
+
+  let previous = find_featured_entry(&mut tx, id).await?;
+
+  let starts_at = schedule.starts_at.unwrap_or(previous.starts_at);
+  if schedule.ends_at.is_some_and(|ends_at| ends_at <= starts_at) {
+    return Err(ApiError::Validation(vec![FieldError::new("ends_at", "must be after starts_at")]));
+  }
+
+  sqlx::query(
+    "UPDATE featured_shaders SET slot = $2, position = $3, starts_at = $4, ends_at = $5
+    WHERE id = $1")
+    .bind(id)
+    .bind(&schedule.slot)
+    .bind(schedule.position)
+    .bind(starts_at)
+    .bind(schedule.ends_at)
+    .execute(&mut *tx)
+    .await?;
+
+  let entry = find_featured_entry(&mut tx, id).await?;
+
+  audit::record(&mut *tx, &meta, AuditEvent::new(Some(admin.id), AuditAction::UpdateFeatured, AuditTarget::Shader, &entry.shader_id)
+    .changes(&previous.schedule_snapshot(), &entry.schedule_snapshot())).await?;
+
+  tx.commit().await?;
+
+  Ok(Json(entry))
+}
+
+pub async fn unfeature_shader(
+  Path(id): Path<i32>,
+  meta: RequestMeta,
+  Admin(admin): Admin,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  let mut tx = router_state.db.begin().await?;
+
+  let entry = find_featured_entry(&mut tx, id).await?;
+
+  sqlx::query("DELETE FROM featured_shaders WHERE id = $1")
+    .bind(id)
+    .execute(&mut *tx)
+    .await?;
+
+  audit::record(&mut *tx, &meta, AuditEvent::new(Some(admin.id), AuditAction::UnfeatureShader, AuditTarget::Shader, &entry.shader_id)
+    .before(entry.schedule_snapshot())).await?;
+
+  tx.commit().await?;
+
+  Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_audit_events(
+  Query(query): Query<AuditQuery>,
+  _admin: Admin,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  let (limit, offset) = page(query.limit, query.offset);
+  let actor = query.actor.as_deref()
+    .map(|actor| parse_user_id("actor", actor))
+    .transpose()?;
+
+  let events: Vec<AuditEventView> = sqlx::query_as(
+    "SELECT audit_events.id, users.user_id AS actor_id, COALESCE(users.username, users.name) AS actor_name,
+    audit_events.action, audit_events.target_type, audit_events.target_id, audit_events.before, audit_events.after,
+    audit_events.request_id, HOST(audit_events.ip_address) AS ip_address, audit_events.user_agent, audit_events.created_at
+    FROM audit_events
+    LEFT JOIN users ON users.id = audit_events.actor_id
+    WHERE ($1::UUID IS NULL OR users.user_id = $1)
+    AND ($2::TEXT IS NULL OR audit_events.action = $2)
+    AND ($3::TEXT IS NULL OR audit_events.target_type = $3)
+    AND ($4::TEXT IS NULL OR audit_events.target_id = $4)
+    AND ($5::TEXT IS NULL OR audit_events.request_id = $5)
+    AND ($6::TIMESTAMPTZ IS NULL OR audit_events.created_at >= $6)
+    AND ($7::TIMESTAMPTZ IS NULL OR audit_events.created_at < $7)
+    ORDER BY audit_events.created_at DESC, audit_events.id DESC LIMIT $8 OFFSET $9")
+    .bind(actor)
+    .bind(query.action)
+    .bind(query.target_type)
+    .bind(query.target_id)
+    .bind(query.request_id)
+    .bind(query.since)
+    .bind(query.until)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(&router_state.db)
+    .await?;
+
+  Ok(Json(events))
+}
+
+pub async fn get_jobs(
+  Query(query): Query<JobQuery>,
+  _admin: Admin,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  let (limit, offset) = page(query.limit, query.offset);
+
+  let jobs: Vec<JobView> = sqlx::query_as(&format!(
+    "{} WHERE ($1::job_status IS NULL OR status = $1) AND ($2::TEXT IS NULL OR kind = $2)
+    ORDER BY created_at DESC, id DESC LIMIT $3 OFFSET $4", JOB_QUERY,
+  ))
+    .bind(query.status)
+    .bind(query.kind)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(&router_state.db)
+    .await?;
+
+  Ok(Json(jobs))
+}
+
+pub async fn retry_job(
+  Path(id): Path<i64>,
+  meta: RequestMeta,
+  Admin(admin): Admin,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  let mut tx = router_state.db.begin().await?;
+
+  let job: Option<JobView> = sqlx::query_as(&format!("{} WHERE id = $1 FOR UPDATE", JOB_QUERY))
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+  let Some(job) = job else {
+    return Err(ApiError::NotFound("job not found".to_string()));
+  };
+
+  if job.status != JobStatus::Dead {
+    return Err(ApiError::Conflict("only dead jobs can be retried".to_string()));
+  }
+
+  let retried: JobView = sqlx::query_as(
+    "UPDATE jobs SET status = 'queued', attempts = 0, run_at = NOW(), finished_at = NULL WHERE id = $1
+    RETURNING id, kind, payload, status, attempts, max_attempts, run_at, locked_by, last_error, created_at, finished_at")
+    .bind(id)
+    .fetch_one(&mut *tx)
+    .await?;
+
+  audit::record(&mut *tx, &meta, AuditEvent::new(Some(admin.id), AuditAction::RetryJob, AuditTarget::Job, id.to_string())
+    .before(serde_json::json!({ "kind": job.kind, "attempts": job.attempts, "last_error": job.last_error }))).await?;
+
+  tx.commit().await?;
+
+  Ok(Json(retried))
+}
+
+pub async fn get_stats(
+  _admin: Admin,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  let stats: SystemStats = sqlx::query_as(
+    "SELECT
+      (SELECT COUNT(*) FROM users WHERE deleted = false) AS users,
+      (SELECT COUNT(*) FROM users WHERE deleted = false AND suspended_at IS NOT NULL) AS suspended_users,
+      (SELECT COUNT(*) FROM users WHERE deleted = false AND deletion_requested_at IS NOT NULL) AS pending_deletions,
+      (SELECT COUNT(*) FROM users WHERE deleted = false AND role = 'moderator') AS moderators,
+      (SELECT COUNT(*) FROM users WHERE deleted = false AND role = 'admin') AS admins,
+      (SELECT COUNT(*) FROM shaders WHERE deleted = false) AS shaders,
+      (SELECT COUNT(*) FROM shaders WHERE deleted = false AND access = 'public') AS public_shaders,
+      (SELECT COUNT(*) FROM shaders WHERE deleted = false AND access = 'unlisted') AS unlisted_shaders,
+      (SELECT COUNT(*) FROM shaders WHERE deleted = false AND access = 'private') AS private_shaders,
+      (SELECT COUNT(*) FROM shaders WHERE deleted = true) AS trashed_shaders,
+      (SELECT COUNT(*) FROM shaders WHERE deleted = false AND hidden = true) AS hidden_shaders,
+      (SELECT COUNT(*) FROM shader_libraries) AS libraries,
+      (SELECT COUNT(*) FROM assets) AS assets,
+      (SELECT COALESCE(SUM(size), 0)::BIGINT FROM asset_blobs) AS asset_bytes,
+      (SELECT COUNT(*) FROM reports WHERE status = 'open') AS open_reports,
+      (SELECT COUNT(*) FROM sessions WHERE expires_at > NOW()) AS active_sessions,
+      (SELECT COUNT(*) FROM sessions WHERE expires_at <= NOW()) AS expired_sessions,
+      (SELECT COUNT(*) FROM refresh_tokens WHERE expires_at < NOW()) AS expired_refresh_tokens,
+      (SELECT COUNT(*) FROM personal_access_tokens WHERE expires_at IS NULL OR expires_at > NOW()) AS access_tokens,
+      (SELECT COUNT(*) FROM jobs WHERE status = 'queued') AS queued_jobs,
+      (SELECT COUNT(*) FROM jobs WHERE status = 'dead') AS dead_jobs")
+    .fetch_one(&router_state.db)
+    .await?;
+
+  Ok(Json(stats))
+}
+
+pub fn build_admin_router() -> axum::Router<RouterState> {
+  axum::Router::new()
+    .route("/users", get(search_users))
+    .route("/users/:user_id/shaders", get(get_user_shaders))
+    .route("/users/:user_id/role", put(update_user_role))
+    .route("/users/:user_id/suspend", post(suspend_user))
+    .route("/users/:user_id/unsuspend", post(unsuspend_user))
+    .route("/featured", get(get_featured_entries))
+    .route("/featured", post(feature_shader))
+    .route("/featured/:id", put(update_featured_entry))
+    .route("/featured/:id", delete(unfeature_shader))
+    .route("/audit", get(get_audit_events))
+    .route("/stats", get(get_stats))
+    .route("/jobs", get(get_jobs))
+    .route("/jobs/:id/retry", post(retry_job))
+    .route("/templates", get(get_builtin_templates))
+    .route("/templates", post(create_builtin_template))
+    .route("/templates/:id", put(update_builtin_template))
+    .route("/templates/:id", delete(delete_builtin_template))
+}
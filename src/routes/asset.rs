@@ -0,0 +1,159 @@
+use axum::{extract::{DefaultBodyLimit, Multipart, Path, State}, http::{header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH, X_CONTENT_TYPE_OPTIONS}, HeaderMap, StatusCode}, response::IntoResponse, routing::{delete, get, post}, Json};
+
+use crate::{assets::{blob_key, find_asset, release_blob, store_asset, Asset, ASSET_QUERY}, env::Env, errors::{ApiError, FieldError}, router_state::{RouterState, UserProfile}, tokens::TokenScope};
+
+pub const MAX_ASSET_NAME_LENGTH: usize = 255;
+const DEFAULT_ASSET_NAME: &str = "untitled";
+// room for the multipart boundaries and the other fields next to the file
+const MULTIPART_OVERHEAD_BYTES: usize = 64 * 1024;
+
+pub async fn upload_asset(
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+  mut multipart: Multipart,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersWrite)?;
+
+  let max_bytes = router_state.env.assets.max_upload_bytes;
+  let mut file: Option<(Option<String>, Vec<u8>)> = None;
+  let mut name: Option<String> = None;
+
+  while let Some(mut field) = multipart.next_field().await? {
+    match field.name() {
+      Some("file") => {
+        let file_name = field.file_name().map(|name| name.to_owned());
+        let mut data = Vec::new();
+
+        while let Some(chunk) = field.chunk().await? {
+          if data.len() + chunk.len() > max_bytes {
+            return Err(ApiError::PayloadTooLarge(format!("assets can be at most {} bytes", max_bytes)));
+          }
+
+          data.extend_from_slice(&chunk);
+        }
+
+        file = Some((file_name, data));
+      },
+      Some("name") => name = Some(field.text().await?),
+      _ => (),
+    }
+  }
+
+  let Some((file_name, data)) = file else {
+    return Err(ApiError::Validation(vec![FieldError::new("file", "is required")]));
+  };
+
+  let name = name.or(file_name).unwrap_or(DEFAULT_ASSET_NAME.to_string());
+  let name = name.trim();
+
+  if name.is_empty() || name.chars().count() > MAX_ASSET_NAME_LENGTH {
+    return Err(ApiError::Validation(vec![
+      FieldError::new("name", format!("must be between 1 and {} characters long", MAX_ASSET_NAME_LENGTH)),
+    ]));
+  }
+
+  let (asset, created) = store_asset(&router_state, &profile, name, data).await?;
+  let status = if created { StatusCode::CREATED } else { StatusCode::OK };
+
+  Ok((status, Json(asset)))
+}
+
+pub async fn get_my_assets(
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersRead)?;
+
+  let assets: Vec<Asset> = sqlx::query_as(&format!("{} WHERE assets.user_id = $1 ORDER BY assets.created_at DESC", ASSET_QUERY))
+    .bind(profile.id)
+    .fetch_all(&router_state.db)
+    .await?;
+
+  Ok(Json(assets))
+}
+
+pub async fn get_asset_content(
+  Path(id): Path<String>,
+  headers: HeaderMap,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  let asset = find_asset(&router_state, &id).await?
+    .ok_or_else(|| ApiError::NotFound("asset not found".to_string()))?;
+
+  let etag = format!("\"{}\"", asset.hash);
+  let cache_headers = [
+    (ETAG, etag.clone()),
+    (CACHE_CONTROL, "public, max-age=31536000, immutable".to_string()),
+  ];
+
+  let not_modified = headers.get(IF_NONE_MATCH)
+    .and_then(|value| value.to_str().ok())
+    .is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag));
+
+  if not_modified {
+    return Ok((StatusCode::NOT_MODIFIED, cache_headers).into_response());
+  }
+
+  let data = router_state.storage.get(&blob_key(&asset.hash)).await
+    .map_err(ApiError::Storage)?
+    .ok_or_else(|| ApiError::Storage(anyhow::anyhow!("blob {} of asset {} is missing", asset.hash, asset.id)))?;
+
+  Ok((
+    cache_headers,
+    [
+      (CONTENT_TYPE, asset.content_type),
+      (X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+    ],
+    data,
+  ).into_response())
+}
+
+pub async fn delete_asset(
+  Path(id): Path<String>,
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersWrite)?;
+
+  let mut tx = router_state.db.begin().await?;
+
+  let hash: Option<(String,)> = sqlx::query_as("DELETE FROM assets WHERE id = $1 AND user_id = $2 RETURNING blob_hash")
+    .bind(&id)
+    .bind(profile.id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+  let Some((hash,)) = hash else {
+    return Err(ApiError::NotFound("asset not found".to_string()));
+  };
+
+  // trashed shaders count too, restoring them shouldn't bring back broken bindings
+  let used_by: Option<(String,)> = sqlx::query_as(
+    "SELECT id FROM shaders WHERE user_id = $1
+    AND jsonb_path_exists(data, '$.**.asset_id ? (@ == $id)', jsonb_build_object('id', $2::TEXT))
+    LIMIT 1")
+    .bind(profile.user_id)
+    .bind(&id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+  if let Some((shader,)) = used_by {
+    return Err(ApiError::Conflict(format!("asset is still used by shader {}", shader)));
+  }
+
+  release_blob(&mut tx, &hash).await?;
+  tx.commit().await?;
+
+  Ok(StatusCode::NO_CONTENT)
+}
+
+pub fn build_asset_router(env: &Env) -> axum::Router<RouterState> {
+  axum::Router::new()
+    .route(
+      "/",
+      post(upload_asset).layer(DefaultBodyLimit::max(env.assets.max_upload_bytes + MULTIPART_OVERHEAD_BYTES)),
+    )
+    .route("/my", get(get_my_assets))
+    .route("/:id", get(get_asset_content))
+    .route("/:id", delete(delete_asset))
+}
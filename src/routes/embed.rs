@@ -0,0 +1,348 @@
+use axum::{extract::{Path, Query, State}, http::header::{CACHE_CONTROL, CONTENT_SECURITY_POLICY, CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS}, response::IntoResponse, routing::get, Json};
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use sqlx::types::uuid::Uuid;
+
+use crate::{assets::thumbnail::Thumbnails, embed::{escape_html, frame_ancestors, normalize_origin, render_player, render_share, PlayerPage, SharePage, SharedImage, DEFAULT_HEIGHT, DEFAULT_WIDTH, MAX_EMBED_ORIGINS, PROVIDER_NAME}, env::Env, errors::ApiError, includes::resolve_shader, router_state::{RouterState, UserProfile}, routes::shader::{AccessLevel, ShaderData}, tokens::TokenScope, validation::{ValidatedJson, Validate, Validator}};
+
+const NONCE_LENGTH: usize = 22;
+// settings changes should reach embeds reasonably fast
+const EMBED_CACHE_CONTROL: &str = "public, max-age=300";
+
+#[derive(Debug, sqlx::FromRow)]
+struct EmbeddableShader {
+  id: String,
+  name: String,
+  description: String,
+  user_id: Uuid,
+  access: AccessLevel,
+  author: String,
+  data: sqlx::types::Json<ShaderData>,
+  thumbnails: Option<sqlx::types::Json<Thumbnails>>,
+  embed_enabled: bool,
+  embed_origins: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct EmbedDocument<'a> {
+  id: &'a str,
+  name: &'a str,
+  #[serde(flatten)]
+  data: &'a ShaderData,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OEmbedQuery {
+  pub url: String,
+  pub maxwidth: Option<u32>,
+  pub maxheight: Option<u32>,
+  pub format: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OEmbed {
+  pub version: &'static str,
+  #[serde(rename = "type")]
+  pub kind: &'static str,
+  pub provider_name: &'static str,
+  pub provider_url: String,
+  pub title: String,
+  pub author_name: String,
+  pub html: String,
+  pub width: u32,
+  pub height: u32,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub thumbnail_url: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub thumbnail_width: Option<u32>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub thumbnail_height: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateEmbedSettings {
+  pub enabled: Option<bool>,
+  pub allowed_origins: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct EmbedSettings {
+  #[sqlx(rename = "embed_enabled")]
+  pub enabled: bool,
+  #[sqlx(rename = "embed_origins")]
+  pub allowed_origins: Vec<String>,
+}
+
+impl Validate for UpdateEmbedSettings {
+  fn validate(&self, v: &mut Validator) {
+    let Some(origins) = &self.allowed_origins else {
+      return;
+    };
+
+    v.check(
+      "allowed_origins",
+      origins.len() <= MAX_EMBED_ORIGINS,
+      format!("can have at most {} origins", MAX_EMBED_ORIGINS),
+    );
+
+    for (i, origin) in origins.iter().enumerate() {
+      v.check(
+        &format!("allowed_origins[{}]", i),
+        normalize_origin(origin).is_some(),
+        "must be an origin like https://example.com or https://*.example.com",
+      );
+    }
+  }
+}
+
+fn embed_url(env: &Env, id: &str) -> String {
+  format!("{}/embed/{}", env.public_url, id)
+}
+
+fn share_url(env: &Env, id: &str) -> String {
+  format!("{}/share/{}", env.public_url, id)
+}
+
+fn shader_link(env: &Env, id: &str) -> String {
+  format!("{}/shader/{}", env.frontend_url, id)
+}
+
+fn shader_id_from_url(env: &Env, url: &str) -> Option<String> {
+  let prefixes = [
+    format!("{}/share/", env.public_url),
+    format!("{}/embed/", env.public_url),
+    format!("{}/shader/", env.frontend_url),
+  ];
+
+  let rest = prefixes.iter().find_map(|prefix| url.strip_prefix(prefix.as_str()))?;
+  let id = rest.split(['/', '?', '#']).next()?;
+
+  (!id.is_empty()).then(|| id.to_string())
+}
+
+async fn find_embeddable_shader(router_state: &RouterState, id: &str) -> Result<EmbeddableShader, ApiError> {
+  let shader: Option<EmbeddableShader> = sqlx::query_as(
+    "SELECT shaders.id, shaders.name, shaders.description, shaders.user_id, shaders.access,
+    COALESCE(users.username, users.name, '') AS author, shaders.data, shaders.thumbnails,
+    shaders.embed_enabled, shaders.embed_origins
+    FROM shaders INNER JOIN users ON users.user_id = shaders.user_id
+    WHERE shaders.id = $1 AND shaders.deleted = false AND shaders.access <> 'private' AND shaders.hidden = false
+    AND users.deleted = false")
+    .bind(id)
+    .fetch_optional(&router_state.db)
+    .await?;
+
+  shader.ok_or_else(|| ApiError::NotFound("shader not found".to_string()))
+}
+
+fn large_thumbnail(env: &Env, shader: &EmbeddableShader) -> Option<(String, u32, u32)> {
+  let thumbnails = shader.thumbnails.as_ref()?;
+  let image = thumbnails.images.iter().find(|image| image.size == "large")?;
+
+  Some((format!("{}{}", env.public_url, image.url), image.width, image.height))
+}
+
+fn fit_player(max_width: Option<u32>, max_height: Option<u32>) -> (u32, u32) {
+  let mut width = DEFAULT_WIDTH;
+  let mut height = DEFAULT_HEIGHT;
+
+  if let Some(max_width) = max_width.filter(|max| *max < width) {
+    width = max_width.max(1);
+    height = (width * DEFAULT_HEIGHT / DEFAULT_WIDTH).max(1);
+  }
+
+  if let Some(max_height) = max_height.filter(|max| *max < height) {
+    height = max_height.max(1);
+    width = (height * DEFAULT_WIDTH / DEFAULT_HEIGHT).max(1);
+  }
+
+  (width, height)
+}
+
+pub async fn get_embed(
+  Path(id): Path<String>,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  let shader = find_embeddable_shader(&router_state, &id).await?;
+
+  if !shader.embed_enabled {
+    return Err(ApiError::Forbidden("embedding is disabled for this shader".to_string()));
+  }
+
+  let mut data = shader.data.0;
+  let resolved = resolve_shader(
+    &router_state.db,
+    shader.user_id,
+    shader.access,
+    None,
+    &data,
+    router_state.env.shader_limits.max_document_bytes,
+  ).await?;
+
+  data.code = resolved.code;
+  for (pass, resolved) in data.passes.iter_mut().zip(resolved.passes) {
+    pass.code = resolved.code;
+  }
+
+  let nonce = nanoid!(NONCE_LENGTH);
+  let link = shader_link(&router_state.env, &shader.id);
+  let html = render_player(PlayerPage {
+    title: &shader.name,
+    link: &link,
+    document: &EmbedDocument { id: &shader.id, name: &shader.name, data: &data },
+    nonce: &nonce,
+  });
+
+  let policy = format!(
+    "default-src 'none'; script-src 'nonce-{0}'; style-src 'nonce-{0}'; img-src 'self'; frame-ancestors {1}",
+    nonce, frame_ancestors(&shader.embed_origins),
+  );
+
+  Ok((
+    [
+      (CONTENT_TYPE, "text/html; charset=utf-8".to_string()),
+      (CONTENT_SECURITY_POLICY, policy),
+      (CACHE_CONTROL, EMBED_CACHE_CONTROL.to_string()),
+      (X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+    ],
+    html,
+  ))
+}
+
+pub async fn get_share_page(
+  Path(id): Path<String>,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  let env = &router_state.env;
+  let shader = find_embeddable_shader(&router_state, &id).await?;
+
+  let nonce = nanoid!(NONCE_LENGTH);
+  let link = shader_link(env, &shader.id);
+  let player = shader.embed_enabled.then(|| embed_url(env, &shader.id));
+  let thumbnail = large_thumbnail(env, &shader);
+  let oembed = format!("{}/oembed?format=json&url={}", env.public_url, urlencode(&share_url(env, &shader.id)));
+
+  let html = render_share(SharePage {
+    title: &shader.name,
+    description: &shader.description,
+    author: &shader.author,
+    link: &link,
+    oembed: &oembed,
+    player: player.as_deref(),
+    image: thumbnail.as_ref().map(|(url, width, height)| SharedImage { url, width: *width, height: *height }),
+    nonce: &nonce,
+  });
+
+  let policy = format!("default-src 'none'; style-src 'nonce-{}'; img-src 'self'; frame-src 'self'", nonce);
+
+  Ok((
+    [
+      (CONTENT_TYPE, "text/html; charset=utf-8".to_string()),
+      (CONTENT_SECURITY_POLICY, policy),
+      (CACHE_CONTROL, EMBED_CACHE_CONTROL.to_string()),
+      (X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+    ],
+    html,
+  ))
+}
+
+fn urlencode(value: &str) -> String {
+  reqwest::Url::parse_with_params("http://localhost", [("v", value)])
+    .ok()
+    .and_then(|url| url.query().map(|query| query.trim_start_matches("v=").to_string()))
+    .unwrap_or_default()
+}
+
+pub async fn get_oembed(
+  Query(query): Query<OEmbedQuery>,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  let env = &router_state.env;
+
+  if query.format.as_deref().is_some_and(|format| format != "json") {
+    return Err(ApiError::NotImplemented("only the json format is supported".to_string()));
+  }
+
+  let id = shader_id_from_url(env, &query.url)
+    .ok_or_else(|| ApiError::NotFound("url doesn't point at a shader".to_string()))?;
+  let shader = find_embeddable_shader(&router_state, &id).await?;
+
+  if !shader.embed_enabled {
+    return Err(ApiError::Forbidden("embedding is disabled for this shader".to_string()));
+  }
+
+  let (width, height) = fit_player(query.maxwidth, query.maxheight);
+  let html = format!(
+    "<iframe src=\"{}\" width=\"{}\" height=\"{}\" frameborder=\"0\" allowfullscreen></iframe>",
+    escape_html(&embed_url(env, &shader.id)), width, height,
+  );
+  let thumbnail = large_thumbnail(env, &shader);
+
+  Ok(Json(OEmbed {
+    version: "1.0",
+    kind: "rich",
+    provider_name: PROVIDER_NAME,
+    provider_url: env.frontend_url.clone(),
+    title: shader.name,
+    author_name: shader.author,
+    html,
+    width,
+    height,
+    thumbnail_url: thumbnail.as_ref().map(|(url, _, _)| url.clone()),
+    thumbnail_width: thumbnail.as_ref().map(|(_, width, _)| *width),
+    thumbnail_height: thumbnail.as_ref().map(|(_, _, height)| *height),
+  }))
+}
+
+pub async fn get_embed_settings(
+  Path(id): Path<String>,
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersRead)?;
+
+  let settings: Option<EmbedSettings> = sqlx::query_as(
+    "SELECT embed_enabled, embed_origins FROM shaders WHERE id = $1 AND user_id = $2 AND deleted = false")
+    .bind(&id)
+    .bind(profile.user_id)
+    .fetch_optional(&router_state.db)
+    .await?;
+
+  settings.map(Json).ok_or_else(|| ApiError::NotFound("shader not found".to_string()))
+}
+
+pub async fn update_embed_settings(
+  Path(id): Path<String>,
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+  ValidatedJson(update): ValidatedJson<UpdateEmbedSettings>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersWrite)?;
+
+  let origins: Option<Vec<String>> = update.allowed_origins.map(|origins| {
+    let mut origins: Vec<String> = origins.iter().filter_map(|origin| normalize_origin(origin)).collect();
+    origins.sort();
+    origins.dedup();
+    origins
+  });
+
+  let settings: Option<EmbedSettings> = sqlx::query_as(
+    "UPDATE shaders SET embed_enabled = COALESCE($3, embed_enabled), embed_origins = COALESCE($4, embed_origins)
+    WHERE id = $1 AND user_id = $2 AND deleted = false
+    RETURNING embed_enabled, embed_origins")
+    .bind(&id)
+    .bind(profile.user_id)
+    .bind(update.enabled)
+    .bind(origins)
+    .fetch_optional(&router_state.db)
+    .await?;
+
+  settings.map(Json).ok_or_else(|| ApiError::NotFound("shader not found".to_string()))
+}
+
+pub fn build_embed_router() -> axum::Router<RouterState> {
+  axum::Router::new()
+    .route("/embed/:id", get(get_embed))
+    .route("/share/:id", get(get_share_page))
+    .route("/oembed", get(get_oembed))
+}
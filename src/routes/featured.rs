@@ -0,0 +1,126 @@
+use axum::{extract::{Query, State}, response::IntoResponse, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::types::uuid::Uuid;
+
+use crate::{errors::ApiError, router_state::RouterState, routes::shader::{is_shader_id, Shader}, validation::{Validate, Validator}};
+
+pub const DEFAULT_SLOT: &str = "front";
+pub const MAX_SLOT_NAME_LENGTH: usize = 64;
+
+const ACTIVE_FILTER: &str = "featured_shaders.starts_at <= NOW()
+  AND (featured_shaders.ends_at IS NULL OR featured_shaders.ends_at > NOW())
+  AND shaders.deleted = false AND shaders.access = 'public' AND shaders.hidden = false";
+
+pub fn featured_entry_query() -> String {
+  format!("SELECT featured_shaders.id, featured_shaders.slot, featured_shaders.shader_id, shaders.name,
+    featured_shaders.position, featured_shaders.starts_at, featured_shaders.ends_at, ({}) AS active,
+    users.user_id AS featured_by, featured_shaders.created_at
+    FROM featured_shaders
+    INNER JOIN shaders ON shaders.id = featured_shaders.shader_id
+    LEFT JOIN users ON users.id = featured_shaders.featured_by", ACTIVE_FILTER)
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct FeaturedEntry {
+  pub id: i32,
+  pub slot: String,
+  pub shader_id: String,
+  pub name: String,
+  pub position: i32,
+  pub starts_at: chrono::DateTime<chrono::Utc>,
+  pub ends_at: Option<chrono::DateTime<chrono::Utc>>,
+  pub active: bool,
+  pub featured_by: Option<Uuid>,
+  pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl FeaturedEntry {
+  pub fn schedule_snapshot(&self) -> serde_json::Value {
+    serde_json::json!({
+      "slot": self.slot,
+      "position": self.position,
+      "starts_at": self.starts_at,
+      "ends_at": self.ends_at,
+    })
+  }
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct FeaturedShader {
+  pub slot: String,
+  pub position: i32,
+  pub featured_until: Option<chrono::DateTime<chrono::Utc>>,
+  #[sqlx(flatten)]
+  pub shader: Shader,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FeaturedSchedule {
+  #[serde(default = "default_slot")]
+  pub slot: String,
+  #[serde(default)]
+  pub position: i32,
+  pub starts_at: Option<chrono::DateTime<chrono::Utc>>,
+  pub ends_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewFeaturedEntry {
+  pub shader_id: String,
+  #[serde(flatten)]
+  pub schedule: FeaturedSchedule,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FeaturedQuery {
+  pub slot: Option<String>,
+}
+
+fn default_slot() -> String {
+  DEFAULT_SLOT.to_string()
+}
+
+pub fn is_slot_name(name: &str) -> bool {
+  !name.is_empty()
+    && name.len() <= MAX_SLOT_NAME_LENGTH
+    && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+impl Validate for FeaturedSchedule {
+  fn validate(&self, v: &mut Validator) {
+    v.check("slot", is_slot_name(&self.slot), format!(
+      "must be 1 to {} lowercase letters, digits, dashes or underscores", MAX_SLOT_NAME_LENGTH,
+    ));
+
+    if let (Some(starts_at), Some(ends_at)) = (self.starts_at, self.ends_at) {
+      v.check("ends_at", ends_at > starts_at, "must be after starts_at");
+    } else if let Some(ends_at) = self.ends_at {
+      v.check("ends_at", ends_at > chrono::Utc::now(), "must be in the future");
+    }
+  }
+}
+
+impl Validate for NewFeaturedEntry {
+  fn validate(&self, v: &mut Validator) {
+    v.check("shader_id", is_shader_id(&self.shader_id), "must be a shader id");
+    self.schedule.validate(v);
+  }
+}
+
+pub async fn get_featured(
+  Query(query): Query<FeaturedQuery>,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  let featured: Vec<FeaturedShader> = sqlx::query_as(&format!(
+    "SELECT shaders.*, featured_shaders.slot, featured_shaders.position, featured_shaders.ends_at AS featured_until
+    FROM featured_shaders
+    INNER JOIN shaders ON shaders.id = featured_shaders.shader_id
+    WHERE {} AND ($1::TEXT IS NULL OR featured_shaders.slot = $1)
+    ORDER BY featured_shaders.slot, featured_shaders.position, featured_shaders.starts_at", ACTIVE_FILTER,
+  ))
+    .bind(query.slot)
+    .fetch_all(&router_state.db)
+    .await?;
+
+  Ok(Json(featured))
+}
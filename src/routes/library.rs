@@ -0,0 +1,185 @@
+use axum::{extract::{Path, State}, http::StatusCode, response::IntoResponse, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use crate::{errors::{ApiError, FieldError}, includes::{is_library_name, resolve_shader, MAX_LIBRARY_NAME_LENGTH}, quotas, router_state::{RouterState, UserProfile}, routes::shader::{get_shader_by_id, ShaderData}, tokens::TokenScope, validation::{ValidatedJson, Validate, Validator}};
+
+#[derive(Debug, Deserialize)]
+pub struct PublishLibrary {
+  pub name: Option<String>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct LibraryRevision {
+  pub revision: i32,
+  pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Library {
+  pub shader_id: String,
+  pub name: String,
+  pub path: String,
+  pub revisions: Vec<LibraryRevision>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PublishedRevision {
+  pub path: String,
+  #[serde(flatten)]
+  pub revision: LibraryRevision,
+}
+
+impl Validate for PublishLibrary {
+  fn validate(&self, v: &mut Validator) {
+    if let Some(name) = &self.name {
+      v.check(
+        "name",
+        is_library_name(name),
+        format!("must be 1 to {} lowercase letters, digits, dashes or underscores", MAX_LIBRARY_NAME_LENGTH),
+      );
+    }
+  }
+}
+
+pub async fn library_path(db: &PgPool, profile: &UserProfile, shader_id: &str) -> Result<Option<String>, ApiError> {
+  let name: Option<(String,)> = sqlx::query_as("SELECT name FROM shader_libraries WHERE shader_id = $1")
+    .bind(shader_id)
+    .fetch_optional(db)
+    .await?;
+
+  Ok(name.zip(profile.username.as_ref()).map(|((name,), username)| format!("{}/{}", username, name)))
+}
+
+pub async fn publish_library_revision(
+  Path(id): Path<String>,
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+  ValidatedJson(publish): ValidatedJson<PublishLibrary>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersWrite)?;
+
+  let shader = get_shader_by_id(&router_state, &id, &profile).await?;
+
+  let mut tx = router_state.db.begin().await?;
+
+  // keeps the username from changing while the library is published under it
+  let (username,): (Option<String>,) = sqlx::query_as("SELECT username FROM users WHERE id = $1 FOR UPDATE")
+    .bind(profile.id)
+    .fetch_one(&mut *tx)
+    .await?;
+
+  let Some(username) = username else {
+    return Err(ApiError::Validation(vec![FieldError::new("username", "set a username before publishing libraries")]));
+  };
+
+  let existing: Option<(String,)> = sqlx::query_as("SELECT name FROM shader_libraries WHERE shader_id = $1 FOR UPDATE")
+    .bind(&id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+  let name = match (existing, publish.name) {
+    (Some((name,)), Some(requested)) if name != requested => {
+      return Err(ApiError::Conflict(format!("shader is published as library {}, libraries can't be renamed", name)));
+    },
+    (Some((name,)), _) => name,
+    (None, Some(name)) => {
+      let taken: Option<(String,)> = sqlx::query_as("SELECT shader_id FROM shader_libraries WHERE user_id = $1 AND name = $2")
+        .bind(profile.user_id)
+        .bind(&name)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+      if let Some((shader_id,)) = taken {
+        return Err(ApiError::Conflict(format!("library {} is already published by shader {}", name, shader_id)));
+      }
+
+      sqlx::query("INSERT INTO shader_libraries (shader_id, user_id, name) VALUES ($1, $2, $3)")
+        .bind(&id)
+        .bind(profile.user_id)
+        .bind(&name)
+        .execute(&mut *tx)
+        .await?;
+
+      name
+    },
+    (None, None) => {
+      return Err(ApiError::Validation(vec![FieldError::new("name", "is required to publish a shader the first time")]));
+    },
+  };
+
+  let path = format!("{}/{}", username, name);
+  let code = shader.data.0.code;
+
+  // a revision has to resolve when it's published, later ones of its includes may still break it
+  let library = ShaderData { code, channels: Vec::new(), passes: Vec::new() };
+  resolve_shader(&router_state.db, profile.user_id, shader.access, Some(&path), &library, router_state.env.shader_limits.max_document_bytes).await?;
+
+  let usage = quotas::lock_usage(&mut tx, profile.id).await?;
+
+  let revision: LibraryRevision = sqlx::query_as(
+    "INSERT INTO shader_library_revisions (shader_id, revision, code)
+    SELECT $1, COALESCE(MAX(revision), 0) + 1, $2 FROM shader_library_revisions WHERE shader_id = $1
+    RETURNING revision, created_at")
+    .bind(&id)
+    .bind(&library.code)
+    .fetch_one(&mut *tx)
+    .await?;
+
+  // the library row locked above keeps concurrent publishes from pruning past each other
+  quotas::prune_revisions(&mut tx, &router_state.env, &id).await?;
+  quotas::check_usage(&mut tx, &router_state.env, profile.id, &usage).await?;
+  tx.commit().await?;
+
+  Ok((StatusCode::CREATED, Json(PublishedRevision { path: format!("{}@{}", path, revision.revision), revision })))
+}
+
+pub async fn get_library(
+  Path(id): Path<String>,
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersRead)?;
+
+  get_shader_by_id(&router_state, &id, &profile).await?;
+
+  let name: Option<(String,)> = sqlx::query_as("SELECT name FROM shader_libraries WHERE shader_id = $1")
+    .bind(&id)
+    .fetch_optional(&router_state.db)
+    .await?;
+
+  let Some((name,)) = name else {
+    return Err(ApiError::NotFound("shader is not published as a library".to_string()));
+  };
+
+  let revisions: Vec<LibraryRevision> = sqlx::query_as(
+    "SELECT revision, created_at FROM shader_library_revisions WHERE shader_id = $1 ORDER BY revision DESC")
+    .bind(&id)
+    .fetch_all(&router_state.db)
+    .await?;
+
+  let path = format!("{}/{}", profile.username.as_deref().unwrap_or_default(), name);
+  Ok(Json(Library { shader_id: id, name, path, revisions }))
+}
+
+pub async fn get_resolved_shader(
+  Path(id): Path<String>,
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersRead)?;
+
+  let shader = get_shader_by_id(&router_state, &id, &profile).await?;
+  let library = library_path(&router_state.db, &profile, &id).await?;
+
+  let resolved = resolve_shader(
+    &router_state.db,
+    profile.user_id,
+    shader.access,
+    library.as_deref(),
+    &shader.data,
+    router_state.env.shader_limits.max_document_bytes,
+  ).await?;
+
+  Ok(Json(resolved))
+}
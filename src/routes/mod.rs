@@ -1,3 +1,11 @@
 
 pub mod shader;
 pub mod oauth;
+pub mod user;
+pub mod asset;
+pub mod library;
+pub mod template;
+pub mod admin;
+pub mod embed;
+pub mod moderation;
+pub mod featured;
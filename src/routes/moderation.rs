@@ -0,0 +1,488 @@
+use axum::{extract::{Path, Query, State}, http::StatusCode, response::IntoResponse, routing::{get, post}, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::uuid::Uuid, PgConnection};
+
+use crate::{audit::{self, AuditAction, AuditEvent, AuditTarget, RequestMeta}, errors::{ApiError, FieldError}, router_state::{Moderator, Role, RouterState, UserProfile}, routes::shader::AccessLevel, validation::{ValidatedJson, Validate, Validator}};
+
+const MAX_REPORT_DETAILS_LENGTH: usize = 2000;
+const MAX_REASON_LENGTH: usize = 2000;
+const MAX_TARGET_ID_LENGTH: usize = 64;
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+const REPORT_QUERY: &str = "SELECT reports.id, reports.target_type, reports.target_id, reports.reason, reports.details,
+  reports.status, reporter.user_id AS reporter, resolver.user_id AS resolved_by, reports.resolved_at, reports.created_at
+  FROM reports
+  INNER JOIN users reporter ON reporter.id = reports.reporter_id
+  LEFT JOIN users resolver ON resolver.id = reports.resolved_by";
+
+const ACTION_QUERY: &str = "SELECT moderation_actions.id, actor.user_id AS actor, moderation_actions.action,
+  moderation_actions.target_type, moderation_actions.target_id, moderation_actions.report_id,
+  moderation_actions.reason, moderation_actions.created_at
+  FROM moderation_actions
+  LEFT JOIN users actor ON actor.id = moderation_actions.actor_id";
+
+// comments can't be reported, and so can't be deleted by moderators, until this backend stores
+// them, there's no comments table for a report to point at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "report_target", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ReportTarget {
+  Shader,
+  User,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "report_reason", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ReportReason {
+  Spam,
+  Harassment,
+  Nsfw,
+  Copyright,
+  Malicious,
+  Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "report_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ReportStatus {
+  Open,
+  Actioned,
+  Dismissed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "moderation_action", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationActionKind {
+  HideShader,
+  UnhideShader,
+  ForcePrivate,
+  SuspendUser,
+  UnsuspendUser,
+  DismissReport,
+}
+
+impl ModerationActionKind {
+  fn target_type(self) -> Option<ReportTarget> {
+    match self {
+      Self::HideShader | Self::UnhideShader | Self::ForcePrivate => Some(ReportTarget::Shader),
+      Self::SuspendUser | Self::UnsuspendUser => Some(ReportTarget::User),
+      // the report names the target
+      Self::DismissReport => None,
+    }
+  }
+
+  fn resolves_reports(self) -> bool {
+    matches!(self, Self::HideShader | Self::ForcePrivate | Self::SuspendUser)
+  }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewReport {
+  pub target_type: ReportTarget,
+  pub target_id: String,
+  pub reason: ReportReason,
+  #[serde(default)]
+  pub details: String,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Report {
+  pub id: i32,
+  pub target_type: ReportTarget,
+  pub target_id: String,
+  pub reason: ReportReason,
+  pub details: String,
+  pub status: ReportStatus,
+  pub reporter: Uuid,
+  pub resolved_by: Option<Uuid>,
+  pub resolved_at: Option<chrono::DateTime<chrono::Utc>>,
+  pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReportQueueQuery {
+  pub status: Option<ReportStatus>,
+  pub target_type: Option<ReportTarget>,
+  pub limit: Option<i64>,
+  pub offset: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReportDetails {
+  #[serde(flatten)]
+  pub report: Report,
+  pub actions: Vec<ModerationAction>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewModerationAction {
+  pub action: ModerationActionKind,
+  pub target_id: Option<String>,
+  pub report_id: Option<i32>,
+  pub reason: String,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct ModerationAction {
+  pub id: i32,
+  pub actor: Option<Uuid>,
+  pub action: ModerationActionKind,
+  pub target_type: ReportTarget,
+  pub target_id: String,
+  pub report_id: Option<i32>,
+  pub reason: String,
+  pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ActionLogQuery {
+  pub target_type: Option<ReportTarget>,
+  pub target_id: Option<String>,
+  pub limit: Option<i64>,
+  pub offset: Option<i64>,
+}
+
+impl Validate for NewReport {
+  fn validate(&self, v: &mut Validator) {
+    v.length("target_id", &self.target_id, 1, MAX_TARGET_ID_LENGTH);
+    v.length("details", &self.details, 0, MAX_REPORT_DETAILS_LENGTH);
+    v.check(
+      "details",
+      self.reason != ReportReason::Other || !self.details.trim().is_empty(),
+      "is required when the reason is other",
+    );
+  }
+}
+
+impl Validate for NewModerationAction {
+  fn validate(&self, v: &mut Validator) {
+    v.length("reason", self.reason.trim(), 1, MAX_REASON_LENGTH);
+
+    match self.action {
+      ModerationActionKind::DismissReport => v.check("report_id", self.report_id.is_some(), "is required to dismiss a report"),
+      _ => v.check(
+        "target_id",
+        self.target_id.as_ref().is_some_and(|id| !id.is_empty() && id.len() <= MAX_TARGET_ID_LENGTH),
+        "is required",
+      ),
+    }
+  }
+}
+
+pub fn page(limit: Option<i64>, offset: Option<i64>) -> (i64, i64) {
+  (limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE), offset.unwrap_or(0).max(0))
+}
+
+pub fn parse_user_id(field: &str, id: &str) -> Result<Uuid, ApiError> {
+  Uuid::parse_str(id).map_err(|_| ApiError::Validation(vec![FieldError::new(field, "is not a valid user id")]))
+}
+
+async fn find_report(conn: &mut PgConnection, id: i32) -> Result<Report, ApiError> {
+  let report: Option<Report> = sqlx::query_as(&format!("{} WHERE reports.id = $1", REPORT_QUERY))
+    .bind(id)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+  report.ok_or_else(|| ApiError::NotFound("report not found".to_string()))
+}
+
+pub async fn create_report(
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+  ValidatedJson(new_report): ValidatedJson<NewReport>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_session()?;
+
+  let target_id = match new_report.target_type {
+    ReportTarget::Shader => {
+      let shader: Option<(String,)> = sqlx::query_as(
+        "SELECT id FROM shaders WHERE id = $1 AND deleted = false
+        AND ((access <> 'private' AND hidden = false) OR user_id = $2)")
+        .bind(&new_report.target_id)
+        .bind(profile.user_id)
+        .fetch_optional(&router_state.db)
+        .await?;
+
+      shader.ok_or_else(|| ApiError::NotFound("shader not found".to_string()))?.0
+    },
+    ReportTarget::User => {
+      let user_id = parse_user_id("target_id", &new_report.target_id)?;
+
+      if user_id == profile.user_id {
+        return Err(ApiError::Validation(vec![FieldError::new("target_id", "you can't report yourself")]));
+      }
+
+      let user: Option<(Uuid,)> = sqlx::query_as("SELECT user_id FROM users WHERE user_id = $1 AND deleted = false")
+        .bind(user_id)
+        .fetch_optional(&router_state.db)
+        .await?;
+
+      user.ok_or_else(|| ApiError::NotFound("user not found".to_string()))?.0.to_string()
+    },
+  };
+
+  let (id,): (i32,) = sqlx::query_as(
+    "INSERT INTO reports (reporter_id, target_type, target_id, reason, details) VALUES ($1, $2, $3, $4, $5)
+    RETURNING id")
+    .bind(profile.id)
+    .bind(new_report.target_type)
+    .bind(&target_id)
+    .bind(new_report.reason)
+    .bind(new_report.details.trim())
+    .fetch_one(&router_state.db)
+    .await?;
+
+  let mut conn = router_state.db.acquire().await?;
+  let report = find_report(&mut conn, id).await?;
+
+  Ok((StatusCode::CREATED, Json(report)))
+}
+
+pub async fn get_reports(
+  Query(query): Query<ReportQueueQuery>,
+  _moderator: Moderator,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  let (limit, offset) = page(query.limit, query.offset);
+  let reports: Vec<Report> = sqlx::query_as(&format!(
+    "{} WHERE reports.status = $1 AND ($2::report_target IS NULL OR reports.target_type = $2)
+    ORDER BY reports.created_at, reports.id LIMIT $3 OFFSET $4", REPORT_QUERY,
+  ))
+    .bind(query.status.unwrap_or(ReportStatus::Open))
+    .bind(query.target_type)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(&router_state.db)
+    .await?;
+
+  Ok(Json(reports))
+}
+
+pub async fn get_report(
+  Path(id): Path<i32>,
+  _moderator: Moderator,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  let mut conn = router_state.db.acquire().await?;
+  let report = find_report(&mut conn, id).await?;
+
+  let actions: Vec<ModerationAction> = sqlx::query_as(&format!(
+    "{} WHERE moderation_actions.target_type = $1 AND moderation_actions.target_id = $2
+    ORDER BY moderation_actions.created_at DESC, moderation_actions.id DESC", ACTION_QUERY,
+  ))
+    .bind(report.target_type)
+    .bind(&report.target_id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+  Ok(Json(ReportDetails { report, actions }))
+}
+
+async fn apply_action(conn: &mut PgConnection, action: ModerationActionKind, target_id: &str) -> Result<bool, ApiError> {
+  let result = match action {
+    ModerationActionKind::HideShader => sqlx::query("UPDATE shaders SET hidden = true WHERE id = $1 AND deleted = false")
+      .bind(target_id)
+      .execute(&mut *conn)
+      .await?,
+    ModerationActionKind::UnhideShader => sqlx::query("UPDATE shaders SET hidden = false WHERE id = $1 AND deleted = false")
+      .bind(target_id)
+      .execute(&mut *conn)
+      .await?,
+    ModerationActionKind::ForcePrivate => sqlx::query(
+      "UPDATE shaders SET access = 'private', access_locked = true WHERE id = $1 AND deleted = false")
+      .bind(target_id)
+      .execute(&mut *conn)
+      .await?,
+    ModerationActionKind::SuspendUser => {
+      let user: Option<(i32,)> = sqlx::query_as(
+        "UPDATE users SET suspended_at = COALESCE(suspended_at, NOW()) WHERE user_id = $1 AND deleted = false
+        RETURNING id")
+        .bind(parse_user_id("target_id", target_id)?)
+        .fetch_optional(&mut *conn)
+        .await?;
+
+      let Some((id,)) = user else {
+        return Ok(false);
+      };
+
+      // tokens stay but can't authenticate while the account is suspended
+      sqlx::query("DELETE FROM sessions WHERE user_id = $1")
+        .bind(id)
+        .execute(&mut *conn)
+        .await?;
+
+      return Ok(true);
+    },
+    ModerationActionKind::UnsuspendUser => sqlx::query("UPDATE users SET suspended_at = NULL WHERE user_id = $1 AND deleted = false")
+      .bind(parse_user_id("target_id", target_id)?)
+      .execute(&mut *conn)
+      .await?,
+    ModerationActionKind::DismissReport => return Ok(true),
+  };
+
+  Ok(result.rows_affected() > 0)
+}
+
+pub async fn take_action(
+  tx: &mut PgConnection,
+  meta: &RequestMeta,
+  profile: &UserProfile,
+  new_action: NewModerationAction,
+) -> Result<ModerationAction, ApiError> {
+  let report = match new_action.report_id {
+    Some(report_id) => {
+      let (status,): (ReportStatus,) = sqlx::query_as("SELECT status FROM reports WHERE id = $1 FOR UPDATE")
+        .bind(report_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("report not found".to_string()))?;
+
+      if status != ReportStatus::Open {
+        return Err(ApiError::Conflict("the report has been resolved already".to_string()));
+      }
+
+      Some(find_report(tx, report_id).await?)
+    },
+    None => None,
+  };
+
+  let (target_type, target_id) = match (new_action.action.target_type(), &report) {
+    (None, Some(report)) => (report.target_type, report.target_id.clone()),
+    (Some(target_type), _) => (target_type, new_action.target_id.clone().unwrap_or_default()),
+    (None, None) => return Err(ApiError::Validation(vec![FieldError::new("report_id", "is required to dismiss a report")])),
+  };
+
+  if report.as_ref().is_some_and(|report| report.target_type != target_type || report.target_id != target_id) {
+    return Err(ApiError::Validation(vec![FieldError::new("report_id", "the report is about another target")]));
+  }
+
+  if target_type == ReportTarget::User {
+    if target_id == profile.user_id.to_string() {
+      return Err(ApiError::Validation(vec![FieldError::new("target_id", "you can't moderate yourself")]));
+    }
+
+    // moderators can't take each other out, only admins act on staff
+    let role: Option<(Role,)> = sqlx::query_as("SELECT role FROM users WHERE user_id = $1")
+      .bind(parse_user_id("target_id", &target_id)?)
+      .fetch_optional(&mut *tx)
+      .await?;
+
+    if role.is_some_and(|(role,)| role > Role::User) {
+      profile.require_role(Role::Admin)?;
+    }
+  }
+
+  // access changes and suspensions go to the audit log too
+  let access: Option<(AccessLevel,)> = match new_action.action {
+    ModerationActionKind::ForcePrivate => sqlx::query_as("SELECT access FROM shaders WHERE id = $1 FOR UPDATE")
+      .bind(&target_id)
+      .fetch_optional(&mut *tx)
+      .await?,
+    _ => None,
+  };
+
+  if !apply_action(tx, new_action.action, &target_id).await? {
+    return Err(ApiError::NotFound("target not found".to_string()));
+  }
+
+  let event = match (new_action.action, access) {
+    (ModerationActionKind::ForcePrivate, Some((access,))) => Some(
+      AuditEvent::new(Some(profile.id), AuditAction::ShaderAccessChange, AuditTarget::Shader, &target_id)
+        .before(serde_json::json!({ "access": access }))
+        .after(serde_json::json!({ "access": AccessLevel::Private, "access_locked": true }))),
+    (ModerationActionKind::SuspendUser, _) => Some(
+      AuditEvent::new(Some(profile.id), AuditAction::UserSuspend, AuditTarget::User, &target_id)
+        .after(serde_json::json!({ "reason": new_action.reason.trim() }))),
+    (ModerationActionKind::UnsuspendUser, _) => Some(
+      AuditEvent::new(Some(profile.id), AuditAction::UserUnsuspend, AuditTarget::User, &target_id)
+        .after(serde_json::json!({ "reason": new_action.reason.trim() }))),
+    _ => None,
+  };
+
+  if let Some(event) = event {
+    audit::record(&mut *tx, meta, event).await?;
+  }
+
+  let (id,): (i32,) = sqlx::query_as(
+    "INSERT INTO moderation_actions (actor_id, action, target_type, target_id, report_id, reason)
+    VALUES ($1, $2, $3, $4, $5, $6) RETURNING id")
+    .bind(profile.id)
+    .bind(new_action.action)
+    .bind(target_type)
+    .bind(&target_id)
+    .bind(new_action.report_id)
+    .bind(new_action.reason.trim())
+    .fetch_one(&mut *tx)
+    .await?;
+
+  if new_action.action == ModerationActionKind::DismissReport {
+    sqlx::query("UPDATE reports SET status = 'dismissed', resolved_by = $2, resolved_at = NOW() WHERE id = $1")
+      .bind(new_action.report_id)
+      .bind(profile.id)
+      .execute(&mut *tx)
+      .await?;
+  } else if new_action.action.resolves_reports() {
+    sqlx::query(
+      "UPDATE reports SET status = 'actioned', resolved_by = $3, resolved_at = NOW()
+      WHERE target_type = $1 AND target_id = $2 AND status = 'open'")
+      .bind(target_type)
+      .bind(&target_id)
+      .bind(profile.id)
+      .execute(&mut *tx)
+      .await?;
+  }
+
+  let action: ModerationAction = sqlx::query_as(&format!("{} WHERE moderation_actions.id = $1", ACTION_QUERY))
+    .bind(id)
+    .fetch_one(&mut *tx)
+    .await?;
+
+  Ok(action)
+}
+
+pub async fn create_action(
+  meta: RequestMeta,
+  Moderator(profile): Moderator,
+  State(router_state): State<RouterState>,
+  ValidatedJson(new_action): ValidatedJson<NewModerationAction>,
+) -> Result<impl IntoResponse, ApiError> {
+  let mut tx = router_state.db.begin().await?;
+  let action = take_action(&mut tx, &meta, &profile, new_action).await?;
+  tx.commit().await?;
+
+  Ok((StatusCode::CREATED, Json(action)))
+}
+
+pub async fn get_actions(
+  Query(query): Query<ActionLogQuery>,
+  _moderator: Moderator,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  let (limit, offset) = page(query.limit, query.offset);
+  let actions: Vec<ModerationAction> = sqlx::query_as(&format!(
+    "{} WHERE ($1::report_target IS NULL OR moderation_actions.target_type = $1)
+    AND ($2::VARCHAR IS NULL OR moderation_actions.target_id = $2)
+    ORDER BY moderation_actions.created_at DESC, moderation_actions.id DESC LIMIT $3 OFFSET $4", ACTION_QUERY,
+  ))
+    .bind(query.target_type)
+    .bind(query.target_id)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(&router_state.db)
+    .await?;
+
+  Ok(Json(actions))
+}
+
+pub fn build_moderation_router() -> axum::Router<RouterState> {
+  axum::Router::new()
+    .route("/reports", post(create_report))
+    .route("/reports", get(get_reports))
+    .route("/reports/:id", get(get_report))
+    .route("/actions", post(create_action))
+    .route("/actions", get(get_actions))
+}
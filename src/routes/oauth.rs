@@ -1,130 +1,709 @@
-
-use axum::{extract::{Query, State}, http::StatusCode, response::{IntoResponse, Redirect}, Extension};
+use axum::{extract::{Path, Query, State}, http::StatusCode, response::{IntoResponse, Redirect}, Extension, Json};
 use axum_extra::extract::{cookie::Cookie, PrivateCookieJar};
-use chrono::{Duration, Local};
-use oauth2::{basic::BasicClient, reqwest::async_http_client, AuthorizationCode, TokenResponse};
-use serde::Deserialize;
+use chrono::{Duration, Utc};
+use oauth2::TokenResponse;
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+
+use crate::{audit::{self, AuditAction, AuditEvent, AuditTarget, RequestMeta}, auth::{build_login_state_cookie, build_login_state_removal_cookie, build_session_cookie, build_session_removal_cookie, ExternalProfile, IdentityProvider, IdentityProviders, LoginState}, constants::{ACCESS_COOKIE_EXPIRE_TIME, ACCESS_TOKEN_EXPIRE_TIME, REFRESH_TOKEN_EXPIRE_DAYS}, errors::ApiError, router_state::{RouterState, UserProfile}, tokens::TokenScope, validation::{validate_payload, Validate, Validator}};
 
-use crate::{constants::{ACCESS_COOKIE_EXPIRE_TIME, ACCESS_TOKEN_EXPIRE_TIME}, errors::ApiError, router_state::{RouterState, UserProfile}};
+const DEV_PROVIDER: &str = "dev";
+const DEV_EMAIL_DOMAIN: &str = "dev.localhost";
+const UNVERIFIED_EMAIL_DOMAIN: &str = "unverified.invalid";
+const DEV_USER_NAME_MAX_LENGTH: usize = 64;
 
 #[derive(Debug, Deserialize)]
 pub struct AuthRequest {
   code: String,
+  state: String,
 }
 
 #[derive(Debug, Deserialize)]
-pub struct OpenIdProfile {
-  pub email: String,
-  pub name: String,
+pub struct DevLoginRequest {
+  name: String,
 }
 
-pub async fn google_callback(
-  State(state): State<RouterState>,
-  jar: PrivateCookieJar,
-  Query(query): Query<AuthRequest>,
-  Extension(oauth_client): Extension<BasicClient>,
-) -> Result<impl IntoResponse, ApiError> {
-  let token = oauth_client
-    .exchange_code(AuthorizationCode::new(query.code))
-    .request_async(async_http_client)
+impl Validate for DevLoginRequest {
+  fn validate(&self, v: &mut Validator) {
+    v.length("name", &self.name, 1, DEV_USER_NAME_MAX_LENGTH);
+    v.check(
+      "name",
+      self.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+      "may only contain letters, digits, '-' and '_'",
+    );
+  }
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct LinkedIdentity {
+  pub id: i32,
+  pub provider: String,
+  pub email: Option<String>,
+  pub email_verified: bool,
+  pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+async fn cancel_account_deletion(
+  tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+  user_id: i32,
+) -> Result<(), ApiError> {
+  let cancelled = sqlx::query("UPDATE users SET deletion_requested_at = NULL
+    WHERE id = $1 AND deletion_requested_at IS NOT NULL")
+    .bind(user_id)
+    .execute(&mut **tx)
     .await?;
 
-  let profile = state.ctx.get("https://openidconnect.googleapis.com/v1/userinfo")
-    .bearer_auth(token.access_token().secret().to_owned())
-    .send()
+  if cancelled.rows_affected() > 0 {
+    log::info!("cancelled pending deletion of user {}", user_id);
+  }
+
+  Ok(())
+}
+
+async fn find_or_create_user(
+  state: &RouterState,
+  provider: &str,
+  profile: &ExternalProfile,
+) -> Result<i32, ApiError> {
+  let mut tx = state.db.begin().await?;
+
+  let identity: Option<(i32,)> = sqlx::query_as(
+    "UPDATE user_identities SET email = $3, email_verified = $4
+    WHERE provider = $1 AND subject = $2
+    RETURNING user_id")
+    .bind(provider)
+    .bind(&profile.subject)
+    .bind(&profile.email)
+    .bind(profile.email_verified)
+    .fetch_optional(&mut *tx)
     .await?;
 
-  let profile = profile.json::<OpenIdProfile>().await.unwrap();
+  if let Some((user_id,)) = identity {
+    // the address was held back while it was unverified, unless someone else proved it first
+    if profile.email_verified {
+      sqlx::query("UPDATE users SET email = $2 WHERE id = $1 AND email LIKE '%@' || $3
+        AND NOT EXISTS (SELECT 1 FROM users WHERE email = $2)")
+        .bind(user_id)
+        .bind(&profile.email)
+        .bind(UNVERIFIED_EMAIL_DOMAIN)
+        .execute(&mut *tx)
+        .await?;
+    }
 
-  let Some(secs) = token.expires_in() else {
-    return Err(ApiError::OptionError);
-  };
+    cancel_account_deletion(&mut tx, user_id).await?;
+    tx.commit().await?;
+    return Ok(user_id);
+  }
+
+  let existing: Option<(i32,)> = sqlx::query_as("SELECT id FROM users WHERE email = $1 LIMIT 1")
+    .bind(&profile.email)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+  // only a verified email proves ownership of an existing account
+  let user_id = match existing {
+    Some((user_id,)) if profile.email_verified => user_id,
+    Some(_) => return Err(ApiError::Conflict(
+      "an account with this email already exists, sign in and link this provider instead".to_string()
+    )),
+    None => {
+      // an unverified address isn't claimed, its owner would be merged into this account otherwise
+      let email = if profile.email_verified {
+        profile.email.clone()
+      } else {
+        format!("unverified-{}@{}", nanoid!(), UNVERIFIED_EMAIL_DOMAIN)
+      };
+
+      let created: Option<(i32,)> = sqlx::query_as("INSERT INTO users (email, name) VALUES ($1, $2)
+        ON CONFLICT (email) DO NOTHING RETURNING id")
+        .bind(email)
+        .bind(&profile.name)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+      let Some((user_id,)) = created else {
+        return Err(ApiError::Conflict("an account with this email was created at the same time, sign in again".to_string()));
+      };
 
-  let secs: i64 = secs.as_secs().try_into().unwrap();
+      user_id
+    },
+  };
 
-  let max_age = Local::now().naive_utc() + Duration::seconds(
-    std::cmp::min(secs, ACCESS_TOKEN_EXPIRE_TIME)
-  );
+  sqlx::query("INSERT INTO user_identities (user_id, provider, subject, email, email_verified)
+    VALUES ($1, $2, $3, $4, $5)")
+    .bind(user_id)
+    .bind(provider)
+    .bind(&profile.subject)
+    .bind(&profile.email)
+    .bind(profile.email_verified)
+    .execute(&mut *tx)
+    .await?;
 
-  let cookie = Cookie::build(("sid", token.access_token().secret().to_owned()))
-    .domain(state.env.frontend_domain)
-    .path("/")
-    .secure(true)
-    .http_only(true)
-    .max_age(cookie::time::Duration::days(ACCESS_COOKIE_EXPIRE_TIME));
+  cancel_account_deletion(&mut tx, user_id).await?;
+  tx.commit().await?;
+  Ok(user_id)
+}
 
-  sqlx::query("INSERT INTO users (email, name) VALUES ($1, $2) ON CONFLICT (email) DO NOTHING")
-    .bind(profile.email.clone())
-    .bind(profile.name.clone())
-    .execute(&state.db)
+async fn start_session(
+  state: &RouterState,
+  meta: &RequestMeta,
+  user_id: i32,
+  provider: &str,
+  session_id: String,
+  expires_in: Option<i64>,
+  refresh_token: Option<String>,
+) -> Result<Cookie<'static>, ApiError> {
+  let (suspended,): (bool,) = sqlx::query_as("SELECT suspended_at IS NOT NULL FROM users WHERE id = $1")
+    .bind(user_id)
+    .fetch_one(&state.db)
     .await?;
 
-  sqlx::query("INSERT INTO sessions (user_id, session_id, expires_at) VALUES (
-    (SELECT id FROM users WHERE email = $1 LIMIT 1),
-    $2, $3)
+  if suspended {
+    audit::record(&state.db, meta, AuditEvent::new(Some(user_id), AuditAction::LoginDenied, AuditTarget::User, user_id.to_string())
+      .after(serde_json::json!({ "provider": provider, "reason": "suspended" }))).await?;
+
+    return Err(ApiError::Forbidden("this account is suspended".to_string()));
+  }
+
+  // sessions that can't be refreshed live as long as the provider token, or the cookie
+  let secs = match refresh_token {
+    Some(_) => std::cmp::min(expires_in.unwrap_or(ACCESS_TOKEN_EXPIRE_TIME), ACCESS_TOKEN_EXPIRE_TIME),
+    None => expires_in.unwrap_or(ACCESS_COOKIE_EXPIRE_TIME),
+  };
+
+  let max_age = Utc::now() + Duration::seconds(secs);
+
+  let (session,): (i32,) = sqlx::query_as("INSERT INTO sessions (user_id, session_id, expires_at) VALUES ($1, $2, $3)
     ON CONFLICT (user_id) DO UPDATE SET
     session_id = excluded.session_id,
-    expires_at = excluded.expires_at")
-    .bind(&profile.email)
-    .bind(token.access_token().secret().to_owned())
+    expires_at = excluded.expires_at
+    RETURNING id")
+    .bind(user_id)
+    .bind(&session_id)
     .bind(max_age)
-    .execute(&state.db)
+    .fetch_one(&state.db)
     .await?;
 
-  let refresh_token = token.refresh_token().unwrap().secret().to_owned();
-  let refresh_token_expires_at = Local::now().naive_utc() + Duration::days(7);
+  audit::record(&state.db, meta, AuditEvent::new(Some(user_id), AuditAction::Login, AuditTarget::Session, session.to_string())
+    .after(serde_json::json!({ "provider": provider, "expires_at": max_age, "refreshable": refresh_token.is_some() }))).await?;
 
-  sqlx::query("INSERT INTO refresh_tokens (user_id, refresh_token, expires_at) VALUES (
-    (SELECT id FROM users WHERE email = $1 LIMIT 1),
-    $2, $3)
-    ON CONFLICT (user_id) DO UPDATE SET
-    refresh_token = excluded.refresh_token,
-    expires_at = excluded.expires_at")
-    .bind(&profile.email)
-    .bind(refresh_token)
-    .bind(refresh_token_expires_at)
-    .execute(&state.db)
-    .await?;
+  match refresh_token {
+    Some(refresh_token) => {
+      let refresh_token_expires_at = Utc::now() + Duration::days(REFRESH_TOKEN_EXPIRE_DAYS);
+
+      sqlx::query("INSERT INTO refresh_tokens (user_id, refresh_token, expires_at, provider) VALUES ($1, $2, $3, $4)
+        ON CONFLICT (user_id) DO UPDATE SET
+        refresh_token = excluded.refresh_token,
+        expires_at = excluded.expires_at,
+        provider = excluded.provider")
+        .bind(user_id)
+        .bind(refresh_token)
+        .bind(refresh_token_expires_at)
+        .bind(provider)
+        .execute(&state.db)
+        .await?;
+    },
+    None => {
+      // a refresh token left over from another provider can't refresh this session
+      sqlx::query("DELETE FROM refresh_tokens WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&state.db)
+        .await?;
+    },
+  };
+
+  Ok(build_session_cookie(&state.env, session_id))
+}
+
+async fn complete_login(
+  state: RouterState,
+  meta: RequestMeta,
+  jar: PrivateCookieJar,
+  provider: &IdentityProvider,
+  query: AuthRequest,
+) -> Result<impl IntoResponse, ApiError> {
+  let login_state = LoginState::take(&jar, &provider.name, &query.state)?;
+  let jar = jar.remove(build_login_state_removal_cookie(&state.env));
+
+  let token = provider.exchange_code(query.code, login_state).await?;
+  let access_token = token.access_token().secret().to_owned();
+
+  let profile = provider.fetch_profile(&state.ctx, &access_token).await?;
+  let user_id = find_or_create_user(&state, &provider.name, &profile).await?;
+
+  // providers like github issue non-expiring tokens without an expires_in
+  let expires_in: Option<i64> = token.expires_in()
+    .map(|secs| secs.as_secs().try_into())
+    .transpose()?;
+
+  let refresh_token = token.refresh_token().map(|token| token.secret().to_owned());
+  let jar = jar.add(start_session(&state, &meta, user_id, &provider.name, access_token, expires_in, refresh_token).await?);
+
+  Ok((
+    jar,
+    Redirect::to("/protected")
+  ))
+}
+
+pub async fn callback(
+  State(state): State<RouterState>,
+  meta: RequestMeta,
+  jar: PrivateCookieJar,
+  Path(provider): Path<String>,
+  Query(query): Query<AuthRequest>,
+  Extension(providers): Extension<IdentityProviders>,
+) -> Result<impl IntoResponse, ApiError> {
+  let provider = providers.get(&provider)?;
+  complete_login(state, meta, jar, provider, query).await
+}
+
+pub async fn google_callback(
+  State(state): State<RouterState>,
+  meta: RequestMeta,
+  jar: PrivateCookieJar,
+  Query(query): Query<AuthRequest>,
+  Extension(providers): Extension<IdentityProviders>,
+) -> Result<impl IntoResponse, ApiError> {
+  let provider = providers.get("google")?;
+  complete_login(state, meta, jar, provider, query).await
+}
+
+pub async fn dev_login(
+  State(state): State<RouterState>,
+  meta: RequestMeta,
+  jar: PrivateCookieJar,
+  Query(query): Query<DevLoginRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+  validate_payload(&query)?;
+
+  let profile = ExternalProfile {
+    subject: query.name.clone(),
+    email: format!("{}@{}", query.name, DEV_EMAIL_DOMAIN),
+    email_verified: true,
+    name: query.name,
+  };
+
+  let user_id = find_or_create_user(&state, DEV_PROVIDER, &profile).await?;
+  log::info!("dev login as {} (user {})", profile.email, user_id);
+
+  let jar = jar.add(start_session(&state, &meta, user_id, DEV_PROVIDER, nanoid!(64), None, None).await?);
 
   Ok((
-    jar.add(cookie),
+    jar,
     Redirect::to("/protected")
   ))
 }
 
+fn start_login(state: &RouterState, jar: PrivateCookieJar, provider: &IdentityProvider) -> Result<impl IntoResponse, ApiError> {
+  let (url, login_state) = provider.login_url();
+  let jar = jar.add(build_login_state_cookie(&state.env, &login_state)?);
+
+  Ok((jar, url))
+}
+
 pub async fn get_login_url(
   State(state): State<RouterState>,
-  Extension(oauth_id): Extension<String>,
+  jar: PrivateCookieJar,
+  Path(provider): Path<String>,
+  Extension(providers): Extension<IdentityProviders>,
+) -> Result<impl IntoResponse, ApiError> {
+  start_login(&state, jar, providers.get(&provider)?)
+}
+
+pub async fn get_google_login_url(
+  State(state): State<RouterState>,
+  jar: PrivateCookieJar,
+  Extension(providers): Extension<IdentityProviders>,
+) -> Result<impl IntoResponse, ApiError> {
+  start_login(&state, jar, providers.get("google")?)
+}
+
+pub async fn get_providers(
+  Extension(providers): Extension<IdentityProviders>,
 ) -> impl IntoResponse {
-  let redirect_url = state.env.frontend_url;
-  format!("https://accounts.google.com/o/oauth2/v2/auth?access_type=offline&prompt=consent&scope=openid%20profile%20email&client_id={oauth_id}&response_type=code&redirect_uri={redirect_url}/auth/google_callback").into_response()
+  Json(providers.names())
+}
+
+// a code planted by someone else would link their identity to this account
+pub async fn link_identity(
+  profile: UserProfile,
+  State(state): State<RouterState>,
+  jar: PrivateCookieJar,
+  Path(provider): Path<String>,
+  Query(query): Query<AuthRequest>,
+  Extension(providers): Extension<IdentityProviders>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_session()?;
+
+  let provider = providers.get(&provider)?;
+  let login_state = LoginState::take(&jar, &provider.name, &query.state)?;
+  let jar = jar.remove(build_login_state_removal_cookie(&state.env));
+
+  let token = provider.exchange_code(query.code, login_state).await?;
+  let external = provider.fetch_profile(&state.ctx, token.access_token().secret()).await?;
+
+  // the conflict update only applies when the identity already belongs to this user
+  let identity: Option<LinkedIdentity> = sqlx::query_as(
+    "INSERT INTO user_identities (user_id, provider, subject, email, email_verified)
+    VALUES ($1, $2, $3, $4, $5)
+    ON CONFLICT (provider, subject) DO UPDATE SET
+    email = excluded.email,
+    email_verified = excluded.email_verified
+    WHERE user_identities.user_id = excluded.user_id
+    RETURNING id, provider, email, email_verified, created_at")
+    .bind(profile.id)
+    .bind(&provider.name)
+    .bind(&external.subject)
+    .bind(&external.email)
+    .bind(external.email_verified)
+    .fetch_optional(&state.db)
+    .await?;
+
+  match identity {
+    Some(identity) => Ok((StatusCode::CREATED, jar, Json(identity))),
+    None => Err(ApiError::Conflict("this identity is already linked to another account".to_string())),
+  }
+}
+
+pub async fn get_identities(
+  profile: UserProfile,
+  State(state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ProfileRead)?;
+
+  let identities: Vec<LinkedIdentity> = sqlx::query_as(
+    "SELECT id, provider, email, email_verified, created_at FROM user_identities
+    WHERE user_id = $1 ORDER BY created_at")
+    .bind(profile.id)
+    .fetch_all(&state.db)
+    .await?;
+
+  Ok(Json(identities))
+}
+
+pub async fn unlink_identity(
+  profile: UserProfile,
+  State(state): State<RouterState>,
+  Path(id): Path<i32>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_session()?;
+
+  let mut tx = state.db.begin().await?;
+
+  // lock the user so concurrent unlinks can't both pass the last identity check
+  sqlx::query("SELECT id FROM users WHERE id = $1 FOR UPDATE")
+    .bind(profile.id)
+    .execute(&mut *tx)
+    .await?;
+
+  let identity: Option<(String,)> = sqlx::query_as("SELECT provider FROM user_identities WHERE id = $1 AND user_id = $2")
+    .bind(id)
+    .bind(profile.id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+  let Some((provider,)) = identity else {
+    return Err(ApiError::NotFound("identity not found".to_string()));
+  };
+
+  let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM user_identities WHERE user_id = $1")
+    .bind(profile.id)
+    .fetch_one(&mut *tx)
+    .await?;
+
+  if count <= 1 {
+    return Err(ApiError::Conflict("cannot unlink the last identity of an account".to_string()));
+  }
+
+  sqlx::query("DELETE FROM user_identities WHERE id = $1")
+    .bind(id)
+    .execute(&mut *tx)
+    .await?;
+
+  // the stored refresh token is useless once no identity of its provider is linked
+  sqlx::query("DELETE FROM refresh_tokens WHERE user_id = $1 AND provider = $2
+    AND NOT EXISTS (SELECT 1 FROM user_identities WHERE user_id = $1 AND provider = $2)")
+    .bind(profile.id)
+    .bind(&provider)
+    .execute(&mut *tx)
+    .await?;
+
+  tx.commit().await?;
+  Ok(StatusCode::NO_CONTENT)
 }
 
 pub async fn validate(
   profile: UserProfile,
-) -> impl IntoResponse {
-  (StatusCode::OK, axum::Json(profile))
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ProfileRead)?;
+
+  Ok((StatusCode::OK, axum::Json(profile)))
 }
 
 pub async fn logout(
+  meta: RequestMeta,
   profile: UserProfile,
   jar: PrivateCookieJar,
   State(state): State<RouterState>,
 ) -> Result<impl IntoResponse, ApiError> {
+  profile.require_session()?;
+
+  let mut tx = state.db.begin().await?;
+
   // remove from sessions
-  let _ = sqlx::query("DELETE FROM sessions WHERE user_id = $1")
-    .bind(&profile.id)
-    .execute(&state.db)
-    .await;
+  let session: Option<(i32,)> = sqlx::query_as("DELETE FROM sessions WHERE user_id = $1 RETURNING id")
+    .bind(profile.id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+  if let Some((session,)) = session {
+    audit::record(&mut *tx, &meta, AuditEvent::new(Some(profile.id), AuditAction::Logout, AuditTarget::Session, session.to_string())).await?;
+  }
 
   // remove from refresh_tokens
-  let _ = sqlx::query("DELETE FROM refresh_tokens WHERE user_id = $1")
-    .bind(&profile.id)
-    .execute(&state.db)
-    .await;
+  sqlx::query("DELETE FROM refresh_tokens WHERE user_id = $1")
+    .bind(profile.id)
+    .execute(&mut *tx)
+    .await?;
+
+  tx.commit().await?;
 
   Ok((
-    jar.remove(Cookie::from("sid")),
+    jar.remove(build_session_removal_cookie(&state.env)),
     StatusCode::OK
   ))
 }
+
+#[cfg(test)]
+mod tests {
+  use std::sync::{Arc, Mutex};
+
+  use axum::{extract::Form, http::{header::AUTHORIZATION, HeaderMap}, routing::{get, post}, Router};
+  use oauth2::{PkceCodeChallenge, PkceCodeVerifier};
+  use serde_json::{json, Value};
+  use tokio::net::TcpListener;
+
+  use crate::{auth::{build_identity_providers, oidc::discover_provider}, env::{test_env, OAuthCredentials, OidcConfig}, test_support::{test_db, test_state}};
+
+  use super::*;
+
+  const PROVIDER: &str = "mock";
+  const AUTH_CODE: &str = "mock-code";
+  const ACCESS_TOKEN: &str = "mock-access-token";
+
+  // The parts of an OIDC issuer a login goes through. Codes are only redeemed with the
+  // verifier of the last login url the issuer saw.
+  #[derive(Clone)]
+  struct MockIssuer {
+    url: String,
+    challenge: Arc<Mutex<Option<String>>>,
+    userinfo: Arc<Mutex<Value>>,
+  }
+
+  #[derive(Deserialize)]
+  struct TokenRequest {
+    grant_type: String,
+    code: String,
+    code_verifier: String,
+  }
+
+  async fn discovery(State(issuer): State<MockIssuer>) -> Json<Value> {
+    Json(json!({
+      "issuer": issuer.url,
+      "authorization_endpoint": format!("{}/authorize", issuer.url),
+      "token_endpoint": format!("{}/token", issuer.url),
+      "userinfo_endpoint": format!("{}/userinfo", issuer.url),
+    }))
+  }
+
+  async fn token(
+    State(issuer): State<MockIssuer>,
+    headers: HeaderMap,
+    Form(request): Form<TokenRequest>,
+  ) -> Result<Json<Value>, StatusCode> {
+    let challenge = PkceCodeChallenge::from_code_verifier_sha256(&PkceCodeVerifier::new(request.code_verifier));
+    let expected = issuer.challenge.lock().unwrap().clone();
+
+    // client credentials come as basic auth
+    let valid = request.grant_type == "authorization_code"
+      && request.code == AUTH_CODE
+      && expected.as_deref() == Some(challenge.as_str())
+      && headers.contains_key(AUTHORIZATION);
+
+    if !valid {
+      return Err(StatusCode::BAD_REQUEST);
+    }
+
+    Ok(Json(json!({ "access_token": ACCESS_TOKEN, "token_type": "bearer", "expires_in": 3600 })))
+  }
+
+  async fn userinfo(State(issuer): State<MockIssuer>, headers: HeaderMap) -> Result<Json<Value>, StatusCode> {
+    let bearer = format!("Bearer {}", ACCESS_TOKEN);
+    if headers.get(AUTHORIZATION).and_then(|value| value.to_str().ok()) != Some(bearer.as_str()) {
+      return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(Json(issuer.userinfo.lock().unwrap().clone()))
+  }
+
+  async fn serve(app: Router) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+
+    tokio::spawn(async move {
+      axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>()).await
+    });
+
+    url
+  }
+
+  async fn start_issuer(userinfo: Value) -> MockIssuer {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let issuer = MockIssuer {
+      url: format!("http://{}", listener.local_addr().unwrap()),
+      challenge: Arc::default(),
+      userinfo: Arc::new(Mutex::new(userinfo)),
+    };
+
+    let app = Router::new()
+      .route("/.well-known/openid-configuration", get(discovery))
+      .route("/token", post(token))
+      .route("/userinfo", get(self::userinfo))
+      .with_state(issuer.clone());
+
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    issuer
+  }
+
+  // Takes the browser to the issuer, which remembers the challenge and hands back the state.
+  fn authorize(issuer: &MockIssuer, login_url: &str) -> String {
+    let url = reqwest::Url::parse(login_url).unwrap();
+    let param = |name: &str| url.query_pairs()
+      .find(|(key, _)| key == name)
+      .map(|(_, value)| value.into_owned())
+      .unwrap();
+
+    *issuer.challenge.lock().unwrap() = Some(param("code_challenge"));
+    param("state")
+  }
+
+  fn credentials() -> OAuthCredentials {
+    OAuthCredentials { client_id: "shaderx".to_string(), client_secret: "secret".to_string() }
+  }
+
+  #[tokio::test]
+  async fn signs_in_against_a_discovered_issuer() {
+    let issuer = start_issuer(json!({
+      "sub": "1234",
+      "email": "ada@example.com",
+      "email_verified": "true",
+      "preferred_username": "ada",
+    })).await;
+
+    let http = reqwest::Client::new();
+    let provider = discover_provider(&http, PROVIDER, &issuer.url, &credentials(), "http://localhost/auth/mock/callback".to_string())
+      .await.unwrap();
+
+    let (url, state) = provider.login_url();
+    assert!(url.starts_with(&format!("{}/authorize?", issuer.url)));
+    assert_eq!(authorize(&issuer, &url), state.csrf);
+
+    // the verifier of another login doesn't redeem this code
+    let (_, other) = provider.login_url();
+    assert!(provider.exchange_code(AUTH_CODE.to_string(), other).await.is_err());
+
+    let token = provider.exchange_code(AUTH_CODE.to_string(), state).await.unwrap();
+    let profile = provider.fetch_profile(&http, token.access_token().secret()).await.unwrap();
+
+    assert_eq!(profile.subject, "1234");
+    assert_eq!(profile.email, "ada@example.com");
+    assert!(profile.email_verified);
+    assert_eq!(profile.name, "ada");
+  }
+
+  // Goes through the login and callback routes like a browser would, `None` when the
+  // callback didn't sign in.
+  async fn sign_in(app_url: &str, issuer: &MockIssuer) -> Option<reqwest::Response> {
+    let http = reqwest::Client::builder()
+      .redirect(reqwest::redirect::Policy::none())
+      .build().unwrap();
+
+    let response = http.get(format!("{}/auth/{}/login", app_url, PROVIDER)).send().await.unwrap();
+    let cookie = response.headers()[reqwest::header::SET_COOKIE].to_str().unwrap()
+      .split(';').next().unwrap()
+      .to_string();
+
+    let state = authorize(issuer, &response.text().await.unwrap());
+
+    let response = http.post(format!("{}/auth/{}/callback", app_url, PROVIDER))
+      .query(&[("code", AUTH_CODE), ("state", &state)])
+      .header(reqwest::header::COOKIE, cookie)
+      .send().await.unwrap();
+
+    response.status().is_redirection().then_some(response)
+  }
+
+  async fn identity_of(db: &sqlx::PgPool, subject: &str) -> Option<(i32, bool, String)> {
+    sqlx::query_as("SELECT users.id, user_identities.email_verified, users.email FROM user_identities
+      INNER JOIN users ON users.id = user_identities.user_id
+      WHERE user_identities.provider = $1 AND user_identities.subject = $2")
+      .bind(PROVIDER)
+      .bind(subject)
+      .fetch_optional(db)
+      .await.unwrap()
+  }
+
+  #[tokio::test]
+  async fn callback_creates_identities() {
+    let Some(db) = test_db().await else {
+      return;
+    };
+
+    let verified = format!("verified-{}", nanoid!());
+    let unverified = format!("unverified-{}", nanoid!());
+    let email = format!("{}@example.com", verified);
+
+    let issuer = start_issuer(json!({ "sub": verified, "email": email, "email_verified": true, "name": "Ada" })).await;
+
+    let mut env = test_env();
+    env.oidc = Some(OidcConfig { name: PROVIDER.to_string(), issuer_url: issuer.url.clone(), credentials: credentials() });
+
+    let state = test_state(db.clone());
+    let providers = build_identity_providers(&env, &state.ctx).await.unwrap();
+
+    let app_url = serve(Router::new()
+      .route("/auth/:provider/login", get(get_login_url))
+      .route("/auth/:provider/callback", post(callback))
+      .with_state(state)
+      .layer(Extension(providers))).await;
+
+    let response = sign_in(&app_url, &issuer).await.expect("verified login failed");
+    assert!(response.headers().get_all(reqwest::header::SET_COOKIE).iter().any(|cookie| cookie.as_bytes().starts_with(b"sid=")));
+
+    let (user_id, email_verified, user_email) = identity_of(&db, &verified).await.expect("identity wasn't created");
+    assert!(email_verified);
+    assert_eq!(user_email, email);
+
+    // signing in again finds the same account
+    sign_in(&app_url, &issuer).await.expect("second login failed");
+    assert_eq!(identity_of(&db, &verified).await.map(|(id, _, _)| id), Some(user_id));
+
+    // an unverified address of someone else's account is refused instead of claimed
+    *issuer.userinfo.lock().unwrap() = json!({ "sub": unverified, "email": email, "email_verified": false, "name": "Mallory" });
+    assert!(sign_in(&app_url, &issuer).await.is_none());
+    assert!(identity_of(&db, &unverified).await.is_none());
+
+    let fresh = format!("{}@example.com", unverified);
+    *issuer.userinfo.lock().unwrap() = json!({ "sub": unverified, "email": fresh, "email_verified": false, "name": "Mallory" });
+    sign_in(&app_url, &issuer).await.expect("unverified login failed");
+
+    let (other_id, email_verified, user_email) = identity_of(&db, &unverified).await.expect("identity wasn't created");
+    assert_ne!(other_id, user_id);
+    assert!(!email_verified);
+    assert!(user_email.ends_with(&format!("@{}", UNVERIFIED_EMAIL_DOMAIN)));
+
+    sqlx::query("DELETE FROM sessions WHERE user_id = ANY($1)").bind([user_id, other_id]).execute(&db).await.unwrap();
+    sqlx::query("DELETE FROM users WHERE id = ANY($1)").bind([user_id, other_id]).execute(&db).await.unwrap();
+  }
+}
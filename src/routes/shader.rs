@@ -1,21 +1,152 @@
 
-use axum::{extract::{Path, State}, http::StatusCode, response::IntoResponse, routing::{delete, get, post, put}, Json};
+use std::collections::{HashMap, HashSet};
+
+use axum::{body::{Body, Bytes}, extract::{DefaultBodyLimit, Path, Query, State}, http::{header::{CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS}, StatusCode}, response::IntoResponse, routing::{delete, get, post, put}, Json};
 use nanoid::nanoid;
 use serde::{Deserialize, Serialize};
-use sqlx::{prelude::FromRow, Execute};
+use sha2::{Digest, Sha256};
+use sqlx::{prelude::FromRow, PgConnection};
+
+use crate::{audit::{self, AuditAction, AuditEvent, AuditTarget, RequestMeta}, archive::{self, ParsedArchive, ParsedShader}, assets::{copy_asset_in, inspect_image, store_asset_in, thumbnail::{delete_thumbnail_files, render_thumbnails, thumbnail_key, ThumbnailImage, Thumbnails}, verify_channel_assets, Asset, ASSET_ID_LENGTH, ASSET_QUERY}, constants::{ARCHIVE_MAX_UPLOAD_BYTES, THUMBNAIL_MAX_UPLOAD_BYTES}, errors::{ApiError, FieldError}, includes::verify_includes, licenses::{check_license, find_license, license_for, Attribution, DEFAULT_LICENSE}, quotas, router_state::{RouterState, UserProfile}, routes::{embed::{get_embed_settings, update_embed_settings}, featured::get_featured, library::{get_library, get_resolved_shader, library_path, publish_library_revision}, template::find_template}, shadertoy::{self, ShadertoyDocument, ShadertoyImport, UnmappedItem}, tokens::TokenScope, validation::{validate_shader_payload, ValidatedJson, Validate, Validator}};
 
-use crate::router_state::{RouterState, UserProfile};
+const SHADER_ID_LENGTH: usize = 6;
+pub const MAX_SHADER_NAME_LENGTH: usize = 255;
+pub const MAX_SHADER_DESCRIPTION_LENGTH: usize = 8192;
+const MAX_PASS_NAME_LENGTH: usize = 32;
+pub const MAX_CHANNELS: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PassKind {
+  Buffer,
+  Common,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextureFilter {
+  Nearest,
+  #[default]
+  Linear,
+  Mipmap,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextureWrap {
+  #[default]
+  Clamp,
+  Repeat,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ChannelSource {
+  Asset { asset_id: String },
+  Buffer { pass: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelBinding {
+  pub channel: u8,
+  #[serde(flatten)]
+  pub source: ChannelSource,
+  #[serde(default)]
+  pub filter: TextureFilter,
+  #[serde(default)]
+  pub wrap: TextureWrap,
+  #[serde(default)]
+  pub vflip: bool,
+}
+
+impl ChannelBinding {
+  pub fn asset_id(&self) -> Option<&str> {
+    match &self.source {
+      ChannelSource::Asset { asset_id } => Some(asset_id),
+      ChannelSource::Buffer { .. } => None,
+    }
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShaderPass {
+  pub name: String,
+  pub kind: PassKind,
+  pub code: String,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub channels: Vec<ChannelBinding>,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ShaderData {
   pub code: String,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub channels: Vec<ChannelBinding>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub passes: Vec<ShaderPass>,
+}
+
+impl ShaderData {
+  pub fn channel_bindings(&self) -> impl Iterator<Item = (String, &ChannelBinding)> {
+    let image = self.channels.iter().enumerate()
+      .map(|(i, binding)| (format!("data.channels[{}]", i), binding));
+
+    let passes = self.passes.iter().enumerate().flat_map(|(p, pass)| {
+      pass.channels.iter().enumerate()
+        .map(move |(i, binding)| (format!("data.passes[{}].channels[{}]", p, i), binding))
+    });
+
+    image.chain(passes)
+  }
+
+  pub fn channel_bindings_mut(&mut self) -> impl Iterator<Item = &mut ChannelBinding> {
+    let passes = self.passes.iter_mut().flat_map(|pass| pass.channels.iter_mut());
+    self.channels.iter_mut().chain(passes)
+  }
+
+  fn validate_channels(&self, v: &mut Validator, field: &str, channels: &[ChannelBinding]) {
+    for (i, binding) in channels.iter().enumerate() {
+      let field = format!("{}[{}]", field, i);
+
+      v.check(
+        &format!("{}.channel", field),
+        binding.channel < MAX_CHANNELS,
+        format!("must be between 0 and {}", MAX_CHANNELS - 1),
+      );
+      v.check(
+        &format!("{}.channel", field),
+        !channels[..i].iter().any(|other| other.channel == binding.channel),
+        "channel is bound more than once",
+      );
+
+      match &binding.source {
+        ChannelSource::Asset { asset_id } => v.check(
+          &format!("{}.asset_id", field),
+          asset_id.len() == ASSET_ID_LENGTH,
+          "is not a valid asset id",
+        ),
+        ChannelSource::Buffer { pass } => v.check(
+          &format!("{}.pass", field),
+          self.passes.iter().any(|other| other.kind == PassKind::Buffer && other.name == *pass),
+          "must name a buffer pass of this shader",
+        ),
+      }
+    }
+  }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct NewShaderData {
   pub name: String,
   pub description: String,
-  pub data: ShaderData,
+  #[serde(default)]
+  pub data: Option<ShaderData>,
+  #[serde(default)]
+  pub template_id: Option<String>,
+  #[serde(default)]
+  pub tags: Option<sqlx::types::JsonValue>,
+  #[serde(default)]
+  pub license: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -25,9 +156,91 @@ pub struct UpdateShaderData {
   pub data: Option<ShaderData>,
   pub access: Option<AccessLevel>,
   pub tags: Option<sqlx::types::JsonValue>,
+  pub license: Option<String>,
+}
+
+impl Validate for ShaderData {
+  fn validate(&self, v: &mut Validator) {
+    // fails closed, a document checked without limits is never accepted
+    let Some(limits) = v.limits() else {
+      v.error("data", "shader documents can't be checked without the shader limits");
+      return;
+    };
+
+    v.max_bytes("data.code", self.code.len(), limits.max_pass_code_bytes);
+    self.validate_channels(v, "data.channels", &self.channels);
+    v.check(
+      "data.passes",
+      self.passes.len() < limits.max_passes,
+      format!("a shader can have at most {} passes including the image pass", limits.max_passes),
+    );
+    v.check(
+      "data.passes",
+      self.passes.iter().filter(|pass| pass.kind == PassKind::Common).count() <= 1,
+      "a shader can have only one common pass",
+    );
+
+    for (i, pass) in self.passes.iter().enumerate() {
+      v.length(&format!("data.passes[{}].name", i), &pass.name, 1, MAX_PASS_NAME_LENGTH);
+      v.check(
+        &format!("data.passes[{}].name", i),
+        !self.passes[..i].iter().any(|other| other.name == pass.name),
+        "pass names must be unique",
+      );
+      v.max_bytes(&format!("data.passes[{}].code", i), pass.code.len(), limits.max_pass_code_bytes);
+      v.check(
+        &format!("data.passes[{}].channels", i),
+        pass.kind != PassKind::Common || pass.channels.is_empty(),
+        "the common pass has no channels",
+      );
+      self.validate_channels(v, &format!("data.passes[{}].channels", i), &pass.channels);
+    }
+
+    // serializing can't fail for plain strings and enums, the size is what ends up in the data column
+    let size = serde_json::to_vec(self).map(|data| data.len()).unwrap_or(0);
+    v.max_bytes("data", size, limits.max_document_bytes);
+  }
+}
+
+impl Validate for NewShaderData {
+  fn validate(&self, v: &mut Validator) {
+    v.length("name", &self.name, 1, MAX_SHADER_NAME_LENGTH);
+    v.length("description", &self.description, 0, MAX_SHADER_DESCRIPTION_LENGTH);
+
+    match (&self.data, &self.template_id) {
+      (Some(data), None) => data.validate(v),
+      (Some(_), Some(_)) => v.error("template_id", "can't be combined with data"),
+      (None, Some(_)) => (),
+      (None, None) => v.error("data", "data or template_id is required"),
+    }
+
+    if let Some(license) = &self.license {
+      v.check("license", find_license(license).is_some(), "must be a supported SPDX license identifier");
+    }
+  }
 }
 
-#[derive(Debug, Serialize, Deserialize, sqlx::Type)]
+impl Validate for UpdateShaderData {
+  fn validate(&self, v: &mut Validator) {
+    if let Some(name) = &self.name {
+      v.length("name", name, 1, MAX_SHADER_NAME_LENGTH);
+    }
+
+    if let Some(description) = &self.description {
+      v.length("description", description, 0, MAX_SHADER_DESCRIPTION_LENGTH);
+    }
+
+    if let Some(data) = &self.data {
+      data.validate(v);
+    }
+
+    if let Some(license) = &self.license {
+      v.check("license", find_license(license).is_some(), "must be a supported SPDX license identifier");
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
 #[sqlx(type_name = "access_level", rename_all = "lowercase")]
 pub enum AccessLevel {
   Public,
@@ -43,23 +256,66 @@ pub struct Shader {
   pub access: AccessLevel,
   pub tags: sqlx::types::JsonValue,
   pub data: sqlx::types::Json<ShaderData>,
+  pub thumbnails: Option<sqlx::types::Json<Thumbnails>>,
+  pub embed_enabled: bool,
+  pub embed_origins: Vec<String>,
+  pub license: String,
+  pub attribution: sqlx::types::Json<Vec<Attribution>>,
+  pub forked_from: Option<String>,
+  pub hidden: bool,
+  pub access_locked: bool,
+  pub deleted_at: Option<chrono::DateTime<chrono::Utc>>,
   pub created_at: chrono::DateTime<chrono::Utc>,
   pub updated_at: chrono::DateTime<chrono::Utc>,
 }
 
+impl Shader {
+  pub fn audit_snapshot(&self) -> serde_json::Value {
+    let data = serde_json::to_vec(&self.data.0).unwrap_or_default();
+
+    serde_json::json!({
+      "name": self.name,
+      "description": self.description,
+      "access": self.access,
+      "tags": self.tags,
+      "data_sha256": hex::encode(Sha256::digest(&data)),
+      "license": self.license,
+      "attribution": self.attribution,
+      "embed_enabled": self.embed_enabled,
+      "embed_origins": self.embed_origins,
+      "hidden": self.hidden,
+      "access_locked": self.access_locked,
+    })
+  }
+}
+
+async fn record_shader_create(conn: &mut PgConnection, meta: &RequestMeta, profile: &UserProfile, shader: &Shader) -> Result<(), ApiError> {
+  audit::record(&mut *conn, meta, AuditEvent::new(Some(profile.id), AuditAction::ShaderCreate, AuditTarget::Shader, &shader.id)
+    .after(shader.audit_snapshot())).await
+}
+
+async fn record_shader_update(conn: &mut PgConnection, meta: &RequestMeta, profile: &UserProfile, before: &Shader, after: &Shader) -> Result<(), ApiError> {
+  audit::record(&mut *conn, meta, AuditEvent::new(Some(profile.id), AuditAction::ShaderUpdate, AuditTarget::Shader, &after.id)
+    .changes(&before.audit_snapshot(), &after.audit_snapshot())).await?;
+
+  if before.access != after.access {
+    audit::record(&mut *conn, meta, AuditEvent::new(Some(profile.id), AuditAction::ShaderAccessChange, AuditTarget::Shader, &after.id)
+      .before(serde_json::json!({ "access": before.access }))
+      .after(serde_json::json!({ "access": after.access }))).await?;
+  }
+
+  Ok(())
+}
+
 async fn generate_shader_id(
   router_state: &RouterState
-) -> Result<String, (axum::http::StatusCode, &'static str)> {
+) -> Result<String, ApiError> {
   loop {
-    let id = nanoid!(6);
-    let shader: Option<Shader> = sqlx::query_as("SELECT id FROM shaders WHERE id = $1")
+    let id = nanoid!(SHADER_ID_LENGTH);
+    let shader: Option<(String,)> = sqlx::query_as("SELECT id FROM shaders WHERE id = $1")
       .bind(&id)
       .fetch_optional(&router_state.db)
-      .await
-      .map_err(|e| {
-        log::error!("failed to execute query: {:?}", e);
-        (axum::http::StatusCode::INTERNAL_SERVER_ERROR, "failed to execute query")
-      })?;
+      .await?;
 
     if shader.is_none() {
       return Ok(id);
@@ -67,72 +323,651 @@ async fn generate_shader_id(
   }
 }
 
-async fn get_shader_by_id(
+pub async fn get_shader_by_id(
   router_state: &RouterState,
   id: &str,
   profile: &UserProfile,
-) -> Result<Shader, (axum::http::StatusCode, &'static str)> {
-  let shader: Result<Shader, _> = sqlx::query_as("SELECT * FROM shaders WHERE id = $1 AND user_id = $2 AND deleted = false")
+) -> Result<Shader, ApiError> {
+  let shader: Option<Shader> = sqlx::query_as("SELECT * FROM shaders WHERE id = $1 AND user_id = $2 AND deleted = false")
     .bind(id)
-    .bind(&profile.user_id)
-    .fetch_one(&router_state.db)
-    .await;
-
-  match shader {
-    Ok(shader) => Ok(shader),
-    Err(e) => {
-      log::error!("failed to execute query: {:?}", e);
-      Err((axum::http::StatusCode::NOT_FOUND, "shader not found"))
-    }
-  }
+    .bind(profile.user_id)
+    .fetch_optional(&router_state.db)
+    .await?;
+
+  shader.ok_or_else(|| ApiError::NotFound("shader not found".to_string()))
+}
+
+async fn create_shader(
+  router_state: &RouterState,
+  meta: &RequestMeta,
+  profile: &UserProfile,
+  new_shader: NewShaderData,
+  attribution: Vec<Attribution>,
+) -> Result<Shader, ApiError> {
+  let license = license_for("license", new_shader.license.as_deref(), &profile.default_license, &attribution)
+    .map_err(|e| ApiError::Validation(vec![e]))?;
+
+  let data = match new_shader.data {
+    Some(data) => data,
+    None => {
+      let template_id = new_shader.template_id.as_deref().unwrap_or_default();
+      let template = find_template(&router_state.db, Some(profile.user_id), template_id).await?
+        .ok_or_else(|| ApiError::Validation(vec![FieldError::new("template_id", "template not found")]))?;
+
+      // the template may have been saved under looser limits
+      validate_shader_payload(&template.data.0, &router_state.env.shader_limits)?;
+      template.data.0
+    },
+  };
+
+  verify_channel_assets(router_state, profile, &data).await?;
+  verify_includes(&router_state.db, profile.user_id, AccessLevel::Private, None, &data, router_state.env.shader_limits.max_document_bytes).await?;
+
+  let id = generate_shader_id(router_state).await?;
+
+  let mut tx = router_state.db.begin().await?;
+  let usage = quotas::lock_usage(&mut tx, profile.id).await?;
+
+  let shader: Shader = sqlx::query_as(
+    "INSERT INTO shaders (user_id, id, name, description, data, tags, license, attribution) VALUES (
+      (SELECT user_id FROM users WHERE user_id = $1 LIMIT 1), $2, $3, $4, $5, COALESCE($6, '{}'), $7, $8)
+      RETURNING *"
+    )
+    .bind(profile.user_id)
+    .bind(&id)
+    .bind(new_shader.name.trim())
+    .bind(&new_shader.description)
+    .bind(sqlx::types::Json(data))
+    .bind(new_shader.tags)
+    .bind(license)
+    .bind(sqlx::types::Json(attribution))
+    .fetch_one(&mut *tx)
+    .await?;
+
+  quotas::check_usage(&mut tx, &router_state.env, profile.id, &usage).await?;
+  record_shader_create(&mut tx, meta, profile, &shader).await?;
+  tx.commit().await?;
+
+  Ok(shader)
 }
 
 pub async fn add_shader(
   State(router_state): State<RouterState>,
+  meta: RequestMeta,
   profile: UserProfile,
-  Json(new_shader): Json<NewShaderData>
-) -> Result<impl IntoResponse, impl IntoResponse> {
-  let id = generate_shader_id(&router_state).await?;
+  ValidatedJson(new_shader): ValidatedJson<NewShaderData>
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersWrite)?;
 
-  let result = sqlx::query(
-    "INSERT INTO shaders (user_id, id, name, description, data) VALUES (
-      (SELECT user_id FROM users WHERE user_id = $1 LIMIT 1), $2, $3, $4, $5)"
-    )
-    .bind(&profile.user_id)
+  let shader = create_shader(&router_state, &meta, &profile, new_shader, Vec::new()).await?;
+  Ok(Json(shader))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForkShader {
+  pub name: Option<String>,
+  pub license: Option<String>,
+}
+
+impl Validate for ForkShader {
+  fn validate(&self, v: &mut Validator) {
+    if let Some(name) = &self.name {
+      v.length("name", name, 1, MAX_SHADER_NAME_LENGTH);
+    }
+
+    if let Some(license) = &self.license {
+      v.check("license", find_license(license).is_some(), "must be a supported SPDX license identifier");
+    }
+  }
+}
+
+#[derive(Debug, FromRow)]
+struct ForkSource {
+  #[sqlx(flatten)]
+  shader: Shader,
+  owner_id: i32,
+  author: String,
+}
+
+pub async fn fork_shader(
+  Path(id): Path<String>,
+  meta: RequestMeta,
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+  ValidatedJson(fork): ValidatedJson<ForkShader>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersWrite)?;
+
+  let source: Option<ForkSource> = sqlx::query_as(
+    "SELECT shaders.*, users.id AS owner_id, COALESCE(users.username, users.name, '') AS author
+    FROM shaders INNER JOIN users ON users.user_id = shaders.user_id
+    WHERE shaders.id = $1 AND shaders.deleted = false AND users.deleted = false
+    AND ((shaders.access <> 'private' AND shaders.hidden = false) OR shaders.user_id = $2)")
     .bind(&id)
-    .bind(&new_shader.name)
-    .bind(&new_shader.description)
-    .bind(sqlx::types::Json(new_shader.data))
-    .execute(&router_state.db)
-    .await;
-
-  match result {
-    Err(e) => return {
-      log::error!("failed to execute query: {:?}", e);
-      Err((StatusCode::INTERNAL_SERVER_ERROR, "failed to execute query"))
-    },
-    _ => (),
+    .bind(profile.user_id)
+    .fetch_optional(&router_state.db)
+    .await?;
+
+  let ForkSource { shader: source, owner_id, author } = source
+    .ok_or_else(|| ApiError::NotFound("shader not found".to_string()))?;
+  let own = owner_id == profile.id;
+
+  if !own && find_license(&source.license).is_some_and(|license| license.terms.no_derivatives) {
+    return Err(ApiError::Forbidden(format!("{} doesn't allow derivatives", source.license)));
+  }
+
+  let mut attribution = source.attribution.0;
+  if !own {
+    attribution.push(Attribution {
+      shader_id: Some(source.id.clone()),
+      name: source.name.clone(),
+      author,
+      license: source.license.clone(),
+      url: Some(format!("{}/shader/{}", router_state.env.frontend_url, source.id)),
+    });
+  }
+
+  let license = license_for("license", fork.license.as_deref(), &source.license, &attribution)
+    .map_err(|e| ApiError::Validation(vec![e]))?;
+
+  // the upstream may have been saved under looser limits
+  let mut data = source.data.0;
+  validate_shader_payload(&data, &router_state.env.shader_limits)?;
+  verify_includes(&router_state.db, profile.user_id, AccessLevel::Private, None, &data, router_state.env.shader_limits.max_document_bytes).await?;
+
+  let mut tx = router_state.db.begin().await?;
+  let usage = quotas::lock_usage(&mut tx, profile.id).await?;
+
+  if own {
+    verify_channel_assets(&router_state, &profile, &data).await?;
+  } else {
+    let ids: Vec<String> = data.channel_bindings()
+      .filter_map(|(_, binding)| binding.asset_id().map(str::to_string))
+      .collect();
+
+    // shared locks keep the owner from deleting the blobs while they're copied
+    let assets: Vec<Asset> = sqlx::query_as(&format!("{} WHERE assets.user_id = $1 AND assets.id = ANY($2) FOR SHARE", ASSET_QUERY))
+      .bind(owner_id)
+      .bind(&ids)
+      .fetch_all(&mut *tx)
+      .await?;
+
+    let mut asset_ids = HashMap::new();
+    for asset in &assets {
+      let (asset_id, _) = copy_asset_in(&mut tx, &router_state, &profile, asset).await?;
+      asset_ids.insert(asset.id.clone(), asset_id);
+    }
+
+    let missing: Vec<FieldError> = data.channel_bindings()
+      .filter(|(_, binding)| binding.asset_id().is_some_and(|asset_id| !asset_ids.contains_key(asset_id)))
+      .map(|(field, _)| FieldError::new(&format!("{}.asset_id", field), "asset not found"))
+      .collect();
+
+    if !missing.is_empty() {
+      return Err(ApiError::Validation(missing));
+    }
+
+    for binding in data.channel_bindings_mut() {
+      if let ChannelSource::Asset { asset_id } = &mut binding.source {
+        if let Some(copied) = asset_ids.get(asset_id.as_str()) {
+          asset_id.clone_from(copied);
+        }
+      }
+    }
+  }
+
+  let fork_id = generate_shader_id(&router_state).await?;
+  let name = fork.name.as_deref().unwrap_or(&source.name).trim().to_string();
+
+  let shader: Shader = sqlx::query_as(
+    "INSERT INTO shaders (user_id, id, name, description, data, tags, license, attribution, forked_from)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING *")
+    .bind(profile.user_id)
+    .bind(&fork_id)
+    .bind(name)
+    .bind(&source.description)
+    .bind(sqlx::types::Json(data))
+    .bind(&source.tags)
+    .bind(license)
+    .bind(sqlx::types::Json(attribution))
+    .bind(&source.id)
+    .fetch_one(&mut *tx)
+    .await?;
+
+  quotas::check_usage(&mut tx, &router_state.env, profile.id, &usage).await?;
+  record_shader_create(&mut tx, &meta, &profile, &shader).await?;
+  tx.commit().await?;
+
+  Ok((StatusCode::CREATED, Json(shader)))
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportedShader {
+  pub shader: Shader,
+  pub unmapped: Vec<UnmappedItem>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+  pub format: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportedShader {
+  pub format: &'static str,
+  pub document: ShadertoyDocument,
+  pub unmapped: Vec<UnmappedItem>,
+}
+
+pub async fn import_shader(
+  State(router_state): State<RouterState>,
+  meta: RequestMeta,
+  profile: UserProfile,
+  Json(document): Json<ShadertoyImport>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersWrite)?;
+
+  let imported = shadertoy::import_shader(document.into_shader())?;
+  let new_shader = NewShaderData {
+    name: imported.name,
+    description: imported.description,
+    data: Some(imported.data),
+    template_id: None,
+    tags: Some(sqlx::types::JsonValue::from(imported.tags)),
+    license: None,
   };
 
-  let shader: Result<Shader, _> = get_shader_by_id(&router_state, &id, &profile).await;
+  validate_shader_payload(&new_shader, &router_state.env.shader_limits)?;
 
-  match shader {
-    Ok(shader) => Ok(Json(shader)),
-    Err(e) => {
-      log::error!("failed to execute query: {:?}", e);
-      Err((StatusCode::INTERNAL_SERVER_ERROR, "failed to execute query"))
+  let shader = create_shader(&router_state, &meta, &profile, new_shader, vec![imported.attribution]).await?;
+  Ok((StatusCode::CREATED, Json(ImportedShader { shader, unmapped: imported.unmapped })))
+}
+
+pub async fn export_shader(
+  Path(id): Path<String>,
+  Query(query): Query<ExportQuery>,
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersRead)?;
+
+  if query.format != "shadertoy" {
+    return Err(ApiError::Validation(vec![FieldError::new("format", "only shadertoy is supported")]));
+  }
+
+  let shader = get_shader_by_id(&router_state, &id, &profile).await?;
+  let username = profile.username.as_deref().unwrap_or(&profile.name);
+  let (document, unmapped) = shadertoy::export_shader(&shader, username);
+
+  Ok(Json(ExportedShader { format: "shadertoy", document, unmapped }))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictStrategy {
+  #[default]
+  NewId,
+  Overwrite,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ArchiveImportQuery {
+  #[serde(default)]
+  pub dry_run: bool,
+  #[serde(default)]
+  pub on_conflict: ConflictStrategy,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveImportAction {
+  Create,
+  NewId,
+  Overwrite,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ArchiveImportEntry {
+  pub archived_id: String,
+  pub id: String,
+  pub name: String,
+  pub action: ArchiveImportAction,
+  pub assets: usize,
+  pub license: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ArchiveImportReport {
+  pub dry_run: bool,
+  pub shaders: Vec<ArchiveImportEntry>,
+}
+
+struct PlannedImport {
+  shader: ParsedShader,
+  id: String,
+  action: ArchiveImportAction,
+  license: &'static str,
+}
+
+pub fn is_shader_id(id: &str) -> bool {
+  id.len() == SHADER_ID_LENGTH && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+pub async fn export_my_shaders(
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersRead)?;
+
+  let shaders: Vec<Shader> = sqlx::query_as(
+    "SELECT * FROM shaders WHERE user_id = $1 AND deleted = false ORDER BY created_at"
+  )
+    .bind(profile.user_id)
+    .fetch_all(&router_state.db)
+    .await?;
+
+  let assets: Vec<Asset> = sqlx::query_as(&format!("{} WHERE assets.user_id = $1", ASSET_QUERY))
+    .bind(profile.id)
+    .fetch_all(&router_state.db)
+    .await?;
+  let assets = assets.into_iter().map(|asset| (asset.id.clone(), asset)).collect();
+
+  let (entries, stream) = archive::zip_stream();
+  let storage = router_state.storage.clone();
+  let author = profile.username.clone().unwrap_or(profile.name.clone());
+
+  tokio::spawn(async move {
+    if let Err(e) = archive::write_shaders(&entries, &storage, author, shaders, assets).await {
+      entries.send(Err(e)).await.ok();
+    }
+  });
+
+  let disposition = format!("attachment; filename=\"shaders-{}.zip\"", chrono::Utc::now().format("%Y-%m-%d"));
+
+  Ok((
+    [
+      (CONTENT_TYPE, "application/zip".to_string()),
+      (CONTENT_DISPOSITION, disposition),
+    ],
+    Body::from_stream(stream),
+  ))
+}
+
+async fn plan_archive_import(
+  conn: &mut PgConnection,
+  router_state: &RouterState,
+  profile: &UserProfile,
+  archive: ParsedArchive,
+  strategy: ConflictStrategy,
+) -> Result<Vec<PlannedImport>, ApiError> {
+  let ParsedArchive { author, mut shaders } = archive;
+  let mut errors = Vec::new();
+  let mut referenced: Vec<(String, String)> = Vec::new();
+  let mut licenses = Vec::new();
+
+  // someone else's export keeps crediting them, under the license they had picked
+  let importer = profile.username.as_deref().unwrap_or(&profile.name);
+  if let Some(author) = author.filter(|author| author != importer) {
+    for shader in &mut shaders {
+      let upstream = shader.meta.license.clone().unwrap_or(DEFAULT_LICENSE.to_string());
+      shader.meta.attribution.push(Attribution {
+        shader_id: None,
+        name: shader.meta.name.trim().to_string(),
+        author: author.clone(),
+        license: upstream,
+        url: None,
+      });
     }
   }
+
+  for shader in &shaders {
+    let mut v = Validator::default().with_limits(&router_state.env.shader_limits);
+    shader.validate(&mut v);
+    errors.extend(v.into_errors().into_iter().map(|e| FieldError::new(&format!("{}.{}", shader.dir, e.field), e.message)));
+
+    let field = format!("{}.license", shader.dir);
+    match license_for(&field, shader.meta.license.as_deref(), &profile.default_license, &shader.meta.attribution) {
+      Ok(license) => licenses.push(license),
+      Err(e) => errors.push(e),
+    }
+
+    for asset in &shader.assets {
+      let path = format!("{}/{}", shader.dir, asset.meta.file);
+
+      match inspect_image(&asset.data, router_state.env.assets.max_dimension) {
+        Ok(_) => (),
+        Err(ApiError::UnsupportedMediaType(message)) => errors.push(FieldError::new(&path, message)),
+        Err(ApiError::Validation(details)) => errors.extend(details.into_iter().map(|e| FieldError::new(&path, e.message))),
+        Err(e) => return Err(e),
+      }
+    }
+
+    // bindings to assets that aren't in the archive have to point at assets the user already has
+    for (field, binding) in shader.data.channel_bindings() {
+      let Some(asset_id) = binding.asset_id() else {
+        continue;
+      };
+
+      if !shader.assets.iter().any(|asset| asset.meta.id == asset_id) {
+        referenced.push((format!("{}.{}.asset_id", shader.dir, field), asset_id.to_string()));
+      }
+    }
+  }
+
+  let ids: Vec<&str> = referenced.iter().map(|(_, id)| id.as_str()).collect();
+  let found: Vec<(String,)> = sqlx::query_as("SELECT id FROM assets WHERE user_id = $1 AND id = ANY($2)")
+    .bind(profile.id)
+    .bind(&ids)
+    .fetch_all(&mut *conn)
+    .await?;
+
+  errors.extend(referenced.iter()
+    .filter(|(_, id)| !found.iter().any(|(found,)| found == id))
+    .map(|(field, _)| FieldError::new(field, "asset not found")));
+
+  if !errors.is_empty() {
+    return Err(ApiError::Validation(errors));
+  }
+
+  let ids: Vec<&str> = shaders.iter().map(|shader| shader.meta.id.as_str()).collect();
+  let mut existing: HashMap<String, (bool, Vec<Attribution>)> = sqlx::query_as::<_, (String, bool, sqlx::types::Json<Vec<Attribution>>)>(
+    "SELECT id, user_id = $2, attribution FROM shaders WHERE id = ANY($1) FOR UPDATE")
+    .bind(&ids)
+    .bind(profile.user_id)
+    .fetch_all(&mut *conn)
+    .await?
+    .into_iter()
+    .map(|(id, own, attribution)| (id, (own, attribution.0)))
+    .collect();
+
+  let mut claimed: HashSet<String> = HashSet::new();
+  let mut planned = Vec::new();
+
+  for (mut shader, mut license) in shaders.into_iter().zip(licenses) {
+    let archived_id = shader.meta.id.clone();
+    let usable = is_shader_id(&archived_id) && !claimed.contains(&archived_id);
+
+    let (id, action) = match existing.remove(&archived_id) {
+      None if usable => (archived_id, ArchiveImportAction::Create),
+      Some((true, stored)) if usable && strategy == ConflictStrategy::Overwrite => {
+        // an edited archive can add credit but not take away what the shader has now
+        let added: Vec<Attribution> = shader.meta.attribution.into_iter()
+          .filter(|entry| !stored.contains(entry))
+          .collect();
+        shader.meta.attribution = stored.into_iter().chain(added).collect();
+
+        let field = format!("{}.license", shader.dir);
+        match license_for(&field, shader.meta.license.as_deref(), &profile.default_license, &shader.meta.attribution) {
+          Ok(kept) => license = kept,
+          Err(e) => errors.push(e),
+        }
+
+        (archived_id, ArchiveImportAction::Overwrite)
+      },
+      _ => loop {
+        let id = nanoid!(SHADER_ID_LENGTH);
+        let taken: Option<(String,)> = sqlx::query_as("SELECT id FROM shaders WHERE id = $1")
+          .bind(&id)
+          .fetch_optional(&mut *conn)
+          .await?;
+
+        if taken.is_none() && !claimed.contains(&id) {
+          break (id, ArchiveImportAction::NewId);
+        }
+      },
+    };
+
+    claimed.insert(id.clone());
+    planned.push(PlannedImport { shader, id, action, license });
+  }
+
+  if !errors.is_empty() {
+    return Err(ApiError::Validation(errors));
+  }
+
+  Ok(planned)
+}
+
+pub async fn import_my_shaders(
+  Query(query): Query<ArchiveImportQuery>,
+  request_meta: RequestMeta,
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+  body: Bytes,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersWrite)?;
+
+  let limits = router_state.env.shader_limits;
+  let max_asset_bytes = router_state.env.assets.max_upload_bytes;
+  let archive = tokio::task::spawn_blocking(move || archive::read_archive(&body, &limits, max_asset_bytes)).await??;
+
+  let mut tx = router_state.db.begin().await?;
+  let usage = quotas::lock_usage(&mut tx, profile.id).await?;
+  let planned = plan_archive_import(&mut tx, &router_state, &profile, archive, query.on_conflict).await?;
+
+  let report = ArchiveImportReport {
+    dry_run: query.dry_run,
+    shaders: planned.iter().map(|planned| ArchiveImportEntry {
+      archived_id: planned.shader.meta.id.clone(),
+      id: planned.id.clone(),
+      name: planned.shader.meta.name.trim().to_string(),
+      action: planned.action,
+      assets: planned.shader.assets.len(),
+      license: planned.license,
+    }).collect(),
+  };
+
+  if query.dry_run {
+    return Ok((StatusCode::OK, Json(report)));
+  }
+
+  for PlannedImport { shader, id, action, license } in planned {
+    let ParsedShader { meta, mut data, assets, .. } = shader;
+
+    let mut asset_ids = HashMap::new();
+    for asset in assets {
+      let (asset_id, _) = store_asset_in(&mut tx, &router_state, &profile, asset.meta.name.trim(), asset.data).await?;
+      asset_ids.insert(asset.meta.id, asset_id);
+    }
+
+    for binding in data.channel_bindings_mut() {
+      if let ChannelSource::Asset { asset_id } = &mut binding.source {
+        if let Some(stored) = asset_ids.get(asset_id.as_str()) {
+          asset_id.clone_from(stored);
+        }
+      }
+    }
+
+    match action {
+      ArchiveImportAction::Create | ArchiveImportAction::NewId => {
+        let shader: Shader = sqlx::query_as(
+          "INSERT INTO shaders (user_id, id, name, description, data, access, tags, created_at, license, attribution)
+          VALUES ($1, $2, $3, $4, $5, $6, COALESCE($7, '{}'), COALESCE($8, NOW()), $9, $10) RETURNING *")
+          .bind(profile.user_id)
+          .bind(&id)
+          .bind(meta.name.trim())
+          .bind(&meta.description)
+          .bind(sqlx::types::Json(data))
+          .bind(meta.access)
+          .bind(meta.tags)
+          .bind(meta.created_at)
+          .bind(license)
+          .bind(sqlx::types::Json(meta.attribution))
+          .fetch_one(&mut *tx)
+          .await?;
+
+        record_shader_create(&mut tx, &request_meta, &profile, &shader).await?;
+      },
+      ArchiveImportAction::Overwrite => {
+        let before: Shader = sqlx::query_as("SELECT * FROM shaders WHERE id = $1 AND user_id = $2")
+          .bind(&id)
+          .bind(profile.user_id)
+          .fetch_one(&mut *tx)
+          .await?;
+
+        let after: Shader = sqlx::query_as(
+          "UPDATE shaders SET name = $3, description = $4, data = $5,
+          access = CASE WHEN access_locked THEN 'private' ELSE $6 END,
+          tags = COALESCE($7, tags), license = $8, attribution = $9,
+          deleted = false, deleted_at = NULL, purge_warned_at = NULL
+          WHERE id = $1 AND user_id = $2 RETURNING *")
+          .bind(&id)
+          .bind(profile.user_id)
+          .bind(meta.name.trim())
+          .bind(&meta.description)
+          .bind(sqlx::types::Json(data))
+          .bind(meta.access)
+          .bind(meta.tags)
+          .bind(license)
+          .bind(sqlx::types::Json(meta.attribution))
+          .fetch_one(&mut *tx)
+          .await?;
+
+        record_shader_update(&mut tx, &request_meta, &profile, &before, &after).await?;
+      },
+    }
+  }
+
+  // checked once for the whole archive, overwrites may free up what new shaders use
+  quotas::check_usage(&mut tx, &router_state.env, profile.id, &usage).await?;
+  tx.commit().await?;
+
+  Ok((StatusCode::CREATED, Json(report)))
 }
 
 pub async fn update_shader(
   State(router_state): State<RouterState>,
   Path(id): Path<String>,
+  meta: RequestMeta,
   profile: UserProfile,
-  Json(update_shader): Json<UpdateShaderData>
-) -> Result<impl IntoResponse, impl IntoResponse> {
+  ValidatedJson(update_shader): ValidatedJson<UpdateShaderData>
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersWrite)?;
+
   let shader = get_shader_by_id(&router_state, &id, &profile).await?;
 
+  if shader.access_locked && update_shader.access.is_some_and(|access| access != AccessLevel::Private) {
+    return Err(ApiError::Forbidden("a moderator has made this shader private".to_string()));
+  }
+
+  // relicensing can't drop the terms of the shaders this one was copied from
+  let license = update_shader.license.as_deref()
+    .map(|license| check_license("license", license, &shader.attribution))
+    .transpose()
+    .map_err(|e| ApiError::Validation(vec![e]))?;
+
+  if let Some(data) = &update_shader.data {
+    verify_channel_assets(&router_state, &profile, data).await?;
+  }
+
+  // includes are checked again when a shader stops being private, see `resolve_shader`
+  let access = update_shader.access.unwrap_or(shader.access);
+  if update_shader.data.is_some() || access != shader.access {
+    let data = update_shader.data.as_ref().unwrap_or(&shader.data.0);
+    let library = library_path(&router_state.db, &profile, &id).await?;
+    verify_includes(&router_state.db, profile.user_id, access, library.as_deref(), data, router_state.env.shader_limits.max_document_bytes).await?;
+  }
+
   let mut query_builder = sqlx::QueryBuilder::new("UPDATE shaders SET");
   let mut updated = false;
   let mut first_update = true;
@@ -140,7 +975,7 @@ pub async fn update_shader(
   if let Some(name) = update_shader.name {
     if !first_update { query_builder.push(","); }
     query_builder.push(" name = ");
-    query_builder.push_bind(name);
+    query_builder.push_bind(name.trim().to_owned());
     updated = true;
     first_update = false;
   }
@@ -153,6 +988,7 @@ pub async fn update_shader(
     first_update = false;
   }
 
+  let data_updated = update_shader.data.is_some();
   if let Some(data) = update_shader.data {
     if !first_update { query_builder.push(","); }
     query_builder.push(" data = ");
@@ -161,10 +997,12 @@ pub async fn update_shader(
     first_update = false;
   }
 
+  // a moderator may lock the shader after the check above
   if let Some(access) = update_shader.access {
     if !first_update { query_builder.push(","); }
-    query_builder.push(" access = ");
+    query_builder.push(" access = CASE WHEN access_locked THEN 'private' ELSE ");
     query_builder.push_bind(access);
+    query_builder.push(" END");
     updated = true;
     first_update = false;
   }
@@ -174,6 +1012,14 @@ pub async fn update_shader(
     query_builder.push(" tags = ");
     query_builder.push_bind(tags);
     updated = true;
+    first_update = false;
+  }
+
+  if let Some(license) = license {
+    if !first_update { query_builder.push(","); }
+    query_builder.push(" license = ");
+    query_builder.push_bind(license);
+    updated = true;
   }
 
   if !updated {
@@ -183,178 +1029,489 @@ pub async fn update_shader(
   query_builder.push(" WHERE id = ");
   query_builder.push_bind(&id);
   query_builder.push(" AND user_id = ");
-  query_builder.push_bind(&profile.user_id);
+  query_builder.push_bind(profile.user_id);
   query_builder.push(" AND deleted = false");
   query_builder.push(" RETURNING *");
 
-  let query = query_builder.build();
-  let result = query
-    .execute(&router_state.db)
-    .await;
+  let mut tx = router_state.db.begin().await?;
 
-  match result {
-    Err(e) => {
-      log::error!("failed to execute query: {:?}", e);
-      Err((StatusCode::INTERNAL_SERVER_ERROR, "failed to execute query"))
-    },
-    Ok(_) => {
-      let shader: Result<Shader, _> = get_shader_by_id(&router_state, &id, &profile).await;
-
-      match shader {
-        Ok(shader) => Ok(Json(shader)),
-        Err(e) => {
-          log::error!("failed to execute query: {:?}", e);
-          Err((StatusCode::INTERNAL_SERVER_ERROR, "failed to execute query"))
-        }
-      }
-    }
+  // locked before the shader, like every other write of the user's shaders
+  let usage = if data_updated {
+    Some(quotas::lock_usage(&mut tx, profile.id).await?)
+  } else {
+    None
+  };
+
+  // the row as it is now, the one read above may be stale by the time the update lands
+  let before: Option<Shader> = sqlx::query_as("SELECT * FROM shaders WHERE id = $1 AND user_id = $2 AND deleted = false FOR UPDATE")
+    .bind(&id)
+    .bind(profile.user_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+  let Some(before) = before else {
+    return Err(ApiError::NotFound("shader not found".to_string()));
+  };
+
+  let after: Shader = query_builder.build_query_as()
+    .fetch_one(&mut *tx)
+    .await?;
+
+  if let Some(usage) = usage {
+    quotas::check_usage(&mut tx, &router_state.env, profile.id, &usage).await?;
   }
+
+  record_shader_update(&mut tx, &meta, &profile, &before, &after).await?;
+  tx.commit().await?;
+
+  Ok(Json(after))
 }
 
 pub async fn get_shaders(
   State(router_state): State<RouterState>
-) -> Result<impl IntoResponse, impl IntoResponse> {
+) -> Result<impl IntoResponse, ApiError> {
   // select all shaders where deleted = false and public = true
-  let shaders: Result<Vec<Shader>, _> = sqlx::query_as("SELECT * FROM shaders WHERE deleted = false AND access = 'public'")
+  let shaders: Vec<Shader> = sqlx::query_as("SELECT * FROM shaders WHERE deleted = false AND access = 'public' AND hidden = false")
     .fetch_all(&router_state.db)
-    .await;
+    .await?;
 
-  match shaders {
-    Ok(shaders) => Ok(Json(shaders)),
-    Err(e) => {
-      log::error!("failed to execute query: {:?}", e);
-      Err((StatusCode::INTERNAL_SERVER_ERROR, "failed to execute query"))
-    }
-  }
+  Ok(Json(shaders))
 }
 
 pub async fn get_shader(
   Path(id): Path<String>,
   profile: UserProfile,
   State(router_state): State<RouterState>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersRead)?;
+
   get_shader_by_id(&router_state, &id, &profile).await
-    .map(|shader| Json(shader))
+    .map(Json)
 }
 
 pub async fn get_my_shaders(
   profile: UserProfile,
   State(router_state): State<RouterState>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersRead)?;
+
   // select all shaders where user_id = profile.user_id and deleted = false
-  let shaders: Result<Vec<Shader>, _> = sqlx::query_as(
+  let shaders: Vec<Shader> = sqlx::query_as(
     "SELECT * FROM shaders WHERE user_id = $1 AND deleted = false"
   )
-    .bind(&profile.user_id)
+    .bind(profile.user_id)
     .fetch_all(&router_state.db)
-    .await;
+    .await?;
 
-  match shaders {
-    Ok(shaders) => Ok(Json(shaders)),
-    Err(e) => {
-      log::error!("failed to execute query: {:?}", e);
-      Err((StatusCode::INTERNAL_SERVER_ERROR, "failed to execute query"))
-    }
-  }
+  Ok(Json(shaders))
 }
 
 pub async fn get_my_deleted_shaders(
   profile: UserProfile,
   State(router_state): State<RouterState>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersRead)?;
+
   // select all shaders where user_id = profile.user_id and deleted = true
-  let shaders: Result<Vec<Shader>, _> = sqlx::query_as(
+  let shaders: Vec<Shader> = sqlx::query_as(
     "SELECT * FROM shaders WHERE user_id = $1 AND deleted = true"
   )
-    .bind(&profile.user_id)
+    .bind(profile.user_id)
     .fetch_all(&router_state.db)
-    .await;
+    .await?;
 
-  match shaders {
-    Ok(shaders) => Ok(Json(shaders)),
-    Err(e) => {
-      log::error!("failed to execute query: {:?}", e);
-      Err((StatusCode::INTERNAL_SERVER_ERROR, "failed to execute query"))
-    }
-  }
+  Ok(Json(shaders))
+}
+
+async fn set_shader_deleted(
+  router_state: &RouterState,
+  meta: &RequestMeta,
+  profile: &UserProfile,
+  id: &str,
+  deleted: bool,
+) -> Result<bool, ApiError> {
+  let mut tx = router_state.db.begin().await?;
+
+  let previous: Option<(bool,)> = sqlx::query_as(
+    "UPDATE shaders SET deleted = $3,
+    deleted_at = CASE WHEN $3 THEN COALESCE(old.deleted_at, NOW()) END,
+    purge_warned_at = CASE WHEN $3 THEN old.purge_warned_at END
+    FROM shaders AS old
+    WHERE shaders.id = $1 AND shaders.user_id = $2 AND old.id = shaders.id
+    RETURNING old.deleted"
+  )
+    .bind(id)
+    .bind(profile.user_id)
+    .bind(deleted)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+  let Some((previous,)) = previous else {
+    return Ok(false);
+  };
+
+  let action = if deleted { AuditAction::ShaderDelete } else { AuditAction::ShaderRestore };
+  audit::record(&mut *tx, meta, AuditEvent::new(Some(profile.id), action, AuditTarget::Shader, id)
+    .before(serde_json::json!({ "deleted": previous }))
+    .after(serde_json::json!({ "deleted": deleted }))).await?;
+
+  tx.commit().await?;
+  Ok(true)
 }
 
 pub async fn delete_shader(
   Path(id): Path<String>,
+  meta: RequestMeta,
   profile: UserProfile,
   State(router_state): State<RouterState>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-  let result = sqlx::query(
-    "UPDATE shaders SET deleted = true WHERE id = $1 AND user_id = $2"
-  )
-    .bind(&id)
-    .bind(&profile.user_id)
-    .execute(&router_state.db)
-    .await;
-
-  match result {
-    Ok(_) => Ok(StatusCode::NO_CONTENT),
-    Err(e) => {
-      log::error!("failed to execute query: {:?}", e);
-      Err((StatusCode::INTERNAL_SERVER_ERROR, "failed to execute query"))
-    }
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersWrite)?;
+
+  if !set_shader_deleted(&router_state, &meta, &profile, &id, true).await? {
+    return Err(ApiError::NotFound("shader not found".to_string()));
   }
+
+  Ok(StatusCode::NO_CONTENT)
 }
 
 pub async fn force_delete_shader(
   Path(id): Path<String>,
+  meta: RequestMeta,
   profile: UserProfile,
   State(router_state): State<RouterState>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-  let result = sqlx::query(
-    "DELETE FROM shaders WHERE id = $1 AND user_id = $2"
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersWrite)?;
+
+  let mut tx = router_state.db.begin().await?;
+
+  let deleted: Option<Shader> = sqlx::query_as(
+    "DELETE FROM shaders WHERE id = $1 AND user_id = $2 RETURNING *"
   )
     .bind(&id)
-    .bind(&profile.user_id)
-    .execute(&router_state.db)
-    .await;
-
-  match result {
-    Ok(_) => Ok(StatusCode::NO_CONTENT),
-    Err(e) => {
-      log::error!("failed to execute query: {:?}", e);
-      Err((StatusCode::INTERNAL_SERVER_ERROR, "failed to execute query"))
-    }
+    .bind(profile.user_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+  let Some(deleted) = deleted else {
+    return Err(ApiError::NotFound("shader not found".to_string()));
+  };
+
+  audit::record(&mut *tx, &meta, AuditEvent::new(Some(profile.id), AuditAction::ShaderForceDelete, AuditTarget::Shader, &id)
+    .before(deleted.audit_snapshot())).await?;
+
+  tx.commit().await?;
+
+  if let Some(thumbnails) = deleted.thumbnails.map(|thumbnails| thumbnails.0) {
+    delete_thumbnail_files(&router_state.storage, &id, &thumbnails).await;
   }
+
+  Ok(StatusCode::NO_CONTENT)
 }
 
 pub async fn restore_shader(
   Path(id): Path<String>,
+  meta: RequestMeta,
   profile: UserProfile,
   State(router_state): State<RouterState>,
-) -> Result<impl IntoResponse, impl IntoResponse> {
-  let result = sqlx::query(
-    "UPDATE shaders SET deleted = false WHERE id = $1 AND user_id = $2"
-  )
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersWrite)?;
+
+  if !set_shader_deleted(&router_state, &meta, &profile, &id, false).await? {
+    return Err(ApiError::NotFound("shader not found".to_string()));
+  }
+
+  Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn upload_thumbnail(
+  Path(id): Path<String>,
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+  body: Bytes,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersWrite)?;
+
+  get_shader_by_id(&router_state, &id, &profile).await?;
+
+  let rendered = tokio::task::spawn_blocking(move || render_thumbnails(&body)).await??;
+  let version = nanoid!(10);
+
+  let mut images = Vec::new();
+  for thumbnail in rendered {
+    let file_name = thumbnail.file_name(&version);
+    let content_type = thumbnail.format.to_mime_type();
+
+    let bytes = thumbnail.data.len() as i64;
+
+    router_state.storage.put(&thumbnail_key(&id, &file_name), content_type, thumbnail.data).await
+      .map_err(ApiError::Storage)?;
+
+    images.push(ThumbnailImage {
+      size: thumbnail.size.to_string(),
+      width: thumbnail.width,
+      height: thumbnail.height,
+      content_type: content_type.to_string(),
+      url: format!("/shader/{}/thumbnail/{}", id, file_name),
+      bytes,
+    });
+  }
+
+  let thumbnails = Thumbnails { version, images };
+
+  let mut tx = router_state.db.begin().await?;
+  let usage = quotas::lock_usage(&mut tx, profile.id).await?;
+
+  let previous: Option<(Option<sqlx::types::Json<Thumbnails>>,)> = sqlx::query_as(
+    "SELECT thumbnails FROM shaders WHERE id = $1 AND user_id = $2 AND deleted = false FOR UPDATE")
     .bind(&id)
-    .bind(&profile.user_id)
-    .execute(&router_state.db)
-    .await;
-
-  match result {
-    Ok(_) => Ok(StatusCode::NO_CONTENT),
-    Err(e) => {
-      log::error!("failed to execute query: {:?}", e);
-      Err((StatusCode::INTERNAL_SERVER_ERROR, "failed to execute query"))
-    }
+    .bind(profile.user_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+  // the shader went away while we were rendering
+  let Some((previous,)) = previous else {
+    tx.rollback().await?;
+    delete_thumbnail_files(&router_state.storage, &id, &thumbnails).await;
+    return Err(ApiError::NotFound("shader not found".to_string()));
+  };
+
+  sqlx::query("UPDATE shaders SET thumbnails = $1 WHERE id = $2")
+    .bind(sqlx::types::Json(&thumbnails))
+    .bind(&id)
+    .execute(&mut *tx)
+    .await?;
+
+  // thumbnails are stored like assets and share their quota
+  if let Err(e) = quotas::check_usage(&mut tx, &router_state.env, profile.id, &usage).await {
+    tx.rollback().await?;
+    delete_thumbnail_files(&router_state.storage, &id, &thumbnails).await;
+    return Err(e);
+  }
+
+  tx.commit().await?;
+
+  if let Some(previous) = previous {
+    delete_thumbnail_files(&router_state.storage, &id, &previous).await;
+  }
+
+  let shader = get_shader_by_id(&router_state, &id, &profile).await?;
+  Ok(Json(shader))
+}
+
+pub async fn delete_thumbnail(
+  Path(id): Path<String>,
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersWrite)?;
+
+  let previous: Option<(Option<sqlx::types::Json<Thumbnails>>,)> = sqlx::query_as(
+    "UPDATE shaders SET thumbnails = NULL
+    FROM (SELECT id, thumbnails FROM shaders WHERE id = $1 AND user_id = $2 AND deleted = false FOR UPDATE) previous
+    WHERE shaders.id = previous.id
+    RETURNING previous.thumbnails")
+    .bind(&id)
+    .bind(profile.user_id)
+    .fetch_optional(&router_state.db)
+    .await?;
+
+  let Some((previous,)) = previous else {
+    return Err(ApiError::NotFound("shader not found".to_string()));
+  };
+
+  if let Some(previous) = previous {
+    delete_thumbnail_files(&router_state.storage, &id, &previous).await;
   }
+
+  Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn get_thumbnail(
+  Path((id, file_name)): Path<(String, String)>,
+  profile: Option<UserProfile>,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  let shader: Option<(sqlx::types::uuid::Uuid, AccessLevel, bool, Option<sqlx::types::Json<Thumbnails>>)> = sqlx::query_as(
+    "SELECT user_id, access, hidden, thumbnails FROM shaders WHERE id = $1 AND deleted = false")
+    .bind(&id)
+    .fetch_optional(&router_state.db)
+    .await?;
+
+  let not_found = || ApiError::NotFound("thumbnail not found".to_string());
+
+  let Some((owner, access, hidden, Some(thumbnails))) = shader else {
+    return Err(not_found());
+  };
+
+  let is_owner = profile.is_some_and(|profile| profile.user_id == owner && profile.has_scope(TokenScope::ShadersRead));
+  if (access == AccessLevel::Private || hidden) && !is_owner {
+    return Err(not_found());
+  }
+
+  let image = thumbnails.find(&file_name).ok_or_else(not_found)?;
+
+  let data = router_state.storage.get(&thumbnail_key(&id, &file_name)).await
+    .map_err(ApiError::Storage)?
+    .ok_or_else(not_found)?;
+
+  let cache_control = if access == AccessLevel::Private || hidden {
+    "private, max-age=31536000, immutable"
+  } else {
+    "public, max-age=31536000, immutable"
+  };
+
+  Ok((
+    [
+      (CONTENT_TYPE, image.content_type.clone()),
+      (CACHE_CONTROL, cache_control.to_string()),
+      (X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+    ],
+    data,
+  ))
 }
 
 pub fn build_shader_router() -> axum::Router<RouterState> {
   axum::Router::new()
     .route("/", post(add_shader))
+    .route("/import", post(import_shader))
     .route("/all", get(get_shaders))
+    .route("/featured", get(get_featured))
     .route("/my", get(get_my_shaders))
+    .route("/my/export", get(export_my_shaders))
+    .route(
+      "/my/import",
+      post(import_my_shaders).layer(DefaultBodyLimit::max(ARCHIVE_MAX_UPLOAD_BYTES)),
+    )
     .route("/archive", get(get_my_deleted_shaders))
     .route("/:id", get(get_shader))
     .route("/:id", put(update_shader))
     .route("/:id/delete", post(delete_shader))
     .route("/:id/restore", post(restore_shader))
     .route("/:id", delete(force_delete_shader))
+    .route("/:id/export", get(export_shader))
+    .route("/:id/fork", post(fork_shader))
+    .route("/:id/resolved", get(get_resolved_shader))
+    .route("/:id/library", get(get_library))
+    .route("/:id/library", post(publish_library_revision))
+    .route("/:id/embed", get(get_embed_settings))
+    .route("/:id/embed", put(update_embed_settings))
+    .route(
+      "/:id/thumbnail",
+      put(upload_thumbnail).layer(DefaultBodyLimit::max(THUMBNAIL_MAX_UPLOAD_BYTES)),
+    )
+    .route("/:id/thumbnail", delete(delete_thumbnail))
+    .route("/:id/thumbnail/:file", get(get_thumbnail))
 }
 
+#[cfg(test)]
+mod tests {
+  use serde_json::{json, Value};
+
+  use crate::{env::ShaderLimits, errors::FieldError, validation::validate_payload};
+
+  use super::*;
+
+  const LIMITS: ShaderLimits = ShaderLimits { max_pass_code_bytes: 16, max_document_bytes: 400, max_passes: 3 };
+
+  fn errors<T: Validate + serde::de::DeserializeOwned>(payload: Value, limits: Option<&ShaderLimits>) -> Vec<String> {
+    let payload: T = serde_json::from_value(payload).unwrap();
+    let result = match limits {
+      Some(limits) => validate_shader_payload(&payload, limits),
+      None => validate_payload(&payload),
+    };
+
+    match result {
+      Ok(()) => Vec::new(),
+      Err(ApiError::Validation(errors)) => errors.into_iter().map(|FieldError { field, .. }| field).collect(),
+      Err(e) => panic!("unexpected error {:?}", e),
+    }
+  }
+
+  fn buffer(name: &str) -> Value {
+    json!({ "name": name, "kind": "buffer", "code": "" })
+  }
+
+  #[test]
+  fn shader_documents() {
+    let asset = "a".repeat(ASSET_ID_LENGTH);
+    let cases: Vec<(Value, Vec<&str>)> = vec![
+      (json!({ "code": "void main() {}" }), vec![]),
+      (json!({ "code": "x".repeat(17) }), vec!["data.code"]),
+      (json!({ "code": "", "passes": [buffer("a"), buffer("b")] }), vec![]),
+      (json!({ "code": "", "passes": [buffer("a"), buffer("b"), buffer("c")] }), vec!["data.passes"]),
+      (json!({ "code": "", "passes": [buffer("a"), buffer("a")] }), vec!["data.passes[1].name"]),
+      (json!({ "code": "", "passes": [buffer("")] }), vec!["data.passes[0].name"]),
+      (json!({ "code": "", "passes": [{ "name": "a", "kind": "buffer", "code": "x".repeat(17) }] }), vec!["data.passes[0].code"]),
+      (
+        json!({ "code": "", "passes": [{ "name": "a", "kind": "common", "code": "" }, { "name": "b", "kind": "common", "code": "" }] }),
+        vec!["data.passes"],
+      ),
+      (
+        json!({ "code": "", "passes": [{ "name": "a", "kind": "common", "code": "", "channels": [{ "channel": 0, "type": "asset", "asset_id": asset }] }] }),
+        vec!["data.passes[0].channels"],
+      ),
+      (json!({ "code": "", "channels": [{ "channel": 3, "type": "asset", "asset_id": asset }] }), vec![]),
+      (json!({ "code": "", "channels": [{ "channel": 4, "type": "asset", "asset_id": asset }] }), vec!["data.channels[0].channel"]),
+      (json!({ "code": "", "channels": [{ "channel": 0, "type": "asset", "asset_id": "short" }] }), vec!["data.channels[0].asset_id"]),
+      (
+        json!({ "code": "", "channels": [{ "channel": 1, "type": "asset", "asset_id": asset }, { "channel": 1, "type": "asset", "asset_id": asset }] }),
+        vec!["data.channels[1].channel"],
+      ),
+      (
+        json!({ "code": "", "passes": [buffer("a")], "channels": [{ "channel": 0, "type": "buffer", "pass": "a" }, { "channel": 1, "type": "buffer", "pass": "b" }] }),
+        vec!["data.channels[1].pass"],
+      ),
+      // every pass fits on its own, together they don't
+      (json!({ "code": "x".repeat(16), "passes": [buffer("a"), buffer("b")], "channels": [
+        { "channel": 0, "type": "asset", "asset_id": asset }, { "channel": 1, "type": "asset", "asset_id": asset },
+        { "channel": 2, "type": "asset", "asset_id": asset }, { "channel": 3, "type": "asset", "asset_id": asset },
+      ] }), vec!["data"]),
+    ];
+
+    for (data, expected) in cases {
+      let payload = json!({ "name": "shader", "description": "", "data": data });
+      assert_eq!(errors::<NewShaderData>(payload, Some(&LIMITS)), expected, "{}", data);
+    }
+  }
+
+  #[test]
+  fn documents_need_limits() {
+    let payload = json!({ "name": "shader", "description": "", "data": { "code": "" } });
+    assert_eq!(errors::<NewShaderData>(payload.clone(), None), ["data"]);
+    assert_eq!(errors::<UpdateShaderData>(payload, None), ["data"]);
+
+    // payloads without a document don't care
+    assert!(errors::<UpdateShaderData>(json!({ "name": "shader" }), None).is_empty());
+    assert!(errors::<NewShaderData>(json!({ "name": "shader", "description": "", "template_id": "blank" }), None).is_empty());
+  }
+
+  #[test]
+  fn shader_fields() {
+    let cases: Vec<(Value, Vec<&str>)> = vec![
+      (json!({ "name": "", "description": "", "data": { "code": "" } }), vec!["name"]),
+      (json!({ "name": "a", "description": "x".repeat(MAX_SHADER_DESCRIPTION_LENGTH + 1), "data": { "code": "" } }), vec!["description"]),
+      (json!({ "name": "a", "description": "", "data": { "code": "" }, "license": "CC-BY-4.0" }), vec![]),
+      (json!({ "name": "a", "description": "", "data": { "code": "" }, "license": "mine" }), vec!["license"]),
+      (json!({ "name": "a", "description": "" }), vec!["data"]),
+      (json!({ "name": "a", "description": "", "data": { "code": "" }, "template_id": "blank" }), vec!["template_id"]),
+    ];
+
+    for (payload, expected) in cases {
+      assert_eq!(errors::<NewShaderData>(payload.clone(), Some(&LIMITS)), expected, "{}", payload);
+    }
+
+    let cases: Vec<(Value, Vec<&str>)> = vec![
+      (json!({}), vec![]),
+      (json!({ "name": " " }), vec!["name"]),
+      (json!({ "name": "x".repeat(MAX_SHADER_NAME_LENGTH + 1), "license": "mine" }), vec!["name", "license"]),
+      (json!({ "data": { "code": "x".repeat(17) } }), vec!["data.code"]),
+    ];
+
+    for (payload, expected) in cases {
+      assert_eq!(errors::<UpdateShaderData>(payload.clone(), Some(&LIMITS)), expected, "{}", payload);
+    }
+
+    assert!(errors::<ForkShader>(json!({}), None).is_empty());
+    assert_eq!(errors::<ForkShader>(json!({ "name": "", "license": "mine" }), None), ["name", "license"]);
+  }
+}
@@ -0,0 +1,216 @@
+use axum::{extract::{Path, State}, http::StatusCode, response::IntoResponse, routing::{delete, get, post, put}, Json};
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use sqlx::{types::uuid::Uuid, PgExecutor, PgPool};
+
+use crate::{assets::verify_channel_assets, errors::ApiError, quotas, router_state::{RouterState, UserProfile}, routes::shader::{ShaderData, MAX_SHADER_DESCRIPTION_LENGTH, MAX_SHADER_NAME_LENGTH}, tokens::TokenScope, validation::{ValidatedJson, Validate, Validator}};
+
+const TEMPLATE_ID_LENGTH: usize = 10;
+
+pub const TEMPLATE_QUERY: &str = "SELECT id, name, description, user_id IS NULL AS builtin, data, position,
+  created_at, updated_at FROM shader_templates";
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Template {
+  pub id: String,
+  pub name: String,
+  pub description: String,
+  pub builtin: bool,
+  pub data: sqlx::types::Json<ShaderData>,
+  pub position: i32,
+  pub created_at: chrono::DateTime<chrono::Utc>,
+  pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewTemplate {
+  pub name: String,
+  #[serde(default)]
+  pub description: String,
+  pub data: ShaderData,
+  #[serde(default)]
+  pub position: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateTemplate {
+  pub name: Option<String>,
+  pub description: Option<String>,
+  pub data: Option<ShaderData>,
+  pub position: Option<i32>,
+}
+
+impl Validate for NewTemplate {
+  fn validate(&self, v: &mut Validator) {
+    v.length("name", &self.name, 1, MAX_SHADER_NAME_LENGTH);
+    v.length("description", &self.description, 0, MAX_SHADER_DESCRIPTION_LENGTH);
+    self.data.validate(v);
+  }
+}
+
+impl Validate for UpdateTemplate {
+  fn validate(&self, v: &mut Validator) {
+    if let Some(name) = &self.name {
+      v.length("name", name, 1, MAX_SHADER_NAME_LENGTH);
+    }
+
+    if let Some(description) = &self.description {
+      v.length("description", description, 0, MAX_SHADER_DESCRIPTION_LENGTH);
+    }
+
+    if let Some(data) = &self.data {
+      data.validate(v);
+    }
+  }
+}
+
+pub async fn find_template(db: &PgPool, owner: Option<Uuid>, id: &str) -> Result<Option<Template>, ApiError> {
+  let template = sqlx::query_as::<_, Template>(&format!(
+    "{} WHERE id = $1 AND (user_id IS NULL OR user_id = $2)", TEMPLATE_QUERY,
+  ))
+    .bind(id)
+    .bind(owner)
+    .fetch_optional(db)
+    .await?;
+
+  Ok(template)
+}
+
+pub async fn insert_template<'e>(db: impl PgExecutor<'e>, id: &str, owner: Option<Uuid>, template: NewTemplate) -> Result<Template, ApiError> {
+  let template = sqlx::query_as::<_, Template>(
+    "INSERT INTO shader_templates (id, user_id, name, description, data, position) VALUES ($1, $2, $3, $4, $5, $6)
+    RETURNING id, name, description, user_id IS NULL AS builtin, data, position, created_at, updated_at")
+    .bind(id)
+    .bind(owner)
+    .bind(template.name.trim())
+    .bind(&template.description)
+    .bind(sqlx::types::Json(template.data))
+    .bind(template.position)
+    .fetch_one(db)
+    .await?;
+
+  Ok(template)
+}
+
+pub async fn update_template_row<'e>(db: impl PgExecutor<'e>, id: &str, owner: Option<Uuid>, update: UpdateTemplate) -> Result<Template, ApiError> {
+  let template = sqlx::query_as::<_, Template>(
+    "UPDATE shader_templates SET name = COALESCE($3, name), description = COALESCE($4, description),
+    data = COALESCE($5, data), position = COALESCE($6, position)
+    WHERE id = $1 AND user_id IS NOT DISTINCT FROM $2
+    RETURNING id, name, description, user_id IS NULL AS builtin, data, position, created_at, updated_at")
+    .bind(id)
+    .bind(owner)
+    .bind(update.name.as_deref().map(str::trim))
+    .bind(update.description)
+    .bind(update.data.map(sqlx::types::Json))
+    .bind(update.position)
+    .fetch_optional(db)
+    .await?;
+
+  template.ok_or_else(|| ApiError::NotFound("template not found".to_string()))
+}
+
+pub async fn delete_template_row(db: &PgPool, id: &str, owner: Option<Uuid>) -> Result<(), ApiError> {
+  let result = sqlx::query("DELETE FROM shader_templates WHERE id = $1 AND user_id IS NOT DISTINCT FROM $2")
+    .bind(id)
+    .bind(owner)
+    .execute(db)
+    .await?;
+
+  if result.rows_affected() == 0 {
+    return Err(ApiError::NotFound("template not found".to_string()));
+  }
+
+  Ok(())
+}
+
+pub async fn get_templates(
+  profile: Option<UserProfile>,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  let owner = profile
+    .filter(|profile| profile.has_scope(TokenScope::ShadersRead))
+    .map(|profile| profile.user_id);
+
+  let templates: Vec<Template> = sqlx::query_as(&format!(
+    "{} WHERE user_id IS NULL OR user_id = $1 ORDER BY user_id IS NOT NULL, position, created_at", TEMPLATE_QUERY,
+  ))
+    .bind(owner)
+    .fetch_all(&router_state.db)
+    .await?;
+
+  Ok(Json(templates))
+}
+
+pub async fn get_template(
+  Path(id): Path<String>,
+  profile: Option<UserProfile>,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  let owner = profile
+    .filter(|profile| profile.has_scope(TokenScope::ShadersRead))
+    .map(|profile| profile.user_id);
+
+  find_template(&router_state.db, owner, &id).await?
+    .map(Json)
+    .ok_or_else(|| ApiError::NotFound("template not found".to_string()))
+}
+
+pub async fn create_template(
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+  ValidatedJson(template): ValidatedJson<NewTemplate>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersWrite)?;
+
+  verify_channel_assets(&router_state, &profile, &template.data).await?;
+
+  let mut tx = router_state.db.begin().await?;
+  let usage = quotas::lock_usage(&mut tx, profile.id).await?;
+  let template = insert_template(&mut *tx, &nanoid!(TEMPLATE_ID_LENGTH), Some(profile.user_id), template).await?;
+  quotas::check_usage(&mut tx, &router_state.env, profile.id, &usage).await?;
+  tx.commit().await?;
+
+  Ok((StatusCode::CREATED, Json(template)))
+}
+
+pub async fn update_template(
+  Path(id): Path<String>,
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+  ValidatedJson(update): ValidatedJson<UpdateTemplate>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersWrite)?;
+
+  if let Some(data) = &update.data {
+    verify_channel_assets(&router_state, &profile, data).await?;
+  }
+
+  let mut tx = router_state.db.begin().await?;
+  let usage = quotas::lock_usage(&mut tx, profile.id).await?;
+  let template = update_template_row(&mut *tx, &id, Some(profile.user_id), update).await?;
+  quotas::check_usage(&mut tx, &router_state.env, profile.id, &usage).await?;
+  tx.commit().await?;
+
+  Ok(Json(template))
+}
+
+pub async fn delete_template(
+  Path(id): Path<String>,
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ShadersWrite)?;
+
+  delete_template_row(&router_state.db, &id, Some(profile.user_id)).await?;
+  Ok(StatusCode::NO_CONTENT)
+}
+
+pub fn build_template_router() -> axum::Router<RouterState> {
+  axum::Router::new()
+    .route("/", get(get_templates))
+    .route("/", post(create_template))
+    .route("/:id", get(get_template))
+    .route("/:id", put(update_template))
+    .route("/:id", delete(delete_template))
+}
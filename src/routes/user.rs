@@ -0,0 +1,468 @@
+use axum::{extract::{Path, Query, State}, http::{header::CONTENT_DISPOSITION, StatusCode}, response::IntoResponse, routing::{delete, get, post, put}, Json};
+use axum_extra::extract::PrivateCookieJar;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{assets::{Asset, ASSET_QUERY}, auth::build_session_removal_cookie, constants::ACCOUNT_DELETION_GRACE_DAYS, errors::ApiError, includes::{is_library_name, MAX_LIBRARY_NAME_LENGTH}, licenses::find_license, notifications::Notification, quotas, router_state::{RouterState, UserProfile}, routes::{moderation::{ReportReason, ReportStatus, ReportTarget}, oauth::LinkedIdentity, shader::Shader, template::{Template, TEMPLATE_QUERY}}, tokens::{display_prefix, generate_token, hash_token, TokenScope}, validation::{ValidatedJson, Validate, Validator}};
+
+const MAX_TOKEN_LIFETIME_DAYS: i64 = 365;
+const MAX_TOKEN_NAME_LENGTH: usize = 255;
+
+#[derive(Debug, Deserialize)]
+pub struct NewAccessToken {
+  pub name: String,
+  pub scopes: Vec<TokenScope>,
+  pub expires_in_days: Option<i64>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct AccessToken {
+  pub id: i32,
+  pub name: String,
+  pub token_prefix: String,
+  pub scopes: Vec<TokenScope>,
+  pub created_at: chrono::DateTime<chrono::Utc>,
+  pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+  pub last_used_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreatedAccessToken {
+  #[serde(flatten)]
+  pub entry: AccessToken,
+  pub token: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AccountExport {
+  pub exported_at: chrono::DateTime<chrono::Utc>,
+  pub profile: UserProfile,
+  pub identities: Vec<LinkedIdentity>,
+  pub access_tokens: Vec<AccessToken>,
+  pub assets: Vec<Asset>,
+  pub shaders: Vec<Shader>,
+  pub archived_shaders: Vec<Shader>,
+  pub library_revisions: Vec<ExportedRevision>,
+  pub templates: Vec<Template>,
+  pub reports: Vec<ExportedReport>,
+  pub notifications: Vec<Notification>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct ExportedRevision {
+  pub shader_id: String,
+  pub library: String,
+  pub revision: i32,
+  pub code: String,
+  pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+// reports the user filed, without the moderator who resolved them
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct ExportedReport {
+  pub id: i32,
+  pub target_type: ReportTarget,
+  pub target_id: String,
+  pub reason: ReportReason,
+  pub details: String,
+  pub status: ReportStatus,
+  pub resolved_at: Option<chrono::DateTime<chrono::Utc>>,
+  pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UsernameUpdate {
+  pub username: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DefaultLicenseUpdate {
+  pub license: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeletionScheduled {
+  pub purge_after: chrono::DateTime<chrono::Utc>,
+}
+
+impl Validate for NewAccessToken {
+  fn validate(&self, v: &mut Validator) {
+    v.length("name", &self.name, 1, MAX_TOKEN_NAME_LENGTH);
+    v.check("scopes", !self.scopes.is_empty(), "needs at least one scope");
+    v.check(
+      "expires_in_days",
+      self.expires_in_days.is_none_or(|days| (1..=MAX_TOKEN_LIFETIME_DAYS).contains(&days)),
+      format!("must be between 1 and {}", MAX_TOKEN_LIFETIME_DAYS),
+    );
+  }
+}
+
+impl Validate for UsernameUpdate {
+  fn validate(&self, v: &mut Validator) {
+    // usernames are the first part of library include paths
+    v.check(
+      "username",
+      is_library_name(&self.username),
+      format!("must be 1 to {} lowercase letters, digits, dashes or underscores", MAX_LIBRARY_NAME_LENGTH),
+    );
+  }
+}
+
+impl Validate for DefaultLicenseUpdate {
+  fn validate(&self, v: &mut Validator) {
+    v.check("license", find_license(&self.license).is_some(), "must be a supported SPDX license identifier");
+  }
+}
+
+pub async fn update_username(
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+  ValidatedJson(update): ValidatedJson<UsernameUpdate>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_session()?;
+
+  if profile.username.as_deref() == Some(update.username.as_str()) {
+    return Ok(Json(profile));
+  }
+
+  let mut tx = router_state.db.begin().await?;
+
+  // publishing locks the user row as well, so a library can't appear between the check and the update
+  sqlx::query("SELECT id FROM users WHERE id = $1 FOR UPDATE")
+    .bind(profile.id)
+    .execute(&mut *tx)
+    .await?;
+
+  let libraries: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM shader_libraries WHERE user_id = $1")
+    .bind(profile.user_id)
+    .fetch_one(&mut *tx)
+    .await?;
+
+  if libraries.0 > 0 {
+    return Err(ApiError::Conflict("the username can't be changed after publishing libraries".to_string()));
+  }
+
+  let updated: UserProfile = sqlx::query_as(
+    "UPDATE users SET username = $2 WHERE id = $1
+    RETURNING id, user_id, email, name, username, default_license, role, created_at, updated_at")
+    .bind(profile.id)
+    .bind(&update.username)
+    .fetch_one(&mut *tx)
+    .await?;
+
+  tx.commit().await?;
+
+  Ok(Json(UserProfile { scopes: profile.scopes, ..updated }))
+}
+
+pub async fn update_default_license(
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+  ValidatedJson(update): ValidatedJson<DefaultLicenseUpdate>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_session()?;
+
+  // validated above, this only picks the canonical spelling
+  let license = find_license(&update.license).map(|license| license.id).unwrap_or_default();
+
+  let updated: UserProfile = sqlx::query_as(
+    "UPDATE users SET default_license = $2 WHERE id = $1
+    RETURNING id, user_id, email, name, username, default_license, role, created_at, updated_at")
+    .bind(profile.id)
+    .bind(license)
+    .fetch_one(&router_state.db)
+    .await?;
+
+  Ok(Json(UserProfile { scopes: profile.scopes, ..updated }))
+}
+
+pub async fn export_account(
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_session()?;
+
+  let identities: Vec<LinkedIdentity> = sqlx::query_as(
+    "SELECT id, provider, email, email_verified, created_at FROM user_identities
+    WHERE user_id = $1 ORDER BY created_at")
+    .bind(profile.id)
+    .fetch_all(&router_state.db)
+    .await?;
+
+  let access_tokens: Vec<AccessToken> = sqlx::query_as(
+    "SELECT id, name, token_prefix, scopes, created_at, expires_at, last_used_at
+    FROM personal_access_tokens WHERE user_id = $1 ORDER BY created_at")
+    .bind(profile.id)
+    .fetch_all(&router_state.db)
+    .await?;
+
+  let assets: Vec<Asset> = sqlx::query_as(&format!("{} WHERE assets.user_id = $1 ORDER BY assets.created_at", ASSET_QUERY))
+    .bind(profile.id)
+    .fetch_all(&router_state.db)
+    .await?;
+
+  let shaders: Vec<Shader> = sqlx::query_as(
+    "SELECT * FROM shaders WHERE user_id = $1 AND deleted = false ORDER BY created_at")
+    .bind(profile.user_id)
+    .fetch_all(&router_state.db)
+    .await?;
+
+  let archived_shaders: Vec<Shader> = sqlx::query_as(
+    "SELECT * FROM shaders WHERE user_id = $1 AND deleted = true ORDER BY created_at")
+    .bind(profile.user_id)
+    .fetch_all(&router_state.db)
+    .await?;
+
+  let library_revisions: Vec<ExportedRevision> = sqlx::query_as(
+    "SELECT shader_libraries.shader_id, shader_libraries.name AS library, shader_library_revisions.revision,
+    shader_library_revisions.code, shader_library_revisions.created_at
+    FROM shader_library_revisions
+    INNER JOIN shader_libraries ON shader_libraries.shader_id = shader_library_revisions.shader_id
+    WHERE shader_libraries.user_id = $1 ORDER BY shader_libraries.shader_id, shader_library_revisions.revision")
+    .bind(profile.user_id)
+    .fetch_all(&router_state.db)
+    .await?;
+
+  let templates: Vec<Template> = sqlx::query_as(&format!("{} WHERE user_id = $1 ORDER BY position, created_at", TEMPLATE_QUERY))
+    .bind(profile.user_id)
+    .fetch_all(&router_state.db)
+    .await?;
+
+  let reports: Vec<ExportedReport> = sqlx::query_as(
+    "SELECT id, target_type, target_id, reason, details, status, resolved_at, created_at FROM reports
+    WHERE reporter_id = $1 ORDER BY created_at, id")
+    .bind(profile.id)
+    .fetch_all(&router_state.db)
+    .await?;
+
+  let notifications: Vec<Notification> = sqlx::query_as(
+    "SELECT id, kind, payload, created_at, read_at FROM notifications WHERE user_id = $1 ORDER BY created_at, id")
+    .bind(profile.id)
+    .fetch_all(&router_state.db)
+    .await?;
+
+  let filename = format!("attachment; filename=\"shaderx-export-{}.json\"", profile.user_id);
+
+  Ok((
+    [(CONTENT_DISPOSITION, filename)],
+    Json(AccountExport {
+      exported_at: Utc::now(),
+      profile,
+      identities,
+      access_tokens,
+      assets,
+      shaders,
+      archived_shaders,
+      library_revisions,
+      templates,
+      reports,
+      notifications,
+    }),
+  ))
+}
+
+pub async fn delete_account(
+  profile: UserProfile,
+  jar: PrivateCookieJar,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_session()?;
+
+  let mut tx = router_state.db.begin().await?;
+
+  let (requested_at,): (chrono::DateTime<chrono::Utc>,) = sqlx::query_as(
+    "UPDATE users SET deletion_requested_at = NOW() WHERE id = $1 RETURNING deletion_requested_at")
+    .bind(profile.id)
+    .fetch_one(&mut *tx)
+    .await?;
+
+  sqlx::query("DELETE FROM sessions WHERE user_id = $1")
+    .bind(profile.id)
+    .execute(&mut *tx)
+    .await?;
+
+  sqlx::query("DELETE FROM refresh_tokens WHERE user_id = $1")
+    .bind(profile.id)
+    .execute(&mut *tx)
+    .await?;
+
+  sqlx::query("DELETE FROM personal_access_tokens WHERE user_id = $1")
+    .bind(profile.id)
+    .execute(&mut *tx)
+    .await?;
+
+  tx.commit().await?;
+
+  Ok((
+    StatusCode::ACCEPTED,
+    jar.remove(build_session_removal_cookie(&router_state.env)),
+    Json(DeletionScheduled {
+      purge_after: requested_at + Duration::days(ACCOUNT_DELETION_GRACE_DAYS),
+    }),
+  ))
+}
+
+pub async fn create_token(
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+  ValidatedJson(new_token): ValidatedJson<NewAccessToken>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_session()?;
+
+  let expires_at = new_token.expires_in_days.map(|days| Utc::now() + Duration::days(days));
+
+  let mut scopes = new_token.scopes;
+  scopes.sort();
+  scopes.dedup();
+
+  let token = generate_token();
+
+  let entry: AccessToken = sqlx::query_as(
+    "INSERT INTO personal_access_tokens (user_id, name, token_prefix, token_hash, scopes, expires_at)
+    VALUES ($1, $2, $3, $4, $5, $6)
+    RETURNING id, name, token_prefix, scopes, created_at, expires_at, last_used_at")
+    .bind(profile.id)
+    .bind(new_token.name.trim())
+    .bind(display_prefix(&token))
+    .bind(hash_token(&token))
+    .bind(scopes)
+    .bind(expires_at)
+    .fetch_one(&router_state.db)
+    .await?;
+
+  Ok((StatusCode::CREATED, Json(CreatedAccessToken { entry, token })))
+}
+
+pub async fn get_tokens(
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_session()?;
+
+  let tokens: Vec<AccessToken> = sqlx::query_as(
+    "SELECT id, name, token_prefix, scopes, created_at, expires_at, last_used_at
+    FROM personal_access_tokens WHERE user_id = $1 ORDER BY created_at DESC")
+    .bind(profile.id)
+    .fetch_all(&router_state.db)
+    .await?;
+
+  Ok(Json(tokens))
+}
+
+pub async fn revoke_token(
+  Path(id): Path<i32>,
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_session()?;
+
+  let result = sqlx::query("DELETE FROM personal_access_tokens WHERE id = $1 AND user_id = $2")
+    .bind(id)
+    .bind(profile.id)
+    .execute(&router_state.db)
+    .await?;
+
+  if result.rows_affected() == 0 {
+    return Err(ApiError::NotFound("token not found".to_string()));
+  }
+
+  Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NotificationQuery {
+  #[serde(default)]
+  pub unread: bool,
+}
+
+pub async fn get_notifications(
+  Query(query): Query<NotificationQuery>,
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ProfileRead)?;
+
+  let notifications: Vec<Notification> = sqlx::query_as(
+    "SELECT id, kind, payload, created_at, read_at FROM notifications
+    WHERE user_id = $1 AND (read_at IS NULL OR NOT $2)
+    ORDER BY created_at DESC, id DESC LIMIT 100")
+    .bind(profile.id)
+    .bind(query.unread)
+    .fetch_all(&router_state.db)
+    .await?;
+
+  Ok(Json(notifications))
+}
+
+pub async fn read_notification(
+  Path(id): Path<i32>,
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ProfileRead)?;
+
+  let result = sqlx::query("UPDATE notifications SET read_at = COALESCE(read_at, NOW()) WHERE id = $1 AND user_id = $2")
+    .bind(id)
+    .bind(profile.id)
+    .execute(&router_state.db)
+    .await?;
+
+  if result.rows_affected() == 0 {
+    return Err(ApiError::NotFound("notification not found".to_string()));
+  }
+
+  Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Serialize)]
+pub struct QuotaUsage {
+  pub used: i64,
+  pub limit: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RevisionUsage {
+  pub used: i64,
+  pub limit_per_shader: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserUsage {
+  pub shaders: QuotaUsage,
+  pub templates: QuotaUsage,
+  pub code_bytes: QuotaUsage,
+  pub asset_bytes: QuotaUsage,
+  pub revisions: RevisionUsage,
+}
+
+pub async fn get_usage(
+  profile: UserProfile,
+  State(router_state): State<RouterState>,
+) -> Result<impl IntoResponse, ApiError> {
+  profile.require_scope(TokenScope::ProfileRead)?;
+
+  let usage = quotas::get_usage(&router_state.db, profile.id).await?;
+  let limits = &router_state.env.quotas;
+
+  Ok(Json(UserUsage {
+    shaders: QuotaUsage { used: usage.shaders, limit: limits.max_shaders },
+    templates: QuotaUsage { used: usage.templates, limit: limits.max_templates },
+    code_bytes: QuotaUsage { used: usage.code_bytes, limit: limits.max_code_bytes },
+    asset_bytes: QuotaUsage { used: usage.asset_bytes, limit: router_state.env.assets.user_quota_bytes },
+    revisions: RevisionUsage { used: usage.revisions, limit_per_shader: limits.max_revisions_per_shader },
+  }))
+}
+
+pub fn build_user_router() -> axum::Router<RouterState> {
+  axum::Router::new()
+    .route("/me", delete(delete_account))
+    .route("/me/export", get(export_account))
+    .route("/me/username", put(update_username))
+    .route("/me/license", put(update_default_license))
+    .route("/me/usage", get(get_usage))
+    .route("/me/notifications", get(get_notifications))
+    .route("/me/notifications/:id/read", post(read_notification))
+    .route("/tokens", post(create_token))
+    .route("/tokens", get(get_tokens))
+    .route("/tokens/:id", delete(revoke_token))
+}
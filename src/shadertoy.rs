@@ -0,0 +1,502 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{errors::{ApiError, FieldError}, licenses::{Attribution, DEFAULT_LICENSE}, routes::shader::{AccessLevel, ChannelBinding, ChannelSource, PassKind, Shader, ShaderData, ShaderPass, TextureFilter, TextureWrap}};
+
+const SHADERTOY_VERSION: &str = "0.1";
+const SHADERTOY_VIEW_URL: &str = "https://www.shadertoy.com/view/";
+const IMAGE_OUTPUT_ID: &str = "4dfGRr";
+const BUFFER_OUTPUTS: [(&str, &str); 4] = [
+  ("Buffer A", "4dXGR8"),
+  ("Buffer B", "XsXGR8"),
+  ("Buffer C", "4sXGR8"),
+  ("Buffer D", "XdfGR8"),
+];
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShadertoyDocument {
+  #[serde(rename = "Shader")]
+  pub shader: ShadertoyShader,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ShadertoyImport {
+  Wrapped(ShadertoyDocument),
+  Bare(ShadertoyShader),
+}
+
+impl ShadertoyImport {
+  pub fn into_shader(self) -> ShadertoyShader {
+    match self {
+      Self::Wrapped(document) => document.shader,
+      Self::Bare(shader) => shader,
+    }
+  }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShadertoyShader {
+  #[serde(default)]
+  pub ver: String,
+  pub info: ShadertoyInfo,
+  pub renderpass: Vec<ShadertoyPass>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShadertoyInfo {
+  pub id: String,
+  pub date: String,
+  pub name: String,
+  pub username: String,
+  pub description: String,
+  pub published: u8,
+  pub tags: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShadertoyPass {
+  #[serde(default)]
+  pub inputs: Vec<ShadertoyInput>,
+  #[serde(default)]
+  pub outputs: Vec<ShadertoyOutput>,
+  pub code: String,
+  #[serde(default)]
+  pub name: String,
+  #[serde(default)]
+  pub description: String,
+  #[serde(rename = "type")]
+  pub kind: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShadertoyInput {
+  #[serde(default)]
+  pub id: Value,
+  #[serde(default, alias = "filepath")]
+  pub src: String,
+  pub ctype: String,
+  pub channel: u8,
+  #[serde(default)]
+  pub sampler: ShadertoySampler,
+  #[serde(default)]
+  pub published: u8,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShadertoyOutput {
+  pub id: Value,
+  pub channel: u8,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShadertoySampler {
+  pub filter: String,
+  pub wrap: String,
+  pub vflip: Value,
+  pub srgb: Value,
+  pub internal: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UnmappedItem {
+  pub path: String,
+  pub reason: String,
+}
+
+impl UnmappedItem {
+  fn new(path: impl Into<String>, reason: impl Into<String>) -> Self {
+    Self { path: path.into(), reason: reason.into() }
+  }
+}
+
+pub struct ImportedShader {
+  pub name: String,
+  pub description: String,
+  pub tags: Vec<String>,
+  pub data: ShaderData,
+  pub attribution: Attribution,
+  pub unmapped: Vec<UnmappedItem>,
+}
+
+fn id_string(id: &Value) -> String {
+  match id {
+    Value::String(id) => id.clone(),
+    other => other.to_string(),
+  }
+}
+
+fn is_true(flag: &Value) -> bool {
+  matches!(flag, Value::Bool(true)) || matches!(flag, Value::String(flag) if flag == "true")
+}
+
+fn flag(value: bool) -> Value {
+  Value::String(value.to_string())
+}
+
+fn import_channels(
+  pass_path: &str,
+  inputs: &[ShadertoyInput],
+  buffers: &HashMap<String, String>,
+  unmapped: &mut Vec<UnmappedItem>,
+) -> Vec<ChannelBinding> {
+  let mut channels = Vec::new();
+
+  for (i, input) in inputs.iter().enumerate() {
+    let path = format!("{}.inputs[{}]", pass_path, i);
+
+    if input.ctype != "buffer" {
+      unmapped.push(UnmappedItem::new(path, format!(
+        "{} inputs aren't supported, upload the texture as an asset and bind it to channel {}",
+        input.ctype, input.channel,
+      )));
+      continue;
+    }
+
+    let Some(pass) = buffers.get(&id_string(&input.id)) else {
+      unmapped.push(UnmappedItem::new(path, "the buffer this input reads from isn't part of the shader"));
+      continue;
+    };
+
+    let filter = match input.sampler.filter.as_str() {
+      "nearest" => TextureFilter::Nearest,
+      "mipmap" => TextureFilter::Mipmap,
+      _ => TextureFilter::Linear,
+    };
+
+    let wrap = match input.sampler.wrap.as_str() {
+      "repeat" => TextureWrap::Repeat,
+      _ => TextureWrap::Clamp,
+    };
+
+    channels.push(ChannelBinding {
+      channel: input.channel,
+      source: ChannelSource::Buffer { pass: pass.clone() },
+      filter,
+      wrap,
+      vflip: is_true(&input.sampler.vflip),
+    });
+  }
+
+  channels
+}
+
+pub fn import_shader(shader: ShadertoyShader) -> Result<ImportedShader, ApiError> {
+  let mut unmapped = Vec::new();
+
+  // inputs reference buffers by the ids of their outputs
+  let buffers: HashMap<String, String> = shader.renderpass.iter()
+    .filter(|pass| pass.kind == "buffer")
+    .flat_map(|pass| pass.outputs.iter().map(move |output| (id_string(&output.id), pass.name.clone())))
+    .collect();
+
+  let mut image: Option<(String, Vec<ChannelBinding>)> = None;
+  let mut passes = Vec::new();
+
+  for (i, pass) in shader.renderpass.iter().enumerate() {
+    let path = format!("renderpass[{}]", i);
+
+    match pass.kind.as_str() {
+      "image" if image.is_none() => {
+        let channels = import_channels(&path, &pass.inputs, &buffers, &mut unmapped);
+        image = Some((pass.code.clone(), channels));
+      },
+      "image" => unmapped.push(UnmappedItem::new(path, "only the first image pass is imported")),
+      "buffer" => passes.push(ShaderPass {
+        name: pass.name.clone(),
+        kind: PassKind::Buffer,
+        code: pass.code.clone(),
+        channels: import_channels(&path, &pass.inputs, &buffers, &mut unmapped),
+      }),
+      "common" => passes.push(ShaderPass {
+        name: if pass.name.is_empty() { "Common".to_string() } else { pass.name.clone() },
+        kind: PassKind::Common,
+        code: pass.code.clone(),
+        channels: Vec::new(),
+      }),
+      kind => unmapped.push(UnmappedItem::new(path, format!("{} passes aren't supported", kind))),
+    }
+  }
+
+  let Some((code, channels)) = image else {
+    return Err(ApiError::Validation(vec![FieldError::new("renderpass", "needs an image pass")]));
+  };
+
+  let attribution = Attribution {
+    shader_id: None,
+    name: shader.info.name.clone(),
+    author: shader.info.username.clone(),
+    license: DEFAULT_LICENSE.to_string(),
+    url: (!shader.info.id.is_empty()).then(|| format!("{}{}", SHADERTOY_VIEW_URL, shader.info.id)),
+  };
+
+  Ok(ImportedShader {
+    name: shader.info.name,
+    description: shader.info.description,
+    tags: shader.info.tags,
+    data: ShaderData { code, channels, passes },
+    attribution,
+    unmapped,
+  })
+}
+
+fn export_inputs(
+  pass_path: &str,
+  channels: &[ChannelBinding],
+  buffers: &HashMap<&str, &str>,
+  unmapped: &mut Vec<UnmappedItem>,
+) -> Vec<ShadertoyInput> {
+  let mut inputs = Vec::new();
+
+  for (i, binding) in channels.iter().enumerate() {
+    let path = format!("{}.channels[{}]", pass_path, i);
+
+    let output = match &binding.source {
+      ChannelSource::Buffer { pass } => buffers.get(pass.as_str()),
+      ChannelSource::Asset { asset_id } => {
+        unmapped.push(UnmappedItem::new(path, format!("asset {} can't be referenced from shadertoy", asset_id)));
+        continue;
+      },
+    };
+
+    let Some(output) = output else {
+      unmapped.push(UnmappedItem::new(path, "reads from a buffer that couldn't be exported"));
+      continue;
+    };
+
+    let filter = match binding.filter {
+      TextureFilter::Nearest => "nearest",
+      TextureFilter::Linear => "linear",
+      TextureFilter::Mipmap => "mipmap",
+    };
+
+    let wrap = match binding.wrap {
+      TextureWrap::Clamp => "clamp",
+      TextureWrap::Repeat => "repeat",
+    };
+
+    let slot = BUFFER_OUTPUTS.iter().position(|(_, id)| id == output).unwrap_or_default();
+
+    inputs.push(ShadertoyInput {
+      id: Value::String(output.to_string()),
+      // the editor shows this image as the preview of buffer inputs
+      src: format!("/media/previz/buffer{:02}.png", slot),
+      ctype: "buffer".to_string(),
+      channel: binding.channel,
+      sampler: ShadertoySampler {
+        filter: filter.to_string(),
+        wrap: wrap.to_string(),
+        vflip: flag(binding.vflip),
+        srgb: flag(false),
+        internal: "byte".to_string(),
+      },
+      published: 1,
+    });
+  }
+
+  inputs
+}
+
+pub fn export_shader(shader: &Shader, username: &str) -> (ShadertoyDocument, Vec<UnmappedItem>) {
+  let mut unmapped = Vec::new();
+  let data = &shader.data.0;
+
+  // our buffers can have any name, shadertoy's are lettered in order
+  let mut buffers: HashMap<&str, &str> = HashMap::new();
+  let mut buffer_slots = BUFFER_OUTPUTS.iter();
+
+  for (i, pass) in data.passes.iter().enumerate() {
+    if pass.kind != PassKind::Buffer {
+      continue;
+    }
+
+    match buffer_slots.next() {
+      Some((slot, output)) => {
+        if pass.name != *slot {
+          unmapped.push(UnmappedItem::new(format!("data.passes[{}].name", i), format!("{} is exported as {}", pass.name, slot)));
+        }
+
+        buffers.insert(&pass.name, output);
+      },
+      None => unmapped.push(UnmappedItem::new(
+        format!("data.passes[{}]", i),
+        format!("shadertoy supports at most {} buffers", BUFFER_OUTPUTS.len()),
+      )),
+    }
+  }
+
+  let mut renderpass = vec![ShadertoyPass {
+    inputs: export_inputs("data", &data.channels, &buffers, &mut unmapped),
+    outputs: vec![ShadertoyOutput { id: Value::String(IMAGE_OUTPUT_ID.to_string()), channel: 0 }],
+    code: data.code.clone(),
+    name: "Image".to_string(),
+    description: String::new(),
+    kind: "image".to_string(),
+  }];
+
+  for (i, pass) in data.passes.iter().enumerate() {
+    let path = format!("data.passes[{}]", i);
+
+    match pass.kind {
+      PassKind::Buffer => {
+        let Some(output) = buffers.get(pass.name.as_str()) else {
+          continue;
+        };
+
+        let name = BUFFER_OUTPUTS.iter()
+          .find(|(_, id)| id == output)
+          .map(|(name, _)| name.to_string())
+          .unwrap_or_default();
+
+        renderpass.push(ShadertoyPass {
+          inputs: export_inputs(&path, &pass.channels, &buffers, &mut unmapped),
+          outputs: vec![ShadertoyOutput { id: Value::String(output.to_string()), channel: 0 }],
+          code: pass.code.clone(),
+          name,
+          description: String::new(),
+          kind: "buffer".to_string(),
+        });
+      },
+      PassKind::Common => renderpass.push(ShadertoyPass {
+        inputs: Vec::new(),
+        outputs: Vec::new(),
+        code: pass.code.clone(),
+        name: "Common".to_string(),
+        description: String::new(),
+        kind: "common".to_string(),
+      }),
+    }
+  }
+
+  let tags = match &shader.tags {
+    Value::Array(tags) => tags.iter().filter_map(|tag| tag.as_str().map(|tag| tag.to_string())).collect(),
+    _ => Vec::new(),
+  };
+
+  let published = match shader.access {
+    AccessLevel::Private => 0,
+    AccessLevel::Public => 1,
+    AccessLevel::Unlisted => 2,
+  };
+
+  let document = ShadertoyDocument {
+    shader: ShadertoyShader {
+      ver: SHADERTOY_VERSION.to_string(),
+      info: ShadertoyInfo {
+        id: shader.id.clone(),
+        date: shader.created_at.timestamp().to_string(),
+        name: shader.name.clone(),
+        username: username.to_string(),
+        description: shader.description.clone(),
+        published,
+        tags,
+      },
+      renderpass,
+    },
+  };
+
+  (document, unmapped)
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use crate::{env::ShaderLimits, test_support::test_shader, validation::validate_shader_payload};
+
+  use super::*;
+
+  fn document(shader: &Shader) -> String {
+    let (document, unmapped) = export_shader(shader, "ada");
+    assert!(unmapped.is_empty(), "{:?}", unmapped);
+    serde_json::to_string(&document).unwrap()
+  }
+
+  fn import(json: &str) -> Result<ImportedShader, ApiError> {
+    let document: ShadertoyImport = serde_json::from_str(json).unwrap();
+    import_shader(document.into_shader())
+  }
+
+  #[test]
+  fn exports_import_unchanged() {
+    let shader = test_shader("AbCdEf", json!({
+      "code": "void mainImage(out vec4 c, in vec2 p) { c = texture(iChannel0, p); }",
+      "channels": [
+        { "channel": 0, "type": "buffer", "pass": "Buffer A", "filter": "nearest", "wrap": "repeat", "vflip": true },
+        { "channel": 2, "type": "buffer", "pass": "Buffer B", "filter": "mipmap" },
+      ],
+      "passes": [
+        { "name": "Common", "kind": "common", "code": "float t() { return 1.0; }" },
+        { "name": "Buffer A", "kind": "buffer", "code": "// a", "channels": [{ "channel": 1, "type": "buffer", "pass": "Buffer A" }] },
+        { "name": "Buffer B", "kind": "buffer", "code": "// b" },
+      ],
+    }));
+
+    let imported = import(&document(&shader)).unwrap();
+    assert!(imported.unmapped.is_empty(), "{:?}", imported.unmapped);
+    assert_eq!(serde_json::to_value(&imported.data).unwrap(), serde_json::to_value(&shader.data.0).unwrap());
+    assert_eq!((imported.name.as_str(), imported.description.as_str()), (shader.name.as_str(), shader.description.as_str()));
+    assert_eq!(json!(imported.tags), shader.tags);
+    assert_eq!(imported.attribution.author, "ada");
+    assert_eq!(imported.attribution.url.as_deref(), Some("https://www.shadertoy.com/view/AbCdEf"));
+
+    // the editor's export has no wrapper around the shader
+    let bare = serde_json::to_string(&serde_json::from_str::<Value>(&document(&shader)).unwrap()["Shader"]).unwrap();
+    let imported = import(&bare).unwrap();
+    assert_eq!(serde_json::to_value(&imported.data).unwrap(), serde_json::to_value(&shader.data.0).unwrap());
+  }
+
+  #[test]
+  fn reports_what_doesnt_map() {
+    let shader = test_shader("AbCdEf", json!({
+      "code": "",
+      "channels": [{ "channel": 0, "type": "asset", "asset_id": "aaaaaaaaaaaa" }],
+      "passes": [
+        { "name": "first", "kind": "buffer", "code": "" },
+        { "name": "Buffer B", "kind": "buffer", "code": "" },
+        { "name": "Buffer C", "kind": "buffer", "code": "" },
+        { "name": "Buffer D", "kind": "buffer", "code": "" },
+        { "name": "fifth", "kind": "buffer", "code": "" },
+      ],
+    }));
+
+    let (_, unmapped) = export_shader(&shader, "ada");
+    let paths: Vec<&str> = unmapped.iter().map(|item| item.path.as_str()).collect();
+    assert_eq!(paths, ["data.passes[0].name", "data.passes[4]", "data.channels[0]"]);
+
+    let imported = import(r#"{ "info": {}, "renderpass": [
+      { "type": "image", "code": "", "inputs": [
+        { "id": 17, "ctype": "texture", "channel": 0 },
+        { "id": "nope", "ctype": "buffer", "channel": 1 }
+      ] },
+      { "type": "image", "code": "" },
+      { "type": "sound", "code": "" }
+    ] }"#).unwrap();
+    let paths: Vec<&str> = imported.unmapped.iter().map(|item| item.path.as_str()).collect();
+    assert_eq!(paths, ["renderpass[0].inputs[0]", "renderpass[0].inputs[1]", "renderpass[1]", "renderpass[2]"]);
+    assert!(imported.data.channels.is_empty());
+    assert_eq!(imported.attribution.url, None);
+  }
+
+  #[test]
+  fn rejects_malformed_documents() {
+    let malformed = [r#"{ "Shader": {} }"#, r#"{ "info": {} }"#, r#"{ "info": {}, "renderpass": [{ "type": "image" }] }"#, "[]"];
+    for json in malformed {
+      assert!(serde_json::from_str::<ShadertoyImport>(json).is_err(), "{}", json);
+    }
+
+    let Err(ApiError::Validation(errors)) = import(r#"{ "info": {}, "renderpass": [{ "type": "buffer", "code": "" }] }"#) else {
+      panic!("expected a shader without an image pass to be refused");
+    };
+    assert_eq!(errors[0].field, "renderpass");
+
+    // imports are checked against the shader limits like any new shader
+    let limits = ShaderLimits { max_pass_code_bytes: 16, max_document_bytes: 1024, max_passes: 8 };
+    let imported = import(&format!(r#"{{ "info": {{}}, "renderpass": [{{ "type": "image", "code": "{}" }}] }}"#, "x".repeat(17))).unwrap();
+    assert!(validate_shader_payload(&imported.data, &limits).is_err());
+  }
+}
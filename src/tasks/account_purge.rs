@@ -0,0 +1,172 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::{assets::{delete_user_assets, thumbnail::{DeleteThumbnailFiles, Thumbnails}}, constants::ACCOUNT_DELETION_GRACE_DAYS, errors::ApiError, jobs::{self, Job}, router_state::RouterState};
+
+async fn purge_account(state: &RouterState, id: i32) -> Result<bool, ApiError> {
+  let mut tx = state.db.begin().await?;
+
+  // another replica may be purging the same account
+  let user: Option<(sqlx::types::uuid::Uuid,)> = sqlx::query_as(
+    "SELECT user_id FROM users WHERE id = $1 AND deleted = false FOR UPDATE SKIP LOCKED")
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+  let Some((user_id,)) = user else {
+    return Ok(false);
+  };
+
+  let thumbnails: Vec<(String, Option<sqlx::types::Json<Thumbnails>>)> = sqlx::query_as(
+    "DELETE FROM shaders WHERE user_id = $1 RETURNING id, thumbnails")
+    .bind(user_id)
+    .fetch_all(&mut *tx)
+    .await?;
+
+  // templates and reports would outlive the tombstone, their foreign keys only cascade on delete
+  sqlx::query("DELETE FROM shader_templates WHERE user_id = $1")
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+
+  sqlx::query("DELETE FROM reports WHERE reporter_id = $1")
+    .bind(id)
+    .execute(&mut *tx)
+    .await?;
+
+  delete_user_assets(&mut tx, id).await?;
+
+  sqlx::query("DELETE FROM sessions WHERE user_id = $1")
+    .bind(id)
+    .execute(&mut *tx)
+    .await?;
+
+  sqlx::query("DELETE FROM refresh_tokens WHERE user_id = $1")
+    .bind(id)
+    .execute(&mut *tx)
+    .await?;
+
+  sqlx::query("DELETE FROM personal_access_tokens WHERE user_id = $1")
+    .bind(id)
+    .execute(&mut *tx)
+    .await?;
+
+  sqlx::query("DELETE FROM user_identities WHERE user_id = $1")
+    .bind(id)
+    .execute(&mut *tx)
+    .await?;
+
+  sqlx::query("DELETE FROM notifications WHERE user_id = $1")
+    .bind(id)
+    .execute(&mut *tx)
+    .await?;
+
+  sqlx::query("UPDATE users SET
+    email = $2, name = '', username = NULL, role = 'user', deleted = true
+    WHERE id = $1")
+    .bind(id)
+    .bind(format!("deleted-{}@deleted.invalid", user_id))
+    .execute(&mut *tx)
+    .await?;
+
+  for (shader_id, thumbnails) in thumbnails {
+    if let Some(sqlx::types::Json(thumbnails)) = thumbnails {
+      jobs::enqueue(&mut *tx, &DeleteThumbnailFiles { shader_id, thumbnails }).await?;
+    }
+  }
+
+  tx.commit().await?;
+
+  Ok(true)
+}
+
+pub async fn purge_deleted_accounts(state: &RouterState) -> Result<u64, ApiError> {
+  let users: Vec<(i32,)> = sqlx::query_as(
+    "SELECT id FROM users
+    WHERE deleted = false AND deletion_requested_at < NOW() - $1 * INTERVAL '1 day'")
+    .bind(ACCOUNT_DELETION_GRACE_DAYS)
+    .fetch_all(&state.db)
+    .await?;
+
+  let mut purged = 0;
+  for (id,) in users {
+    if purge_account(state, id).await? {
+      purged += 1;
+    }
+  }
+
+  Ok(purged)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PurgeDeletedAccounts {}
+
+#[async_trait]
+impl Job for PurgeDeletedAccounts {
+  const KIND: &'static str = "purge_deleted_accounts";
+
+  async fn run(self, state: &RouterState) -> Result<(), ApiError> {
+    let purged = purge_deleted_accounts(state).await?;
+    if purged > 0 {
+      log::info!("purged {} deleted accounts", purged);
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use crate::{notifications::notify, test_support::{create_user, delete_user, test_db, test_state}};
+
+  use super::*;
+
+  #[tokio::test]
+  async fn purges_everything_of_the_user() {
+    let Some(db) = test_db().await else {
+      return;
+    };
+
+    let state = test_state(db);
+    let user = create_user(&state.db).await;
+    let shader_id = nanoid::nanoid!(6);
+
+    let statements = [
+      "INSERT INTO shaders (id, user_id, name, data) VALUES ($2, $1, 'lib', '{\"code\": \"\"}')",
+      "INSERT INTO shader_libraries (shader_id, user_id, name) VALUES ($2, $1, 'lib')",
+      "INSERT INTO shader_library_revisions (shader_id, revision, code) VALUES ($2, 1, '')",
+      "INSERT INTO shader_templates (id, user_id, name, data) VALUES ($2, $1, 'template', '{}')",
+    ];
+    for statement in statements {
+      sqlx::query(statement).bind(user.user_id).bind(&shader_id).execute(&state.db).await.unwrap();
+    }
+
+    sqlx::query("INSERT INTO reports (reporter_id, target_type, target_id, reason) VALUES ($1, 'shader', 'xxxxxx', 'spam')")
+      .bind(user.id)
+      .execute(&state.db)
+      .await.unwrap();
+    notify(&state.db, user.id, "test", json!({})).await.unwrap();
+
+    assert!(purge_account(&state, user.id).await.unwrap());
+    assert!(!purge_account(&state, user.id).await.unwrap());
+
+    let user_id = user.user_id.to_string();
+    let id = user.id.to_string();
+    let counts = [
+      ("SELECT COUNT(*) FROM shaders WHERE user_id::TEXT = $1", &user_id, 0),
+      ("SELECT COUNT(*) FROM shader_library_revisions WHERE shader_id = $1", &shader_id, 0),
+      ("SELECT COUNT(*) FROM shader_templates WHERE user_id::TEXT = $1", &user_id, 0),
+      ("SELECT COUNT(*) FROM reports WHERE reporter_id::TEXT = $1", &id, 0),
+      ("SELECT COUNT(*) FROM notifications WHERE user_id::TEXT = $1", &id, 0),
+      ("SELECT COUNT(*) FROM users WHERE id::TEXT = $1 AND deleted AND username IS NULL", &id, 1),
+    ];
+    for (query, id, expected) in counts {
+      let (count,): (i64,) = sqlx::query_as(query).bind(id).fetch_one(&state.db).await.unwrap();
+      assert_eq!(count, expected, "{}", query);
+    }
+
+    delete_user(&state.db, &user).await;
+  }
+}
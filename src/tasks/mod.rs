@@ -0,0 +1,25 @@
+use crate::{assets::{thumbnail::DeleteThumbnailFiles, DeleteBlobFile}, constants::{ACCOUNT_PURGE_SCHEDULE, RATE_LIMIT_GC_SCHEDULE, SHADER_PURGE_SCHEDULE}, env::{Env, RateLimitBackend}, jobs::JobRegistry};
+
+pub mod account_purge;
+pub mod rate_limit_gc;
+pub mod session_gc;
+pub mod shader_purge;
+
+pub fn build_job_registry(env: &Env) -> JobRegistry {
+  let mut registry = JobRegistry::new()
+    .register::<DeleteThumbnailFiles>()
+    .register::<DeleteBlobFile>()
+    .schedule("account_purge", ACCOUNT_PURGE_SCHEDULE, account_purge::PurgeDeletedAccounts {})
+    .schedule("shader_purge", SHADER_PURGE_SCHEDULE, shader_purge::PurgeTrashedShaders {});
+
+  // a schedule that isn't registered is dropped, so turning it off takes effect on restart
+  if let Some(config) = &env.session_gc {
+    registry = registry.schedule("session_gc", &config.schedule, session_gc::SweepExpiredSessions {});
+  }
+
+  if env.rate_limits.backend == RateLimitBackend::Postgres {
+    registry = registry.schedule("rate_limit_gc", RATE_LIMIT_GC_SCHEDULE, rate_limit_gc::SweepRateLimits {});
+  }
+
+  registry
+}
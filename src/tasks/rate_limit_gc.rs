@@ -0,0 +1,20 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::{errors::ApiError, jobs::Job, router_state::RouterState};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SweepRateLimits {}
+
+#[async_trait]
+impl Job for SweepRateLimits {
+  const KIND: &'static str = "sweep_rate_limits";
+
+  async fn run(self, state: &RouterState) -> Result<(), ApiError> {
+    sqlx::query("DELETE FROM rate_limits WHERE window_end < NOW()")
+      .execute(&state.db)
+      .await?;
+
+    Ok(())
+  }
+}
@@ -0,0 +1,69 @@
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgArguments, query::Query, PgPool, Postgres};
+
+use crate::{constants::{SESSION_GC_BATCH_SIZE, SESSION_REFRESH_GRACE_TIME}, errors::ApiError, jobs::Job, router_state::RouterState};
+
+async fn delete_in_batches<'q>(db: &PgPool, query: impl Fn() -> Query<'q, Postgres, PgArguments>) -> Result<u64, ApiError> {
+  let mut deleted = 0;
+
+  loop {
+    let batch = query()
+      .bind(SESSION_GC_BATCH_SIZE)
+      .execute(db)
+      .await?
+      .rows_affected();
+
+    deleted += batch;
+    if batch < SESSION_GC_BATCH_SIZE as u64 {
+      return Ok(deleted);
+    }
+  }
+}
+
+// sessions with a live refresh token get refreshed on their next request, so they're kept
+pub async fn sweep_expired_sessions(db: &PgPool, grace_hours: i64) -> Result<(u64, u64), ApiError> {
+  let refresh_tokens = delete_in_batches(db, || sqlx::query(
+    "DELETE FROM refresh_tokens WHERE id IN (
+      SELECT id FROM refresh_tokens WHERE expires_at < NOW() LIMIT $1
+    )")).await?;
+
+  // past the grace period the middleware gives sessions whose refresh failed
+  let sessions = delete_in_batches(db, || sqlx::query(
+    "DELETE FROM sessions WHERE id IN (
+      SELECT id FROM sessions
+      WHERE expires_at < NOW() - $1 * INTERVAL '1 second' - $2 * INTERVAL '1 hour'
+      AND NOT EXISTS (
+        SELECT 1 FROM refresh_tokens WHERE refresh_tokens.user_id = sessions.user_id AND refresh_tokens.expires_at > NOW()
+      )
+      LIMIT $3
+    )")
+    .bind(SESSION_REFRESH_GRACE_TIME)
+    .bind(grace_hours)).await?;
+
+  Ok((sessions, refresh_tokens))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SweepExpiredSessions {}
+
+#[async_trait]
+impl Job for SweepExpiredSessions {
+  const KIND: &'static str = "sweep_expired_sessions";
+
+  async fn run(self, state: &RouterState) -> Result<(), ApiError> {
+    // queued before the sweeper was turned off
+    let Some(config) = &state.env.session_gc else {
+      return Ok(());
+    };
+
+    // there's no metrics pipeline to report to, the counts are logged and what's left to
+    // sweep shows in the admin stats
+    match sweep_expired_sessions(&state.db, config.grace_hours).await? {
+      (0, 0) => (),
+      (sessions, refresh_tokens) => log::info!("swept {} expired sessions and {} expired refresh tokens", sessions, refresh_tokens),
+    }
+
+    Ok(())
+  }
+}
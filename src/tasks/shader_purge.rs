@@ -0,0 +1,168 @@
+use std::collections::{BTreeMap, HashSet};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::{assets::{release_blob, thumbnail::DeleteThumbnailFiles}, audit::{self, AuditAction, AuditEvent, AuditTarget, RequestMeta}, constants::SHADER_PURGE_LOCK, errors::ApiError, jobs::{self, Job}, notifications::{notify, SHADER_PURGE_SCHEDULED}, router_state::RouterState, routes::shader::{Shader, ShaderData}};
+
+async fn warn_owners(state: &RouterState) -> Result<u64, ApiError> {
+  let retention = &state.env.shader_trash;
+  let mut tx = state.db.begin().await?;
+
+  let warned: Vec<(i32, String, String, chrono::DateTime<chrono::Utc>)> = sqlx::query_as(
+    "UPDATE shaders SET purge_warned_at = NOW() FROM users
+    WHERE users.user_id = shaders.user_id AND shaders.deleted = true AND shaders.purge_warned_at IS NULL
+    AND shaders.deleted_at < NOW() - ($1 - $2) * INTERVAL '1 day'
+    RETURNING users.id, shaders.id, shaders.name,
+      GREATEST(shaders.deleted_at + $1 * INTERVAL '1 day', NOW() + $2 * INTERVAL '1 day')")
+    .bind(retention.retention_days)
+    .bind(retention.warning_days)
+    .fetch_all(&mut *tx)
+    .await?;
+
+  let mut by_owner: BTreeMap<i32, Vec<serde_json::Value>> = BTreeMap::new();
+  for (owner, id, name, purge_at) in &warned {
+    by_owner.entry(*owner).or_default().push(serde_json::json!({
+      "id": id,
+      "name": name,
+      "purge_at": purge_at,
+    }));
+  }
+
+  for (owner, shaders) in by_owner {
+    notify(&mut *tx, owner, SHADER_PURGE_SCHEDULED, serde_json::json!({ "shaders": shaders })).await?;
+  }
+
+  tx.commit().await?;
+
+  Ok(warned.len() as u64)
+}
+
+async fn bound_assets(conn: &mut sqlx::PgConnection, user_id: sqlx::types::uuid::Uuid) -> Result<HashSet<String>, ApiError> {
+  let documents: Vec<(sqlx::types::Json<ShaderData>,)> = sqlx::query_as(
+    "SELECT data FROM shaders WHERE user_id = $1
+    UNION ALL SELECT data FROM shader_templates WHERE user_id = $1")
+    .bind(user_id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+  Ok(documents.iter()
+    .flat_map(|(data,)| data.channel_bindings().filter_map(|(_, binding)| binding.asset_id().map(str::to_string)))
+    .collect())
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct PurgedShader {
+  #[sqlx(flatten)]
+  shader: Shader,
+  user_id: sqlx::types::uuid::Uuid,
+  owner_id: i32,
+}
+
+async fn purge_shader(state: &RouterState, id: &str) -> Result<bool, ApiError> {
+  let mut tx = state.db.begin().await?;
+
+  // the owner may be restoring it right now
+  let expired: Option<(String,)> = sqlx::query_as(
+    "SELECT id FROM shaders
+    WHERE id = $1 AND deleted = true AND deleted_at < NOW() - $2 * INTERVAL '1 day'
+    AND purge_warned_at <= NOW() - $3 * INTERVAL '1 day'
+    FOR UPDATE SKIP LOCKED")
+    .bind(id)
+    .bind(state.env.shader_trash.retention_days)
+    .bind(state.env.shader_trash.warning_days)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+  if expired.is_none() {
+    return Ok(false);
+  }
+
+  // libraries and their revisions go with the shader
+  let PurgedShader { shader, user_id, owner_id } = sqlx::query_as(
+    "DELETE FROM shaders USING users WHERE shaders.id = $1 AND users.user_id = shaders.user_id
+    RETURNING shaders.*, users.id AS owner_id")
+    .bind(id)
+    .fetch_one(&mut *tx)
+    .await?;
+
+  let still_bound = bound_assets(&mut tx, user_id).await?;
+  let orphaned: Vec<&str> = shader.data.channel_bindings()
+    .filter_map(|(_, binding)| binding.asset_id())
+    .filter(|asset_id| !still_bound.contains(*asset_id))
+    .collect();
+
+  let hashes: Vec<(String,)> = sqlx::query_as("DELETE FROM assets WHERE user_id = $1 AND id = ANY($2) RETURNING blob_hash")
+    .bind(owner_id)
+    .bind(&orphaned)
+    .fetch_all(&mut *tx)
+    .await?;
+
+  for (hash,) in hashes {
+    release_blob(&mut tx, &hash).await?;
+  }
+
+  audit::record(&mut *tx, &RequestMeta::default(), AuditEvent::new(None, AuditAction::ShaderPurge, AuditTarget::Shader, id)
+    .before(shader.audit_snapshot())).await?;
+
+  if let Some(thumbnails) = shader.thumbnails {
+    jobs::enqueue(&mut *tx, &DeleteThumbnailFiles { shader_id: shader.id, thumbnails: thumbnails.0 }).await?;
+  }
+
+  tx.commit().await?;
+
+  Ok(true)
+}
+
+// replicas take turns through an advisory lock, none when another one holds it
+pub async fn purge_trashed_shaders(state: &RouterState) -> Result<Option<(u64, u64)>, ApiError> {
+  let mut lock = state.db.begin().await?;
+
+  let (locked,): (bool,) = sqlx::query_as("SELECT pg_try_advisory_xact_lock($1)")
+    .bind(SHADER_PURGE_LOCK)
+    .fetch_one(&mut *lock)
+    .await?;
+
+  if !locked {
+    return Ok(None);
+  }
+
+  let warned = warn_owners(state).await?;
+
+  // shaders warned about late, after downtime or a longer warning period, get the full period
+  let shaders: Vec<(String,)> = sqlx::query_as(
+    "SELECT id FROM shaders WHERE deleted = true AND deleted_at < NOW() - $1 * INTERVAL '1 day'
+    AND purge_warned_at <= NOW() - $2 * INTERVAL '1 day'")
+    .bind(state.env.shader_trash.retention_days)
+    .bind(state.env.shader_trash.warning_days)
+    .fetch_all(&state.db)
+    .await?;
+
+  let mut purged = 0;
+  for (id,) in shaders {
+    if purge_shader(state, &id).await? {
+      purged += 1;
+    }
+  }
+
+  lock.commit().await?;
+
+  Ok(Some((warned, purged)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PurgeTrashedShaders {}
+
+#[async_trait]
+impl Job for PurgeTrashedShaders {
+  const KIND: &'static str = "purge_trashed_shaders";
+
+  async fn run(self, state: &RouterState) -> Result<(), ApiError> {
+    match purge_trashed_shaders(state).await? {
+      Some((0, 0)) | None => (),
+      Some((warned, purged)) => log::info!("warned owners of {} trashed shaders, purged {}", warned, purged),
+    }
+
+    Ok(())
+  }
+}
@@ -0,0 +1,79 @@
+use sqlx::{postgres::PgPoolOptions, types::Json, PgPool};
+
+use crate::{env::{test_env, AssetStorageConfig, Env}, router_state::{RouterState, UserProfile}, routes::shader::{AccessLevel, Shader}};
+
+const NAME_ALPHABET: [char; 36] = [
+  'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r',
+  's', 't', 'u', 'v', 'w', 'x', 'y', 'z', '0', '1', '2', '3', '4', '5', '6', '7', '8', '9',
+];
+
+// tests that need postgres run against TEST_DATABASE_URL and are skipped without it
+pub async fn test_db() -> Option<PgPool> {
+  let Ok(url) = std::env::var("TEST_DATABASE_URL") else {
+    eprintln!("TEST_DATABASE_URL isn't set, skipping");
+    return None;
+  };
+
+  let db = PgPoolOptions::new()
+    .max_connections(5)
+    .connect(&url)
+    .await.expect("failed to connect to TEST_DATABASE_URL");
+
+  sqlx::migrate!().run(&db).await.expect("failed to run migrations");
+
+  Some(db)
+}
+
+pub fn temp_dir() -> std::path::PathBuf {
+  let dir = std::env::temp_dir().join(format!("shaderx-test-{}", nanoid::nanoid!()));
+  std::fs::create_dir_all(&dir).unwrap();
+  dir
+}
+
+// assets go to a directory of their own so tests don't see each other's files
+pub fn test_state(db: PgPool) -> RouterState {
+  let mut env: Env = test_env();
+  env.assets.storage = AssetStorageConfig::Local { root: temp_dir().to_string_lossy().into_owned() };
+
+  RouterState::new(db, &env)
+}
+
+pub async fn create_user(db: &PgPool) -> UserProfile {
+  let name = format!("test_{}", nanoid::nanoid!(12, &NAME_ALPHABET));
+
+  sqlx::query_as("INSERT INTO users (email, name, username) VALUES ($1, $2, $2) RETURNING *")
+    .bind(format!("{}@example.com", name))
+    .bind(&name)
+    .fetch_one(db)
+    .await.unwrap()
+}
+
+pub async fn delete_user(db: &PgPool, user: &UserProfile) {
+  sqlx::query("DELETE FROM shaders WHERE user_id = $1").bind(user.user_id).execute(db).await.unwrap();
+  sqlx::query("DELETE FROM sessions WHERE user_id = $1").bind(user.id).execute(db).await.unwrap();
+  sqlx::query("DELETE FROM users WHERE id = $1").bind(user.id).execute(db).await.unwrap();
+}
+
+pub fn test_shader(id: &str, data: serde_json::Value) -> Shader {
+  let now = chrono::Utc::now();
+
+  Shader {
+    id: id.to_string(),
+    name: "Test shader".to_string(),
+    description: "a shader of the tests".to_string(),
+    access: AccessLevel::Unlisted,
+    tags: serde_json::json!(["test", "buffers"]),
+    data: Json(serde_json::from_value(data).unwrap()),
+    thumbnails: None,
+    embed_enabled: false,
+    embed_origins: Vec::new(),
+    license: "MIT".to_string(),
+    attribution: Json(Vec::new()),
+    forked_from: None,
+    hidden: false,
+    access_locked: false,
+    deleted_at: None,
+    created_at: now,
+    updated_at: now,
+  }
+}
@@ -0,0 +1,34 @@
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub const TOKEN_PREFIX: &str = "shx_";
+const TOKEN_LENGTH: usize = 40;
+const DISPLAY_PREFIX_LENGTH: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "token_scope")]
+pub enum TokenScope {
+  #[serde(rename = "shaders:read")]
+  #[sqlx(rename = "shaders:read")]
+  ShadersRead,
+  #[serde(rename = "shaders:write")]
+  #[sqlx(rename = "shaders:write")]
+  ShadersWrite,
+  #[serde(rename = "profile:read")]
+  #[sqlx(rename = "profile:read")]
+  ProfileRead,
+}
+
+pub fn generate_token() -> String {
+  format!("{}{}", TOKEN_PREFIX, nanoid!(TOKEN_LENGTH))
+}
+
+// tokens are random enough that a plain digest is safe to store
+pub fn hash_token(token: &str) -> String {
+  hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+pub fn display_prefix(token: &str) -> String {
+  token.chars().take(DISPLAY_PREFIX_LENGTH).collect()
+}
@@ -0,0 +1,186 @@
+use axum::{async_trait, extract::{FromRequest, Request}, response::{IntoResponse, Response}, Json};
+use serde::de::DeserializeOwned;
+
+use crate::{env::ShaderLimits, errors::{ApiError, FieldError}, router_state::RouterState};
+
+pub trait Validate {
+  fn validate(&self, v: &mut Validator);
+}
+
+#[derive(Default)]
+pub struct Validator<'a> {
+  limits: Option<&'a ShaderLimits>,
+  errors: Vec<FieldError>,
+}
+
+impl<'a> Validator<'a> {
+  pub fn with_limits(mut self, limits: &'a ShaderLimits) -> Self {
+    self.limits = Some(limits);
+    self
+  }
+
+  pub fn limits(&self) -> Option<ShaderLimits> {
+    self.limits.copied()
+  }
+
+  pub fn error(&mut self, field: &str, message: impl Into<String>) {
+    self.errors.push(FieldError::new(field, message));
+  }
+
+  pub fn check(&mut self, field: &str, valid: bool, message: impl Into<String>) {
+    if !valid {
+      self.error(field, message);
+    }
+  }
+
+  // characters, which is what postgres counts for VARCHAR(n)
+  pub fn length(&mut self, field: &str, value: &str, min: usize, max: usize) {
+    let length = value.chars().count();
+
+    if min > 0 && value.trim().is_empty() {
+      self.error(field, "must not be empty");
+    } else if length < min || length > max {
+      self.error(field, format!("must be between {} and {} characters long", min, max));
+    }
+  }
+
+  pub fn max_bytes(&mut self, field: &str, size: usize, max: usize) {
+    if size > max {
+      self.error(field, format!("is {} bytes, the limit is {} bytes", size, max));
+    }
+  }
+
+  pub fn into_errors(self) -> Vec<FieldError> {
+    self.errors
+  }
+
+  pub fn finish(self) -> Result<(), ApiError> {
+    if !self.errors.is_empty() {
+      return Err(ApiError::Validation(self.errors));
+    }
+
+    Ok(())
+  }
+}
+
+pub fn validate_payload<T: Validate>(value: &T) -> Result<(), ApiError> {
+  let mut validator = Validator::default();
+  value.validate(&mut validator);
+  validator.finish()
+}
+
+pub fn validate_shader_payload<T: Validate>(value: &T, limits: &ShaderLimits) -> Result<(), ApiError> {
+  let mut validator = Validator::default().with_limits(limits);
+  value.validate(&mut validator);
+  validator.finish()
+}
+
+pub struct ValidatedJson<T>(pub T);
+
+#[async_trait]
+impl<T> FromRequest<RouterState> for ValidatedJson<T>
+where
+  T: DeserializeOwned + Validate,
+{
+  type Rejection = Response;
+
+  async fn from_request(req: Request, state: &RouterState) -> Result<Self, Self::Rejection> {
+    let Json(value) = Json::<T>::from_request(req, state).await
+      .map_err(IntoResponse::into_response)?;
+
+    // limits are cheap to hand along, whether or not the payload has a shader document
+    validate_shader_payload(&value, &state.env.shader_limits)
+      .map_err(IntoResponse::into_response)?;
+
+    Ok(Self(value))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use axum::{body::Body, http::{header::CONTENT_TYPE, StatusCode}};
+  use serde::Deserialize;
+  use sqlx::postgres::PgPoolOptions;
+
+  use crate::test_support::test_state;
+
+  use super::*;
+
+  fn fields(errors: &[FieldError]) -> Vec<(&str, &str)> {
+    errors.iter().map(|error| (error.field.as_str(), error.message.as_str())).collect()
+  }
+
+  #[test]
+  fn lengths_count_characters() {
+    let cases = [
+      ("abc", 1, 5, None),
+      ("", 0, 5, None),
+      ("ééé", 1, 3, None),
+      ("", 1, 5, Some("must not be empty")),
+      ("   ", 1, 5, Some("must not be empty")),
+      ("abcdef", 1, 5, Some("must be between 1 and 5 characters long")),
+      ("ab", 3, 5, Some("must be between 3 and 5 characters long")),
+    ];
+
+    for (value, min, max, expected) in cases {
+      let mut v = Validator::default();
+      v.length("name", value, min, max);
+      let errors = v.into_errors();
+      assert_eq!(fields(&errors), expected.map(|message| ("name", message)).into_iter().collect::<Vec<_>>(), "{:?}", value);
+    }
+  }
+
+  #[test]
+  fn collects_every_error() {
+    let mut v = Validator::default();
+    v.max_bytes("code", 10, 10);
+    v.check("passes", true, "unused");
+    v.max_bytes("code", 11, 10);
+    v.check("passes", false, "too many");
+    assert!(v.limits().is_none());
+
+    let Err(ApiError::Validation(errors)) = v.finish() else {
+      panic!("expected validation errors");
+    };
+    assert_eq!(fields(&errors), [("code", "is 11 bytes, the limit is 10 bytes"), ("passes", "too many")]);
+    assert!(Validator::default().finish().is_ok());
+  }
+
+  #[derive(Deserialize)]
+  struct Payload {
+    name: String,
+  }
+
+  impl Validate for Payload {
+    fn validate(&self, v: &mut Validator) {
+      v.length("name", &self.name, 1, 8);
+      v.check("limits", v.limits().is_some(), "are missing");
+    }
+  }
+
+  #[tokio::test]
+  async fn extracts_valid_json_only() {
+    // never connects, nothing here touches the database
+    let db = PgPoolOptions::new().connect_lazy("postgres://localhost/unused").unwrap();
+    let state = test_state(db);
+
+    let cases = [
+      (r#"{"name": "ok"}"#, None),
+      (r#"{"name": ""}"#, Some(StatusCode::UNPROCESSABLE_ENTITY)),
+      (r#"{"name": "far too long"}"#, Some(StatusCode::UNPROCESSABLE_ENTITY)),
+      (r#"{"title": "ok"}"#, Some(StatusCode::UNPROCESSABLE_ENTITY)),
+      (r#"{"name": "#, Some(StatusCode::BAD_REQUEST)),
+    ];
+
+    for (body, expected) in cases {
+      let request = Request::builder()
+        .method("POST")
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+        .unwrap();
+
+      let status = ValidatedJson::<Payload>::from_request(request, &state).await.err().map(|response| response.status());
+      assert_eq!(status, expected, "{}", body);
+    }
+  }
+}